@@ -0,0 +1,73 @@
+//! Compara el logger que escribe directamente desde cada hilo con el
+//! que encola los mensajes para un hilo propio, con varios hilos
+//! logueando a la vez a un archivo, como los workers en modo debug.
+//!
+//! Correr con `cargo bench --bench logger`.
+use std::{
+    env, fs,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use dinero_oxidado::{
+    Logger,
+    cli::{ConfiguracionRotacion, PoliticaColaLlena, TaggedLogger},
+};
+
+const HILOS: u32 = 8;
+const MENSAJES_POR_HILO: u32 = 20_000;
+const CAPACIDAD_COLA: usize = 8192;
+
+/// Devuelve cuánto tardaron los hilos en loguear todos sus mensajes y
+/// cuánto se tardó hasta que quedaron escritos
+fn medir(logger: Logger) -> (Duration, Duration) {
+    let logger = Arc::new(logger);
+    let inicio = Instant::now();
+    let handles: Vec<_> = (0..HILOS)
+        .map(|id| {
+            let log = TaggedLogger::new(&format!("WORKER CashIn {}", id), logger.clone()).con_campo("worker_id", id);
+            thread::spawn(move || {
+                for i in 0..MENSAJES_POR_HILO {
+                    log.write(&format!("TransaccionAutorizada (id = {})", i));
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let logueado = inicio.elapsed();
+    // Soltar el logger espera a que se escriba lo encolado
+    drop(logger);
+
+    (logueado, inicio.elapsed())
+}
+
+fn main() {
+    let ruta = env::temp_dir().join("dinero_oxidado_bench_logger.log");
+    let ruta = ruta.to_str().unwrap();
+    let total = (HILOS * MENSAJES_POR_HILO) as f64;
+
+    println!("{} hilos, {} mensajes cada uno", HILOS, MENSAJES_POR_HILO);
+    println!("{:<28} {:>12} {:>12} {:>14} {:>8}", "Logger", "Logueado", "Escrito", "Mensajes/s", "Líneas");
+    let escenarios = [
+        ("Directo", None),
+        ("Segundo plano (bloquear)", Some(PoliticaColaLlena::Bloquear)),
+        ("Segundo plano (descartar)", Some(PoliticaColaLlena::Descartar)),
+    ];
+    for (nombre, politica) in escenarios.iter() {
+        let logger = Logger::new_to_file_con_rotacion(ruta, ConfiguracionRotacion::default(), "").unwrap();
+        let logger = match politica {
+            Some(politica) => logger.en_segundo_plano(CAPACIDAD_COLA, *politica),
+            None => logger,
+        };
+        let (logueado, escrito) = medir(logger);
+        let lineas = fs::read_to_string(ruta).unwrap().lines().count();
+        println!(
+            "{:<28} {:>12.2?} {:>12.2?} {:>14.0} {:>8}",
+            nombre, logueado, escrito, total / logueado.as_secs_f64(), lineas
+        );
+    }
+    let _ = fs::remove_file(ruta);
+}
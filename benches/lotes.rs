@@ -0,0 +1,93 @@
+//! Compara cuántas transacciones por segundo liquida el pipeline con y
+//! sin autorización por lotes, con el proveedor simulado contestando
+//! con demora y con una cuota de llamadas.
+//!
+//! Correr con `cargo bench --bench lotes`.
+use std::{
+    env, fs,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+use dinero_oxidado::{
+    Cliente, Configuracion, Logger, Pipeline, Redactor, TipoTransaccion, Transaccion, Traza,
+    cli::ConfiguracionRotacion,
+};
+
+const CLIENTES: usize = 50;
+const TRANSACCIONES: u32 = 1_000;
+const WORKERS: u32 = 4;
+const LOTE: &str = "20:5";
+
+/// El proveedor es el cuello de botella: la ia no demora y no detecta
+/// lavado, así que se liquida todo
+fn crear_configuracion(directorio: &Path, perfil: &str, limite: Option<&str>, lote: Option<&str>) -> Configuracion {
+    let mut configuracion = Configuracion::default();
+    let en_directorio = |archivo: &str| directorio.join(archivo).to_string_lossy().into_owned();
+    let archivos = &mut configuracion.archivos;
+    archivos.saldos = en_directorio("saldos.csv");
+    archivos.rechazadas = en_directorio("rechazadas.csv");
+    archivos.fallidas = en_directorio("fallidas.csv");
+    configuracion.workers.cash_in = WORKERS;
+    configuracion.workers.cash_out = WORKERS;
+    configuracion.ia.probabilidad_lavado = 0.0;
+    configuracion.ia.tiempo_maximo_ms = 1;
+    configuracion.semillas.ia = Some(1);
+    configuracion.semillas.proveedor = Some(1);
+    configuracion.proveedor.perfil = perfil.into();
+    configuracion.proveedor.limite = limite.map(String::from);
+    configuracion.proveedor.lote = lote.map(String::from);
+
+    configuracion
+}
+
+/// Devuelve cuánto tardó en terminar de liquidar todas las transacciones
+fn medir(configuracion: &Configuracion, log: &str) -> Duration {
+    let clientes: Vec<_> = (0..CLIENTES).map(|_| Arc::new(Cliente::con_saldo(Uuid::new_v4(), 1_000.0))).collect();
+    let logger = Arc::new(Logger::new_to_file_con_rotacion(log, ConfiguracionRotacion::default(), "").unwrap());
+    let pipeline = Pipeline::iniciar(configuracion, Arc::new(clientes.clone()), logger, Arc::new(Redactor::default())).unwrap();
+    let inicio = Instant::now();
+    for id in 0..TRANSACCIONES {
+        let cliente = &clientes[id as usize % CLIENTES];
+        let transaccion = Transaccion {
+            id,
+            id_cliente: cliente.id,
+            timestamp: 112_315_846_128,
+            tipo: TipoTransaccion::CashIn,
+            monto: 10.0,
+            traza: Traza::default(),
+            prioridad: None,
+        };
+        pipeline.enviar(transaccion).unwrap();
+    }
+    pipeline.terminar().unwrap();
+
+    inicio.elapsed()
+}
+
+fn main() {
+    let directorio = env::temp_dir().join("dinero_oxidado_bench_lotes");
+    let _ = fs::remove_dir_all(&directorio);
+    fs::create_dir_all(&directorio).unwrap();
+    let log = directorio.join("log.txt");
+    let log = log.to_str().unwrap();
+
+    println!("{} transacciones, {} workers por tipo, lotes de {}", TRANSACCIONES, WORKERS, LOTE);
+    println!("{:<30} {:>12} {:>12} {:>12} {:>12}", "Proveedor", "Sin lotes", "Tx/s", "Con lotes", "Tx/s");
+    let escenarios = [
+        ("lento", None, "demora media 40 ms"),
+        ("normal", Some("200"), "cuota de 200 llamadas/s"),
+    ];
+    for (perfil, limite, descripcion) in escenarios.iter() {
+        let sin_lotes = medir(&crear_configuracion(&directorio, perfil, *limite, None), log);
+        let con_lotes = medir(&crear_configuracion(&directorio, perfil, *limite, Some(LOTE)), log);
+        let por_segundo = |duracion: Duration| TRANSACCIONES as f64 / duracion.as_secs_f64();
+        println!(
+            "{:<30} {:>12.2?} {:>12.0} {:>12.2?} {:>12.0}",
+            descripcion, sin_lotes, por_segundo(sin_lotes), con_lotes, por_segundo(con_lotes)
+        );
+    }
+    let _ = fs::remove_dir_all(&directorio);
+}
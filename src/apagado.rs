@@ -0,0 +1,125 @@
+use std::{
+    sync::{Arc, atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering}},
+    thread,
+    time::Duration,
+};
+use signal_hook::{consts::{SIGINT, SIGTERM}, iterator::Signals, low_level::signal_name};
+
+use crate::logger::{NivelLog, TaggedLogger};
+
+/// Qué hacer cuando hay que abortar la corrida. No vuelve: termina el
+/// proceso.
+pub type Abortar = Arc<dyn Fn() + Send + Sync>;
+
+/// Pedido de apagado de la corrida. Con el primer pedido el procesador
+/// deja de leer el archivo y el resto de las etapas terminan lo que ya
+/// está en curso; con el segundo, o si el drenaje tarda demasiado, se
+/// aborta.
+#[derive(Debug, Default)]
+pub struct Apagado {
+    pedidos: AtomicU32,
+    terminado: AtomicBool,
+    sin_leer: AtomicUsize,
+}
+
+impl Apagado {
+    /// Registra un pedido de apagado y devuelve cuántos van
+    pub fn pedir(&self) -> u32 {
+        self.pedidos.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    pub fn drenando(&self) -> bool {
+        self.pedidos.load(Ordering::SeqCst) > 0
+    }
+
+    /// La corrida terminó de drenar: ya no se aborta por timeout
+    pub fn terminar(&self) {
+        self.terminado.store(true, Ordering::SeqCst);
+    }
+
+    pub fn terminado(&self) -> bool {
+        self.terminado.load(Ordering::SeqCst)
+    }
+
+    /// Transacciones que quedaron en el archivo sin leer
+    pub fn dejar_sin_leer(&self, cantidad: usize) {
+        self.sin_leer.store(cantidad, Ordering::SeqCst);
+    }
+
+    pub fn sin_leer(&self) -> usize {
+        self.sin_leer.load(Ordering::SeqCst)
+    }
+
+    /// Atiende una señal: la primera empieza el drenaje y arma el
+    /// timeout, las siguientes abortan
+    pub fn atender_senal(self: &Arc<Self>, log: &TaggedLogger, senal: &str, espera_maxima: Duration, abortar: &Abortar) {
+        if self.pedir() > 1 {
+            log.evento(NivelLog::Error, &format!("{} recibida otra vez: abortando", senal), &[]);
+            abortar();
+            return;
+        }
+
+        log.warn(&format!(
+            "{} recibida: no se leen más transacciones y se terminan las que están en curso. Otra señal aborta la corrida",
+            senal
+        ));
+        let apagado = self.clone();
+        let log = log.clone();
+        let abortar = abortar.clone();
+        thread::spawn(move || {
+            thread::sleep(espera_maxima);
+            if !apagado.terminado() {
+                log.evento(NivelLog::Error, &format!("Las transacciones en curso no terminaron en {:?}: abortando", espera_maxima), &[]);
+                abortar();
+            }
+        });
+    }
+}
+
+/// Atiende SIGINT y SIGTERM en un hilo suelto mientras dura el proceso
+pub fn escuchar_senales(log: TaggedLogger, apagado: Arc<Apagado>, espera_maxima: Duration, abortar: Abortar) -> Result<(), String> {
+    let mut senales = Signals::new([SIGINT, SIGTERM]).map_err(|e| format!("No se pudieron atender las señales: {}", e))?;
+    thread::spawn(move || {
+        for senal in senales.forever() {
+            apagado.atender_senal(&log, signal_name(senal).unwrap_or("Señal"), espera_maxima, &abortar);
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::logger::Logger;
+
+    #[test]
+    fn la_segunda_senal_o_el_timeout_abortan() {
+        let log = TaggedLogger::new("APAGADO", Arc::new(Logger::new_to_stdout()));
+        let abortos = Arc::new(Mutex::new(0));
+        let abortos_c = abortos.clone();
+        let abortar: Abortar = Arc::new(move || *abortos_c.lock().unwrap() += 1);
+
+        let apagado = Arc::new(Apagado::default());
+        assert!(!apagado.drenando());
+        apagado.atender_senal(&log, "SIGINT", Duration::from_secs(60), &abortar);
+        assert!(apagado.drenando());
+        assert_eq!(*abortos.lock().unwrap(), 0);
+        apagado.atender_senal(&log, "SIGINT", Duration::from_secs(60), &abortar);
+        assert_eq!(*abortos.lock().unwrap(), 1);
+
+        let apagado = Arc::new(Apagado::default());
+        apagado.atender_senal(&log, "SIGTERM", Duration::from_millis(20), &abortar);
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(*abortos.lock().unwrap(), 2);
+
+        // Si la corrida terminó a tiempo no se aborta
+        let apagado = Arc::new(Apagado::default());
+        apagado.atender_senal(&log, "SIGTERM", Duration::from_millis(20), &abortar);
+        apagado.terminar();
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(*abortos.lock().unwrap(), 2);
+    }
+}
@@ -0,0 +1,33 @@
+//! Mensajes de la API HTTP del proveedor de autorizaciones. Los
+//! comparten el cliente (`proveedor_http`) y el binario `proveedor_local`.
+//!
+//! El cliente envía un `POST` a la url configurada con un
+//! `PedidoAutorizacion` en JSON. El proveedor contesta 200 con un
+//! `ResultadoAutorizacion`, 429 si está limitando solicitudes, 503 si
+//! no está disponible y cualquier otro código ante un error interno.
+//!
+//! Para pedir varias autorizaciones en una llamada se envía un arreglo
+//! de pedidos a la url terminada en `SUFIJO_LOTE`. El proveedor contesta
+//! un arreglo de resultados en el mismo orden; los códigos de error se
+//! aplican a todo el lote.
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub const RUTA_AUTORIZACIONES: &str = "/autorizaciones";
+pub const SUFIJO_LOTE: &str = "/lote";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PedidoAutorizacion {
+    pub id_transaccion: u32,
+    pub id_cliente: Uuid,
+    /// "cash_in" o "cash_out"
+    pub tipo: String,
+    pub monto: f32,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "resultado", rename_all = "snake_case")]
+pub enum ResultadoAutorizacion {
+    Autorizada { hash: Uuid },
+    Denegada { motivo: String },
+}
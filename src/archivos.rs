@@ -0,0 +1,184 @@
+use std::{io, sync::{Arc, mpsc::{Receiver, TryRecvError}}};
+use csv::{Reader, Writer};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    cliente::Cliente,
+    estados::EstadoTransaccion,
+    transaccion::{TipoTransaccion, Transaccion},
+};
+
+pub const ARCHIVO_TRANSACCIONES: &str = "transacciones.csv";
+pub const ARCHIVO_CLIENTES: &str = "clientes.csv";
+pub const ARCHIVO_SALDOS: &str = "saldos.csv";
+pub const ARCHIVO_RECHAZADAS: &str = "rechazadas.csv";
+pub const ARCHIVO_FALLIDAS: &str = "fallidas.csv";
+pub const ARCHIVO_ESTADOS: &str = "estados.csv";
+pub const ARCHIVO_REPORTE: &str = "reporte.csv";
+pub const ARCHIVO_EN_CURSO: &str = "en_curso.csv";
+pub const ARCHIVO_DECISIONES: &str = "decisiones.csv";
+pub const ARCHIVO_COMPARACION: &str = "comparacion_sombra.csv";
+
+/// Archivos que lee y escribe el pipeline en una corrida
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArchivosCorrida {
+    /// Entrada: transacciones pendientes
+    pub transacciones: String,
+    /// Entrada: clientes con su saldo al terminar la simulación
+    pub clientes: String,
+    pub saldos: String,
+    pub rechazadas: String,
+    pub fallidas: String,
+    pub estados: String,
+    /// Salida de `reportar`: resumen por cliente
+    pub reporte: String,
+    /// Solo si se aborta la corrida: las transacciones que no llegaron a
+    /// un estado final
+    pub en_curso: String,
+    /// Solo con un modelo cargado: su decisión sobre cada transacción
+    pub decisiones: String,
+    /// Solo con un detector en modo sombra: dónde coincide y dónde no con
+    /// el detector activo
+    pub comparacion: String,
+}
+
+impl Default for ArchivosCorrida {
+    fn default() -> Self {
+        Self {
+            transacciones: ARCHIVO_TRANSACCIONES.into(),
+            clientes: ARCHIVO_CLIENTES.into(),
+            saldos: ARCHIVO_SALDOS.into(),
+            rechazadas: ARCHIVO_RECHAZADAS.into(),
+            fallidas: ARCHIVO_FALLIDAS.into(),
+            estados: ARCHIVO_ESTADOS.into(),
+            reporte: ARCHIVO_REPORTE.into(),
+            en_curso: ARCHIVO_EN_CURSO.into(),
+            decisiones: ARCHIVO_DECISIONES.into(),
+            comparacion: ARCHIVO_COMPARACION.into(),
+        }
+    }
+}
+
+/// Un cliente en el archivo de clientes. El id va sin redactar: el
+/// archivo es entrada del pipeline, como el de transacciones.
+#[derive(Debug, Serialize, Deserialize)]
+struct RegistroCliente {
+    #[serde(rename = "User_id")]
+    id: Uuid,
+    #[serde(rename = "Balance")]
+    saldo: f32,
+}
+
+/// Una fila de `saldos.csv`, con lo que hace falta para verificar y
+/// resumir la corrida
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RegistroSaldo {
+    #[serde(rename = "Transaction")]
+    pub id_transaccion: u32,
+    #[serde(rename = "Type")]
+    pub tipo: TipoTransaccion,
+    #[serde(rename = "Amount")]
+    pub monto: f32,
+    #[serde(rename = "Final_balance")]
+    pub saldo_final: f32,
+}
+
+/// Una fila de `rechazadas.csv` o `fallidas.csv`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RegistroRechazo {
+    #[serde(rename = "Transaction")]
+    pub id_transaccion: u32,
+    #[serde(rename = "Type")]
+    pub tipo: TipoTransaccion,
+    #[serde(rename = "Amount")]
+    pub monto: f32,
+    #[serde(rename = "Reason")]
+    pub motivo: String,
+}
+
+/// Una fila de `estados.csv`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RegistroEstadoFinal {
+    #[serde(rename = "Transaction")]
+    pub id_transaccion: u32,
+    #[serde(rename = "State")]
+    pub estado: EstadoTransaccion,
+}
+
+pub fn escribir_clientes(clientes: &[Arc<Cliente>], ruta_archivo: &str) -> Result<(), csv::Error> {
+    let mut writer = Writer::from_path(ruta_archivo)?;
+    for cliente in clientes {
+        writer.serialize(RegistroCliente { id: cliente.id, saldo: cliente.get_saldos().contable })?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Espera lo próximo que hay que escribir con `writer`. Si no hay nada
+/// pendiente antes vacía su buffer, así una corrida abortada no deja
+/// filas a medio escribir. Devuelve None cuando se cerró el canal.
+pub fn recibir_vaciando<T, W: io::Write>(rx: &Receiver<T>, writer: &mut Writer<W>) -> Option<T> {
+    match rx.try_recv() {
+        Ok(valor) => Some(valor),
+        Err(TryRecvError::Empty) => {
+            writer.flush().expect("No se pudo escribir el archivo de salida");
+            rx.recv().ok()
+        }
+        Err(TryRecvError::Disconnected) => None,
+    }
+}
+
+/// Carga los clientes de un archivo escrito por `escribir_clientes`
+pub fn leer_clientes(ruta_archivo: &str) -> Result<Arc<Vec<Arc<Cliente>>>, csv::Error> {
+    let clientes = leer::<RegistroCliente>(ruta_archivo)?
+        .into_iter()
+        .map(|registro| Arc::new(Cliente::con_saldo(registro.id, registro.saldo)))
+        .collect();
+
+    Ok(Arc::new(clientes))
+}
+
+/// Lee las filas de un archivo de transacciones o de salida del
+/// pipeline, en orden
+pub fn leer<T: serde::de::DeserializeOwned>(ruta_archivo: &str) -> Result<Vec<T>, csv::Error> {
+    Reader::from_path(ruta_archivo)?.deserialize().collect()
+}
+
+/// Las transacciones del archivo de entrada separadas de las que repiten
+/// un id anterior, que el procesador manda a rechazadas
+pub fn leer_entrada(ruta_archivo: &str) -> Result<(Vec<Transaccion>, Vec<Transaccion>), csv::Error> {
+    let mut vistas = std::collections::HashSet::new();
+    Ok(leer::<Transaccion>(ruta_archivo)?
+        .into_iter()
+        .partition(|transaccion| vistas.insert(transaccion.id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Mutex, atomic::AtomicU32};
+    use rand::{SeedableRng, prelude::StdRng};
+
+    use super::*;
+
+    #[test]
+    fn los_clientes_se_cargan_con_el_saldo_escrito() {
+        let ruta_archivo_tests = "archivo_tests_15.csv";
+        let rng = Arc::new(Mutex::new(StdRng::seed_from_u64(264)));
+        let clientes: Vec<_> = (0..3)
+            .map(|_| Arc::new(Cliente::new(Uuid::new_v4(), Arc::new(AtomicU32::new(1)), rng.clone())))
+            .collect();
+        clientes[0].cash_in(10.25);
+
+        escribir_clientes(&clientes, ruta_archivo_tests).unwrap();
+        let cargados = leer_clientes(ruta_archivo_tests).unwrap();
+
+        assert_eq!(cargados.len(), 3);
+        for (cliente, cargado) in clientes.iter().zip(cargados.iter()) {
+            assert_eq!(cargado.id, cliente.id);
+            assert_eq!(cargado.get_saldos(), cliente.get_saldos());
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+
+const INTERVALO_DEFAULT: u64 = 500; // 500 millis
+const ESPERA_PARA_SUBIR_DEFAULT: u64 = 500; // 500 millis
+const CICLOS_DEFAULT: u32 = 3;
+const MINIMO_HILOS_DEFAULT: u32 = 1;
+const MAXIMO_HILOS_DEFAULT: u32 = 20;
+
+/// Cuántos hilos puede tener un pool
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitesPool {
+    pub minimo: u32,
+    pub maximo: u32,
+}
+
+impl Default for LimitesPool {
+    fn default() -> Self {
+        Self { minimo: MINIMO_HILOS_DEFAULT, maximo: MAXIMO_HILOS_DEFAULT }
+    }
+}
+
+impl LimitesPool {
+    pub fn validar(&self) -> Result<(), String> {
+        if self.minimo == 0 || self.minimo > self.maximo {
+            return Err(format!("el rango {}..{} tiene que ir de al menos 1 hilo a un máximo no menor", self.minimo, self.maximo));
+        }
+        Ok(())
+    }
+
+    pub fn contiene(&self, hilos: u32) -> bool {
+        (self.minimo..=self.maximo).contains(&hilos)
+    }
+}
+
+/// Cuándo se agregan o retiran hilos de los pools de workers y de
+/// procesadores ia. La cantidad de `[workers]` es con la que arranca
+/// cada pool.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfiguracionAutoescalado {
+    pub activo: bool,
+    /// Cada cuántos milisegundos se revisa cada pool
+    pub intervalo_ms: u64,
+    /// Se agrega un hilo si, al ritmo actual, vaciar la cola llevaría más
+    /// de estos milisegundos
+    pub espera_para_subir_ms: u64,
+    /// Revisiones seguidas que tiene que cumplirse una condición para
+    /// agregar o retirar un hilo
+    pub ciclos: u32,
+    pub cash_in: LimitesPool,
+    pub cash_out: LimitesPool,
+    pub ia: LimitesPool,
+}
+
+impl Default for ConfiguracionAutoescalado {
+    fn default() -> Self {
+        Self {
+            activo: false,
+            intervalo_ms: INTERVALO_DEFAULT,
+            espera_para_subir_ms: ESPERA_PARA_SUBIR_DEFAULT,
+            ciclos: CICLOS_DEFAULT,
+            cash_in: LimitesPool::default(),
+            cash_out: LimitesPool::default(),
+            ia: LimitesPool::default(),
+        }
+    }
+}
+
+impl ConfiguracionAutoescalado {
+    pub fn intervalo(&self) -> Duration {
+        Duration::from_millis(self.intervalo_ms)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Escalado {
+    Subir,
+    Bajar,
+}
+
+impl Escalado {
+    pub fn nombre(&self) -> &'static str {
+        match self {
+            Escalado::Subir => "subir",
+            Escalado::Bajar => "bajar",
+        }
+    }
+}
+
+/// Cómo estaba un pool al revisarlo
+#[derive(Debug, Clone, Copy)]
+pub struct MuestraPool {
+    /// Transacciones esperando en la cola del pool
+    pub profundidad: f64,
+    /// Transacciones que terminaron los hilos del pool desde que arrancó
+    pub terminadas: u64,
+    pub hilos: u32,
+    /// Hilos que estaban con una transacción
+    pub ocupados: u32,
+}
+
+/// Decide cuándo agregar o retirar un hilo de un pool. Agrega cuando la
+/// cola tardaría demasiado en vaciarse y retira cuando la cola está vacía
+/// y sobran hilos; para no oscilar cada condición se tiene que cumplir
+/// varias revisiones seguidas y las cuentas vuelven a cero después de
+/// cada cambio.
+#[derive(Debug)]
+pub struct Autoescalador {
+    limites: LimitesPool,
+    espera_para_subir: Duration,
+    ciclos: u32,
+    terminadas: u64,
+    /// Transacciones por segundo entre las dos últimas revisiones
+    rendimiento: f64,
+    saturado: u32,
+    ocioso: u32,
+}
+
+impl Autoescalador {
+    pub fn new(configuracion: &ConfiguracionAutoescalado, limites: LimitesPool) -> Self {
+        Self {
+            limites,
+            espera_para_subir: Duration::from_millis(configuracion.espera_para_subir_ms),
+            ciclos: configuracion.ciclos,
+            terminadas: 0,
+            rendimiento: 0.0,
+            saturado: 0,
+            ocioso: 0,
+        }
+    }
+
+    pub fn rendimiento(&self) -> f64 {
+        self.rendimiento
+    }
+
+    /// Revisa el pool, `transcurrido` después de la revisión anterior
+    pub fn revisar(&mut self, muestra: MuestraPool, transcurrido: Duration) -> Option<Escalado> {
+        self.rendimiento = muestra.terminadas.saturating_sub(self.terminadas) as f64 / transcurrido.as_secs_f64();
+        self.terminadas = muestra.terminadas;
+
+        let espera = muestra.profundidad / self.rendimiento;
+        let saturado = muestra.profundidad > 0.0 && espera > self.espera_para_subir.as_secs_f64();
+        let ocioso = muestra.profundidad <= 0.0 && muestra.ocupados < muestra.hilos;
+        self.saturado = if saturado { self.saturado + 1 } else { 0 };
+        self.ocioso = if ocioso { self.ocioso + 1 } else { 0 };
+
+        let escalado = if self.saturado >= self.ciclos && muestra.hilos < self.limites.maximo {
+            Escalado::Subir
+        } else if self.ocioso >= self.ciclos && muestra.hilos > self.limites.minimo {
+            Escalado::Bajar
+        } else {
+            return None;
+        };
+        self.saturado = 0;
+        self.ocioso = 0;
+
+        Some(escalado)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sube_con_la_cola_atrasada_y_baja_con_hilos_ociosos_entre_los_limites() {
+        let configuracion = ConfiguracionAutoescalado { ciclos: 2, ..ConfiguracionAutoescalado::default() };
+        let mut autoescalador = Autoescalador::new(&configuracion, LimitesPool { minimo: 2, maximo: 3 });
+        let segundo = Duration::from_secs(1);
+        let muestra = |profundidad: f64, terminadas: u64, hilos: u32, ocupados: u32| MuestraPool { profundidad, terminadas, hilos, ocupados };
+
+        // 40 en cola a 10 por segundo son 4 segundos: hacen falta dos
+        // revisiones seguidas para subir
+        assert_eq!(autoescalador.revisar(muestra(40.0, 10, 2, 2), segundo), None);
+        assert_eq!(autoescalador.rendimiento(), 10.0);
+        assert_eq!(autoescalador.revisar(muestra(40.0, 20, 2, 2), segundo), Some(Escalado::Subir));
+        // Después de subir se vuelve a contar, y no se pasa del máximo
+        assert_eq!(autoescalador.revisar(muestra(40.0, 30, 3, 3), segundo), None);
+        assert_eq!(autoescalador.revisar(muestra(40.0, 40, 3, 3), segundo), None);
+
+        // Sin cola pero con todos los hilos ocupados no se baja
+        assert_eq!(autoescalador.revisar(muestra(0.0, 50, 3, 3), segundo), None);
+        assert_eq!(autoescalador.revisar(muestra(0.0, 60, 3, 2), segundo), None);
+        assert_eq!(autoescalador.revisar(muestra(0.0, 70, 3, 1), segundo), Some(Escalado::Bajar));
+        assert_eq!(autoescalador.revisar(muestra(0.0, 70, 2, 0), segundo), None);
+        assert_eq!(autoescalador.revisar(muestra(0.0, 70, 2, 0), segundo), None);
+
+        // Una cola que se vacía rápido no hace subir
+        assert_eq!(autoescalador.revisar(muestra(2.0, 170, 2, 2), segundo), None);
+        assert_eq!(autoescalador.revisar(muestra(2.0, 270, 2, 2), segundo), None);
+    }
+}
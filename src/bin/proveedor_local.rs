@@ -0,0 +1,160 @@
+//! Proveedor de autorizaciones local que implementa la API HTTP de
+//! `api_proveedor_http`, para correr el pipeline contra un proveedor
+//! fuera de proceso sin depender de ningún servicio externo.
+//!
+//! Al iniciar escribe en stdout "Escuchando en <host:puerto>".
+extern crate clap;
+
+use std::{
+    io::{prelude::*, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+use clap::{App, Arg};
+use rand::{Rng, SeedableRng, prelude::StdRng};
+use uuid::{Builder, Variant, Version};
+
+use dinero_oxidado::api_proveedor_http::{PedidoAutorizacion, ResultadoAutorizacion, RUTA_AUTORIZACIONES, SUFIJO_LOTE};
+
+const PUERTO_DEFAULT: &str = "0";
+const PROBABILIDAD_DE_DENEGADA_DEFAULT: &str = "0.05";
+
+fn main() {
+    if let Err(e) = real_main() {
+        println!("ERROR: {}", e);
+    }
+}
+
+fn real_main() -> Result<(), String> {
+    let argumentos = App::new("Proveedor local")
+        .about("Proveedor de autorizaciones HTTP para pruebas")
+        .arg(Arg::with_name("Puerto")
+            .short("p")
+            .long("puerto")
+            .takes_value(true)
+            .help("Puerto en el que escuchar (0 elige uno libre)"))
+        .arg(Arg::with_name("Semilla")
+            .short("s")
+            .long("semilla")
+            .takes_value(true)
+            .help("Semilla para las decisiones del proveedor"))
+        .arg(Arg::with_name("Probabilidad denegada")
+            .short("d")
+            .long("probabilidad_denegada")
+            .takes_value(true)
+            .help("Probabilidad de denegar una transacción"))
+        .get_matches();
+
+    let puerto = argumentos.value_of("Puerto").unwrap_or(PUERTO_DEFAULT);
+    let semilla = match argumentos.value_of("Semilla") {
+        Some(semilla) => semilla.parse::<u64>().map_err(|e| format!("Semilla inválida: {}", e))?,
+        None => rand::thread_rng().gen()
+    };
+    let probabilidad_denegada = argumentos
+        .value_of("Probabilidad denegada")
+        .unwrap_or(PROBABILIDAD_DE_DENEGADA_DEFAULT)
+        .parse::<f64>()
+        .map_err(|e| format!("Probabilidad inválida: {}", e))?;
+
+    let listener = TcpListener::bind(format!("127.0.0.1:{}", puerto)).map_err(|e| e.to_string())?;
+    println!("Escuchando en {}", listener.local_addr().map_err(|e| e.to_string())?);
+    std::io::stdout().flush().map_err(|e| e.to_string())?;
+
+    let rng = Arc::new(Mutex::new(StdRng::seed_from_u64(semilla)));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(_) => continue
+        };
+        let rng = rng.clone();
+        thread::spawn(move || {
+            // Si el cliente cortó la conexión no hay nada que hacer
+            let _ = atender(stream, &rng, probabilidad_denegada);
+        });
+    }
+
+    Ok(())
+}
+
+fn atender(mut stream: TcpStream, rng: &Mutex<StdRng>, probabilidad_denegada: f64) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut linea_pedido = String::new();
+    reader.read_line(&mut linea_pedido)?;
+    let mut largo_cuerpo = 0;
+    loop {
+        let mut encabezado = String::new();
+        if reader.read_line(&mut encabezado)? == 0 || encabezado.trim().is_empty() {
+            break;
+        }
+        if let Some((nombre, valor)) = encabezado.split_once(':') {
+            if nombre.eq_ignore_ascii_case("content-length") {
+                largo_cuerpo = valor.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    let mut cuerpo = vec![0; largo_cuerpo];
+    reader.read_exact(&mut cuerpo)?;
+
+    let mut partes = linea_pedido.split_whitespace();
+    if partes.next() != Some("POST") {
+        return responder(&mut stream, 404, "");
+    }
+    let ruta = partes.next().unwrap_or_default();
+    let ruta_lote = format!("{}{}", RUTA_AUTORIZACIONES, SUFIJO_LOTE);
+    let cuerpo = if ruta == RUTA_AUTORIZACIONES {
+        match serde_json::from_slice::<PedidoAutorizacion>(&cuerpo) {
+            Ok(pedido) => {
+                let resultado = decidir(&pedido, &mut rng.lock().expect("rng poisoned"), probabilidad_denegada);
+                serde_json::to_string(&resultado).expect("resultado no serializable")
+            }
+            Err(_) => return responder(&mut stream, 400, "")
+        }
+    } else if ruta == ruta_lote {
+        match serde_json::from_slice::<Vec<PedidoAutorizacion>>(&cuerpo) {
+            Ok(pedidos) => {
+                let mut rng = rng.lock().expect("rng poisoned");
+                let resultados: Vec<_> = pedidos
+                    .iter()
+                    .map(|pedido| decidir(pedido, &mut rng, probabilidad_denegada))
+                    .collect();
+                serde_json::to_string(&resultados).expect("resultados no serializables")
+            }
+            Err(_) => return responder(&mut stream, 400, "")
+        }
+    } else {
+        return responder(&mut stream, 404, "");
+    };
+
+    responder(&mut stream, 200, &cuerpo)
+}
+
+fn decidir(pedido: &PedidoAutorizacion, rng: &mut StdRng, probabilidad_denegada: f64) -> ResultadoAutorizacion {
+    if pedido.monto <= 0.0 {
+        return ResultadoAutorizacion::Denegada { motivo: format!("Monto inválido: {}", pedido.monto) };
+    }
+    if rng.gen::<f64>() < probabilidad_denegada {
+        return ResultadoAutorizacion::Denegada { motivo: "Operación no permitida por el proveedor".into() };
+    }
+
+    let hash = Builder::from_bytes(rng.gen())
+        .set_variant(Variant::RFC4122)
+        .set_version(Version::Random)
+        .build();
+    ResultadoAutorizacion::Autorizada { hash }
+}
+
+fn responder(stream: &mut TcpStream, estado: u16, cuerpo: &str) -> std::io::Result<()> {
+    let texto_estado = match estado {
+        200 => "OK",
+        400 => "Bad Request",
+        _ => "Not Found",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        estado, texto_estado, cuerpo.len(), cuerpo
+    )?;
+    stream.flush()
+}
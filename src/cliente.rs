@@ -2,17 +2,67 @@ extern crate csv;
 
 use std::{fs::File, sync::{Arc, Mutex, atomic::{AtomicU32, Ordering}}, time::SystemTime};
 use csv::Writer;
-use rand::{Rng, prelude::StdRng};
+use rand::{Rng, SeedableRng, prelude::StdRng};
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
-use crate::transaccion::{Transaccion, TipoTransaccion};
+use crate::{transaccion::{Transaccion, TipoTransaccion}, traza::Traza};
+
+/// Saldo contable y la parte retenida por cash outs que todavía no se
+/// liquidaron. El disponible es la diferencia entre ambos.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Saldo {
+    pub contable: f32,
+    pub retenido: f32,
+}
+
+impl Saldo {
+    pub fn disponible(&self) -> f32 {
+        self.contable - self.retenido
+    }
+}
+
+/// Rangos de la simulación de un día de operaciones entre clientes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfiguracionSimulacion {
+    /// Cantidad de clientes que se simulan
+    pub clientes: u32,
+    pub saldo_inicial_minimo: f32,
+    pub saldo_inicial_maximo: f32,
+    /// Cantidad de transferencias que hace cada cliente
+    pub operaciones_minimas: u32,
+    pub operaciones_maximas: u32,
+    pub monto_minimo: f32,
+    pub monto_maximo: f32,
+    /// Probabilidad de que una transferencia quede pendiente en el
+    /// archivo de transacciones en vez de aplicarse en el momento
+    pub probabilidad_pendiente: f64,
+}
+
+impl Default for ConfiguracionSimulacion {
+    fn default() -> Self {
+        Self {
+            clientes: CANTIDAD_CLIENTES,
+            saldo_inicial_minimo: SALDO_INICIAL_MINIMO,
+            saldo_inicial_maximo: SALDO_INICIAL_MAXIMO,
+            operaciones_minimas: CANTIDAD_MINIMA_OPERACIONES,
+            operaciones_maximas: CANTIDAD_MAXIMA_OPERACIONES,
+            monto_minimo: MONTO_MINIMO_TRANSFERENCIA,
+            monto_maximo: MONTO_MAXIMO_TRANSFERENCIA,
+            probabilidad_pendiente: PROBABILIDAD_TRANSACCION_NO_PROCESADA,
+        }
+    }
+}
 
 pub struct Cliente {
     pub id: Uuid,
-    saldo: Mutex<f32>,
+    saldo: Mutex<Saldo>,
+    simulacion: Arc<ConfiguracionSimulacion>,
     n_transaccion: Arc<AtomicU32>,
     rng: Arc<Mutex<StdRng>>,
 }
 
+const CANTIDAD_CLIENTES: u32 = 10;
 const SALDO_INICIAL_MINIMO: f32 = 100.0;
 const SALDO_INICIAL_MAXIMO: f32 = 10000.0;
 const CANTIDAD_MINIMA_OPERACIONES: u32 = 10;
@@ -20,28 +70,54 @@ const CANTIDAD_MAXIMA_OPERACIONES: u32 = 100;
 const MONTO_MINIMO_TRANSFERENCIA: f32 = 10.0;
 const MONTO_MAXIMO_TRANSFERENCIA: f32 = 1000.0;
 const PROBABILIDAD_TRANSACCION_NO_PROCESADA: f64 = 0.1; // 10%
+const RESIDUO_RETENCION: f32 = 0.01;
 
 impl Cliente {
+    /// Cliente con la simulación por defecto
+    #[cfg(test)]
     pub fn new(
                id: Uuid,
                n_transaccion: Arc<AtomicU32>,
                rng: Arc<Mutex<StdRng>>) -> Self {
-        let saldo_inicial = rng.lock().expect("poisoned").gen_range(SALDO_INICIAL_MINIMO..SALDO_INICIAL_MAXIMO);
+        Self::simulado(id, Arc::new(ConfiguracionSimulacion::default()), n_transaccion, rng)
+    }
+
+    /// Cliente que opera según la simulación dada, con un saldo inicial
+    /// al azar dentro de su rango
+    pub fn simulado(
+               id: Uuid,
+               simulacion: Arc<ConfiguracionSimulacion>,
+               n_transaccion: Arc<AtomicU32>,
+               rng: Arc<Mutex<StdRng>>) -> Self {
+        let saldo_inicial = rng.lock().expect("poisoned").gen_range(simulacion.saldo_inicial_minimo..simulacion.saldo_inicial_maximo);
         Self {
             id,
-            saldo: Mutex::new(saldo_inicial),
+            saldo: Mutex::new(Saldo { contable: saldo_inicial, retenido: 0.0 }),
+            simulacion,
             n_transaccion,
             rng
         }
     }
 
+    /// Cliente con un saldo contable conocido, por ejemplo leído del
+    /// archivo de clientes. No simula operaciones.
+    pub fn con_saldo(id: Uuid, saldo: f32) -> Self {
+        Self {
+            id,
+            saldo: Mutex::new(Saldo { contable: saldo, retenido: 0.0 }),
+            simulacion: Arc::new(ConfiguracionSimulacion::default()),
+            n_transaccion: Arc::new(AtomicU32::new(1)),
+            rng: Arc::new(Mutex::new(StdRng::seed_from_u64(0)))
+        }
+    }
+
     pub fn operar(&self, clientes: Arc<Vec<Arc<Cliente>>>, archivo: Arc<Mutex<Writer<File>>>) {
-        let cantidad_operaciones = self.rng.lock().expect("poisoned").gen_range(CANTIDAD_MINIMA_OPERACIONES..CANTIDAD_MAXIMA_OPERACIONES);
+        let cantidad_operaciones = self.rng.lock().expect("poisoned").gen_range(self.simulacion.operaciones_minimas..self.simulacion.operaciones_maximas);
         for _ in 0..cantidad_operaciones {
             let indice_cliente_destino = self.rng.lock().expect("poisoned").gen_range(0..clientes.len() - 1);
             let cliente_destino = &clientes[indice_cliente_destino];
 
-            let monto_transferencia = self.rng.lock().expect("poisoned").gen_range(MONTO_MINIMO_TRANSFERENCIA..MONTO_MAXIMO_TRANSFERENCIA);
+            let monto_transferencia = self.rng.lock().expect("poisoned").gen_range(self.simulacion.monto_minimo..self.simulacion.monto_maximo);
             self.realizar_transferencia(cliente_destino, monto_transferencia, archivo.clone());
         }
 
@@ -49,7 +125,7 @@ impl Cliente {
 
     fn realizar_transferencia(&self, cliente_destino: &Arc<Cliente>, monto: f32, archivo: Arc<Mutex<Writer<File>>>) {
         let procesar_ahora: f64 = self.rng.lock().expect("poisoned").gen();
-        if procesar_ahora < PROBABILIDAD_TRANSACCION_NO_PROCESADA {
+        if procesar_ahora < self.simulacion.probabilidad_pendiente {
             self.escribir_transaccion_pendiente(TipoTransaccion::CashOut, monto, archivo.clone());
             cliente_destino.escribir_transaccion_pendiente(TipoTransaccion::CashIn, monto, archivo);
         } else {
@@ -67,27 +143,70 @@ impl Cliente {
             id_cliente: self.id,
             timestamp,
             tipo,
-            monto
+            monto,
+            traza: Traza::default(),
+            prioridad: None
         }).unwrap();
     }
 
     pub fn cash_in(&self, monto: f32) {
         let mut saldo = self.saldo.lock().expect("poisoned");
-        *saldo += monto;
+        saldo.contable += monto;
     }
 
     pub fn cash_out(&self, monto: f32) {
         let mut saldo = self.saldo.lock().expect("poisoned");
-        *saldo -= monto;
+        saldo.contable -= monto;
     }
 
-    pub fn get_saldo(&self) -> f32 {
-        let saldo = self.saldo.lock().expect("poisoned");
-        *saldo
+    /// Retiene el monto del saldo disponible. Devuelve false, sin
+    /// retener nada, si el disponible no alcanza.
+    pub fn retener(&self, monto: f32) -> bool {
+        let mut saldo = self.saldo.lock().expect("poisoned");
+        if saldo.disponible() < monto {
+            return false;
+        }
+        saldo.retenido += monto;
+        true
+    }
+
+    /// Devuelve al disponible un monto retenido que no se va a liquidar
+    pub fn liberar(&self, monto: f32) {
+        let mut saldo = self.saldo.lock().expect("poisoned");
+        saldo.retenido = descontar_retencion(saldo.retenido, monto);
+    }
+
+    /// Liquida un monto retenido descontándolo del saldo contable
+    pub fn capturar(&self, monto: f32) {
+        let mut saldo = self.saldo.lock().expect("poisoned");
+        saldo.retenido = descontar_retencion(saldo.retenido, monto);
+        saldo.contable -= monto;
+    }
+
+    pub fn get_saldos(&self) -> Saldo {
+        *self.saldo.lock().expect("poisoned")
+    }
+}
+
+/// Los montos son f32: al soltar la última retención puede quedar un
+/// residuo de redondeo que no corresponde a ninguna transacción
+fn descontar_retencion(retenido: f32, monto: f32) -> f32 {
+    let restante = retenido - monto;
+    if restante.abs() < RESIDUO_RETENCION {
+        0.0
+    } else {
+        restante
     }
 }
 
+/// Busca al cliente con el id dado
+pub fn buscar_cliente(clientes: &[Arc<Cliente>], id: Uuid) -> Option<&Arc<Cliente>> {
+    clientes.iter().find(|cliente| cliente.id == id)
+}
+
 #[cfg(test)]
+// Los tests originales escriben los literales a su manera
+#[allow(clippy::mistyped_literal_suffixes)]
 mod tests {
     use super::*;
     use csv::StringRecord;
@@ -97,26 +216,44 @@ mod tests {
     #[test]
     fn saldo_inicial_esta_entre_el_minimo_y_maximo() {
         let cliente = crear_cliente();
-        assert!(cliente.get_saldo() >= SALDO_INICIAL_MINIMO);
-        assert!(cliente.get_saldo() <= SALDO_INICIAL_MAXIMO);
+        assert!(cliente.get_saldos().contable >= SALDO_INICIAL_MINIMO);
+        assert!(cliente.get_saldos().contable <= SALDO_INICIAL_MAXIMO);
     }
 
     #[test]
     fn cash_out_reduce_saldo() {
         let cliente = crear_cliente();
-        let saldo = cliente.get_saldo();
+        let saldo = cliente.get_saldos().contable;
         let monto = 33.3;
         cliente.cash_out(monto);
-        assert_eq!(cliente.get_saldo(), saldo - monto);
+        assert_eq!(cliente.get_saldos().contable, saldo - monto);
     }
 
     #[test]
     fn cash_out_aumenta_saldo() {
         let cliente = crear_cliente();
-        let saldo = cliente.get_saldo();
+        let saldo = cliente.get_saldos().contable;
         let monto = 33.3;
         cliente.cash_in(monto);
-        assert_eq!(cliente.get_saldo(), saldo + monto);
+        assert_eq!(cliente.get_saldos().contable, saldo + monto);
+    }
+
+    #[test]
+    fn retencion_reduce_el_disponible_hasta_capturarla_o_liberarla() {
+        let cliente = crear_cliente();
+        let saldo = cliente.get_saldos().contable;
+
+        assert!(cliente.retener(50.0));
+        assert!(!cliente.retener(saldo));
+        assert_eq!(cliente.get_saldos(), Saldo { contable: saldo, retenido: 50.0 });
+        assert_eq!(cliente.get_saldos().disponible(), saldo - 50.0);
+
+        cliente.capturar(50.0);
+        assert_eq!(cliente.get_saldos(), Saldo { contable: saldo - 50.0, retenido: 0.0 });
+
+        assert!(cliente.retener(20.0));
+        cliente.liberar(20.0);
+        assert_eq!(cliente.get_saldos(), Saldo { contable: saldo - 50.0, retenido: 0.0 });
     }
 
     #[test]
@@ -124,16 +261,16 @@ mod tests {
         let ruta_archivo_tests = "archivo_tests_3.csv";
         let cliente1 = crear_cliente();
         let cliente2 = Arc::new(crear_cliente());
-        let saldo1 = cliente1.get_saldo();
-        let saldo2 = cliente2.get_saldo();
+        let saldo1 = cliente1.get_saldos().contable;
+        let saldo2 = cliente2.get_saldos().contable;
         let monto = 105.25;
         cliente1.realizar_transferencia(
             &cliente2,
             monto,
             Arc::new(Mutex::new(Writer::from_path(ruta_archivo_tests).unwrap()))
         );
-        assert_eq!(cliente1.get_saldo(), saldo1 - monto);
-        assert_eq!(cliente2.get_saldo(), saldo2 + monto);
+        assert_eq!(cliente1.get_saldos().contable, saldo1 - monto);
+        assert_eq!(cliente2.get_saldos().contable, saldo2 + monto);
     }
 
     #[test]
@@ -141,16 +278,16 @@ mod tests {
         let ruta_archivo_tests = "archivo_tests_4.csv";
         let cliente1 = crear_cliente_con_semilla(21_64);
         let cliente2 = Arc::new(crear_cliente());
-        let saldo1 = cliente1.get_saldo();
-        let saldo2 = cliente2.get_saldo();
+        let saldo1 = cliente1.get_saldos().contable;
+        let saldo2 = cliente2.get_saldos().contable;
         let monto = 105.25;
         cliente1.realizar_transferencia(
             &cliente2,
             monto,
             Arc::new(Mutex::new(Writer::from_path(ruta_archivo_tests).unwrap()))
         );
-        assert_eq!(cliente1.get_saldo(), saldo1);
-        assert_eq!(cliente2.get_saldo(), saldo2);
+        assert_eq!(cliente1.get_saldos().contable, saldo1);
+        assert_eq!(cliente2.get_saldos().contable, saldo2);
         let mut reader = csv::Reader::from_path(ruta_archivo_tests).unwrap();
         let mut record = StringRecord::new();
         reader.read_record(&mut record).unwrap();
@@ -0,0 +1,775 @@
+use std::{path::Path, time::Duration};
+use clap::ArgMatches;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use toml::Value;
+
+use crate::{
+    archivos::ArchivosCorrida,
+    autoescalado::ConfiguracionAutoescalado,
+    cliente::ConfiguracionSimulacion,
+    ia::ConfiguracionIA,
+    limitador::Cuota,
+    logger::{FiltroNiveles, FormatoLog, NivelLog, PoliticaColaLlena, VARIABLE_FILTRO_LOG},
+    perfil_fallas::{Caida, PerfilFallas, parsear_caidas, parsear_cambio_perfil},
+    prioridad::ConfiguracionPrioridad,
+    proveedor_autorizacion::ConfiguracionLote,
+    redaccion::{Redactor, VARIABLE_CLAVE_REDACCION},
+    reintentos::PoliticaReintentos,
+    rotacion::ConfiguracionRotacion,
+    supervisor::PoliticaEtapa,
+};
+
+/// Se carga si existe y no se indicó otro archivo con --config
+pub const ARCHIVO_CONFIGURACION: &str = "dinero.toml";
+const PREFIJO_ENTORNO: &str = "DINERO";
+const CANTIDAD_DE_WORKERS_DEFAULT: u32 = 10;
+const CAPACIDAD_COLA_WORKERS_DEFAULT: usize = 100;
+const ESPERA_MAXIMA_APAGADO_DEFAULT: u64 = 30; // 30 segundos
+const SIN_PROGRESO_DEFAULT: u64 = 60; // 60 segundos
+const REINICIOS_DEFAULT: u32 = 3;
+const POLITICA_REINICIABLES_DEFAULT: &str = "reiniciar";
+const POLITICA_DEFAULT: &str = "fallar";
+const PERFIL_PROVEEDOR_DEFAULT: &str = "normal";
+const ARCHIVO_DEBUG: &str = "debug.txt";
+const FORMATO_LOG_DEFAULT: &str = "texto";
+const CAPACIDAD_COLA_LOG_DEFAULT: usize = 8192;
+const POLITICA_COLA_LOG_DEFAULT: &str = "bloquear";
+const REDACCION_DEFAULT: &str = "ninguno";
+
+/// Cada opción que se puede sobrescribir, con el argumento de `cli.yml`
+/// que la pisa. Todas se pueden pisar también con la variable de
+/// entorno DINERO_<CLAVE>, por ejemplo DINERO_WORKERS_IA.
+const OPCIONES: &[(&str, Option<&str>)] = &[
+    ("redaccion", Some("Redaccion")),
+    ("simulacion.clientes", Some("Clientes")),
+    ("simulacion.saldo_inicial_minimo", None),
+    ("simulacion.saldo_inicial_maximo", None),
+    ("simulacion.operaciones_minimas", None),
+    ("simulacion.operaciones_maximas", None),
+    ("simulacion.monto_minimo", None),
+    ("simulacion.monto_maximo", None),
+    ("simulacion.probabilidad_pendiente", None),
+    ("workers.ia", Some("Workers ia")),
+    ("workers.cash_in", Some("Workers cashin")),
+    ("workers.cash_out", Some("Workers cashout")),
+    ("workers.cola", None),
+    ("autoescalado.activo", Some("Autoescalar")),
+    ("autoescalado.intervalo_ms", None),
+    ("autoescalado.espera_para_subir_ms", None),
+    ("autoescalado.ciclos", None),
+    ("autoescalado.cash_in.minimo", None),
+    ("autoescalado.cash_in.maximo", None),
+    ("autoescalado.cash_out.minimo", None),
+    ("autoescalado.cash_out.maximo", None),
+    ("autoescalado.ia.minimo", None),
+    ("autoescalado.ia.maximo", None),
+    ("prioridad.monto_alta", Some("Prioridad monto")),
+    ("prioridad.clientes", None),
+    ("prioridad.envejecimiento_ms", None),
+    ("semillas.simulacion", Some("Semilla simulacion")),
+    ("semillas.ia", Some("Semilla ia")),
+    ("semillas.proveedor", Some("Semilla proveedor")),
+    ("archivos.transacciones", Some("Transacciones")),
+    ("archivos.clientes", Some("Archivo clientes")),
+    ("archivos.saldos", Some("Saldos")),
+    ("archivos.rechazadas", Some("Rechazadas")),
+    ("archivos.fallidas", Some("Fallidas")),
+    ("archivos.estados", Some("Estados")),
+    ("archivos.reporte", Some("Reporte")),
+    ("archivos.en_curso", None),
+    ("archivos.decisiones", Some("Decisiones")),
+    ("archivos.comparacion", Some("Comparacion")),
+    ("ia.tiempo_maximo_ms", None),
+    ("ia.probabilidad_lavado", None),
+    ("ia.perfiles.activo", Some("Anomalias")),
+    ("ia.perfiles.umbral", Some("Umbral anomalias")),
+    ("ia.perfiles.minimo_operaciones", None),
+    ("ia.perfiles.calentamiento", None),
+    ("ia.perfiles.ventana", None),
+    ("ia.perfiles.archivo", Some("Perfiles")),
+    ("ia.modelo.archivo", Some("Modelo")),
+    ("ia.modelo.umbral", Some("Umbral modelo")),
+    ("ia.sombra.tiempo_maximo_ms", None),
+    ("ia.sombra.probabilidad_lavado", None),
+    ("ia.sombra.perfiles.activo", None),
+    ("ia.sombra.perfiles.umbral", None),
+    ("ia.sombra.perfiles.minimo_operaciones", None),
+    ("ia.sombra.perfiles.calentamiento", None),
+    ("ia.sombra.perfiles.ventana", None),
+    ("ia.sombra.modelo.archivo", Some("Modelo sombra")),
+    ("ia.sombra.modelo.umbral", Some("Umbral sombra")),
+    ("proveedor.perfil", Some("Perfil proveedor")),
+    ("proveedor.caidas", Some("Caidas proveedor")),
+    ("proveedor.cambio_perfil", Some("Cambio perfil")),
+    ("proveedor.timeout_ms", Some("Timeout proveedor")),
+    ("proveedor.intentos", Some("Intentos proveedor")),
+    ("proveedor.url", Some("Url proveedor")),
+    ("proveedor.limite", Some("Limite proveedor")),
+    ("proveedor.limite_cash_in", Some("Limite cashin")),
+    ("proveedor.limite_cash_out", Some("Limite cashout")),
+    ("proveedor.lote", Some("Lote")),
+    ("log.debug", Some("Debug")),
+    ("log.archivo", Some("Log archivo")),
+    ("log.nivel", Some("Log nivel")),
+    ("log.formato", Some("Log formato")),
+    ("log.cola", Some("Log cola")),
+    ("log.cola_llena", Some("Log cola llena")),
+    ("log.rotar_tamano", Some("Log rotar tamano")),
+    ("log.rotar_cada", Some("Log rotar cada")),
+    ("log.comprimir", Some("Log comprimir")),
+    ("log.retener", Some("Log retener")),
+    ("observabilidad.traza", Some("Traza")),
+    ("observabilidad.metricas", Some("Metricas")),
+    ("observabilidad.puerto_metricas", Some("Puerto metricas")),
+    ("observabilidad.consultar_estados", Some("Consultar estados")),
+    ("apagado.espera_maxima", None),
+    ("supervisor.sin_progreso", None),
+    ("supervisor.reinicios", None),
+    ("supervisor.procesador", None),
+    ("supervisor.workers", None),
+    ("supervisor.ia", None),
+    ("supervisor.worker_final", None),
+    ("supervisor.rechazos", None),
+];
+
+/// Configuración de una corrida. Se arma con los valores por defecto,
+/// después el archivo, después los argumentos y por último las
+/// variables de entorno.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Configuracion {
+    /// Cómo se muestran los ids de clientes: ninguno, mascara o seudonimo.
+    /// La clave de los seudónimos no forma parte de la configuración:
+    /// viene de DINERO_CLAVE_REDACCION.
+    pub redaccion: String,
+    pub simulacion: ConfiguracionSimulacion,
+    pub workers: ConfiguracionWorkers,
+    pub autoescalado: ConfiguracionAutoescalado,
+    pub prioridad: ConfiguracionPrioridad,
+    pub semillas: Semillas,
+    pub archivos: ArchivosCorrida,
+    pub ia: ConfiguracionIA,
+    pub proveedor: ConfiguracionProveedor,
+    pub log: ConfiguracionLog,
+    pub observabilidad: ConfiguracionObservabilidad,
+    pub apagado: ConfiguracionApagado,
+    pub supervisor: ConfiguracionSupervisor,
+}
+
+impl Default for Configuracion {
+    fn default() -> Self {
+        Self {
+            redaccion: REDACCION_DEFAULT.into(),
+            simulacion: ConfiguracionSimulacion::default(),
+            workers: ConfiguracionWorkers::default(),
+            autoescalado: ConfiguracionAutoescalado::default(),
+            prioridad: ConfiguracionPrioridad::default(),
+            semillas: Semillas::default(),
+            archivos: ArchivosCorrida::default(),
+            ia: ConfiguracionIA::default(),
+            proveedor: ConfiguracionProveedor::default(),
+            log: ConfiguracionLog::default(),
+            observabilidad: ConfiguracionObservabilidad::default(),
+            apagado: ConfiguracionApagado::default(),
+            supervisor: ConfiguracionSupervisor::default(),
+        }
+    }
+}
+
+/// Cantidad de hilos de cada etapa del pipeline
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfiguracionWorkers {
+    pub ia: u32,
+    pub cash_in: u32,
+    pub cash_out: u32,
+    /// Capacidad de la cola de transacciones leídas que espera a los
+    /// workers de cada tipo, y de la de autorizadas que espera a la IA
+    pub cola: usize,
+}
+
+impl Default for ConfiguracionWorkers {
+    fn default() -> Self {
+        Self {
+            ia: CANTIDAD_DE_WORKERS_DEFAULT,
+            cash_in: CANTIDAD_DE_WORKERS_DEFAULT,
+            cash_out: CANTIDAD_DE_WORKERS_DEFAULT,
+            cola: CAPACIDAD_COLA_WORKERS_DEFAULT,
+        }
+    }
+}
+
+/// Semillas de los generadores. Las que faltan se eligen al azar y se
+/// informan en el log para poder repetir la corrida.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Semillas {
+    #[serde(serialize_with = "serializar_semilla", deserialize_with = "deserializar_semilla")]
+    pub simulacion: Option<u64>,
+    #[serde(serialize_with = "serializar_semilla", deserialize_with = "deserializar_semilla")]
+    pub ia: Option<u64>,
+    #[serde(serialize_with = "serializar_semilla", deserialize_with = "deserializar_semilla")]
+    pub proveedor: Option<u64>,
+}
+
+/// Los enteros de TOML llegan hasta i64::MAX: las semillas más grandes
+/// se escriben como texto
+fn serializar_semilla<S: Serializer>(semilla: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+    match semilla {
+        Some(semilla) if *semilla > i64::MAX as u64 => serializer.serialize_str(&semilla.to_string()),
+        Some(semilla) => serializer.serialize_u64(*semilla),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn deserializar_semilla<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Semilla {
+        Numero(u64),
+        Texto(String),
+    }
+
+    match Semilla::deserialize(deserializer)? {
+        Semilla::Numero(semilla) => Ok(Some(semilla)),
+        Semilla::Texto(texto) => texto
+            .parse()
+            .map(Some)
+            .map_err(|_| serde::de::Error::custom(format!("semilla inválida '{}': se espera un entero entre 0 y {}", texto, u64::MAX))),
+    }
+}
+
+/// Proveedor de autorizaciones y cómo se lo llama. Los textos tienen
+/// el mismo formato que los argumentos correspondientes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfiguracionProveedor {
+    pub perfil: String,
+    pub caidas: Option<String>,
+    pub cambio_perfil: Option<String>,
+    pub timeout_ms: u64,
+    pub intentos: u32,
+    pub url: Option<String>,
+    pub limite: Option<String>,
+    pub limite_cash_in: Option<String>,
+    pub limite_cash_out: Option<String>,
+    pub lote: Option<String>,
+}
+
+impl Default for ConfiguracionProveedor {
+    fn default() -> Self {
+        let politica = PoliticaReintentos::default();
+        Self {
+            perfil: PERFIL_PROVEEDOR_DEFAULT.into(),
+            caidas: None,
+            cambio_perfil: None,
+            timeout_ms: politica.timeout.as_millis() as u64,
+            intentos: politica.maximo_intentos,
+            url: None,
+            limite: None,
+            limite_cash_in: None,
+            limite_cash_out: None,
+            lote: None,
+        }
+    }
+}
+
+impl ConfiguracionProveedor {
+    /// El perfil con sus propias caídas más las de `caidas`
+    pub fn perfil_fallas(&self) -> Result<PerfilFallas, String> {
+        Ok(PerfilFallas::desde_nombre(&self.perfil)?.con_caidas(self.caidas()?))
+    }
+
+    /// El perfil al que se cambia en medio de la corrida, que también
+    /// respeta las caídas de `caidas`
+    pub fn cambio_perfil(&self) -> Result<Option<(Duration, PerfilFallas)>, String> {
+        let cambio = self.cambio_perfil.as_deref().map(parsear_cambio_perfil).transpose()?;
+        cambio.map(|(demora, perfil)| Ok((demora, perfil.con_caidas(self.caidas()?)))).transpose()
+    }
+
+    fn caidas(&self) -> Result<Vec<Caida>, String> {
+        parsear_caidas(self.caidas.as_deref().unwrap_or(""))
+    }
+
+    pub fn politica_reintentos(&self) -> PoliticaReintentos {
+        PoliticaReintentos {
+            timeout: Duration::from_millis(self.timeout_ms),
+            maximo_intentos: self.intentos,
+            ..PoliticaReintentos::default()
+        }
+    }
+
+    pub fn cuotas(&self) -> Result<CuotasProveedor, String> {
+        let cuota = |texto: &Option<String>| texto.as_deref().map(Cuota::parsear).transpose();
+        Ok(CuotasProveedor {
+            global: cuota(&self.limite)?,
+            cash_in: cuota(&self.limite_cash_in)?,
+            cash_out: cuota(&self.limite_cash_out)?,
+        })
+    }
+
+    pub fn lote(&self) -> Result<Option<ConfiguracionLote>, String> {
+        self.lote.as_deref().map(ConfiguracionLote::parsear).transpose()
+    }
+}
+
+/// Cuotas de llamadas al proveedor: la que comparten todos los workers
+/// y las de cada tipo
+pub struct CuotasProveedor {
+    pub global: Option<Cuota>,
+    pub cash_in: Option<Cuota>,
+    pub cash_out: Option<Cuota>,
+}
+
+/// Destino, formato y rotación del log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfiguracionLog {
+    /// Escribir el log en `archivo` en vez de la salida estándar
+    pub debug: bool,
+    pub archivo: String,
+    /// Sin nivel se escribe hasta info, o hasta debug en modo debug
+    pub nivel: Option<String>,
+    pub formato: String,
+    /// Con 0 cada hilo escribe directamente
+    pub cola: usize,
+    pub cola_llena: String,
+    pub rotar_tamano: Option<String>,
+    /// Segundos
+    pub rotar_cada: Option<u64>,
+    pub comprimir: bool,
+    pub retener: usize,
+}
+
+impl Default for ConfiguracionLog {
+    fn default() -> Self {
+        Self {
+            debug: false,
+            archivo: ARCHIVO_DEBUG.into(),
+            nivel: None,
+            formato: FORMATO_LOG_DEFAULT.into(),
+            cola: CAPACIDAD_COLA_LOG_DEFAULT,
+            cola_llena: POLITICA_COLA_LOG_DEFAULT.into(),
+            rotar_tamano: None,
+            rotar_cada: None,
+            comprimir: false,
+            retener: ConfiguracionRotacion::default().maximo_archivos,
+        }
+    }
+}
+
+impl ConfiguracionLog {
+    pub fn filtro(&self) -> Result<FiltroNiveles, String> {
+        match &self.nivel {
+            Some(nivel) => FiltroNiveles::parsear(nivel),
+            None if self.debug => Ok(FiltroNiveles::new(NivelLog::Debug)),
+            None => Ok(FiltroNiveles::default()),
+        }
+    }
+
+    pub fn formato(&self) -> Result<FormatoLog, String> {
+        FormatoLog::desde_nombre(&self.formato)
+    }
+
+    pub fn politica_cola(&self) -> Result<PoliticaColaLlena, String> {
+        PoliticaColaLlena::desde_nombre(&self.cola_llena)
+    }
+
+    pub fn rotacion(&self) -> Result<ConfiguracionRotacion, String> {
+        Ok(ConfiguracionRotacion {
+            tamano_maximo: self.rotar_tamano.as_deref().map(ConfiguracionRotacion::parsear_tamano).transpose()?,
+            intervalo: self.rotar_cada.map(Duration::from_secs),
+            comprimir: self.comprimir,
+            maximo_archivos: self.retener,
+        })
+    }
+}
+
+/// Salidas opcionales para seguir la corrida
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfiguracionObservabilidad {
+    pub traza: Option<String>,
+    pub metricas: Option<String>,
+    pub puerto_metricas: Option<u16>,
+    pub consultar_estados: bool,
+}
+
+/// Qué pasa al recibir SIGINT o SIGTERM
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfiguracionApagado {
+    /// Segundos que se esperan las transacciones en curso antes de abortar
+    pub espera_maxima: u64,
+}
+
+impl Default for ConfiguracionApagado {
+    fn default() -> Self {
+        Self { espera_maxima: ESPERA_MAXIMA_APAGADO_DEFAULT }
+    }
+}
+
+/// Qué hace el supervisor con cada etapa que entra en pánico o deja de
+/// avanzar: reiniciar, fallar o continuar
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfiguracionSupervisor {
+    /// Segundos que una etapa puede estar con una transacción sin
+    /// terminarla, y que la corrida puede estar sin que ninguna avance
+    pub sin_progreso: u64,
+    /// Veces que se reinicia cada hilo antes de dar la corrida por fallida
+    pub reinicios: u32,
+    pub procesador: String,
+    pub workers: String,
+    pub ia: String,
+    pub worker_final: String,
+    /// Los workers de rechazadas y de fallidas
+    pub rechazos: String,
+}
+
+impl Default for ConfiguracionSupervisor {
+    fn default() -> Self {
+        Self {
+            sin_progreso: SIN_PROGRESO_DEFAULT,
+            reinicios: REINICIOS_DEFAULT,
+            procesador: POLITICA_DEFAULT.into(),
+            workers: POLITICA_REINICIABLES_DEFAULT.into(),
+            ia: POLITICA_REINICIABLES_DEFAULT.into(),
+            worker_final: POLITICA_DEFAULT.into(),
+            rechazos: POLITICA_DEFAULT.into(),
+        }
+    }
+}
+
+/// La política de cada etapa del pipeline
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PoliticasSupervisor {
+    pub procesador: PoliticaEtapa,
+    pub workers: PoliticaEtapa,
+    pub ia: PoliticaEtapa,
+    pub worker_final: PoliticaEtapa,
+    pub rechazos: PoliticaEtapa,
+}
+
+impl ConfiguracionSupervisor {
+    /// Solo se pueden reiniciar los workers y los procesadores ia: el
+    /// resto de las etapas es dueña de su canal de entrada y lo pierde
+    /// con el hilo
+    pub fn politicas(&self) -> Result<PoliticasSupervisor, String> {
+        let unica = |clave: &str, nombre: &str| match PoliticaEtapa::desde_nombre(nombre)? {
+            PoliticaEtapa::Reiniciar => Err(format!("{}: no se puede reiniciar, tiene un único hilo", clave)),
+            politica => Ok(politica),
+        };
+
+        Ok(PoliticasSupervisor {
+            procesador: unica("procesador", &self.procesador)?,
+            workers: PoliticaEtapa::desde_nombre(&self.workers)?,
+            ia: PoliticaEtapa::desde_nombre(&self.ia)?,
+            worker_final: unica("worker_final", &self.worker_final)?,
+            rechazos: unica("rechazos", &self.rechazos)?,
+        })
+    }
+
+    pub fn sin_progreso(&self) -> Duration {
+        Duration::from_secs(self.sin_progreso)
+    }
+}
+
+impl Configuracion {
+    /// Arma la configuración de la corrida: el archivo de --config (o
+    /// dinero.toml si existe), pisado por los argumentos y éstos por las
+    /// variables de entorno
+    pub fn cargar(argumentos: &ArgMatches) -> Result<Self, String> {
+        let mut configuracion = match argumentos.value_of("Config") {
+            Some(ruta) => Self::leer(ruta)?,
+            None if Path::new(ARCHIVO_CONFIGURACION).exists() => Self::leer(ARCHIVO_CONFIGURACION)?,
+            None => Self::default(),
+        };
+        for (clave, valor) in valores_de_argumentos(argumentos) {
+            configuracion.sobrescribir(clave, &valor, "los argumentos")?;
+        }
+        for (clave, variable, valor) in valores_del_entorno(|variable| std::env::var(variable).ok()) {
+            configuracion.sobrescribir(clave, &valor, &format!("la variable {}", variable))?;
+        }
+        configuracion.validar()?;
+
+        Ok(configuracion)
+    }
+
+    pub fn leer(ruta: &str) -> Result<Self, String> {
+        let texto = std::fs::read_to_string(ruta).map_err(|e| format!("No se pudo leer {}: {}", ruta, e))?;
+        Self::desde_texto(&texto).map_err(|e| format!("{}: {}", ruta, e))
+    }
+
+    pub fn desde_texto(texto: &str) -> Result<Self, String> {
+        toml::from_str(texto).map_err(|e| e.to_string())
+    }
+
+    pub fn a_texto(&self) -> String {
+        toml::to_string_pretty(self).expect("la configuración siempre se puede escribir")
+    }
+
+    /// Pisa la opción `clave` con un valor escrito como en el archivo. Si
+    /// no es un valor TOML válido para la opción se lo toma como texto, así
+    /// `--perfil_proveedor lento` no necesita comillas.
+    pub fn sobrescribir(&mut self, clave: &str, valor: &str, origen: &str) -> Result<(), String> {
+        let literal = toml::from_str::<toml::value::Table>(&format!("valor = {}", valor))
+            .ok()
+            .and_then(|mut tabla| tabla.remove("valor"));
+        let texto = Value::String(valor.to_string());
+        let resultado = match literal {
+            Some(literal) => self.con_valor(clave, literal).or_else(|e| self.con_valor(clave, texto).map_err(|_| e)),
+            None => self.con_valor(clave, texto),
+        };
+        *self = resultado.map_err(|e| format!("{} inválido en {}: '{}' ({})", clave, origen, valor, e))?;
+
+        Ok(())
+    }
+
+    fn con_valor(&self, clave: &str, valor: Value) -> Result<Self, toml::de::Error> {
+        let mut raiz = Value::try_from(self).expect("la configuración siempre se puede escribir");
+        let mut tabla = raiz.as_table_mut().expect("la configuración es una tabla");
+        let mut partes: Vec<&str> = clave.split('.').collect();
+        let campo = partes.pop().expect("clave vacía");
+        for seccion in partes {
+            tabla = tabla
+                .entry(seccion)
+                .or_insert_with(|| Value::Table(Default::default()))
+                .as_table_mut()
+                .expect("las secciones son tablas");
+        }
+        tabla.insert(campo.to_string(), valor);
+
+        raiz.try_into()
+    }
+
+    /// Revisa todas las opciones y devuelve un error con la lista de
+    /// las que no son válidas
+    pub fn validar(&self) -> Result<(), String> {
+        let mut errores = vec![];
+        let mut revisar = |clave: &str, resultado: Result<(), String>| {
+            if let Err(e) = resultado {
+                errores.push(format!("{}: {}", clave, e));
+            }
+        };
+        let positivo = |valor: u64| if valor > 0 { Ok(()) } else { Err("debe ser mayor que 0".to_string()) };
+        let probabilidad = |valor: f64| {
+            if (0.0..=1.0).contains(&valor) { Ok(()) } else { Err(format!("{} no es una probabilidad entre 0 y 1", valor)) }
+        };
+        let rango = |minimo: f64, maximo: f64| {
+            if minimo >= 0.0 && minimo < maximo { Ok(()) } else { Err(format!("el rango {}..{} está vacío o es negativo", minimo, maximo)) }
+        };
+
+        revisar("redaccion", self.redactor().map(drop));
+
+        let simulacion = &self.simulacion;
+        revisar("simulacion.clientes", if simulacion.clientes >= 2 { Ok(()) } else { Err("se necesitan al menos 2 clientes".into()) });
+        revisar("simulacion.saldo_inicial", rango(simulacion.saldo_inicial_minimo.into(), simulacion.saldo_inicial_maximo.into()));
+        revisar("simulacion.operaciones", rango(simulacion.operaciones_minimas.into(), simulacion.operaciones_maximas.into()));
+        revisar("simulacion.monto", rango(simulacion.monto_minimo.into(), simulacion.monto_maximo.into()));
+        revisar("simulacion.probabilidad_pendiente", probabilidad(simulacion.probabilidad_pendiente));
+
+        revisar("workers.ia", positivo(self.workers.ia.into()));
+        revisar("workers.cash_in", positivo(self.workers.cash_in.into()));
+        revisar("workers.cash_out", positivo(self.workers.cash_out.into()));
+        revisar("workers.cola", positivo(self.workers.cola as u64));
+
+        let autoescalado = &self.autoescalado;
+        revisar("autoescalado.intervalo_ms", positivo(autoescalado.intervalo_ms));
+        revisar("autoescalado.ciclos", positivo(autoescalado.ciclos.into()));
+        let pools = [
+            ("cash_in", autoescalado.cash_in, self.workers.cash_in),
+            ("cash_out", autoescalado.cash_out, self.workers.cash_out),
+            ("ia", autoescalado.ia, self.workers.ia),
+        ];
+        for (pool, limites, hilos) in pools {
+            revisar(&format!("autoescalado.{}", pool), limites.validar().and_then(|_| {
+                if !autoescalado.activo || limites.contiene(hilos) {
+                    Ok(())
+                } else {
+                    Err(format!("workers.{} ({}) está fuera del rango {}..{}", pool, hilos, limites.minimo, limites.maximo))
+                }
+            }));
+        }
+
+        revisar("prioridad.monto_alta", match self.prioridad.monto_alta {
+            Some(monto) if monto <= 0.0 => Err(format!("{} no es un monto positivo", monto)),
+            _ => Ok(()),
+        });
+        revisar("prioridad.envejecimiento_ms", positivo(self.prioridad.envejecimiento_ms));
+
+        // La sombra se valida igual que el detector activo
+        let mut detectores = vec![("ia", &self.ia)];
+        if let Some(sombra) = &self.ia.sombra {
+            revisar("ia.sombra.sombra", if sombra.sombra.is_none() { Ok(()) } else { Err("la sombra no puede tener otra sombra".into()) });
+            detectores.push(("ia.sombra", sombra));
+        }
+        for (seccion, ia) in detectores {
+            revisar(&format!("{}.tiempo_maximo_ms", seccion), positivo(ia.tiempo_maximo_ms));
+            revisar(&format!("{}.probabilidad_lavado", seccion), probabilidad(ia.probabilidad_lavado));
+            let perfiles = &ia.perfiles;
+            revisar(&format!("{}.perfiles.umbral", seccion), if perfiles.umbral > 0.0 { Ok(()) } else { Err(format!("{} no es un puntaje positivo", perfiles.umbral)) });
+            revisar(&format!("{}.perfiles.calentamiento", seccion), perfiles.calentamiento().map(drop));
+            revisar(&format!("{}.perfiles.ventana", seccion), positivo(perfiles.ventana));
+            revisar(&format!("{}.modelo.umbral", seccion), probabilidad(ia.modelo.umbral));
+        }
+
+        let proveedor = &self.proveedor;
+        revisar("proveedor.perfil", proveedor.perfil_fallas().map(drop));
+        revisar("proveedor.cambio_perfil", proveedor.cambio_perfil().map(drop));
+        revisar("proveedor.timeout_ms", positivo(proveedor.timeout_ms));
+        revisar("proveedor.intentos", positivo(proveedor.intentos.into()));
+        revisar("proveedor.limite", proveedor.cuotas().map(drop));
+        revisar("proveedor.lote", proveedor.lote().map(drop));
+
+        let log = &self.log;
+        revisar("log.nivel", log.filtro().map(drop));
+        revisar("log.formato", log.formato().map(drop));
+        revisar("log.cola_llena", log.politica_cola().map(drop));
+        revisar("log.rotar_tamano", log.rotacion().map(drop));
+        revisar("log.rotar_cada", log.rotar_cada.map_or(Ok(()), positivo));
+
+        revisar("apagado.espera_maxima", positivo(self.apagado.espera_maxima));
+
+        revisar("supervisor.sin_progreso", positivo(self.supervisor.sin_progreso));
+        revisar("supervisor", self.supervisor.politicas().map(drop));
+
+        if errores.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Configuración inválida:\n  {}", errores.join("\n  ")))
+        }
+    }
+
+    /// La clave de los seudónimos viene del entorno para que no quede en
+    /// el encabezado del log ni en `config mostrar`
+    pub fn redactor(&self) -> Result<Redactor, String> {
+        Redactor::desde_nombre(&self.redaccion, std::env::var(VARIABLE_CLAVE_REDACCION).ok().as_deref())
+    }
+}
+
+/// Opciones pasadas por argumento. Las que no llevan valor valen true.
+fn valores_de_argumentos(argumentos: &ArgMatches) -> Vec<(&'static str, String)> {
+    OPCIONES
+        .iter()
+        .filter_map(|(clave, argumento)| {
+            let argumento = (*argumento)?;
+            if !argumentos.is_present(argumento) {
+                return None;
+            }
+            Some((*clave, argumentos.value_of(argumento).unwrap_or("true").to_string()))
+        })
+        .collect()
+}
+
+/// Opciones definidas en variables de entorno, con el nombre de la
+/// variable. DINERO_LOG se sigue aceptando para `log.nivel`.
+fn valores_del_entorno(entorno: impl Fn(&str) -> Option<String>) -> Vec<(&'static str, String, String)> {
+    OPCIONES
+        .iter()
+        .filter_map(|(clave, _)| {
+            let mut variables = vec![format!("{}_{}", PREFIJO_ENTORNO, clave.replace('.', "_").to_uppercase())];
+            if *clave == "log.nivel" {
+                variables.push(VARIABLE_FILTRO_LOG.to_string());
+            }
+            variables
+                .into_iter()
+                .find_map(|variable| entorno(&variable).map(|valor| (*clave, variable, valor)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn el_entorno_pisa_a_los_argumentos_y_estos_al_archivo() {
+        let mut configuracion = Configuracion::desde_texto(
+            "[workers]\nia = 3\ncash_in = 4\n\n[proveedor]\nperfil = \"lento\"\n\n[semillas]\nia = \"18446744073709551615\"\n"
+        ).unwrap();
+        assert_eq!(configuracion.semillas.ia, Some(u64::MAX));
+        assert_eq!(configuracion.workers.cash_out, 10);
+        // El detector de anomalías solo rechaza si se lo activa
+        assert!(!configuracion.ia.perfiles.activo);
+
+        configuracion.sobrescribir("workers.ia", "5", "los argumentos").unwrap();
+        configuracion.sobrescribir("ia.perfiles.activo", "true", "los argumentos").unwrap();
+        configuracion.sobrescribir("proveedor.perfil", "inestable", "los argumentos").unwrap();
+        configuracion.sobrescribir("log.debug", "true", "los argumentos").unwrap();
+        let entorno: HashMap<_, _> = vec![
+            ("DINERO_WORKERS_IA", "7"),
+            ("DINERO_LOG", "warn"),
+            ("DINERO_ARCHIVOS_SALDOS", "s.csv"),
+        ].into_iter().collect();
+        for (clave, variable, valor) in valores_del_entorno(|variable| entorno.get(variable).map(|valor| valor.to_string())) {
+            configuracion.sobrescribir(clave, &valor, &variable).unwrap();
+        }
+
+        assert_eq!((configuracion.workers.ia, configuracion.workers.cash_in), (7, 4));
+        assert_eq!(configuracion.proveedor.perfil, "inestable");
+        assert_eq!(configuracion.log.nivel.as_deref(), Some("warn"));
+        assert!(configuracion.log.debug);
+        assert!(configuracion.ia.perfiles.activo);
+        assert_eq!(configuracion.archivos.saldos, "s.csv");
+        assert_eq!(configuracion.validar(), Ok(()));
+        // Lo que se muestra se vuelve a leer igual
+        assert_eq!(Configuracion::desde_texto(&configuracion.a_texto()).unwrap(), configuracion);
+    }
+
+    #[test]
+    fn los_errores_indican_la_opcion_y_el_origen() {
+        let error = Configuracion::desde_texto("[workers]\nia = \"diez\"\n").unwrap_err();
+        assert!(error.contains("workers.ia"), "{}", error);
+        assert!(error.contains("line 2"), "{}", error);
+        let error = Configuracion::desde_texto("[worker]\nia = 2\n").unwrap_err();
+        assert!(error.contains("unknown field `worker`"), "{}", error);
+
+        let mut configuracion = Configuracion::default();
+        let error = configuracion.sobrescribir("workers.cash_out", "-2", "la variable DINERO_WORKERS_CASH_OUT").unwrap_err();
+        assert!(error.starts_with("workers.cash_out inválido en la variable DINERO_WORKERS_CASH_OUT: '-2'"), "{}", error);
+        assert_eq!(configuracion, Configuracion::default());
+
+        configuracion.workers.ia = 0;
+        configuracion.simulacion.monto_minimo = 500.0;
+        configuracion.simulacion.monto_maximo = 50.0;
+        configuracion.ia.probabilidad_lavado = 1.5;
+        configuracion.proveedor.lote = Some("cero".into());
+        let error = configuracion.validar().unwrap_err();
+        assert!(error.contains("workers.ia: debe ser mayor que 0"), "{}", error);
+        assert!(error.contains("simulacion.monto: el rango 500..50"), "{}", error);
+        assert!(error.contains("ia.probabilidad_lavado: 1.5 no es una probabilidad"), "{}", error);
+        assert!(error.contains("proveedor.lote: Lote inválido 'cero'"), "{}", error);
+    }
+
+    #[test]
+    fn el_perfil_caido_sigue_caido_con_o_sin_otras_caidas() {
+        let mut configuracion = Configuracion::desde_texto("[proveedor]\nperfil = \"caido\"\n").unwrap();
+        assert!(configuracion.proveedor.perfil_fallas().unwrap().en_caida(Duration::from_secs(30)));
+
+        configuracion.sobrescribir("proveedor.caidas", "5-8", "los argumentos").unwrap();
+        configuracion.sobrescribir("proveedor.cambio_perfil", "2:normal", "los argumentos").unwrap();
+        assert!(configuracion.proveedor.perfil_fallas().unwrap().en_caida(Duration::from_secs(30)));
+        let (_, nuevo_perfil) = configuracion.proveedor.cambio_perfil().unwrap().unwrap();
+        assert!(nuevo_perfil.en_caida(Duration::from_secs(6)));
+        assert!(!nuevo_perfil.en_caida(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn la_sombra_se_configura_y_se_valida_como_el_detector_activo() {
+        let mut configuracion = Configuracion::default();
+        configuracion.sobrescribir("ia.sombra.modelo.archivo", "candidato.json", "los argumentos").unwrap();
+        let sombra = configuracion.ia.sombra.as_deref().unwrap();
+        assert_eq!(sombra.modelo.archivo.as_deref(), Some("candidato.json"));
+        assert_eq!(sombra.probabilidad_lavado, configuracion.ia.probabilidad_lavado);
+        assert_eq!(Configuracion::desde_texto(&configuracion.a_texto()).unwrap(), configuracion);
+
+        configuracion.sobrescribir("ia.sombra.modelo.umbral", "2", "los argumentos").unwrap();
+        configuracion.sobrescribir("ia.sombra.sombra.probabilidad_lavado", "0.5", "los argumentos").unwrap();
+        let error = configuracion.validar().unwrap_err();
+        assert!(error.contains("ia.sombra.modelo.umbral: 2 no es una probabilidad"), "{}", error);
+        assert!(error.contains("ia.sombra.sombra: la sombra no puede tener otra sombra"), "{}", error);
+    }
+}
@@ -0,0 +1,46 @@
+use std::sync::Arc;
+
+use crate::{
+    apagado::Apagado,
+    cliente::Cliente,
+    estados::AlmacenEstados,
+    metricas::RegistroMetricas,
+    redaccion::Redactor,
+    supervisor::Latido,
+    traza::{RegistroTrazas, Traza},
+};
+
+/// Estado de la corrida que comparten las etapas del pipeline
+#[derive(Clone)]
+pub struct ContextoPipeline {
+    pub clientes: Arc<Vec<Arc<Cliente>>>,
+    pub estados: Arc<AlmacenEstados>,
+    pub metricas: Arc<RegistroMetricas>,
+    /// Solo se guardan las trazas si se van a exportar
+    pub trazas: Option<Arc<RegistroTrazas>>,
+    /// Oculta los ids de clientes en los archivos que se exportan
+    pub redactor: Arc<Redactor>,
+    /// Avisa a las etapas que se pidió terminar la corrida
+    pub apagado: Arc<Apagado>,
+    /// Lo marca el hilo de la etapa para que el supervisor sepa si avanza
+    pub latido: Arc<Latido>,
+}
+
+impl ContextoPipeline {
+    /// El contexto para el hilo de una etapa, con su propio latido
+    pub fn con_latido(&self, latido: Arc<Latido>) -> Self {
+        Self { latido, ..self.clone() }
+    }
+
+    /// Guarda la traza de una transacción que terminó su recorrido,
+    /// junto con el estado en el que terminó
+    pub fn completar_traza(&self, id_transaccion: u32, traza: Traza) {
+        if let Some(trazas) = &self.trazas {
+            let resultado = self.estados
+                .consultar(id_transaccion)
+                .map(|registro| registro.estado.to_string())
+                .unwrap_or_default();
+            trazas.completar(id_transaccion, &resultado, traza);
+        }
+    }
+}
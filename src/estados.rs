@@ -0,0 +1,308 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    io::BufRead,
+    sync::{Arc, Mutex, RwLock, mpsc::{channel, Receiver, Sender}},
+    thread,
+    thread::JoinHandle,
+    time::SystemTime,
+};
+use csv::Writer;
+use serde::{Deserialize, Serialize, Serializer, ser::SerializeStruct};
+
+use crate::logger::{NivelLog, TaggedLogger};
+
+/// Etapa del ciclo de vida en la que está una transacción
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EstadoTransaccion {
+    /// Leída del archivo
+    Pendiente,
+    /// Enviada al pool de workers de su tipo
+    Enrutada,
+    /// Autorizada por el proveedor
+    Autorizada,
+    /// Aprobada por la IA
+    Validada,
+    /// Aplicada al saldo del cliente
+    Liquidada,
+    Rechazada,
+    /// Agotó los reintentos con el proveedor
+    Fallida,
+}
+
+impl EstadoTransaccion {
+    pub fn puede_pasar_a(&self, siguiente: EstadoTransaccion) -> bool {
+        use EstadoTransaccion::*;
+        matches!(
+            (self, siguiente),
+            (Pendiente, Enrutada)
+                | (Enrutada, Autorizada)
+                | (Enrutada, Rechazada)
+                | (Enrutada, Fallida)
+                | (Autorizada, Validada)
+                | (Autorizada, Rechazada)
+                | (Validada, Liquidada)
+        )
+    }
+
+    /// Si la transacción ya no va a cambiar de estado
+    pub fn es_final(&self) -> bool {
+        matches!(self, EstadoTransaccion::Liquidada | EstadoTransaccion::Rechazada | EstadoTransaccion::Fallida)
+    }
+}
+
+impl fmt::Display for EstadoTransaccion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Último estado conocido de una transacción
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegistroEstado {
+    pub id_transaccion: u32,
+    pub estado: EstadoTransaccion,
+    pub motivo: Option<String>,
+    pub timestamp: u128,
+}
+
+impl Serialize for RegistroEstado {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("RegistroEstado", 4)?;
+        state.serialize_field("Transaction", &self.id_transaccion)?;
+        state.serialize_field("State", &self.estado)?;
+        state.serialize_field("Reason", &self.motivo.as_deref().unwrap_or(""))?;
+        state.serialize_field("Timestamp", &self.timestamp)?;
+        state.end()
+    }
+}
+
+impl fmt::Display for RegistroEstado {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.motivo {
+            Some(motivo) => write!(f, "Transacción {}: {} ({})", self.id_transaccion, self.estado, motivo),
+            None => write!(f, "Transacción {}: {}", self.id_transaccion, self.estado),
+        }
+    }
+}
+
+/// Estado de todas las transacciones de la corrida. Cada etapa registra
+/// el estado al que pasa cada transacción y solo se aceptan las
+/// transiciones válidas.
+pub struct AlmacenEstados {
+    log: TaggedLogger,
+    estados: RwLock<HashMap<u32, RegistroEstado>>,
+    /// Reciben cada transacción que llega a un estado final
+    suscriptores: Mutex<Vec<Sender<RegistroEstado>>>,
+}
+
+impl AlmacenEstados {
+    pub fn new(log: TaggedLogger) -> Self {
+        Self {
+            log,
+            estados: RwLock::new(HashMap::new()),
+            suscriptores: Mutex::new(vec![]),
+        }
+    }
+
+    /// Pasa la transacción al estado dado. Las transacciones nuevas solo
+    /// pueden empezar como Pendiente. Devuelve false, sin cambiar nada,
+    /// si la transición no es válida; el intento queda en el log.
+    pub fn registrar(&self, id_transaccion: u32, estado: EstadoTransaccion, motivo: Option<&str>) -> bool {
+        let mut estados = self.estados.write().expect("estados poisoned");
+        let valida = match estados.get(&id_transaccion) {
+            Some(registro) => registro.estado.puede_pasar_a(estado),
+            None => estado == EstadoTransaccion::Pendiente,
+        };
+        if !valida {
+            let anterior = estados.get(&id_transaccion).map(|registro| registro.estado);
+            self.log.evento(
+                NivelLog::Warn,
+                &format!("Transición inválida de la transacción {}: {:?} -> {}", id_transaccion, anterior, estado),
+                &[("transaction_id", &id_transaccion)]
+            );
+            return false;
+        }
+
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).expect("SystemTime before UNIX EPOCH!").as_millis();
+        let registro = RegistroEstado {
+            id_transaccion,
+            estado,
+            motivo: motivo.map(String::from),
+            timestamp,
+        };
+        estados.insert(id_transaccion, registro.clone());
+        drop(estados);
+        if estado.es_final() {
+            // Los suscriptores que soltaron su receptor se descartan
+            self.suscriptores.lock().expect("suscriptores poisoned").retain(|suscriptor| suscriptor.send(registro.clone()).is_ok());
+        }
+
+        true
+    }
+
+    /// Recibe, a partir de ahora, cada transacción que termina liquidada,
+    /// rechazada o fallida
+    pub fn suscribir(&self) -> Receiver<RegistroEstado> {
+        let (tx, rx) = channel();
+        self.suscriptores.lock().expect("suscriptores poisoned").push(tx);
+        rx
+    }
+
+    pub fn consultar(&self, id_transaccion: u32) -> Option<RegistroEstado> {
+        self.estados.read().expect("estados poisoned").get(&id_transaccion).cloned()
+    }
+
+    /// Cantidad de transacciones en cada estado
+    pub fn resumen(&self) -> HashMap<EstadoTransaccion, usize> {
+        let mut resumen = HashMap::new();
+        for registro in self.estados.read().expect("estados poisoned").values() {
+            *resumen.entry(registro.estado).or_insert(0) += 1;
+        }
+
+        resumen
+    }
+
+    /// Cantidad de transacciones que todavía no llegaron a un estado final
+    pub fn en_curso(&self) -> usize {
+        self.estados.read().expect("estados poisoned").values().filter(|registro| !registro.estado.es_final()).count()
+    }
+
+    /// Escribe el estado de cada transacción, ordenadas por id
+    pub fn volcar(&self, ruta_archivo: &str) -> Result<(), csv::Error> {
+        self.volcar_si(ruta_archivo, |_| true).map(drop)
+    }
+
+    /// Escribe las transacciones que todavía no llegaron a un estado
+    /// final y devuelve cuántas son
+    pub fn volcar_en_curso(&self, ruta_archivo: &str) -> Result<usize, csv::Error> {
+        self.volcar_si(ruta_archivo, |registro| !registro.estado.es_final())
+    }
+
+    fn volcar_si(&self, ruta_archivo: &str, incluir: impl Fn(&RegistroEstado) -> bool) -> Result<usize, csv::Error> {
+        let mut registros: Vec<_> = self.estados
+            .read()
+            .expect("estados poisoned")
+            .values()
+            .filter(|registro| incluir(registro))
+            .cloned()
+            .collect();
+        registros.sort_by_key(|registro| registro.id_transaccion);
+        let cantidad = registros.len();
+
+        let mut writer = Writer::from_path(ruta_archivo)?;
+        for registro in registros {
+            writer.serialize(registro)?;
+        }
+        writer.flush()?;
+
+        Ok(cantidad)
+    }
+
+    /// Deja la transacción en el estado dado sin validar la transición,
+    /// para preparar tests de etapas intermedias
+    #[cfg(test)]
+    pub fn forzar(&self, id_transaccion: u32, estado: EstadoTransaccion) {
+        self.estados.write().expect("estados poisoned").insert(id_transaccion, RegistroEstado {
+            id_transaccion,
+            estado,
+            motivo: None,
+            timestamp: 0,
+        });
+    }
+}
+
+/// Atiende consultas de estado mientras corre el pipeline: por cada
+/// línea de la entrada con un id de transacción escribe su estado en el
+/// log. Termina cuando se cierra la entrada.
+pub fn iniciar_consulta_estados<E>(log: TaggedLogger, estados: Arc<AlmacenEstados>, entrada: E) -> JoinHandle<()>
+where
+    E: BufRead + Send + 'static,
+{
+    thread::spawn(move || {
+        for linea in entrada.lines() {
+            let linea = match linea {
+                Ok(l) => l,
+                Err(_) => break
+            };
+            let linea = linea.trim();
+            if linea.is_empty() {
+                continue;
+            }
+            match linea.parse::<u32>() {
+                Ok(id) => match estados.consultar(id) {
+                    Some(registro) => log.write(&format!("{}", registro)),
+                    None => log.write(&format!("Transacción {}: sin registrar", id)),
+                },
+                Err(_) => log.write(&format!("Consulta inválida '{}': se espera un id de transacción", linea)),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::Logger;
+    use EstadoTransaccion::*;
+
+    #[test]
+    fn almacen_acepta_el_ciclo_completo_y_rechaza_saltos() {
+        let almacen = crear_almacen();
+
+        assert!(!almacen.registrar(1, Enrutada, None));
+        for estado in [Pendiente, Enrutada, Autorizada, Validada, Liquidada] {
+            assert!(almacen.registrar(1, estado, None));
+        }
+        assert!(!almacen.registrar(1, Rechazada, Some("tarde")));
+        assert_eq!(almacen.consultar(1).unwrap().estado, Liquidada);
+
+        assert!(almacen.registrar(2, Pendiente, None));
+        assert!(!almacen.registrar(2, Liquidada, None));
+        assert!(!almacen.registrar(2, Pendiente, None));
+        assert_eq!(almacen.consultar(2).unwrap().estado, Pendiente);
+        assert_eq!(almacen.consultar(3), None);
+    }
+
+    #[test]
+    fn volcar_escribe_los_estados_ordenados_por_id() {
+        let ruta_archivo_tests = "archivo_tests_6.csv";
+        let almacen = crear_almacen();
+        for id in [7, 3] {
+            almacen.registrar(id, Pendiente, None);
+            almacen.registrar(id, Enrutada, None);
+        }
+        almacen.registrar(7, Fallida, Some("Sin autorización"));
+        almacen.volcar(ruta_archivo_tests).unwrap();
+
+        let mut reader = csv::Reader::from_path(ruta_archivo_tests).unwrap();
+        let registros: Vec<_> = reader.records().map(|r| r.unwrap()).collect();
+        assert_eq!(reader.headers().unwrap(), vec!["Transaction", "State", "Reason", "Timestamp"]);
+        assert_eq!(registros[0].iter().take(3).collect::<Vec<_>>(), vec!["3", "enrutada", ""]);
+        assert_eq!(registros[1].iter().take(3).collect::<Vec<_>>(), vec!["7", "fallida", "Sin autorización"]);
+        assert_eq!(almacen.resumen()[&Enrutada], 1);
+    }
+
+    #[test]
+    fn los_suscriptores_reciben_solo_los_estados_finales() {
+        let almacen = crear_almacen();
+        let suscripcion = almacen.suscribir();
+        for estado in [Pendiente, Enrutada, Fallida] {
+            almacen.registrar(5, estado, Some("Sin autorización").filter(|_| estado == Fallida));
+        }
+        almacen.registrar(6, Pendiente, None);
+        assert_eq!(almacen.en_curso(), 1);
+
+        let registro = suscripcion.try_recv().unwrap();
+        assert_eq!((registro.id_transaccion, registro.estado, registro.motivo.as_deref()), (5, Fallida, Some("Sin autorización")));
+        assert!(suscripcion.try_recv().is_err());
+    }
+
+    fn crear_almacen() -> AlmacenEstados {
+        AlmacenEstados::new(TaggedLogger::new("ESTADOS", Arc::new(Logger::new_to_stdout())))
+    }
+}
@@ -0,0 +1,825 @@
+use std::{
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError},
+        Arc, Mutex,
+    },
+    thread,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    autoescalado::LimitesPool,
+    contexto::ContextoPipeline,
+    estados::EstadoTransaccion,
+    logger::{Logger, NivelLog, TaggedLogger},
+    metricas::{cola_reintentos_de_tipo, LATENCIA_PRIORIDAD, RECHAZOS},
+    prioridad::{canal_prioridad, ConfiguracionPrioridad, EmisorPrioridad, ReceptorPrioridad},
+    proveedor_autorizacion::ConfiguracionLote,
+    reintentos::{ColaReintentos, TransaccionEstacionada},
+    supervisor::{IniciarHilo, Latido, PoliticaEtapa, Pool, Supervisor},
+    transaccion::{Transaccion, TransaccionAutorizada, TransaccionExitosa, TransaccionRechazada},
+    traza::EtapaTraza,
+};
+
+// Cada cuánto se revisa la cola de reintentos, y si hay que retirar el
+// hilo, mientras no llegan transacciones nuevas
+const INTERVALO_SONDEO: Duration = Duration::from_millis(10);
+
+/// Lo que viaja de una etapa a otra: una transacción con lo que le
+/// fueron agregando las etapas anteriores
+pub trait EnPipeline: Send + 'static {
+    fn transaccion(&self) -> &Transaccion;
+    fn transaccion_mut(&mut self) -> &mut Transaccion;
+    /// La transacción sola, para rechazarla
+    fn en_transaccion(self) -> Transaccion;
+}
+
+impl EnPipeline for Transaccion {
+    fn transaccion(&self) -> &Transaccion {
+        self
+    }
+
+    fn transaccion_mut(&mut self) -> &mut Transaccion {
+        self
+    }
+
+    fn en_transaccion(self) -> Transaccion {
+        self
+    }
+}
+
+impl EnPipeline for TransaccionAutorizada {
+    fn transaccion(&self) -> &Transaccion {
+        &self.transaccion
+    }
+
+    fn transaccion_mut(&mut self) -> &mut Transaccion {
+        &mut self.transaccion
+    }
+
+    fn en_transaccion(self) -> Transaccion {
+        self.transaccion
+    }
+}
+
+impl EnPipeline for TransaccionExitosa {
+    fn transaccion(&self) -> &Transaccion {
+        &self.transaccion.transaccion
+    }
+
+    fn transaccion_mut(&mut self) -> &mut Transaccion {
+        &mut self.transaccion.transaccion
+    }
+
+    fn en_transaccion(self) -> Transaccion {
+        self.transaccion.transaccion
+    }
+}
+
+/// Por qué se rechazó una transacción. La categoría es una de pocas y
+/// etiqueta la métrica de rechazos; el detalle queda en el log y en
+/// rechazadas.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rechazo {
+    pub categoria: &'static str,
+    pub detalle: String,
+}
+
+impl Rechazo {
+    pub fn new(categoria: &'static str, detalle: impl Into<String>) -> Self {
+        Self { categoria, detalle: detalle.into() }
+    }
+}
+
+/// Un motivo fijo es a la vez su categoría
+impl From<&'static str> for Rechazo {
+    fn from(motivo: &'static str) -> Self {
+        Self::new(motivo, motivo)
+    }
+}
+
+/// Qué pasa con una transacción después de que la procesó una etapa
+#[derive(Debug)]
+pub enum Resultado<E, S> {
+    /// Pasa a la etapa siguiente
+    Seguir(S),
+    /// Va a rechazos con el motivo
+    Rechazar(E, Rechazo),
+    /// Va a fallidas (dead letter) con el motivo
+    Fallar(E, String),
+    /// Se vuelve a procesar después de la espera, con un intento más
+    Reintentar(E, Duration),
+    /// Se vuelve a procesar después de la espera, sin contar un intento
+    Postergar(E, Duration),
+}
+
+/// Lo que necesita una etapa para procesar: el log de su hilo y el
+/// estado compartido de la corrida
+pub struct ContextoEtapa {
+    pub log: TaggedLogger,
+    pub pipeline: ContextoPipeline,
+}
+
+/// Un paso del pipeline. La etapa solo decide qué hacer con cada
+/// transacción: recibirla, reintentarla, registrar su estado, trazarla,
+/// medirla y entregarla a la etapa siguiente, a rechazos o a fallidas
+/// queda a cargo de `iniciar_etapa`.
+pub trait Etapa: Send + 'static {
+    type Entrada: EnPipeline;
+    type Salida: EnPipeline;
+    /// Nombre de la etapa en las métricas
+    const METRICAS: &'static str;
+    /// Paso que se anota en la traza de cada transacción
+    const TRAZA: Option<EtapaTraza> = None;
+    /// Estado en el que quedan las transacciones que siguen
+    const ESTADO: Option<EstadoTransaccion> = None;
+
+    fn procesar(&mut self, entrada: Self::Entrada, intentos: u32, contexto: &ContextoEtapa) -> Resultado<Self::Entrada, Self::Salida>;
+
+    /// Se llama con cada transacción nueva antes de procesarla por
+    /// primera vez; un error la rechaza con ese motivo
+    fn admitir(&mut self, _entrada: &Self::Entrada, _contexto: &ContextoEtapa) -> Result<(), Rechazo> {
+        Ok(())
+    }
+
+    /// Si devuelve una configuración las transacciones se juntan en
+    /// lotes y se procesan con `procesar_lote`
+    fn lote(&self) -> Option<ConfiguracionLote> {
+        None
+    }
+
+    /// Procesa un lote con los intentos de cada transacción. Devuelve un
+    /// resultado por transacción, en el mismo orden.
+    fn procesar_lote(&mut self, lote: Vec<(Self::Entrada, u32)>, contexto: &ContextoEtapa) -> Vec<Resultado<Self::Entrada, Self::Salida>> {
+        lote.into_iter().map(|(entrada, intentos)| self.procesar(entrada, intentos, contexto)).collect()
+    }
+
+    fn iniciar(&mut self, _contexto: &ContextoEtapa) {}
+
+    /// Se llama cuando no hay nada para procesar, antes de esperar
+    fn en_espera(&mut self, _contexto: &ContextoEtapa) {}
+
+    fn terminar(&mut self, _contexto: &ContextoEtapa) {}
+}
+
+/// Cómo se crea el canal de entrada de una etapa. Los dos tienen un
+/// carril por prioridad.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Canal {
+    Ilimitado,
+    /// Quien envía espera si el canal ya tiene esta cantidad
+    Acotado(usize),
+}
+
+impl Canal {
+    /// Una normal que esperó `envejecimiento` pasa delante de las de
+    /// prioridad alta
+    pub fn crear<T>(self, envejecimiento: Duration) -> (EmisorPrioridad<T>, Receptor<T>) {
+        let capacidad = match self {
+            Canal::Ilimitado => None,
+            Canal::Acotado(capacidad) => Some(capacidad),
+        };
+        let (tx, rx) = canal_prioridad(capacidad, envejecimiento);
+        (tx, Receptor::Prioridad(rx))
+    }
+}
+
+/// El lado que recibe de un canal: uno común, en orden de llegada, o uno
+/// con carriles por prioridad
+pub enum Receptor<T> {
+    Fifo(Arc<Mutex<Receiver<T>>>),
+    Prioridad(ReceptorPrioridad<T>),
+}
+
+impl<T> Clone for Receptor<T> {
+    fn clone(&self) -> Self {
+        match self {
+            Receptor::Fifo(rx) => Receptor::Fifo(rx.clone()),
+            Receptor::Prioridad(rx) => Receptor::Prioridad(rx.clone()),
+        }
+    }
+}
+
+impl<T> From<Receiver<T>> for Receptor<T> {
+    fn from(rx: Receiver<T>) -> Self {
+        Receptor::Fifo(Arc::new(Mutex::new(rx)))
+    }
+}
+
+impl<T> Receptor<T> {
+    /// Las etapas esperan con límite; solo los tests esperan sin él
+    #[cfg(test)]
+    pub fn recv(&self) -> Result<T, std::sync::mpsc::RecvError> {
+        match self {
+            Receptor::Fifo(rx) => rx.lock().expect("Mutex de transacciones poisoned").recv(),
+            Receptor::Prioridad(rx) => rx.recv(),
+        }
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        match self {
+            Receptor::Fifo(rx) => rx.lock().expect("Mutex de transacciones poisoned").try_recv(),
+            Receptor::Prioridad(rx) => rx.try_recv(),
+        }
+    }
+
+    pub fn recv_timeout(&self, espera: Duration) -> Result<T, RecvTimeoutError> {
+        match self {
+            Receptor::Fifo(rx) => rx.lock().expect("Mutex de transacciones poisoned").recv_timeout(espera),
+            Receptor::Prioridad(rx) => rx.recv_timeout(espera),
+        }
+    }
+}
+
+/// De dónde toman transacciones los hilos de una etapa. Todos comparten
+/// el canal y la cola de reintentos.
+pub struct EntradaEtapa<T> {
+    pub canal: Receptor<T>,
+    /// Nombre del canal en las métricas
+    pub cola: &'static str,
+    pub reintentos: Arc<ColaReintentos<T>>,
+}
+
+impl<T> Clone for EntradaEtapa<T> {
+    fn clone(&self) -> Self {
+        Self { canal: self.canal.clone(), cola: self.cola, reintentos: self.reintentos.clone() }
+    }
+}
+
+impl<T> EntradaEtapa<T> {
+    pub fn new(canal: impl Into<Receptor<T>>, cola: &'static str) -> Self {
+        Self { canal: canal.into(), cola, reintentos: Arc::new(ColaReintentos::default()) }
+    }
+}
+
+/// La entrada de la etapa siguiente
+pub struct Siguiente<T> {
+    pub emisor: EmisorPrioridad<T>,
+    pub cola: &'static str,
+}
+
+impl<T> Clone for Siguiente<T> {
+    fn clone(&self) -> Self {
+        Self { emisor: self.emisor.clone(), cola: self.cola }
+    }
+}
+
+/// Adónde entrega una etapa cada transacción según cómo terminó. Sin
+/// etapa siguiente, las que siguen terminan su recorrido ahí.
+pub struct SalidasEtapa<S> {
+    pub siguiente: Option<Siguiente<S>>,
+    pub rechazadas: Sender<TransaccionRechazada>,
+    /// Transacciones que no se pudieron procesar (dead letter)
+    pub fallidas: Sender<TransaccionRechazada>,
+}
+
+impl<S> Clone for SalidasEtapa<S> {
+    fn clone(&self) -> Self {
+        Self { siguiente: self.siguiente.clone(), rechazadas: self.rechazadas.clone(), fallidas: self.fallidas.clone() }
+    }
+}
+
+/// Arranca un hilo que crea la etapa con `crear` y procesa lo que llega
+/// a `entrada` hasta que se cierra el canal y no quedan reintentos, o
+/// hasta que el supervisor pide retirarlo
+pub fn iniciar_etapa<E, F>(crear: F,
+                           log: TaggedLogger,
+                           entrada: EntradaEtapa<E::Entrada>,
+                           salidas: SalidasEtapa<E::Salida>,
+                           contexto: ContextoPipeline)
+    -> JoinHandle<()>
+where
+    E: Etapa,
+    F: FnOnce() -> E + Send + 'static,
+{
+    thread::spawn(move || {
+        let hilo = HiloEtapa {
+            etapa: crear(),
+            contexto: ContextoEtapa { log, pipeline: contexto },
+            entrada,
+            salidas,
+        };
+
+        hilo.procesar();
+    })
+}
+
+struct HiloEtapa<E: Etapa> {
+    etapa: E,
+    contexto: ContextoEtapa,
+    entrada: EntradaEtapa<E::Entrada>,
+    salidas: SalidasEtapa<E::Salida>,
+}
+
+impl<E: Etapa> HiloEtapa<E> {
+    fn procesar(mut self) {
+        self.contexto.log.write("Etapa iniciada");
+        self.etapa.iniciar(&self.contexto);
+        while let Some(estacionada) = self.obtener_trabajo() {
+            self.contexto.pipeline.latido.empezar(estacionada.transaccion.transaccion().id);
+            let lote = match self.etapa.lote() {
+                Some(configuracion) => self.juntar_lote(estacionada, configuracion),
+                None => vec![estacionada],
+            };
+            let intentos: Vec<u32> = lote.iter().map(|estacionada| estacionada.intentos).collect();
+            let entradas = lote.into_iter().map(|estacionada| (estacionada.transaccion, estacionada.intentos));
+            let resultados = match self.etapa.lote() {
+                Some(_) => self.etapa.procesar_lote(entradas.collect(), &self.contexto),
+                None => entradas.map(|(entrada, intentos)| self.etapa.procesar(entrada, intentos, &self.contexto)).collect(),
+            };
+            for (resultado, intentos) in resultados.into_iter().zip(intentos) {
+                self.resolver(resultado, intentos);
+            }
+            self.contexto.pipeline.latido.terminar();
+        }
+        self.etapa.terminar(&self.contexto);
+        self.contexto.log.write("Etapa terminada");
+    }
+
+    /// Devuelve lo próximo a procesar: primero las estacionadas cuya
+    /// espera venció y después las nuevas. Devuelve None cuando no quedan
+    /// ni nuevas ni estacionadas, o si el supervisor pidió retirar el hilo.
+    fn obtener_trabajo(&mut self) -> Option<TransaccionEstacionada<E::Entrada>> {
+        loop {
+            // Las estacionadas quedan para el resto del pool
+            if self.contexto.pipeline.latido.retiro_pedido() {
+                return None;
+            }
+            if let Some(estacionada) = self.tomar_estacionada() {
+                return Some(estacionada);
+            }
+
+            match self.obtener_nueva(INTERVALO_SONDEO) {
+                Ok(Some(entrada)) => return Some(TransaccionEstacionada::new(entrada)),
+                Ok(None) | Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => {
+                    if self.entrada.reintentos.is_empty() {
+                        return None;
+                    }
+                    thread::sleep(INTERVALO_SONDEO);
+                }
+            }
+        }
+    }
+
+    /// Junta transacciones a partir de `primera` hasta completar el
+    /// tamaño del lote o hasta que pase la espera configurada
+    fn juntar_lote(&mut self, primera: TransaccionEstacionada<E::Entrada>, configuracion: ConfiguracionLote) -> Vec<TransaccionEstacionada<E::Entrada>> {
+        let limite = Instant::now() + configuracion.espera;
+        let mut lote = vec![primera];
+        while lote.len() < configuracion.tamano {
+            if let Some(estacionada) = self.tomar_estacionada() {
+                lote.push(estacionada);
+                continue;
+            }
+
+            let restante = limite.saturating_duration_since(Instant::now());
+            if restante == Duration::from_secs(0) {
+                break;
+            }
+            match self.obtener_nueva(restante.min(INTERVALO_SONDEO)) {
+                Ok(Some(entrada)) => lote.push(TransaccionEstacionada::new(entrada)),
+                Ok(None) | Err(RecvTimeoutError::Timeout) => continue,
+                // No van a llegar más: no tiene sentido seguir esperando
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        lote
+    }
+
+    /// Espera una transacción nueva. Devuelve None si la etapa no la
+    /// admitió y ya se rechazó.
+    fn obtener_nueva(&mut self, espera: Duration) -> Result<Option<E::Entrada>, RecvTimeoutError> {
+        let canal = &self.entrada.canal;
+        let mut entrada = match canal.try_recv() {
+            Ok(entrada) => entrada,
+            Err(TryRecvError::Empty) => {
+                self.etapa.en_espera(&self.contexto);
+                canal.recv_timeout(espera)?
+            }
+            Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+        };
+        if let Some(traza) = self.traza() {
+            entrada.transaccion_mut().traza.entrar(traza);
+        }
+        let metricas = &self.contexto.pipeline.metricas;
+        metricas.desencolar(self.entrada.cola);
+        metricas.entrada(E::METRICAS, self.contexto.log.tag());
+
+        match self.etapa.admitir(&entrada, &self.contexto) {
+            Ok(()) => Ok(Some(entrada)),
+            Err(rechazo) => {
+                self.rechazar(entrada, &rechazo);
+                Ok(None)
+            }
+        }
+    }
+
+    /// El paso a anotar en la traza, si la corrida guarda las trazas
+    fn traza(&self) -> Option<EtapaTraza> {
+        E::TRAZA.filter(|_| self.contexto.pipeline.trazas.is_some())
+    }
+
+    fn tomar_estacionada(&self) -> Option<TransaccionEstacionada<E::Entrada>> {
+        let mut estacionada = self.entrada.reintentos.tomar_vencida()?;
+        self.contexto.pipeline.metricas.desencolar(cola_reintentos_de_tipo(estacionada.transaccion.transaccion().tipo));
+        if let Some(etapa) = self.traza() {
+            let traza = &mut estacionada.transaccion.transaccion_mut().traza;
+            traza.salir(EtapaTraza::EsperaReintento);
+            traza.entrar(etapa);
+        }
+
+        Some(estacionada)
+    }
+
+    /// La espera hasta el reintento no cuenta como tiempo en la etapa
+    fn estacionar(&self, mut entrada: E::Entrada, intentos: u32, espera: Duration) {
+        if let Some(etapa) = self.traza() {
+            let traza = &mut entrada.transaccion_mut().traza;
+            traza.salir(etapa);
+            traza.entrar(EtapaTraza::EsperaReintento);
+        }
+        self.contexto.pipeline.metricas.encolar(cola_reintentos_de_tipo(entrada.transaccion().tipo));
+        self.entrada.reintentos.estacionar(TransaccionEstacionada {
+            transaccion: entrada,
+            intentos,
+            proximo_intento: Instant::now() + espera,
+        });
+    }
+
+    fn resolver(&self, resultado: Resultado<E::Entrada, E::Salida>, intentos: u32) {
+        match resultado {
+            Resultado::Seguir(salida) => self.seguir(salida),
+            Resultado::Rechazar(entrada, rechazo) => self.rechazar(entrada, &rechazo),
+            Resultado::Fallar(entrada, motivo) => self.fallar(entrada, &motivo),
+            Resultado::Reintentar(entrada, espera) => self.estacionar(entrada, intentos + 1, espera),
+            Resultado::Postergar(entrada, espera) => self.estacionar(entrada, intentos, espera),
+        }
+    }
+
+    fn seguir(&self, mut salida: E::Salida) {
+        let traza = self.traza();
+        let transaccion = salida.transaccion_mut();
+        if let Some(traza) = traza {
+            transaccion.traza.salir(traza);
+        }
+        let pipeline = &self.contexto.pipeline;
+        let resultado = match E::ESTADO {
+            Some(estado) => {
+                pipeline.estados.registrar(transaccion.id, estado, None);
+                estado.to_string().to_lowercase()
+            }
+            None => "siguio".to_string(),
+        };
+        pipeline.metricas.salida(E::METRICAS, self.contexto.log.tag(), &resultado);
+
+        match &self.salidas.siguiente {
+            Some(siguiente) => {
+                pipeline.metricas.encolar(siguiente.cola);
+                siguiente.emisor.send(salida).expect("Channel cerrado");
+            }
+            None => {
+                // Terminó su recorrido: cuánto tardó desde que entró, por carril
+                if let Some(duracion) = transaccion.traza.duracion() {
+                    let prioridad = transaccion.prioridad.unwrap_or_default();
+                    pipeline.metricas.observar(&LATENCIA_PRIORIDAD, &[("prioridad", prioridad.nombre())], duracion.as_secs_f64());
+                }
+                pipeline.completar_traza(transaccion.id, std::mem::take(&mut transaccion.traza))
+            }
+        }
+    }
+
+    fn rechazar(&self, entrada: E::Entrada, rechazo: &Rechazo) {
+        let motivo = rechazo.detalle.as_str();
+        let transaccion_rechazada = TransaccionRechazada::new(self.salir(entrada), motivo);
+        self.contexto.log.evento(
+            NivelLog::Info,
+            &format!("{}", transaccion_rechazada),
+            &[("transaction_id", &transaccion_rechazada.transaccion.id), ("client_id", &transaccion_rechazada.transaccion.id_cliente)]
+        );
+        let pipeline = &self.contexto.pipeline;
+        pipeline.estados.registrar(transaccion_rechazada.transaccion.id, EstadoTransaccion::Rechazada, Some(motivo));
+        pipeline.metricas.salida(E::METRICAS, self.contexto.log.tag(), "rechazada");
+        pipeline.metricas.incrementar(&RECHAZOS, &[("etapa", E::METRICAS), ("motivo", rechazo.categoria)]);
+        self.salidas.rechazadas.send(transaccion_rechazada).expect("Channel cerrado");
+    }
+
+    fn fallar(&self, entrada: E::Entrada, motivo: &str) {
+        let transaccion_fallida = TransaccionRechazada::new(self.salir(entrada), motivo);
+        self.contexto.log.evento(
+            NivelLog::Warn,
+            &format!("Transacción fallida: {}", transaccion_fallida),
+            &[("transaction_id", &transaccion_fallida.transaccion.id), ("client_id", &transaccion_fallida.transaccion.id_cliente)]
+        );
+        let pipeline = &self.contexto.pipeline;
+        pipeline.estados.registrar(transaccion_fallida.transaccion.id, EstadoTransaccion::Fallida, Some(motivo));
+        pipeline.metricas.salida(E::METRICAS, self.contexto.log.tag(), "fallida");
+        self.salidas.fallidas.send(transaccion_fallida).expect("Channel cerrado");
+    }
+
+    /// La transacción que deja la etapa sin seguir, con su paso cerrado
+    fn salir(&self, entrada: E::Entrada) -> Transaccion {
+        let mut transaccion = entrada.en_transaccion();
+        if let Some(traza) = self.traza() {
+            transaccion.traza.salir(traza);
+        }
+
+        transaccion
+    }
+}
+
+/// Nombre, hilos y canal de entrada de una etapa del pipeline
+#[derive(Debug, Clone)]
+pub struct OpcionesEtapa {
+    /// Cada hilo se llama como la etapa seguida de su número
+    pub nombre: String,
+    /// Nombre del canal de entrada en las métricas
+    pub cola: &'static str,
+    pub canal: Canal,
+    pub politica: PoliticaEtapa,
+    pub hilos: u32,
+    pub limites: LimitesPool,
+}
+
+impl OpcionesEtapa {
+    /// Un hilo que hace fallar la corrida si entra en pánico, con un canal
+    /// de entrada ilimitado
+    pub fn new(nombre: &str, cola: &'static str) -> Self {
+        Self {
+            nombre: nombre.to_string(),
+            cola,
+            canal: Canal::Ilimitado,
+            politica: PoliticaEtapa::Fallar,
+            hilos: 1,
+            limites: LimitesPool::default(),
+        }
+    }
+
+    pub fn con_canal(self, canal: Canal) -> Self {
+        Self { canal, ..self }
+    }
+
+    pub fn con_politica(self, politica: PoliticaEtapa) -> Self {
+        Self { politica, ..self }
+    }
+
+    /// Cuántos hilos arrancan y entre cuántos los mueve el autoescalado
+    pub fn con_hilos(self, hilos: u32, limites: LimitesPool) -> Self {
+        Self { hilos, limites, ..self }
+    }
+}
+
+/// Arma el pool de una etapa cuando se sabe adónde entrega
+type Productor<T> = Box<dyn FnOnce(Siguiente<T>) -> Pool>;
+
+/// Transacciones que salen de una o más etapas, o de un canal que ya
+/// existe, y que todavía no se conectaron con la etapa que las recibe
+pub struct Flujo<T> {
+    origen: Origen<T>,
+}
+
+enum Origen<T> {
+    Canal(Receptor<T>),
+    Etapas(Vec<Productor<T>>),
+}
+
+impl<T> Flujo<T> {
+    /// Lo que llega por un canal que alimenta algo de afuera del pipeline
+    pub fn desde(canal: impl Into<Receptor<T>>) -> Self {
+        Self { origen: Origen::Canal(canal.into()) }
+    }
+
+    /// Junta las salidas de dos flujos de etapas en el mismo canal
+    pub fn unir(self, otro: Flujo<T>) -> Self {
+        match (self.origen, otro.origen) {
+            (Origen::Etapas(mut productores), Origen::Etapas(otros)) => {
+                productores.extend(otros);
+                Self { origen: Origen::Etapas(productores) }
+            }
+            _ => panic!("Solo se pueden unir las salidas de etapas"),
+        }
+    }
+}
+
+/// Conecta las etapas del pipeline y deja sus hilos a cargo del
+/// supervisor. Cada etapa arranca cuando se conecta la que recibe lo que
+/// entrega, así los canales quedan en orden y se cierran cuando terminan
+/// todos los hilos que les escriben.
+pub struct ConstructorPipeline<'a> {
+    supervisor: &'a mut Supervisor,
+    contexto: ContextoPipeline,
+    logger: Arc<Logger>,
+    rechazadas: Sender<TransaccionRechazada>,
+    fallidas: Sender<TransaccionRechazada>,
+    /// De los canales que se crean entre etapas
+    envejecimiento: Duration,
+}
+
+impl<'a> ConstructorPipeline<'a> {
+    pub fn new(supervisor: &'a mut Supervisor,
+               contexto: ContextoPipeline,
+               logger: Arc<Logger>,
+               rechazadas: Sender<TransaccionRechazada>,
+               fallidas: Sender<TransaccionRechazada>) -> Self {
+        Self { supervisor, contexto, logger, rechazadas, fallidas, envejecimiento: ConfiguracionPrioridad::default().envejecimiento() }
+    }
+
+    /// Cuánto espera una transacción normal en la cola de una etapa antes
+    /// de pasar delante de las de prioridad alta
+    pub fn con_envejecimiento(self, envejecimiento: Duration) -> Self {
+        Self { envejecimiento, ..self }
+    }
+
+    /// Agrega una etapa con un pool de hilos intercambiables, cada uno con
+    /// su instancia de la etapa creada por `crear`
+    pub fn etapa<E, F>(&mut self, flujo: Flujo<E::Entrada>, opciones: OpcionesEtapa, crear: F) -> Flujo<E::Salida>
+    where
+        E: Etapa,
+        F: Fn() -> E + Send + Sync + 'static,
+    {
+        let entrada = self.conectar(flujo, &opciones);
+        let crear = Arc::new(crear);
+        let contexto = self.contexto.clone();
+        let logger = self.logger.clone();
+        let (rechazadas, fallidas) = (self.rechazadas.clone(), self.fallidas.clone());
+        let productor: Productor<E::Salida> = Box::new(move |siguiente| {
+            let salidas = SalidasEtapa { siguiente: Some(siguiente), rechazadas, fallidas };
+            let nombre = opciones.nombre.clone();
+            let iniciar: IniciarHilo = Box::new(move |id, latido| {
+                let crear = crear.clone();
+                iniciar_etapa(
+                    move || crear(),
+                    TaggedLogger::new(&format!("{} {}", nombre, id), logger.clone()).con_campo("worker_id", id),
+                    entrada.clone(),
+                    salidas.clone(),
+                    contexto.con_latido(latido)
+                )
+            });
+            Pool {
+                nombre: opciones.nombre,
+                politica: opciones.politica,
+                hilos: opciones.hilos,
+                cola: opciones.cola,
+                limites: opciones.limites,
+                iniciar,
+            }
+        });
+
+        Flujo { origen: Origen::Etapas(vec![productor]) }
+    }
+
+    /// Termina el pipeline con una etapa de un único hilo, que no se puede
+    /// reiniciar, donde las transacciones terminan su recorrido
+    pub fn etapa_final<E, F>(mut self, flujo: Flujo<E::Entrada>, opciones: OpcionesEtapa, crear: F)
+    where
+        E: Etapa,
+        F: FnOnce() -> E + Send + 'static,
+    {
+        let entrada = self.conectar(flujo, &opciones);
+        let latido = Arc::new(Latido::default());
+        let handle = iniciar_etapa(
+            crear,
+            TaggedLogger::new(&opciones.nombre, self.logger.clone()),
+            entrada,
+            SalidasEtapa { siguiente: None, rechazadas: self.rechazadas, fallidas: self.fallidas },
+            self.contexto.con_latido(latido.clone())
+        );
+        self.supervisor.vigilar(&opciones.nombre, opciones.politica, latido, handle);
+    }
+
+    /// Crea el canal de entrada de una etapa, si hace falta, y arranca las
+    /// etapas que le escriben
+    fn conectar<T: EnPipeline>(&mut self, flujo: Flujo<T>, opciones: &OpcionesEtapa) -> EntradaEtapa<T> {
+        let canal = match flujo.origen {
+            Origen::Canal(canal) => canal,
+            Origen::Etapas(productores) => {
+                let (emisor, canal) = opciones.canal.crear(self.envejecimiento);
+                for productor in productores {
+                    self.supervisor.vigilar_pool(productor(Siguiente { emisor: emisor.clone(), cola: opciones.cola }));
+                }
+                canal
+            }
+        };
+
+        EntradaEtapa::new(canal, opciones.cola)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use uuid::Uuid;
+    use crate::{
+        apagado::Apagado,
+        estados::AlmacenEstados,
+        metricas::RegistroMetricas,
+        redaccion::Redactor,
+        transaccion::TipoTransaccion,
+        traza::RegistroTrazas,
+    };
+
+    /// Rechaza los montos grandes y reintenta una vez los que terminan en 7
+    struct ControlMontos {
+        maximo: f32,
+    }
+
+    impl Etapa for ControlMontos {
+        type Entrada = Transaccion;
+        type Salida = Transaccion;
+        const METRICAS: &'static str = "control";
+        const TRAZA: Option<EtapaTraza> = Some(EtapaTraza::Worker);
+
+        fn admitir(&mut self, transaccion: &Transaccion, _contexto: &ContextoEtapa) -> Result<(), Rechazo> {
+            if transaccion.monto > self.maximo {
+                return Err("Monto excedido".into());
+            }
+            Ok(())
+        }
+
+        fn procesar(&mut self, transaccion: Transaccion, intentos: u32, _contexto: &ContextoEtapa) -> Resultado<Transaccion, Transaccion> {
+            match (transaccion.id % 10, intentos) {
+                (7, 0) => Resultado::Reintentar(transaccion, Duration::from_millis(1)),
+                (9, _) => Resultado::Fallar(transaccion, "Sin control".to_string()),
+                _ => Resultado::Seguir(transaccion),
+            }
+        }
+    }
+
+    /// Deja el monto de cada transacción en el canal dado
+    struct Registrar {
+        montos: Sender<f32>,
+    }
+
+    impl Etapa for Registrar {
+        type Entrada = Transaccion;
+        type Salida = Transaccion;
+        const METRICAS: &'static str = "registro";
+        const ESTADO: Option<EstadoTransaccion> = Some(EstadoTransaccion::Autorizada);
+        const TRAZA: Option<EtapaTraza> = Some(EtapaTraza::WorkerFinal);
+
+        fn procesar(&mut self, transaccion: Transaccion, _intentos: u32, _contexto: &ContextoEtapa) -> Resultado<Transaccion, Transaccion> {
+            self.montos.send(transaccion.monto).unwrap();
+            Resultado::Seguir(transaccion)
+        }
+    }
+
+    #[test]
+    fn el_constructor_conecta_etapas_propias_con_rechazos_reintentos_y_fallidas() {
+        let logger = Arc::new(Logger::new_to_stdout());
+        let estados = Arc::new(AlmacenEstados::new(TaggedLogger::new("ESTADOS", logger.clone())));
+        let trazas = Arc::new(RegistroTrazas::default());
+        let contexto = ContextoPipeline {
+            clientes: Arc::new(vec![]),
+            estados: estados.clone(),
+            metricas: Arc::new(RegistroMetricas::default()),
+            trazas: Some(trazas.clone()),
+            redactor: Arc::new(Redactor::default()),
+            apagado: Arc::new(Apagado::default()),
+            latido: Arc::new(Latido::default()),
+        };
+        let mut supervisor = Supervisor::new(TaggedLogger::new("SUPERVISOR", logger.clone()), Duration::from_secs(60), 0);
+        let (tx_rechazadas, rx_rechazadas) = channel();
+        let (tx_fallidas, rx_fallidas) = channel();
+        let (tx_transacciones, rx_transacciones) = channel();
+        let (tx_montos, rx_montos) = channel();
+
+        let mut pipeline = ConstructorPipeline::new(&mut supervisor, contexto, logger, tx_rechazadas, tx_fallidas);
+        let controladas = pipeline.etapa(
+            Flujo::desde(rx_transacciones),
+            OpcionesEtapa::new("CONTROL", "control").con_hilos(2, LimitesPool::default()),
+            || ControlMontos { maximo: 100.0 }
+        );
+        pipeline.etapa_final(
+            controladas,
+            OpcionesEtapa::new("REGISTRO", "registro").con_canal(Canal::Acotado(1)),
+            move || Registrar { montos: tx_montos }
+        );
+
+        for (id, monto) in [(1, 10.0), (2, 500.0), (7, 20.0), (9, 30.0)] {
+            estados.forzar(id, EstadoTransaccion::Enrutada);
+            tx_transacciones.send(Transaccion::de_prueba(id, Uuid::new_v4(), TipoTransaccion::CashIn, monto)).unwrap();
+        }
+        drop(tx_transacciones);
+        assert_eq!(supervisor.esperar(), Ok(vec![]));
+
+        let mut montos: Vec<f32> = rx_montos.iter().collect();
+        montos.sort_by(f32::total_cmp);
+        assert_eq!(montos, vec![10.0, 20.0]);
+        assert_eq!(estados.consultar(7).unwrap().estado, EstadoTransaccion::Autorizada);
+        let rechazada = rx_rechazadas.recv().unwrap();
+        assert_eq!((rechazada.transaccion.id, rechazada.motivo.as_str()), (2, "Monto excedido"));
+        assert_eq!(estados.consultar(2).unwrap().estado, EstadoTransaccion::Rechazada);
+        assert_eq!(rx_fallidas.recv().unwrap().transaccion.id, 9);
+        assert!(rx_rechazadas.recv().is_err());
+        // El reintento esperó fuera de la etapa: dos pasos por el worker
+        // para la 7 y uno para la 1, separados de su espera
+        let latencias = trazas.latencias();
+        let cantidad = |etapa| latencias.iter().find(|l| l.etapa == etapa).map(|l| l.cantidad);
+        assert_eq!(cantidad(EtapaTraza::Worker), Some(3));
+        assert_eq!(cantidad(EtapaTraza::EsperaReintento), Some(1));
+        assert_eq!(cantidad(EtapaTraza::WorkerFinal), Some(2));
+    }
+}
@@ -1,120 +1,237 @@
-use std::{sync::{
-        mpsc::{Sender, Receiver},
-        Arc, Mutex,
-    }, thread, thread::JoinHandle, time::Duration};
+use std::{sync::{Arc, Mutex}, thread, time::{Duration, Instant}};
 use rand::{Rng, SeedableRng, prelude::StdRng};
+use serde::{Deserialize, Serialize};
 use crate::{
-    logger::{Logger, TaggedLogger},
-    transaccion::TransaccionAutorizada
+    cliente::buscar_cliente,
+    estados::EstadoTransaccion,
+    etapa::{ContextoEtapa, Etapa, Resultado},
+    logger::NivelLog,
+    metricas::LATENCIA_IA,
+    modelo::{ClasificadorFraude, ConfiguracionModelo, VectorCaracteristicas},
+    perfiles::{Anomalia, ConfiguracionPerfiles, PerfilesClientes},
+    sombra::RegistroSombra,
+    transaccion::{TipoTransaccion, TransaccionAutorizada},
+    traza::EtapaTraza,
 };
 
 const TIEMPO_MAXIMO_IA: u64 = 25; // 25 millis
 const PROBABILIDAD_DE_INVALIDA: f64 = 0.1; // 10%
 
-/// Inicia n_procesadores de autorizacion ia
-pub fn iniciar_procesadores_ia(n_procesadores: u32,
-                               rx_transacciones_autorizadas: Arc<Mutex<Receiver<TransaccionAutorizada>>>,
-                               tx_transacciones_validas: Sender<TransaccionAutorizada>,
-                               semilla: u64,
-                               logger: Arc<Logger>)
-    -> Vec<JoinHandle<()>>
-{
-    let mut handles_procesadores_ia = vec![];
-    let rng = Arc::new(Mutex::new(StdRng::seed_from_u64(semilla)));
-    for procesador_id in 0..n_procesadores {
-        handles_procesadores_ia.push(
-            ProcesadorIA::iniciar(
-                TaggedLogger::new(&format!("PROCESADOR IA {}", procesador_id), logger.clone()),
-                rx_transacciones_autorizadas.clone(),
-                tx_transacciones_validas.clone(),
-                rng.clone()
-            )
-        );
+/// Comportamiento del detector de lavado de dinero
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfiguracionIA {
+    /// Cada validación tarda al azar entre 0 y estos milisegundos
+    pub tiempo_maximo_ms: u64,
+    /// Probabilidad de marcar una transacción como lavado de dinero
+    pub probabilidad_lavado: f64,
+    pub perfiles: ConfiguracionPerfiles,
+    pub modelo: ConfiguracionModelo,
+    /// Detector candidato que corre en modo sombra: ve las mismas
+    /// transacciones pero sus decisiones no afectan la liquidación
+    pub sombra: Option<Box<ConfiguracionIA>>,
+}
+
+impl Default for ConfiguracionIA {
+    fn default() -> Self {
+        Self {
+            tiempo_maximo_ms: TIEMPO_MAXIMO_IA,
+            probabilidad_lavado: PROBABILIDAD_DE_INVALIDA,
+            perfiles: ConfiguracionPerfiles::default(),
+            modelo: ConfiguracionModelo::default(),
+            sombra: None,
+        }
+    }
+}
+
+/// Lo que decide un detector sobre una transacción, antes de actuar
+#[derive(Debug, Clone, PartialEq)]
+pub struct Veredicto {
+    /// Motivo del rechazo; None si se aprueba
+    pub motivo: Option<&'static str>,
+    /// Probabilidad de fraude del modelo o, sin modelo, el valor al azar
+    /// (más alto es más sospechoso)
+    pub puntaje: f64,
+    /// Qué detector dio el puntaje
+    pub detector: String,
+    /// Solo si la rechazó el perfil del cliente
+    pub anomalia: Option<Anomalia>,
+}
+
+impl Veredicto {
+    pub fn rechazada(&self) -> bool {
+        self.motivo.is_some()
     }
 
-    handles_procesadores_ia
+    /// Por qué se decidió así, para el log y el informe de la sombra
+    pub fn razon(&self) -> String {
+        match (self.motivo, &self.anomalia) {
+            (Some(motivo), Some(anomalia)) => format!("{}: {}", motivo, anomalia),
+            (Some(motivo), None) => format!("{}: {} dio {:.3}", motivo, self.detector, self.puntaje),
+            (None, _) => format!("Aprobada: {} dio {:.3}", self.detector, self.puntaje),
+        }
+    }
 }
 
+/// Detector de lavado de dinero. Los procesadores de un pool comparten
+/// el generador, así la corrida se repite con la misma semilla, el
+/// modelo y los perfiles de los clientes.
 pub struct ProcesadorIA {
-    log: TaggedLogger,
-    rx_transacciones_autorizadas: Arc<Mutex<Receiver<TransaccionAutorizada>>>,
-    tx_transacciones_validas: Sender<TransaccionAutorizada>,
+    configuracion: ConfiguracionIA,
     rng: Arc<Mutex<StdRng>>,
+    perfiles: Option<Arc<PerfilesClientes>>,
+    clasificador: Option<Arc<ClasificadorFraude>>,
+    /// Detector candidato que ve las mismas transacciones sin decidir nada
+    sombra: Option<(Box<ProcesadorIA>, Arc<RegistroSombra>)>,
 }
 
 impl ProcesadorIA {
-    pub fn iniciar(log: TaggedLogger,
-                   rx_transacciones_autorizadas: Arc<Mutex<Receiver<TransaccionAutorizada>>>,
-                   tx_transacciones_validas: Sender<TransaccionAutorizada>,
-                   rng: Arc<Mutex<StdRng>>)
-        -> JoinHandle<()>
-    {
-        thread::spawn(move || {
-            let procesador = Self {
-                log,
-                rx_transacciones_autorizadas,
-                tx_transacciones_validas,
-                rng,
-            };
-
-            procesador.procesar_transacciones();
-        })
-    }
-
-    fn procesar_transacciones(&self) {
-        self.log.write("Procesador iniciado");
-        while let Some(transaccion) = self.obtener_transaccion() {
-            let validacion = self.detectar_lavado(transaccion);
-            match validacion {
-                Ok(transaccion_validada) => {
-                    self.log.write(&*format!("Transacción validada: {}", transaccion_validada));
-                    self.enviar_transaccion_validada(transaccion_validada)
-                },
-                Err(transaccion_invalidada) => self.log.write(&format!("Lavado de dinero detectado: {}", transaccion_invalidada)),
+    pub fn new(configuracion: ConfiguracionIA, rng: Arc<Mutex<StdRng>>) -> Self {
+        Self { configuracion, rng, perfiles: None, clasificador: None, sombra: None }
+    }
+
+    /// Decide con el modelo cargado en vez de al azar
+    pub fn con_clasificador(self, clasificador: Arc<ClasificadorFraude>) -> Self {
+        Self { clasificador: Some(clasificador), ..self }
+    }
+
+    /// Además del detector al azar, rechaza lo que se aparta del
+    /// comportamiento habitual del cliente
+    pub fn con_perfiles(self, perfiles: Arc<PerfilesClientes>) -> Self {
+        Self { perfiles: Some(perfiles), ..self }
+    }
+
+    /// La sombra evalúa cada transacción junto a este detector y su
+    /// veredicto solo queda en el registro
+    pub fn con_sombra(self, sombra: ProcesadorIA, registro: Arc<RegistroSombra>) -> Self {
+        Self { sombra: Some((Box::new(sombra), registro)), ..self }
+    }
+
+    /// El generador que comparten los procesadores de un pool
+    pub fn generador(semilla: u64) -> Arc<Mutex<StdRng>> {
+        Arc::new(Mutex::new(StdRng::seed_from_u64(semilla)))
+    }
+
+    /// Decide sin tocar saldos ni estados. Lo que no es anómalo pasa a
+    /// formar parte del perfil del cliente. Solo el detector activo simula
+    /// la demora del detector al azar.
+    fn evaluar(&self, autorizada: &TransaccionAutorizada, contexto: &ContextoEtapa, demorar: bool) -> Veredicto {
+        let transaccion = &autorizada.transaccion;
+        let mut veredicto = match &self.clasificador {
+            Some(clasificador) => {
+                let saldo = buscar_cliente(&contexto.pipeline.clientes, transaccion.id_cliente).map(|cliente| cliente.get_saldos());
+                let historial = self.perfiles.as_ref().map(|perfiles| perfiles.historial(transaccion)).unwrap_or_default();
+                let decision = clasificador.decidir(transaccion.id, &VectorCaracteristicas::calcular(transaccion, saldo, historial));
+                Veredicto {
+                    motivo: Some("Fraude según el modelo").filter(|_| decision.rechazada),
+                    puntaje: decision.probabilidad,
+                    detector: format!("el modelo {} con umbral {}", decision.version, clasificador.umbral()),
+                    anomalia: None,
+                }
+            }
+            None => {
+                let valida = self.sortear(demorar);
+                Veredicto {
+                    motivo: Some("Lavado de dinero detectado").filter(|_| valida < self.configuracion.probabilidad_lavado),
+                    puntaje: 1.0 - valida,
+                    detector: format!("el detector al azar con umbral {}", 1.0 - self.configuracion.probabilidad_lavado),
+                    anomalia: None,
+                }
+            }
+        };
+        if !veredicto.rechazada() {
+            if let Some(anomalia) = self.perfiles.as_ref().and_then(|perfiles| perfiles.evaluar(transaccion)) {
+                veredicto.motivo = Some("Comportamiento anómalo del cliente");
+                veredicto.anomalia = Some(anomalia);
             }
         }
-        self.log.write("Procesador terminado");
-    }
-
-    fn obtener_transaccion(&self) -> Option<TransaccionAutorizada> {
-        let proveedor = self
-            .rx_transacciones_autorizadas
-            .lock()
-            .expect("Mutex de transacciones poisoned");
-        
-        match proveedor.recv() {
-            Ok(t) => Some(t),
-            Err(_) => None
-        }
+
+        veredicto
     }
 
-    fn detectar_lavado(&self, transaccion: TransaccionAutorizada) -> Result<TransaccionAutorizada, TransaccionAutorizada> {
+    fn sortear(&self, demorar: bool) -> f64 {
         let mut rng = self.rng.lock().expect("posioned rng");
-        thread::sleep(
-            Duration::from_millis(
-                rng.gen_range(0..TIEMPO_MAXIMO_IA) as u64
-            )
-        );
-        let valida: f64 = rng.gen();
-        if valida < PROBABILIDAD_DE_INVALIDA {
-            Err(transaccion)
-        } else {
-            Ok(transaccion)
+        if demorar {
+            thread::sleep(
+                Duration::from_millis(
+                    rng.gen_range(0..self.configuracion.tiempo_maximo_ms)
+                )
+            );
         }
+        rng.gen()
     }
+}
 
-    fn enviar_transaccion_validada(&self, transaccion_validada: TransaccionAutorizada) {
-        self.tx_transacciones_validas.send(transaccion_validada).expect("Channel cerrado");
+impl Etapa for ProcesadorIA {
+    type Entrada = TransaccionAutorizada;
+    type Salida = TransaccionAutorizada;
+    const METRICAS: &'static str = "ia";
+    const TRAZA: Option<EtapaTraza> = Some(EtapaTraza::ProcesadorIA);
+    const ESTADO: Option<EstadoTransaccion> = Some(EstadoTransaccion::Validada);
+
+    /// Rechaza las transacciones con lavado de dinero o anómalas para su
+    /// cliente, liberando el saldo que retuvo el worker si era un cash out
+    fn procesar(&mut self, transaccion: TransaccionAutorizada, _intentos: u32, contexto: &ContextoEtapa) -> Resultado<TransaccionAutorizada, TransaccionAutorizada> {
+        let inicio = Instant::now();
+        let veredicto = self.evaluar(&transaccion, contexto, true);
+        contexto.pipeline.metricas.observar(&LATENCIA_IA, &[("worker", contexto.log.tag())], inicio.elapsed().as_secs_f64());
+        // La sombra ve la transacción igual que el detector activo, antes
+        // de que se libere ningún saldo, y su tiempo no cuenta como latencia
+        if let Some((sombra, registro)) = &self.sombra {
+            registro.registrar(transaccion.transaccion.id, &veredicto, &sombra.evaluar(&transaccion, contexto, false));
+        }
+        let id = transaccion.transaccion.id;
+        contexto.log.evento(
+            if veredicto.anomalia.is_some() { NivelLog::Warn } else { NivelLog::Debug },
+            &format!("Veredicto de la transacción {}: {}", id, veredicto.razon()),
+            &[("transaction_id", &id), ("client_id", &transaccion.transaccion.id_cliente)]
+        );
+        match veredicto.motivo {
+            None => {
+                contexto.log.evento(
+                    NivelLog::Info,
+                    &format!("Transacción validada: {}", transaccion),
+                    &[("transaction_id", &transaccion.transaccion.id), ("client_id", &transaccion.transaccion.id_cliente)]
+                );
+                Resultado::Seguir(transaccion)
+            },
+            Some(motivo) => {
+                let transaccion_invalidada = transaccion;
+                let transaccion = &transaccion_invalidada.transaccion;
+                if transaccion.tipo == TipoTransaccion::CashOut {
+                    if let Some(cliente) = buscar_cliente(&contexto.pipeline.clientes, transaccion.id_cliente) {
+                        cliente.liberar(transaccion.monto);
+                    }
+                }
+                Resultado::Rechazar(transaccion_invalidada, motivo.into())
+            },
+        }
     }
 }
 
 #[cfg(test)]
+// Los tests originales escriben los literales a su manera
+#[allow(clippy::mistyped_literal_suffixes, clippy::inconsistent_digit_grouping, clippy::redundant_field_names)]
 mod tests {
-    use std::sync::mpsc::channel;
+    use std::sync::{atomic::AtomicU32, mpsc::{channel, Receiver, Sender}};
 
     use super::*;
     use uuid::Uuid;
-    use crate::transaccion::{Transaccion, TransaccionAutorizada, TipoTransaccion};
+    use crate::{
+        apagado::Apagado,
+        cliente::Cliente,
+        contexto::ContextoPipeline,
+        estados::AlmacenEstados,
+        etapa::{EntradaEtapa, SalidasEtapa, Siguiente, iniciar_etapa},
+        logger::{Logger, TaggedLogger},
+        metricas::RegistroMetricas,
+        prioridad::{EmisorPrioridad, canal_prioridad},
+        redaccion::Redactor,
+        transaccion::{Transaccion, TransaccionAutorizada, TransaccionRechazada, TipoTransaccion},
+        traza::Traza,
+        sombra::ResumenSombra,
+        supervisor::Latido,
+    };
 
     #[test]
     fn procesador_ia_enviar_transaccion_validada_sino_detecta_lavado_de_dinero() {
@@ -124,7 +241,9 @@ mod tests {
             id_cliente: Uuid::new_v4(),
             timestamp: 112315846_128,
             tipo: TipoTransaccion::CashIn,
-            monto: 123.33
+            monto: 123.33,
+            traza: Traza::default(),
+            prioridad: None
         };
         let hash = Uuid::new_v4();
         let transaccion_autorizada = TransaccionAutorizada {
@@ -132,16 +251,18 @@ mod tests {
             autorizacion: hash
         };
 
-        let (tx_transacciones_autorizadas, rx_transacciones_autorizadas_) = channel();
-        let rx_transacciones_autorizadas = Arc::new(Mutex::new(rx_transacciones_autorizadas_));
-        let (tx_transacciones_validadas, rx_transacciones_validadas) = channel();
+        let (tx_transacciones_autorizadas, rx_transacciones_autorizadas) = channel();
+        let (tx_transacciones_validadas, rx_transacciones_validadas) = canal_prioridad(None, Duration::ZERO);
 
         tx_transacciones_autorizadas.send(transaccion_autorizada).unwrap();
 
-        ProcesadorIA::iniciar(crear_logger(),
-                   rx_transacciones_autorizadas,
+        let (tx_transacciones_rechazadas, _rx_transacciones_rechazadas) = channel();
+
+        iniciar_procesador(rx_transacciones_autorizadas,
                    tx_transacciones_validadas,
-                   Arc::new(Mutex::new(StdRng::seed_from_u64(2_64))));
+                   tx_transacciones_rechazadas,
+                   crear_contexto(vec![], crear_almacen(id_transaccion)),
+                   crear_procesador(2_64));
         let recibida = rx_transacciones_validadas.recv().unwrap();
         assert_eq!(recibida.transaccion.id, id_transaccion);
         assert_eq!(recibida.autorizacion, hash);
@@ -150,12 +271,21 @@ mod tests {
     #[test]
     fn procesador_ia_no_envia_transaccion_validada_si_detecta_lavado_de_dinero() {
         let id_transaccion = 2;
+        let cliente = Arc::new(Cliente::new(
+            Uuid::new_v4(),
+            Arc::new(AtomicU32::new(1)),
+            Arc::new(Mutex::new(StdRng::seed_from_u64(264)))
+        ));
+        let monto = 123.33;
+        assert!(cliente.retener(monto));
         let transaccion = Transaccion {
             id: id_transaccion,
-            id_cliente: Uuid::new_v4(),
+            id_cliente: cliente.id,
             timestamp: 112315846_128,
-            tipo: TipoTransaccion::CashIn,
-            monto: 123.33
+            tipo: TipoTransaccion::CashOut,
+            monto,
+            traza: Traza::default(),
+            prioridad: None
         };
         let hash = Uuid::new_v4();
         let transaccion_autorizada = TransaccionAutorizada {
@@ -163,20 +293,134 @@ mod tests {
             autorizacion: hash
         };
 
-        let (tx_transacciones_autorizadas, rx_transacciones_autorizadas_) = channel();
-        let rx_transacciones_autorizadas = Arc::new(Mutex::new(rx_transacciones_autorizadas_));
-        let (tx_transacciones_validadas, rx_transacciones_validadas) = channel();
+        let (tx_transacciones_autorizadas, rx_transacciones_autorizadas) = channel();
+        let (tx_transacciones_validadas, rx_transacciones_validadas) = canal_prioridad(None, Duration::ZERO);
 
         tx_transacciones_autorizadas.send(transaccion_autorizada).unwrap();
 
-        let handle = ProcesadorIA::iniciar(crear_logger(),
-                   rx_transacciones_autorizadas,
+        let (tx_transacciones_rechazadas, rx_transacciones_rechazadas) = channel();
+        let estados = crear_almacen(id_transaccion);
+
+        let handle = iniciar_procesador(rx_transacciones_autorizadas,
                    tx_transacciones_validadas,
-                   Arc::new(Mutex::new(StdRng::seed_from_u64(34_64))));
+                   tx_transacciones_rechazadas,
+                   crear_contexto(vec![cliente.clone()], estados.clone()),
+                   crear_procesador(34_64));
         drop(tx_transacciones_autorizadas);
         handle.join().unwrap();
         let resultado = rx_transacciones_validadas.try_recv();
         assert!(resultado.is_err());
+        let rechazada = rx_transacciones_rechazadas.recv().unwrap();
+        assert_eq!(rechazada.transaccion.id, id_transaccion);
+        assert_eq!(rechazada.motivo, "Lavado de dinero detectado");
+        assert_eq!(estados.consultar(id_transaccion).unwrap().estado, EstadoTransaccion::Rechazada);
+        // La retención del cash out se devuelve al disponible
+        assert_eq!(cliente.get_saldos().retenido, 0.0);
+    }
+
+    #[test]
+    fn procesador_ia_rechaza_lo_que_se_aparta_del_perfil_del_cliente() {
+        let id_cliente = Uuid::new_v4();
+        let configuracion = ConfiguracionIA { probabilidad_lavado: 0.0, tiempo_maximo_ms: 1, ..Default::default() };
+        let perfiles = Arc::new(PerfilesClientes::cargar(ConfiguracionPerfiles {
+            minimo_operaciones: 10,
+            calentamiento: "aprobar".into(),
+            ..Default::default()
+        }).unwrap());
+        let estados = Arc::new(AlmacenEstados::new(crear_logger()));
+        let (tx_transacciones_autorizadas, rx_transacciones_autorizadas) = channel();
+        for (id, monto) in (1..=10).map(|id| (id, 100.0 + id as f32)).chain([(11, 9000.0), (12, 104.0)]) {
+            estados.forzar(id, EstadoTransaccion::Autorizada);
+            let transaccion = Transaccion::de_prueba(id, id_cliente, TipoTransaccion::CashIn, monto);
+            tx_transacciones_autorizadas.send(TransaccionAutorizada { transaccion, autorizacion: Uuid::new_v4() }).unwrap();
+        }
+        drop(tx_transacciones_autorizadas);
+
+        let (tx_transacciones_validadas, rx_transacciones_validadas) = canal_prioridad(None, Duration::ZERO);
+        let (tx_transacciones_rechazadas, rx_transacciones_rechazadas) = channel();
+        iniciar_procesador(rx_transacciones_autorizadas,
+                   tx_transacciones_validadas,
+                   tx_transacciones_rechazadas,
+                   crear_contexto(vec![], estados.clone()),
+                   ProcesadorIA::new(configuracion, ProcesadorIA::generador(1)).con_perfiles(perfiles.clone()))
+            .join()
+            .unwrap();
+
+        assert_eq!(rx_transacciones_validadas.iter().count(), 11);
+        let rechazada = rx_transacciones_rechazadas.recv().unwrap();
+        assert_eq!((rechazada.transaccion.id, rechazada.motivo.as_str()), (11, "Comportamiento anómalo del cliente"));
+        assert_eq!(perfiles.perfil(id_cliente).unwrap().operaciones, 11);
+    }
+
+    #[test]
+    fn la_sombra_ve_las_mismas_transacciones_sin_rechazar_ninguna() {
+        let estados = Arc::new(AlmacenEstados::new(crear_logger()));
+        let (tx_transacciones_autorizadas, rx_transacciones_autorizadas) = channel();
+        for id in 1..=5 {
+            estados.forzar(id, EstadoTransaccion::Autorizada);
+            let transaccion = Transaccion::de_prueba(id, Uuid::new_v4(), TipoTransaccion::CashIn, 50.0);
+            tx_transacciones_autorizadas.send(TransaccionAutorizada { transaccion, autorizacion: Uuid::new_v4() }).unwrap();
+        }
+        drop(tx_transacciones_autorizadas);
+        let aprueba_todo = ConfiguracionIA { probabilidad_lavado: 0.0, tiempo_maximo_ms: 1, ..Default::default() };
+        let rechaza_todo = ConfiguracionIA { probabilidad_lavado: 1.0, ..aprueba_todo.clone() };
+        let archivo = std::env::temp_dir().join(format!("comparacion_{}.csv", Uuid::new_v4()));
+        let registro = Arc::new(RegistroSombra::crear(archivo.to_str().unwrap()).unwrap());
+
+        let (tx_transacciones_validadas, rx_transacciones_validadas) = canal_prioridad(None, Duration::ZERO);
+        let (tx_transacciones_rechazadas, rx_transacciones_rechazadas) = channel();
+        iniciar_procesador(rx_transacciones_autorizadas,
+                   tx_transacciones_validadas,
+                   tx_transacciones_rechazadas,
+                   crear_contexto(vec![], estados),
+                   ProcesadorIA::new(aprueba_todo, ProcesadorIA::generador(1))
+                       .con_sombra(ProcesadorIA::new(rechaza_todo, ProcesadorIA::generador(2)), registro.clone()))
+            .join()
+            .unwrap();
+
+        assert_eq!(rx_transacciones_validadas.iter().count(), 5);
+        assert!(rx_transacciones_rechazadas.try_recv().is_err());
+        assert_eq!(registro.resumen(), ResumenSombra { total: 5, coincidencias: 0, solo_activo: 0, solo_sombra: 5 });
+        std::fs::remove_file(archivo).unwrap();
+    }
+
+    fn iniciar_procesador(rx_transacciones_autorizadas: Receiver<TransaccionAutorizada>,
+                          tx_transacciones_validadas: EmisorPrioridad<TransaccionAutorizada>,
+                          tx_transacciones_rechazadas: Sender<TransaccionRechazada>,
+                          contexto: ContextoPipeline,
+                          procesador: ProcesadorIA) -> thread::JoinHandle<()> {
+        let (tx_transacciones_fallidas, _) = channel();
+        iniciar_etapa(move || procesador,
+                   crear_logger(),
+                   EntradaEtapa::new(rx_transacciones_autorizadas, "ia"),
+                   SalidasEtapa {
+                       siguiente: Some(Siguiente { emisor: tx_transacciones_validadas, cola: "final" }),
+                       rechazadas: tx_transacciones_rechazadas,
+                       fallidas: tx_transacciones_fallidas,
+                   },
+                   contexto)
+    }
+
+    fn crear_procesador(semilla: u64) -> ProcesadorIA {
+        ProcesadorIA::new(ConfiguracionIA::default(), ProcesadorIA::generador(semilla))
+    }
+
+    fn crear_contexto(clientes: Vec<Arc<Cliente>>, estados: Arc<AlmacenEstados>) -> ContextoPipeline {
+        ContextoPipeline {
+            clientes: Arc::new(clientes),
+            estados,
+            metricas: Arc::new(RegistroMetricas::default()),
+            trazas: None,
+            redactor: Arc::new(Redactor::default()),
+            apagado: Arc::new(Apagado::default()),
+            latido: Arc::new(Latido::default()),
+        }
+    }
+
+    fn crear_almacen(id_autorizada: u32) -> Arc<AlmacenEstados> {
+        let estados = AlmacenEstados::new(crear_logger());
+        estados.forzar(id_autorizada, EstadoTransaccion::Autorizada);
+        Arc::new(estados)
     }
 
     fn crear_logger() -> TaggedLogger {
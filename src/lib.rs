@@ -0,0 +1,84 @@
+//! Pipeline de procesamiento de transacciones de cash in y cash out: los
+//! workers piden la autorización al proveedor, el procesador ia busca
+//! lavado de dinero y el worker final liquida en el saldo del cliente.
+//!
+//! `Pipeline` corre todas las etapas dentro de otro programa; el binario
+//! `dinero-oxidado` es la línea de comandos sobre esta biblioteca.
+
+extern crate rand;
+extern crate csv;
+extern crate serde;
+extern crate clap;
+
+mod cliente;
+mod procesador;
+mod logger;
+mod proveedor_externo;
+mod worker;
+mod transaccion;
+mod simulacion;
+mod ia;
+mod modelo;
+mod perfiles;
+mod sombra;
+mod worker_final;
+mod rechazos;
+mod perfil_fallas;
+mod reintentos;
+mod proveedor_autorizacion;
+mod proveedor_http;
+pub mod api_proveedor_http;
+mod limitador;
+mod estados;
+mod contexto;
+mod traza;
+mod metricas;
+mod rotacion;
+mod redaccion;
+mod archivos;
+mod verificacion;
+mod reporte;
+mod configuracion;
+mod apagado;
+mod supervisor;
+mod autoescalado;
+mod etapa;
+mod pipeline;
+mod prioridad;
+
+// La API para correr el pipeline dentro de otro programa
+pub use cliente::{Cliente, Saldo};
+pub use configuracion::Configuracion;
+pub use contexto::ContextoPipeline;
+pub use estados::{EstadoTransaccion, RegistroEstado};
+pub use etapa::{ContextoEtapa, Etapa, Rechazo, Resultado};
+pub use ia::ProcesadorIA;
+pub use logger::Logger;
+pub use metricas::LATENCIA_PRIORIDAD;
+pub use pipeline::Pipeline;
+pub use prioridad::Prioridad;
+pub use procesador::Enrutador;
+pub use proveedor_autorizacion::{ProveedorAutorizacion, ProveedorMock};
+pub use proveedor_externo::RespuestaAutorizacion;
+pub use redaccion::Redactor;
+pub use transaccion::{TipoTransaccion, Transaccion, TransaccionRechazada};
+pub use traza::Traza;
+pub use worker::Worker;
+pub use worker_final::WorkerFinal;
+
+/// Lo que usa la línea de comandos además de la API de arriba. No es
+/// parte de la biblioteca: puede cambiar junto con el binario.
+#[doc(hidden)]
+pub mod cli {
+    pub use crate::{
+        apagado::{Abortar, escuchar_senales},
+        archivos::{ArchivosCorrida, escribir_clientes, leer_clientes},
+        configuracion::ConfiguracionLog,
+        estados::{AlmacenEstados, iniciar_consulta_estados},
+        logger::{NivelLog, PoliticaColaLlena, TaggedLogger},
+        reporte::armar_reporte,
+        rotacion::ConfiguracionRotacion,
+        simulacion::simular_transacciones,
+        verificacion::verificar,
+    };
+}
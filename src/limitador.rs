@@ -0,0 +1,264 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    proveedor_autorizacion::{ProveedorAutorizacion, ProveedorCerrado},
+    proveedor_externo::RespuestaAutorizacion,
+    transaccion::{TipoTransaccion, Transaccion},
+};
+
+/// Cantidad de llamadas por segundo permitidas y cuántas se pueden
+/// hacer de golpe
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cuota {
+    pub tasa: f64,
+    pub rafaga: f64,
+}
+
+impl Cuota {
+    /// Interpreta una cuota de la forma <tasa>[:<ráfaga>]. Sin ráfaga
+    /// se permite un segundo de llamadas de golpe.
+    pub fn parsear(texto: &str) -> Result<Self, String> {
+        let (tasa, rafaga) = match texto.split_once(':') {
+            Some((tasa, rafaga)) => (tasa, Some(rafaga)),
+            None => (texto, None),
+        };
+        let positivo = |valor: &str| {
+            valor
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|v| v.is_finite() && *v > 0.0)
+                .ok_or_else(|| format!("Cuota inválida '{}': se espera <tasa>[:<ráfaga>] con valores positivos", texto))
+        };
+        let tasa = positivo(tasa)?;
+        let rafaga = match rafaga {
+            Some(rafaga) => positivo(rafaga)?,
+            None => tasa.max(1.0),
+        };
+
+        Ok(Self { tasa, rafaga })
+    }
+}
+
+/// Token bucket. Las llamadas que no encuentran tokens no fallan: se
+/// les reserva el próximo token disponible y esperan su turno.
+pub struct CuboTokens {
+    cuota: Cuota,
+    estado: Mutex<(f64, Instant)>,
+}
+
+impl CuboTokens {
+    pub fn new(cuota: Cuota) -> Self {
+        Self {
+            cuota,
+            estado: Mutex::new((cuota.rafaga, Instant::now())),
+        }
+    }
+
+    /// Toma un token y devuelve cuánto hay que esperar para usarlo
+    pub fn reservar(&self) -> Duration {
+        let mut estado = self.estado.lock().expect("cubo poisoned");
+        let (tokens, ultima_recarga) = *estado;
+        let ahora = Instant::now();
+        let recargados = (tokens + ahora.duration_since(ultima_recarga).as_secs_f64() * self.cuota.tasa)
+            .min(self.cuota.rafaga);
+
+        // Los tokens negativos son turnos ya reservados por otros
+        let restantes = recargados - 1.0;
+        *estado = (restantes, ahora);
+
+        if restantes >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-restantes / self.cuota.tasa)
+        }
+    }
+}
+
+/// Uso de la cuota de un tipo de transacción durante la corrida
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UsoCuota {
+    pub llamadas: u64,
+    pub demoradas: u64,
+    pub espera_total: Duration,
+    pub espera_maxima: Duration,
+}
+
+impl fmt::Display for UsoCuota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} llamadas, {} demoradas por cuota, espera total {:?} (máxima {:?})",
+            self.llamadas, self.demoradas, self.espera_total, self.espera_maxima
+        )
+    }
+}
+
+/// Proveedor que respeta una cuota global y otra por tipo de
+/// transacción antes de delegar en el proveedor real
+pub struct ProveedorLimitado {
+    proveedor: Arc<dyn ProveedorAutorizacion>,
+    global: Option<CuboTokens>,
+    cash_in: Option<CuboTokens>,
+    cash_out: Option<CuboTokens>,
+    uso_global: Mutex<UsoCuota>,
+    uso_cash_in: Mutex<UsoCuota>,
+    uso_cash_out: Mutex<UsoCuota>,
+}
+
+impl ProveedorLimitado {
+    pub fn new(proveedor: Arc<dyn ProveedorAutorizacion>,
+               global: Option<Cuota>,
+               cash_in: Option<Cuota>,
+               cash_out: Option<Cuota>) -> Self {
+        Self {
+            proveedor,
+            global: global.map(CuboTokens::new),
+            cash_in: cash_in.map(CuboTokens::new),
+            cash_out: cash_out.map(CuboTokens::new),
+            uso_global: Mutex::new(UsoCuota::default()),
+            uso_cash_in: Mutex::new(UsoCuota::default()),
+            uso_cash_out: Mutex::new(UsoCuota::default()),
+        }
+    }
+
+    pub fn uso(&self, tipo: TipoTransaccion) -> UsoCuota {
+        let uso = match tipo {
+            TipoTransaccion::CashIn => &self.uso_cash_in,
+            TipoTransaccion::CashOut => &self.uso_cash_out,
+        };
+        *uso.lock().expect("uso poisoned")
+    }
+
+    /// Uso de la cuota que comparten todos los workers, si hay una
+    pub fn uso_global(&self) -> Option<UsoCuota> {
+        self.global.as_ref().map(|_| *self.uso_global.lock().expect("uso poisoned"))
+    }
+
+    /// Espera el turno de una llamada con transacciones de los tipos
+    /// dados y devuelve cuánto esperó. Un lote consume un único token de
+    /// cada cuota que alcanza.
+    pub fn esperar_turno(&self, tipos: &[TipoTransaccion]) -> Duration {
+        let mut cubos = vec![&self.global];
+        let mut usos = vec![];
+        if self.global.is_some() {
+            usos.push(&self.uso_global);
+        }
+        for tipo in [TipoTransaccion::CashIn, TipoTransaccion::CashOut] {
+            if tipos.contains(&tipo) {
+                let (cubo, uso) = match tipo {
+                    TipoTransaccion::CashIn => (&self.cash_in, &self.uso_cash_in),
+                    TipoTransaccion::CashOut => (&self.cash_out, &self.uso_cash_out),
+                };
+                cubos.push(cubo);
+                usos.push(uso);
+            }
+        }
+        let espera = cubos
+            .iter()
+            .filter_map(|cubo| cubo.as_ref())
+            .map(CuboTokens::reservar)
+            .max()
+            .unwrap_or_default();
+
+        for uso in usos {
+            let mut uso = uso.lock().expect("uso poisoned");
+            uso.llamadas += 1;
+            if espera > Duration::from_secs(0) {
+                uso.demoradas += 1;
+                uso.espera_total += espera;
+                uso.espera_maxima = uso.espera_maxima.max(espera);
+            }
+        }
+        // Al alcanzar la cuota se hace cola en vez de fallar
+        thread::sleep(espera);
+        espera
+    }
+}
+
+impl ProveedorAutorizacion for ProveedorLimitado {
+    fn solicitar(&self, transaccion: &Transaccion, timeout: Duration) -> Result<RespuestaAutorizacion, ProveedorCerrado> {
+        self.esperar_turno(&[transaccion.tipo]);
+        self.proveedor.solicitar(transaccion, timeout)
+    }
+
+    fn solicitar_lote(&self, transacciones: &[&Transaccion], timeout: Duration) -> Result<Vec<RespuestaAutorizacion>, ProveedorCerrado> {
+        let tipos: Vec<_> = transacciones.iter().map(|transaccion| transaccion.tipo).collect();
+        self.esperar_turno(&tipos);
+        self.proveedor.solicitar_lote(transacciones, timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+    use crate::proveedor_autorizacion::ProveedorMock;
+
+    #[test]
+    fn cubo_permite_la_rafaga_y_despues_espacia_las_llamadas() {
+        let cubo = CuboTokens::new(Cuota { tasa: 100.0, rafaga: 2.0 });
+        assert_eq!(cubo.reservar(), Duration::from_secs(0));
+        assert_eq!(cubo.reservar(), Duration::from_secs(0));
+        let tercera = cubo.reservar();
+        let cuarta = cubo.reservar();
+        assert!(tercera > Duration::from_millis(5) && tercera <= Duration::from_millis(10));
+        assert!(cuarta > tercera);
+    }
+
+    #[test]
+    fn proveedor_limitado_aplica_la_cuota_de_cada_tipo_por_separado() {
+        let proveedor = ProveedorLimitado::new(
+            Arc::new(ProveedorMock::default()),
+            None,
+            Some(Cuota { tasa: 100.0, rafaga: 1.0 }),
+            None
+        );
+        for id in 0..3 {
+            proveedor.solicitar(&crear_transaccion(id, TipoTransaccion::CashIn), Duration::from_secs(1)).unwrap();
+            proveedor.solicitar(&crear_transaccion(id, TipoTransaccion::CashOut), Duration::from_secs(1)).unwrap();
+        }
+
+        let uso_cash_in = proveedor.uso(TipoTransaccion::CashIn);
+        assert_eq!(uso_cash_in.llamadas, 3);
+        assert!(uso_cash_in.demoradas >= 1);
+        assert_eq!(proveedor.uso(TipoTransaccion::CashOut).demoradas, 0);
+        assert!(proveedor.uso_global().is_none());
+    }
+
+    #[test]
+    fn un_lote_consume_un_solo_token() {
+        let proveedor = ProveedorLimitado::new(
+            Arc::new(ProveedorMock::default()),
+            Some(Cuota { tasa: 1.0, rafaga: 1.0 }),
+            None,
+            None
+        );
+        let transacciones: Vec<_> = (0..5).map(|id| crear_transaccion(id, TipoTransaccion::CashIn)).collect();
+        let lote: Vec<_> = transacciones.iter().collect();
+        let respuestas = proveedor.solicitar_lote(&lote, Duration::from_secs(1)).unwrap();
+
+        assert_eq!(respuestas.len(), 5);
+        let uso = proveedor.uso(TipoTransaccion::CashIn);
+        assert_eq!(uso.llamadas, 1);
+        assert_eq!(uso.demoradas, 0);
+        assert_eq!(proveedor.uso_global().unwrap().llamadas, 1);
+    }
+
+    #[test]
+    fn parsear_cuota_con_y_sin_rafaga() {
+        assert_eq!(Cuota::parsear("50:5"), Ok(Cuota { tasa: 50.0, rafaga: 5.0 }));
+        assert_eq!(Cuota::parsear("20"), Ok(Cuota { tasa: 20.0, rafaga: 20.0 }));
+        assert!(Cuota::parsear("0").is_err());
+        assert!(Cuota::parsear("diez:2").is_err());
+    }
+
+    fn crear_transaccion(id: u32, tipo: TipoTransaccion) -> Transaccion {
+        Transaccion::de_prueba(id, Uuid::new_v4(), tipo, 10.0)
+    }
+}
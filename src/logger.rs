@@ -1,36 +1,390 @@
 use std::{
-    fs::File, 
-    io::prelude::*, 
-    sync::{Arc, Mutex}, 
+    fmt,
+    io::{self, prelude::*, BufWriter},
+    panic,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TryRecvError, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+    thread::JoinHandle,
     time::{Duration, Instant}
 };
+use serde_json::{Map, Value};
+
+use crate::{
+    redaccion::Redactor,
+    rotacion::{ArchivoRotativo, ConfiguracionRotacion},
+};
+
+/// Variable de entorno con el filtro de niveles, si no se pasa por CLI
+pub const VARIABLE_FILTRO_LOG: &str = "DINERO_LOG";
+/// Campos cuyo valor identifica a un cliente y pasa por el redactor
+pub const CAMPOS_SENSIBLES: [&str; 1] = ["client_id"];
+// Cuánto se espera a que el hilo del log termine de escribir lo encolado
+const ESPERA_VACIADO: Duration = Duration::from_secs(1);
+
+/// Importancia de un mensaje, de mayor a menor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum NivelLog {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl NivelLog {
+    pub fn desde_nombre(nombre: &str) -> Result<Self, String> {
+        match nombre.trim().to_lowercase().as_str() {
+            "error" => Ok(NivelLog::Error),
+            "warn" => Ok(NivelLog::Warn),
+            "info" => Ok(NivelLog::Info),
+            "debug" => Ok(NivelLog::Debug),
+            "trace" => Ok(NivelLog::Trace),
+            _ => Err(format!("Nivel de log inválido '{}': se espera error, warn, info, debug o trace", nombre)),
+        }
+    }
+
+    fn nombre(&self) -> &'static str {
+        match self {
+            NivelLog::Error => "error",
+            NivelLog::Warn => "warn",
+            NivelLog::Info => "info",
+            NivelLog::Debug => "debug",
+            NivelLog::Trace => "trace",
+        }
+    }
+}
+
+impl fmt::Display for NivelLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.nombre().to_uppercase())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FormatoLog {
+    /// Una línea legible por mensaje
+    Texto,
+    /// Un objeto JSON por línea (JSON Lines)
+    Json,
+}
+
+impl FormatoLog {
+    /// Primera línea de cada archivo de log, con el id de la corrida y
+    /// los parámetros con los que se lanzó
+    pub fn encabezado(&self, id_corrida: &str, parametros: &str) -> String {
+        match self {
+            FormatoLog::Texto => format!("# Corrida {} | {}\n", id_corrida, parametros),
+            FormatoLog::Json => format!("{}\n", serde_json::json!({ "run_id": id_corrida, "params": parametros })),
+        }
+    }
+
+    pub fn desde_nombre(nombre: &str) -> Result<Self, String> {
+        match nombre {
+            "texto" => Ok(FormatoLog::Texto),
+            "json" => Ok(FormatoLog::Json),
+            _ => Err(format!("Formato de log inválido '{}': se espera texto o json", nombre)),
+        }
+    }
+}
+
+/// Nivel mínimo de los mensajes que se escriben, general y por
+/// etiqueta
+#[derive(Debug, Clone, PartialEq)]
+pub struct FiltroNiveles {
+    nivel: NivelLog,
+    por_etiqueta: Vec<(String, NivelLog)>,
+}
+
+impl Default for FiltroNiveles {
+    fn default() -> Self {
+        Self::new(NivelLog::Info)
+    }
+}
+
+impl FiltroNiveles {
+    pub fn new(nivel: NivelLog) -> Self {
+        Self {
+            nivel,
+            por_etiqueta: vec![],
+        }
+    }
+
+    /// Interpreta un filtro de la forma <nivel>,<etiqueta>=<nivel>,...
+    /// por ejemplo "warn,WORKER=debug". Una etiqueta aplica a todas las
+    /// que empiezan con ella y gana la más larga.
+    pub fn parsear(texto: &str) -> Result<Self, String> {
+        let mut filtro = Self::default();
+        for parte in texto.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            match parte.split_once('=') {
+                Some((etiqueta, nivel)) => filtro.por_etiqueta.push((etiqueta.trim().to_string(), NivelLog::desde_nombre(nivel)?)),
+                None => filtro.nivel = NivelLog::desde_nombre(parte)?,
+            }
+        }
+        filtro.por_etiqueta.sort_by_key(|(etiqueta, _)| std::cmp::Reverse(etiqueta.len()));
+
+        Ok(filtro)
+    }
+
+    pub fn habilitado(&self, etiqueta: &str, nivel: NivelLog) -> bool {
+        let minimo = self.por_etiqueta
+            .iter()
+            .find(|(prefijo, _)| etiqueta.starts_with(prefijo.as_str()))
+            .map_or(self.nivel, |(_, nivel)| *nivel);
+
+        nivel <= minimo
+    }
+}
+
+/// Qué hacer con un mensaje cuando la cola del hilo del log está llena
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PoliticaColaLlena {
+    /// Esperar a que haya lugar: no se pierde ningún mensaje
+    Bloquear,
+    /// Descartar el mensaje
+    Descartar,
+    /// Descartar el mensaje y llevar la cuenta de los descartados
+    Contar,
+}
+
+impl PoliticaColaLlena {
+    pub fn desde_nombre(nombre: &str) -> Result<Self, String> {
+        match nombre {
+            "bloquear" => Ok(PoliticaColaLlena::Bloquear),
+            "descartar" => Ok(PoliticaColaLlena::Descartar),
+            "contar" => Ok(PoliticaColaLlena::Contar),
+            _ => Err(format!("Política de cola llena inválida '{}': se espera bloquear, descartar o contar", nombre)),
+        }
+    }
+}
+
+enum MensajeLog {
+    Linea(String),
+    /// Pide escribir todo lo encolado hasta acá y avisar al terminar
+    Vaciar(Sender<()>),
+}
+
+/// Hilo que escribe los mensajes del log con buffer, alimentado por una
+/// cola acotada. Al soltarse escribe lo que quedó encolado.
+struct SegundoPlano {
+    tx: Option<SyncSender<MensajeLog>>,
+    hilo: Option<JoinHandle<()>>,
+    politica: PoliticaColaLlena,
+    descartados: AtomicU64,
+}
+
+impl SegundoPlano {
+    fn iniciar(destino: DestinoLog, capacidad: usize, politica: PoliticaColaLlena) -> Self {
+        let (tx, rx) = sync_channel(capacidad);
+        let hilo = thread::spawn(move || escribir_en_segundo_plano(rx, destino));
+
+        Self {
+            tx: Some(tx),
+            hilo: Some(hilo),
+            politica,
+            descartados: AtomicU64::new(0),
+        }
+    }
+
+    fn encolar(&self, linea: String) {
+        let tx = match &self.tx {
+            Some(tx) => tx,
+            None => return
+        };
+        match self.politica {
+            PoliticaColaLlena::Bloquear => {
+                let _ = tx.send(MensajeLog::Linea(linea));
+            }
+            PoliticaColaLlena::Descartar => {
+                let _ = tx.try_send(MensajeLog::Linea(linea));
+            }
+            PoliticaColaLlena::Contar => {
+                if let Err(TrySendError::Full(_)) = tx.try_send(MensajeLog::Linea(linea)) {
+                    self.descartados.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    fn vaciar(&self) {
+        if let Some(tx) = &self.tx {
+            let (tx_listo, rx_listo) = channel();
+            if tx.send(MensajeLog::Vaciar(tx_listo)).is_ok() {
+                let _ = rx_listo.recv_timeout(ESPERA_VACIADO);
+            }
+        }
+    }
+}
+
+impl Drop for SegundoPlano {
+    fn drop(&mut self) {
+        // Cerrar la cola hace que el hilo escriba lo pendiente y termine
+        drop(self.tx.take());
+        if let Some(hilo) = self.hilo.take() {
+            let _ = hilo.join();
+        }
+    }
+}
+
+/// Escribe los mensajes a medida que llegan y baja el buffer cada vez
+/// que la cola se vacía. Los errores de escritura se ignoran: el log no
+/// debe frenar el pipeline.
+fn escribir_en_segundo_plano(rx: Receiver<MensajeLog>, mut destino: DestinoLog) {
+    loop {
+        let mensaje = match rx.try_recv() {
+            Ok(mensaje) => mensaje,
+            Err(TryRecvError::Empty) => {
+                let _ = destino.flush();
+                match rx.recv() {
+                    Ok(mensaje) => mensaje,
+                    Err(_) => break
+                }
+            }
+            Err(TryRecvError::Disconnected) => break,
+        };
+        match mensaje {
+            MensajeLog::Linea(linea) => {
+                let _ = destino.escribir(&linea);
+            }
+            MensajeLog::Vaciar(listo) => {
+                let _ = destino.flush();
+                let _ = listo.send(());
+            }
+        }
+    }
+    let _ = destino.flush();
+}
+
+/// Dónde escribe el hilo del log
+enum DestinoLog {
+    Consola(BufWriter<io::Stdout>),
+    Archivo(ArchivoRotativo),
+}
+
+impl DestinoLog {
+    fn escribir(&mut self, mensaje: &str) -> io::Result<()> {
+        match self {
+            DestinoLog::Consola(consola) => consola.write_all(mensaje.as_bytes()),
+            DestinoLog::Archivo(archivo) => archivo.escribir(mensaje),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            DestinoLog::Consola(consola) => consola.flush(),
+            DestinoLog::Archivo(archivo) => archivo.flush(),
+        }
+    }
+}
 
 pub struct Logger {
-    file: Option<Mutex<File>>,
-    timer: Instant
+    file: Option<Mutex<ArchivoRotativo>>,
+    timer: Instant,
+    formato: FormatoLog,
+    filtro: FiltroNiveles,
+    redactor: Arc<Redactor>,
+    segundo_plano: Option<SegundoPlano>,
 }
 
 impl Logger {
     pub fn new_to_stdout() -> Self {
-        Self { file: None, timer: Instant::now() }
+        Self {
+            file: None,
+            timer: Instant::now(),
+            formato: FormatoLog::Texto,
+            filtro: FiltroNiveles::default(),
+            redactor: Arc::new(Redactor::default()),
+            segundo_plano: None,
+        }
     }
 
+    #[cfg(test)]
     pub fn new_to_file(path: &str) -> Result<Self, String> {
+        Self::new_to_file_con_rotacion(path, ConfiguracionRotacion::default(), "")
+    }
+
+    /// Escribe a un archivo que se rota según la configuración. Cada
+    /// archivo empieza con el encabezado dado.
+    pub fn new_to_file_con_rotacion(path: &str, rotacion: ConfiguracionRotacion, encabezado: &str) -> Result<Self, String> {
         Ok(Self {
             file: Some(Mutex::new(
-                File::create(path)
+                ArchivoRotativo::new(path, rotacion, encabezado)
                     .map_err(|e| e.to_string())?
             )),
-            timer: Instant::now()
+            ..Self::new_to_stdout()
         })
     }
 
+    pub fn con_formato(self, formato: FormatoLog) -> Self {
+        Self { formato, ..self }
+    }
+
+    pub fn con_filtro(self, filtro: FiltroNiveles) -> Self {
+        Self { filtro, ..self }
+    }
+
+    /// Los valores de los campos sensibles se escriben redactados
+    pub fn con_redactor(self, redactor: Arc<Redactor>) -> Self {
+        Self { redactor, ..self }
+    }
+
+    /// Pasa la escritura a un hilo propio: quien loguea solo encola el
+    /// mensaje, y si la cola de `capacidad` mensajes está llena aplica
+    /// la política dada.
+    pub fn en_segundo_plano(mut self, capacidad: usize, politica: PoliticaColaLlena) -> Self {
+        let destino = match self.file.take() {
+            Some(file) => DestinoLog::Archivo(file.into_inner().expect("log poisoned")),
+            None => DestinoLog::Consola(BufWriter::new(io::stdout())),
+        };
+        self.segundo_plano = Some(SegundoPlano::iniciar(destino, capacidad, politica));
+
+        self
+    }
+
+    /// Mensajes descartados porque la cola estaba llena, con la
+    /// política Contar
+    pub fn descartados(&self) -> u64 {
+        self.segundo_plano
+            .as_ref()
+            .map_or(0, |segundo_plano| segundo_plano.descartados.load(Ordering::Relaxed))
+    }
+
+    /// Espera a que se escriba todo lo logueado hasta ahora
+    pub fn vaciar(&self) {
+        match &self.segundo_plano {
+            Some(segundo_plano) => segundo_plano.vaciar(),
+            None => {
+                let _ = io::stdout().flush();
+            }
+        }
+    }
+
+    /// Vacía el log antes de informar un panic, para no perder los
+    /// mensajes que llevaron a él
+    pub fn vaciar_al_entrar_en_panico(logger: &Arc<Logger>) {
+        let logger = Arc::downgrade(logger);
+        let anterior = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if let Some(logger) = logger.upgrade() {
+                logger.vaciar();
+            }
+            anterior(info);
+        }));
+    }
+
     /// Escribe msg al log sin agregar nada (ni salto de línea,
     /// ni etiquetas).
     pub fn write_raw(&self, msg: &str) {
-        if let Some(file_mutex) = &self.file {
+        if let Some(segundo_plano) = &self.segundo_plano {
+            segundo_plano.encolar(msg.to_string());
+        } else if let Some(file_mutex) = &self.file {
+            // Sin hilo propio no hay quien vacíe el buffer más tarde
             let mut file = file_mutex.lock().expect("log poisoned");
-            file.write_all(msg.as_bytes())
+            file.escribir(msg)
+                .and_then(|_| file.flush())
                 .expect("No se puede escribir al archivo de log.");
         } else {
             print!("{}", msg);
@@ -42,10 +396,47 @@ impl Logger {
     pub fn get_elapsed_time(&self) -> Duration {
         self.timer.elapsed()
     }
+
+    /// Escribe un mensaje con sus campos en el formato configurado, si
+    /// el filtro lo deja pasar
+    pub fn registrar(&self, tag: &str, nivel: NivelLog, msg: &str, campos: &[(&str, &str)]) {
+        if !self.filtro.habilitado(tag, nivel) {
+            return;
+        }
+        let time = self.get_elapsed_time();
+        let campos: Vec<(&str, String)> = campos
+            .iter()
+            .map(|(clave, valor)| match CAMPOS_SENSIBLES.contains(clave) {
+                true => (*clave, self.redactor.redactar(valor)),
+                false => (*clave, valor.to_string()),
+            })
+            .collect();
+
+        let linea = match self.formato {
+            FormatoLog::Texto => {
+                let campos: String = campos.iter().map(|(clave, valor)| format!(" {}={}", clave, valor)).collect();
+                format!("{:8.3}| {:>5}| {:>12}| {}{}\n", time.as_secs_f32(), nivel, tag, msg, campos)
+            }
+            FormatoLog::Json => {
+                let mut objeto = Map::new();
+                objeto.insert("ts".into(), Value::from(time.as_secs_f64()));
+                objeto.insert("level".into(), Value::from(nivel.nombre()));
+                objeto.insert("tag".into(), Value::from(tag));
+                objeto.insert("msg".into(), Value::from(msg));
+                for (clave, valor) in campos {
+                    objeto.insert(clave.to_string(), Value::from(valor.as_str()));
+                }
+                format!("{}\n", Value::Object(objeto))
+            }
+        };
+        self.write_raw(&linea);
+    }
 }
 
+#[derive(Clone)]
 pub struct TaggedLogger {
     tag: String,
+    campos: Vec<(&'static str, String)>,
     logger: Arc<Logger>
 }
 
@@ -53,19 +444,119 @@ impl TaggedLogger {
     pub fn new(tag: &str, logger: Arc<Logger>) -> Self {
         Self {
             tag: tag.into(),
+            campos: vec![],
             logger
         }
     }
 
-    /// Escribe un mensaje al log. 
+    /// Agrega un campo fijo a todos los mensajes de este logger
+    pub fn con_campo(mut self, clave: &'static str, valor: impl fmt::Display) -> Self {
+        self.campos.push((clave, valor.to_string()));
+        self
+    }
+
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    /// Escribe un mensaje al log.
     ///
     /// El mensaje estará marcado con la etiqueta correspondiente
     /// y un timestamp.
     pub fn write(&self, msg: &str) {
-        let time = self.logger.get_elapsed_time();
+        self.evento(NivelLog::Info, msg, &[]);
+    }
+
+    pub fn warn(&self, msg: &str) {
+        self.evento(NivelLog::Warn, msg, &[]);
+    }
+
+    pub fn debug(&self, msg: &str) {
+        self.evento(NivelLog::Debug, msg, &[]);
+    }
 
-        self.logger.write_raw(&format!(
-            "{:8.3}| {:>12}| {}\n", time.as_secs_f32(), self.tag, msg
-        ));
+    /// Escribe un mensaje con campos propios además de los fijos del
+    /// logger
+    pub fn evento(&self, nivel: NivelLog, msg: &str, campos: &[(&str, &dyn fmt::Display)]) {
+        if !self.logger.filtro.habilitado(&self.tag, nivel) {
+            return;
+        }
+        let valores: Vec<String> = campos.iter().map(|(_, valor)| valor.to_string()).collect();
+        let todos: Vec<(&str, &str)> = self.campos
+            .iter()
+            .map(|(clave, valor)| (*clave, valor.as_str()))
+            .chain(campos.iter().zip(&valores).map(|((clave, _), valor)| (*clave, valor.as_str())))
+            .collect();
+
+        self.logger.registrar(&self.tag, nivel, msg, &todos);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn filtro_aplica_el_nivel_de_la_etiqueta_mas_larga() {
+        let filtro = FiltroNiveles::parsear("warn, WORKER=debug, WORKER CashOut=error").unwrap();
+
+        assert!(filtro.habilitado("CONTROLADOR", NivelLog::Warn));
+        assert!(!filtro.habilitado("CONTROLADOR", NivelLog::Info));
+        assert!(filtro.habilitado("WORKER CashIn 3", NivelLog::Debug));
+        assert!(!filtro.habilitado("WORKER CashOut 3", NivelLog::Warn));
+        assert!(FiltroNiveles::parsear("WORKER=mucho").is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn formato_json_escribe_una_linea_por_mensaje_con_sus_campos() {
+        let ruta_archivo_tests = "archivo_tests_9.log";
+        let logger = Arc::new(
+            Logger::new_to_file(ruta_archivo_tests)
+                .unwrap()
+                .con_formato(FormatoLog::Json)
+                .con_filtro(FiltroNiveles::new(NivelLog::Debug))
+        );
+        let log = TaggedLogger::new("WORKER CashIn 0", logger).con_campo("worker_id", 0);
+        log.evento(NivelLog::Warn, "Transacción fallida", &[("transaction_id", &7)]);
+        log.evento(NivelLog::Trace, "Oculto", &[]);
+        log.debug("Visible");
+
+        let contenido = fs::read_to_string(ruta_archivo_tests).unwrap();
+        let lineas: Vec<Value> = contenido.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lineas.len(), 2);
+        assert_eq!(lineas[0]["level"], "warn");
+        assert_eq!(lineas[0]["tag"], "WORKER CashIn 0");
+        assert_eq!(lineas[0]["msg"], "Transacción fallida");
+        assert_eq!(lineas[0]["worker_id"], "0");
+        assert_eq!(lineas[0]["transaction_id"], "7");
+        assert_eq!(lineas[1]["msg"], "Visible");
+    }
+
+    #[test]
+    fn logger_en_segundo_plano_escribe_todo_en_orden_al_vaciar_y_al_soltarse() {
+        let ruta_archivo_tests = "archivo_tests_10.log";
+        let logger = Arc::new(
+            Logger::new_to_file(ruta_archivo_tests)
+                .unwrap()
+                .en_segundo_plano(4, PoliticaColaLlena::Bloquear)
+        );
+        let log = TaggedLogger::new("TEST", logger.clone());
+        for i in 0..100 {
+            log.write(&i.to_string());
+        }
+        logger.vaciar();
+        assert_eq!(fs::read_to_string(ruta_archivo_tests).unwrap().lines().count(), 100);
+
+        log.write("100");
+        drop(log);
+        drop(logger);
+        let mensajes: Vec<String> = fs::read_to_string(ruta_archivo_tests)
+            .unwrap()
+            .lines()
+            .map(|linea| linea.rsplit("| ").next().unwrap().to_string())
+            .collect();
+        assert_eq!(mensajes, (0..=100).map(|i| i.to_string()).collect::<Vec<_>>());
+    }
+}
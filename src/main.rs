@@ -1,31 +1,20 @@
-extern crate rand;
-extern crate csv;
-extern crate serde;
-extern crate clap;
-
-mod cliente;
-mod procesador;
-mod logger;
-mod proveedor_externo;
-mod worker;
-mod transaccion;
-mod simulacion;
-mod ia;
-mod worker_final;
-
-use std::sync::{Arc, Mutex, mpsc::channel};
+use std::{io::BufReader, sync::Arc, time::Duration};
 use rand::Rng;
+use uuid::Uuid;
 
-use clap::App;
+use clap::{App, ArgMatches};
 
-use logger::{Logger, TaggedLogger};
-use simulacion::simular_transacciones;
-use procesador::Procesador;
-use proveedor_externo::ProveedorExterno;
-use worker::iniciar_workers_de_tipo;
-use worker::TipoWorker;
-use ia::iniciar_procesadores_ia;
-use worker_final::WorkerFinal;
+use dinero_oxidado::{
+    Configuracion, LATENCIA_PRIORIDAD, Logger, Pipeline, Prioridad, Redactor,
+    cli::{
+        self, Abortar, AlmacenEstados, ArchivosCorrida, ConfiguracionLog, NivelLog, TaggedLogger,
+        armar_reporte, escribir_clientes, escuchar_senales, iniciar_consulta_estados, leer_clientes,
+        simular_transacciones,
+    },
+};
+
+// Código de salida de una corrida abortada por señal
+const CODIGO_ABORTADA: i32 = 130;
 
 fn main()  {
     if let Err(e) = real_main() {
@@ -33,132 +22,260 @@ fn main()  {
     }
 }
 
-const CANTIDAD_DE_CLIENTES_DEFAULT: &str = "10";
-const CANTIDAD_DE_IA_DEFAULT: &str = "10";
-const CANTIDAD_DE_CASHIN_DEFAULT: &str = "10";
-const CANTIDAD_DE_CASHOUT_DEFAULT: &str = "10";
-const ARCHIVO_TRANSACCIONES: &str = "transacciones.csv";
-
 fn real_main() -> Result<(), String> {
     // Parser de argumentos 
     let yaml = clap::load_yaml!("cli.yml");
     let argumentos = App::from_yaml(yaml).get_matches();
+    // Sin subcomando se corre todo con los valores por defecto
+    let (comando, argumentos) = match argumentos.subcommand() {
+        ("config", Some(argumentos_config)) => {
+            let (_, argumentos_mostrar) = argumentos_config.subcommand();
+            return mostrar_configuracion(argumentos_mostrar.expect("config requiere un subcomando"));
+        },
+        (comando, Some(argumentos_comando)) => (comando, argumentos_comando),
+        _ => ("todo", &argumentos),
+    };
 
-    let exe = &std::env::args().collect::<Vec<String>>()[0];
-    let modo_debug = argumentos.is_present("Debug");
-    let cantidad_clientes = argumentos.value_of("Clientes").unwrap_or(CANTIDAD_DE_CLIENTES_DEFAULT).parse::<u32>().unwrap();
-    let cantidad_workers_ia = argumentos.value_of("Workers ia").unwrap_or(CANTIDAD_DE_IA_DEFAULT).parse::<u32>().unwrap();
-    let cantidad_workers_cashin = argumentos.value_of("Workers cashin").unwrap_or(CANTIDAD_DE_CASHIN_DEFAULT).parse::<u32>().unwrap();
-    let cantidad_workers_cashout = argumentos.value_of("Workers cashout").unwrap_or(CANTIDAD_DE_CASHOUT_DEFAULT).parse::<u32>().unwrap();
+    let configuracion = Configuracion::cargar(argumentos)?;
+    let redactor = Arc::new(configuracion.redactor()?);
+    let id_corrida = Uuid::new_v4().to_string();
+    let logger = iniciar_logger(&configuracion.log, &id_corrida, redactor.clone())?;
 
-    let mut rng = rand::thread_rng();
-    let semilla_simulaciones = argumentos
-        .value_of("Semilla simulacion")
-        .unwrap_or(&format!("{}", rng.gen::<u64>()))
-        .parse::<u64>()
-        .unwrap();
-    let semilla_ia = argumentos
-        .value_of("Semilla ia")
-        .unwrap_or(&format!("{}", rng.gen::<u64>()))
-        .parse::<u64>()
-        .unwrap();
-
-    // Inicializo el logger
-    let logger = Arc::new(if modo_debug {
-        Logger::new_to_file("debug.txt").expect("No se pudo crear el archivo de log.")
+    let log = TaggedLogger::new("CONTROLADOR", logger.clone());
+    log.write(&format!("Corrida {}", id_corrida));
+    let archivos = &configuracion.archivos;
+
+    let resultado = match comando {
+        "simular" => simular(&configuracion, &logger),
+        "procesar" => procesar(&configuracion, &logger, &redactor),
+        "verificar" => verificar(archivos, &log, &redactor),
+        "reportar" => reportar(archivos, &log, &redactor),
+        _ => simular(&configuracion, &logger).and_then(|_| procesar(&configuracion, &logger, &redactor)),
+    };
+
+    if resultado.is_ok() {
+        if logger.descartados() > 0 {
+            log.warn(&format!("{} mensajes del log descartados por cola llena", logger.descartados()));
+        }
+        log.write("Terminado");
+    }
+    // Algunos hilos sueltos siguen teniendo el logger: no alcanza con soltarlo
+    logger.vaciar();
+    resultado
+}
+
+/// Escribe la configuración que usaría una corrida con estos argumentos
+fn mostrar_configuracion(argumentos: &ArgMatches) -> Result<(), String> {
+    print!("{}", Configuracion::cargar(argumentos)?.a_texto());
+    Ok(())
+}
+
+fn iniciar_logger(configuracion: &ConfiguracionLog, id_corrida: &str, redactor: Arc<Redactor>) -> Result<Arc<Logger>, String> {
+    let formato_log = configuracion.formato()?;
+    let logger = if configuracion.debug {
+        let parametros = std::env::args().collect::<Vec<_>>().join(" ");
+        Logger::new_to_file_con_rotacion(
+            &configuracion.archivo,
+            configuracion.rotacion()?,
+            &formato_log.encabezado(id_corrida, &parametros)
+        )?
     } else {
         Logger::new_to_stdout()
+    }.con_formato(formato_log).con_filtro(configuracion.filtro()?).con_redactor(redactor);
+    // Sin cola se escribe directamente desde cada hilo, como antes
+    let logger = Arc::new(if configuracion.cola > 0 {
+        logger.en_segundo_plano(configuracion.cola, configuracion.politica_cola()?)
+    } else {
+        logger
     });
+    Logger::vaciar_al_entrar_en_panico(&logger);
 
-    let log = TaggedLogger::new("CONTROLADOR", logger.clone());
-    log.write(&format!("Iniciando simulación con: {} -o {} -i {} -p {} -c {} -s {} -a {}", exe, cantidad_workers_cashout, cantidad_workers_cashin, cantidad_workers_ia, cantidad_clientes, semilla_simulaciones, semilla_ia));
+    Ok(logger)
+}
 
-    log.write("Simulando transacciones");
+/// Genera las transacciones pendientes y deja los clientes, con el saldo
+/// al terminar la simulación, en el archivo de clientes
+fn simular(configuracion: &Configuracion, logger: &Arc<Logger>) -> Result<(), String> {
+    let log = TaggedLogger::new("CONTROLADOR", logger.clone());
+    let archivos = &configuracion.archivos;
+    let exe = &std::env::args().collect::<Vec<String>>()[0];
+    let cantidad_clientes = configuracion.simulacion.clientes;
+    let semilla_simulaciones = configuracion.semillas.simulacion.unwrap_or_else(|| rand::thread_rng().gen());
 
+    log.write(&format!("Simulando transacciones con: {} simular -c {} -s {}", exe, cantidad_clientes, semilla_simulaciones));
     let clientes = simular_transacciones(
         TaggedLogger::new("SIMULACION", logger.clone()),
-        ARCHIVO_TRANSACCIONES,
-        cantidad_clientes,
+        &archivos.transacciones,
+        configuracion.simulacion.clone(),
         semilla_simulaciones
-    ).expect("Error al generar el archivo de transacciones");
+    ).map_err(|e| format!("Error al generar el archivo de transacciones: {}", e))?;
+
+    escribir_clientes(&clientes, &archivos.clientes).map_err(|e| format!("No se pudo escribir {}: {}", archivos.clientes, e))?;
+    log.write(&format!("Clientes escritos en {}", archivos.clientes));
+    Ok(())
+}
+
+/// Corre el pipeline sobre los archivos de transacciones y de clientes
+fn procesar(configuracion: &Configuracion, logger: &Arc<Logger>, redactor: &Arc<Redactor>) -> Result<(), String> {
+    let archivos = &configuracion.archivos;
+    let exe = &std::env::args().collect::<Vec<String>>()[0];
+    let workers = &configuracion.workers;
+
+    // Las semillas se eligen acá para que queden en el log
+    let mut configuracion = configuracion.clone();
+    let mut rng = rand::thread_rng();
+    let semilla_ia = *configuracion.semillas.ia.get_or_insert_with(|| rng.gen());
+    let semilla_proveedor = *configuracion.semillas.proveedor.get_or_insert_with(|| rng.gen());
+    let archivo_traza = configuracion.observabilidad.traza.as_deref();
+    let archivo_metricas = configuracion.observabilidad.metricas.as_deref();
+
+    let log = TaggedLogger::new("CONTROLADOR", logger.clone());
+    log.write(&format!("Procesando con: {} procesar -o {} -i {} -p {} -a {} -e {}", exe, workers.cash_out, workers.cash_in, workers.ia, semilla_ia, semilla_proveedor));
+
+    let clientes = leer_clientes(&archivos.clientes).map_err(|e| format!("No se pudo leer {}: {}", archivos.clientes, e))?;
+    log.write(&format!("{} clientes leídos de {}", clientes.len(), archivos.clientes));
+
+    let pipeline = Pipeline::iniciar(&configuracion, clientes.clone(), logger.clone(), redactor.clone())?;
+    let contexto = pipeline.contexto().clone();
+    let estados = &contexto.estados;
+    escuchar_senales(
+        TaggedLogger::new("APAGADO", logger.clone()),
+        contexto.apagado.clone(),
+        Duration::from_secs(configuracion.apagado.espera_maxima),
+        abortar_corrida(estados.clone(), archivos.clone(), logger.clone())
+    )?;
+    if configuracion.observabilidad.consultar_estados {
+        // Hilo suelto: termina solo cuando se cierra la entrada
+        iniciar_consulta_estados(
+            TaggedLogger::new("CONSULTA", logger.clone()),
+            estados.clone(),
+            BufReader::new(std::io::stdin())
+        );
+        log.write("Escribir un id de transacción para consultar su estado");
+    }
 
     log.write("Iniciando procesador del archivo");
-    let (tx_cashin, rx_cashin) = channel();
-    let (tx_cashout, rx_cashout) = channel();
-    let handle_procesador = match Procesador::iniciar(ARCHIVO_TRANSACCIONES, tx_cashin, tx_cashout) {
-        Ok(r) => r,
-        Err(e) => return Err(format!("{}", e))
+    pipeline.leer_archivo(&archivos.transacciones)?;
+
+    let incidentes = match pipeline.terminar() {
+        Ok(incidentes) => incidentes,
+        Err(diagnostico) => {
+            // Las etapas que siguen vivas terminan con el proceso
+            let en_curso = volcar_estados(estados, archivos)?;
+            return Err(format!(
+                "Corrida fallida: {}. Quedaron {} transacciones en curso, registradas en {}",
+                diagnostico, en_curso, archivos.en_curso
+            ));
+        }
     };
 
-    log.write("Iniciando proveedor externo de hashes");
-    let (rx_hash, handle_hash) = ProveedorExterno::iniciar();
-    let proveedor_autorizacion = Arc::new(Mutex::new(rx_hash));
-
-    let (tx_transacciones_autorizadas, rx_transacciones_autorizadas_s) = channel();
-    let rx_transacciones_autorizadas = Arc::new(Mutex::new(rx_transacciones_autorizadas_s));
-
-    let (tx_transacciones_validadas, rx_transacciones_validadas) = channel();
-
-    let handles_procesadores_ia = iniciar_procesadores_ia(
-        cantidad_workers_ia,
-        rx_transacciones_autorizadas,
-        tx_transacciones_validadas,
-        semilla_ia,
-        logger.clone()
-    );
-
-    log.write("Iniciando workers cash in");
-    let handles_worker_cash_in = iniciar_workers_de_tipo(
-        cantidad_workers_cashin,
-        TipoWorker::CashIn,
-        Arc::new(Mutex::new(rx_cashin)),
-        proveedor_autorizacion.clone(),
-        tx_transacciones_autorizadas.clone(),
-        logger.clone()
-    );
-    log.write("Iniciando workers cash out");
-    let handles_worker_cash_out = iniciar_workers_de_tipo(
-        cantidad_workers_cashout,
-        TipoWorker::CashOut,
-        Arc::new(Mutex::new(rx_cashout)),
-        proveedor_autorizacion,
-        tx_transacciones_autorizadas,
-        logger.clone()
-    );
-
-    let handle_worker_final = WorkerFinal::iniciar(
-        TaggedLogger::new("WORKER FINAL", logger),
-        rx_transacciones_validadas,
-        clientes
-    );
-
-    // Esperar que finalicen todos los demas hilos
-    handle_worker_final.join().expect("Cannot join worker thread");
-    log.write("Todas las operaciones fueron procesadas. Finalizando.");
-
-    for handle_procesador_ia in handles_procesadores_ia {
-        handle_procesador_ia.join().expect("Cannot join ia thread");
+    // Con todas las transacciones resueltas no debería quedar nada retenido
+    for cliente in clientes.iter() {
+        let saldo = cliente.get_saldos();
+        log.write(&format!(
+            "Cliente {}: saldo contable {}, retenido {}, disponible {}",
+            redactor.cliente(&cliente.id), saldo.contable, saldo.retenido, saldo.disponible()
+        ));
     }
 
-    // Esperar a que finalicen los workers
-    for handle_worker in handles_worker_cash_in {
-        handle_worker.join().expect("Cannot join worker thread");
+    let mut resumen: Vec<_> = estados.resumen().into_iter().collect();
+    resumen.sort_by_key(|(estado, _)| *estado as u8);
+    log.write(&format!("Transacciones por estado: {:?}", resumen));
+    if incidentes.is_empty() {
+        estados.volcar(&archivos.estados).map_err(|e| format!("No se pudo escribir {}: {}", archivos.estados, e))?;
+    } else {
+        let en_curso = volcar_estados(estados, archivos)?;
+        log.warn(&format!(
+            "Corrida degradada por {} incidentes ({}). Quedaron {} transacciones sin terminar, registradas en {}",
+            incidentes.len(), incidentes.join("; "), en_curso, archivos.en_curso
+        ));
+    }
+    if contexto.apagado.drenando() {
+        let leidas: usize = resumen.iter().map(|(_, cantidad)| cantidad).sum();
+        log.warn(&format!(
+            "Corrida interrumpida: se terminaron las {} transacciones leídas y quedaron {} sin leer en {}",
+            leidas, contexto.apagado.sin_leer(), archivos.transacciones
+        ));
     }
-    for handle_worker in handles_worker_cash_out {
-        handle_worker.join().expect("Cannot join worker thread");
+
+    for prioridad in Prioridad::TODAS.iter() {
+        if let Some(latencia) = contexto.metricas.resumen(&LATENCIA_PRIORIDAD, &[("prioridad", prioridad.nombre())]) {
+            log.write(&format!("Latencia de prioridad {}: {}", prioridad, latencia));
+        }
     }
-    log.write("Todos los workers finalizaron");
-    
-    // Detener el hasher
-    // Podría bloquearse si rx_hash no se cierra antes
-    handle_hash.join().expect("Cannot join hasher thread");
-    log.write("El proveedor externo finalizó");
-
-    // Esperar a que termine el procesador
-    // Podría bloquearse si rx_cashin/rx_cashout no se cierran antes
-    handle_procesador.join().expect("Cannot join processor thread");
-    log.write("El procesador de archivo terminó");
-
-    log.write("Terminado");
+
+    if let (Some(trazas), Some(archivo_traza)) = (&contexto.trazas, archivo_traza) {
+        for latencias in trazas.latencias() {
+            log.write(&format!("Latencias {}", latencias));
+        }
+        trazas.exportar_chrome(archivo_traza).map_err(|e| format!("No se pudo escribir {}: {}", archivo_traza, e))?;
+        log.write(&format!("Traza de la corrida escrita en {}", archivo_traza));
+    }
+
+    if let Some(archivo_metricas) = archivo_metricas {
+        contexto.metricas.escribir(archivo_metricas).map_err(|e| format!("No se pudo escribir {}: {}", archivo_metricas, e))?;
+        log.write(&format!("Métricas de la corrida escritas en {}", archivo_metricas));
+    }
+
+    Ok(())
+}
+
+/// Al abortar se registran las transacciones que no llegaron a un estado
+/// final y el estado de todas, y se termina sin esperar a las etapas
+fn abortar_corrida(estados: Arc<AlmacenEstados>, archivos: ArchivosCorrida, logger: Arc<Logger>) -> Abortar {
+    Arc::new(move || {
+        let log = TaggedLogger::new("APAGADO", logger.clone());
+        match volcar_estados(&estados, &archivos) {
+            Ok(en_curso) => log.evento(
+                NivelLog::Error,
+                &format!("Corrida abortada con {} transacciones en curso, registradas en {}", en_curso, archivos.en_curso),
+                &[]
+            ),
+            Err(e) => log.evento(NivelLog::Error, &e, &[]),
+        }
+        logger.vaciar();
+        std::process::exit(CODIGO_ABORTADA);
+    })
+}
+
+/// Escribe el estado de todas las transacciones y, aparte, las que no
+/// llegaron a un estado final. Devuelve cuántas son estas últimas.
+fn volcar_estados(estados: &AlmacenEstados, archivos: &ArchivosCorrida) -> Result<usize, String> {
+    let en_curso = estados
+        .volcar_en_curso(&archivos.en_curso)
+        .map_err(|e| format!("No se pudo escribir {}: {}", archivos.en_curso, e))?;
+    estados.volcar(&archivos.estados).map_err(|e| format!("No se pudo escribir {}: {}", archivos.estados, e))?;
+
+    Ok(en_curso)
+}
+
+/// Concilia las salidas de una corrida e informa cada discrepancia
+fn verificar(archivos: &ArchivosCorrida, log: &TaggedLogger, redactor: &Redactor) -> Result<(), String> {
+    let verificacion = cli::verificar(archivos, redactor)
+        .map_err(|e| format!("No se pudieron leer los archivos de la corrida: {}", e))?;
+    for discrepancia in &verificacion.discrepancias {
+        log.warn(discrepancia);
+    }
+    log.write(&format!("Verificación: {}", verificacion));
+
+    if !verificacion.discrepancias.is_empty() {
+        return Err(format!("La verificación encontró {} discrepancias", verificacion.discrepancias.len()));
+    }
+    Ok(())
+}
+
+/// Resume la corrida en el log y escribe el resumen por cliente
+fn reportar(archivos: &ArchivosCorrida, log: &TaggedLogger, redactor: &Redactor) -> Result<(), String> {
+    let archivo_reporte = &archivos.reporte;
+    let reporte = armar_reporte(archivos).map_err(|e| format!("No se pudieron leer los archivos de la corrida: {}", e))?;
+    for tipo in &reporte.por_tipo {
+        log.write(&format!("Transacciones {}", tipo));
+    }
+    for (motivo, cantidad) in &reporte.motivos {
+        log.write(&format!("Motivo '{}': {} transacciones", motivo, cantidad));
+    }
+
+    reporte.escribir(archivo_reporte, redactor).map_err(|e| format!("No se pudo escribir {}: {}", archivo_reporte, e))?;
+    log.write(&format!("Resumen de {} clientes escrito en {}", reporte.clientes.len(), archivo_reporte));
     Ok(())
 }
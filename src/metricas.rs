@@ -0,0 +1,407 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write as _},
+    fs,
+    io::{self, prelude::*, BufReader},
+    net::{SocketAddr, TcpListener},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use crate::{logger::TaggedLogger, transaccion::TipoTransaccion};
+
+const LIMITES_LATENCIA: [f64; 12] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+const RUTA_METRICAS: &str = "/metrics";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TipoMetrica {
+    Contador,
+    Medidor,
+    Histograma,
+}
+
+impl TipoMetrica {
+    fn nombre_prometheus(&self) -> &'static str {
+        match self {
+            TipoMetrica::Contador => "counter",
+            TipoMetrica::Medidor => "gauge",
+            TipoMetrica::Histograma => "histogram",
+        }
+    }
+}
+
+pub struct Metrica {
+    pub nombre: &'static str,
+    pub tipo: TipoMetrica,
+    pub ayuda: &'static str,
+}
+
+pub const TRANSACCIONES_ENTRANTES: Metrica = Metrica {
+    nombre: "dinero_transacciones_entrantes_total",
+    tipo: TipoMetrica::Contador,
+    ayuda: "Transacciones que tomó cada etapa",
+};
+pub const TRANSACCIONES_SALIENTES: Metrica = Metrica {
+    nombre: "dinero_transacciones_salientes_total",
+    tipo: TipoMetrica::Contador,
+    ayuda: "Transacciones que soltó cada etapa, según cómo terminaron en ella",
+};
+pub const RECHAZOS: Metrica = Metrica {
+    nombre: "dinero_rechazos_total",
+    tipo: TipoMetrica::Contador,
+    ayuda: "Transacciones rechazadas por categoría de motivo",
+};
+pub const PROFUNDIDAD_COLA: Metrica = Metrica {
+    nombre: "dinero_profundidad_cola",
+    tipo: TipoMetrica::Medidor,
+    ayuda: "Transacciones esperando en cada cola",
+};
+pub const LATENCIA_PROVEEDOR: Metrica = Metrica {
+    nombre: "dinero_latencia_proveedor_segundos",
+    tipo: TipoMetrica::Histograma,
+    ayuda: "Duración de cada llamada al proveedor de autorizaciones",
+};
+pub const ESPERA_CUOTA_PROVEEDOR: Metrica = Metrica {
+    nombre: "dinero_espera_cuota_proveedor_segundos",
+    tipo: TipoMetrica::Histograma,
+    ayuda: "Espera por la cuota de llamadas antes de cada llamada al proveedor",
+};
+pub const LATENCIA_IA: Metrica = Metrica {
+    nombre: "dinero_latencia_ia_segundos",
+    tipo: TipoMetrica::Histograma,
+    ayuda: "Duración de la detección de lavado de cada transacción",
+};
+pub const LIQUIDACIONES_POR_SEGUNDO: Metrica = Metrica {
+    nombre: "dinero_liquidaciones_por_segundo",
+    tipo: TipoMetrica::Medidor,
+    ayuda: "Transacciones liquidadas por segundo desde que arrancó el worker final",
+};
+pub const HILOS_POOL: Metrica = Metrica {
+    nombre: "dinero_hilos_pool",
+    tipo: TipoMetrica::Medidor,
+    ayuda: "Hilos de cada pool de workers o de procesadores ia",
+};
+pub const LATENCIA_PRIORIDAD: Metrica = Metrica {
+    nombre: "dinero_latencia_prioridad_segundos",
+    tipo: TipoMetrica::Histograma,
+    ayuda: "Tiempo de cada transacción liquidada desde que entró al pipeline, por prioridad",
+};
+pub const ESCALADOS: Metrica = Metrica {
+    nombre: "dinero_escalados_total",
+    tipo: TipoMetrica::Contador,
+    ayuda: "Hilos que el autoescalado agregó o retiró de cada pool",
+};
+
+/// Cola de entrada del pool de workers del tipo dado
+pub fn cola_de_tipo(tipo: TipoTransaccion) -> &'static str {
+    match tipo {
+        TipoTransaccion::CashIn => "worker_cash_in",
+        TipoTransaccion::CashOut => "worker_cash_out",
+    }
+}
+
+/// Cola de reintentos del pool de workers del tipo dado
+pub fn cola_reintentos_de_tipo(tipo: TipoTransaccion) -> &'static str {
+    match tipo {
+        TipoTransaccion::CashIn => "reintentos_cash_in",
+        TipoTransaccion::CashOut => "reintentos_cash_out",
+    }
+}
+
+type Etiquetas = Vec<(String, String)>;
+
+#[derive(Debug, Clone)]
+struct Histograma {
+    cuentas: Vec<u64>,
+    suma: f64,
+    cantidad: u64,
+}
+
+#[derive(Debug, Clone)]
+enum Valor {
+    Numero(f64),
+    Histograma(Histograma),
+}
+
+struct Familia {
+    tipo: TipoMetrica,
+    ayuda: &'static str,
+    series: BTreeMap<Etiquetas, Valor>,
+}
+
+/// Métricas de la corrida, etiquetadas por etapa y por worker
+#[derive(Default)]
+pub struct RegistroMetricas {
+    familias: Mutex<BTreeMap<&'static str, Familia>>,
+}
+
+impl RegistroMetricas {
+    fn actualizar(&self, metrica: &Metrica, etiquetas: &[(&str, &str)], actualizacion: impl FnOnce(&mut Valor)) {
+        let etiquetas = etiquetas.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let mut familias = self.familias.lock().expect("metricas poisoned");
+        let familia = familias.entry(metrica.nombre).or_insert_with(|| Familia {
+            tipo: metrica.tipo,
+            ayuda: metrica.ayuda,
+            series: BTreeMap::new(),
+        });
+        let valor = familia.series.entry(etiquetas).or_insert_with(|| match metrica.tipo {
+            TipoMetrica::Histograma => Valor::Histograma(Histograma {
+                cuentas: vec![0; LIMITES_LATENCIA.len()],
+                suma: 0.0,
+                cantidad: 0,
+            }),
+            _ => Valor::Numero(0.0),
+        });
+        actualizacion(valor);
+    }
+
+    pub fn incrementar(&self, metrica: &Metrica, etiquetas: &[(&str, &str)]) {
+        self.sumar(metrica, etiquetas, 1.0);
+    }
+
+    pub fn sumar(&self, metrica: &Metrica, etiquetas: &[(&str, &str)], delta: f64) {
+        self.actualizar(metrica, etiquetas, |valor| {
+            if let Valor::Numero(n) = valor {
+                *n += delta;
+            }
+        });
+    }
+
+    pub fn fijar(&self, metrica: &Metrica, etiquetas: &[(&str, &str)], nuevo: f64) {
+        self.actualizar(metrica, etiquetas, |valor| {
+            if let Valor::Numero(n) = valor {
+                *n = nuevo;
+            }
+        });
+    }
+
+    pub fn observar(&self, metrica: &Metrica, etiquetas: &[(&str, &str)], observacion: f64) {
+        self.actualizar(metrica, etiquetas, |valor| {
+            if let Valor::Histograma(h) = valor {
+                for (cuenta, limite) in h.cuentas.iter_mut().zip(LIMITES_LATENCIA.iter()) {
+                    if observacion <= *limite {
+                        *cuenta += 1;
+                    }
+                }
+                h.suma += observacion;
+                h.cantidad += 1;
+            }
+        });
+    }
+
+    /// Valor actual de un contador o medidor; 0 si todavía no se registró
+    pub fn valor(&self, metrica: &Metrica, etiquetas: &[(&str, &str)]) -> f64 {
+        let etiquetas: Etiquetas = etiquetas.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let familias = self.familias.lock().expect("metricas poisoned");
+        match familias.get(metrica.nombre).and_then(|familia| familia.series.get(&etiquetas)) {
+            Some(Valor::Numero(n)) => *n,
+            _ => 0.0,
+        }
+    }
+
+    /// Cantidad, promedio y percentiles de un histograma. Los percentiles
+    /// son el límite del balde en el que caen; None si ya no entran en el
+    /// último.
+    pub fn resumen(&self, metrica: &Metrica, etiquetas: &[(&str, &str)]) -> Option<ResumenHistograma> {
+        let etiquetas: Etiquetas = etiquetas.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let familias = self.familias.lock().expect("metricas poisoned");
+        let histograma = match familias.get(metrica.nombre)?.series.get(&etiquetas)? {
+            Valor::Histograma(h) if h.cantidad > 0 => h,
+            _ => return None,
+        };
+        let percentil = |p: f64| {
+            let posicion = (p * histograma.cantidad as f64).ceil() as u64;
+            histograma.cuentas.iter().zip(LIMITES_LATENCIA.iter()).find(|(cuenta, _)| **cuenta >= posicion).map(|(_, limite)| *limite)
+        };
+
+        Some(ResumenHistograma {
+            cantidad: histograma.cantidad,
+            promedio: histograma.suma / histograma.cantidad as f64,
+            percentiles: [percentil(0.5), percentil(0.9), percentil(0.99)],
+        })
+    }
+
+    /// Una transacción entró a la etapa
+    pub fn entrada(&self, etapa: &str, worker: &str) {
+        self.incrementar(&TRANSACCIONES_ENTRANTES, &[("etapa", etapa), ("worker", worker)]);
+    }
+
+    /// Una transacción salió de la etapa con el resultado dado
+    pub fn salida(&self, etapa: &str, worker: &str, resultado: &str) {
+        self.incrementar(&TRANSACCIONES_SALIENTES, &[("etapa", etapa), ("worker", worker), ("resultado", resultado)]);
+    }
+
+    pub fn encolar(&self, cola: &str) {
+        self.sumar(&PROFUNDIDAD_COLA, &[("cola", cola)], 1.0);
+    }
+
+    pub fn desencolar(&self, cola: &str) {
+        self.sumar(&PROFUNDIDAD_COLA, &[("cola", cola)], -1.0);
+    }
+
+    /// Métricas en el formato de texto de Prometheus
+    pub fn exportar_prometheus(&self) -> String {
+        let mut texto = String::new();
+        for (nombre, familia) in self.familias.lock().expect("metricas poisoned").iter() {
+            let _ = writeln!(texto, "# HELP {} {}", nombre, familia.ayuda);
+            let _ = writeln!(texto, "# TYPE {} {}", nombre, familia.tipo.nombre_prometheus());
+            for (etiquetas, valor) in &familia.series {
+                match valor {
+                    Valor::Numero(n) => {
+                        let _ = writeln!(texto, "{}{} {}", nombre, formatear_etiquetas(etiquetas, None), n);
+                    }
+                    Valor::Histograma(h) => {
+                        for (cuenta, limite) in h.cuentas.iter().zip(LIMITES_LATENCIA.iter()) {
+                            let le = limite.to_string();
+                            let _ = writeln!(texto, "{}_bucket{} {}", nombre, formatear_etiquetas(etiquetas, Some(&le)), cuenta);
+                        }
+                        let _ = writeln!(texto, "{}_bucket{} {}", nombre, formatear_etiquetas(etiquetas, Some("+Inf")), h.cantidad);
+                        let _ = writeln!(texto, "{}_sum{} {}", nombre, formatear_etiquetas(etiquetas, None), h.suma);
+                        let _ = writeln!(texto, "{}_count{} {}", nombre, formatear_etiquetas(etiquetas, None), h.cantidad);
+                    }
+                }
+            }
+        }
+
+        texto
+    }
+
+    pub fn escribir(&self, ruta_archivo: &str) -> io::Result<()> {
+        fs::write(ruta_archivo, self.exportar_prometheus())
+    }
+}
+
+/// Resumen de las observaciones de un histograma, en segundos
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResumenHistograma {
+    pub cantidad: u64,
+    pub promedio: f64,
+    /// p50, p90 y p99
+    pub percentiles: [Option<f64>; 3],
+}
+
+impl fmt::Display for ResumenHistograma {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} transacciones, promedio {:.1} ms", self.cantidad, self.promedio * 1000.0)?;
+        for (nombre, percentil) in ["p50", "p90", "p99"].iter().zip(self.percentiles.iter()) {
+            match percentil {
+                Some(limite) => write!(f, ", {} <= {} ms", nombre, limite * 1000.0)?,
+                None => write!(f, ", {} > {} ms", nombre, LIMITES_LATENCIA[LIMITES_LATENCIA.len() - 1] * 1000.0)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn formatear_etiquetas(etiquetas: &Etiquetas, le: Option<&str>) -> String {
+    let mut pares: Vec<String> = etiquetas
+        .iter()
+        .map(|(clave, valor)| format!("{}=\"{}\"", clave, escapar(valor)))
+        .collect();
+    if let Some(le) = le {
+        pares.push(format!("le=\"{}\"", le));
+    }
+    if pares.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pares.join(","))
+    }
+}
+
+fn escapar(valor: &str) -> String {
+    valor.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Sirve las métricas por HTTP en `RUTA_METRICAS` mientras dura la
+/// corrida. Devuelve la dirección en la que escucha.
+pub fn iniciar_servidor_metricas(log: TaggedLogger, metricas: Arc<RegistroMetricas>, puerto: u16) -> Result<SocketAddr, String> {
+    let listener = TcpListener::bind(("127.0.0.1", puerto)).map_err(|e| format!("No se pudo escuchar en el puerto {}: {}", puerto, e))?;
+    let direccion = listener.local_addr().map_err(|e| e.to_string())?;
+
+    // Hilo suelto: termina con el proceso
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(s) => s,
+                Err(_) => continue
+            };
+            let mut linea_pedido = String::new();
+            if BufReader::new(&stream).read_line(&mut linea_pedido).is_err() {
+                continue;
+            }
+            let mut partes = linea_pedido.split_whitespace();
+            let respuesta = if partes.next() == Some("GET") && partes.next() == Some(RUTA_METRICAS) {
+                let cuerpo = metricas.exportar_prometheus();
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    cuerpo.len(), cuerpo
+                )
+            } else {
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+            };
+            if stream.write_all(respuesta.as_bytes()).is_err() {
+                log.warn("No se pudo responder un pedido de métricas");
+            }
+        }
+    });
+
+    Ok(direccion)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpStream;
+
+    use super::*;
+    use crate::logger::Logger;
+
+    #[test]
+    fn exportar_prometheus_acumula_contadores_y_buckets() {
+        let metricas = RegistroMetricas::default();
+        metricas.entrada("worker", "WORKER CashIn 0");
+        metricas.entrada("worker", "WORKER CashIn 0");
+        metricas.encolar("ia");
+        metricas.observar(&LATENCIA_IA, &[("worker", "0")], 0.02);
+        metricas.observar(&LATENCIA_IA, &[("worker", "0")], 0.3);
+
+        let texto = metricas.exportar_prometheus();
+        assert!(texto.contains("# TYPE dinero_transacciones_entrantes_total counter\n"));
+        assert!(texto.contains("dinero_transacciones_entrantes_total{etapa=\"worker\",worker=\"WORKER CashIn 0\"} 2\n"));
+        assert!(texto.contains("dinero_profundidad_cola{cola=\"ia\"} 1\n"));
+        assert!(texto.contains("dinero_latencia_ia_segundos_bucket{worker=\"0\",le=\"0.01\"} 0\n"));
+        assert!(texto.contains("dinero_latencia_ia_segundos_bucket{worker=\"0\",le=\"0.025\"} 1\n"));
+        assert!(texto.contains("dinero_latencia_ia_segundos_bucket{worker=\"0\",le=\"+Inf\"} 2\n"));
+        assert!(texto.contains("dinero_latencia_ia_segundos_count{worker=\"0\"} 2\n"));
+    }
+
+    #[test]
+    fn resumen_da_percentiles_por_limite_de_balde() {
+        let metricas = RegistroMetricas::default();
+        let alta = [("prioridad", "alta")];
+        for observacion in [0.002, 0.002, 0.02, 7.0] {
+            metricas.observar(&LATENCIA_PRIORIDAD, &alta, observacion);
+        }
+
+        let resumen = metricas.resumen(&LATENCIA_PRIORIDAD, &alta).unwrap();
+        assert_eq!(resumen.cantidad, 4);
+        assert!((resumen.promedio - 1.756).abs() < 1e-9);
+        assert_eq!(resumen.percentiles, [Some(0.0025), None, None]);
+        assert_eq!(metricas.resumen(&LATENCIA_PRIORIDAD, &[("prioridad", "normal")]), None);
+    }
+
+    #[test]
+    fn servidor_de_metricas_responde_el_texto_de_prometheus() {
+        let metricas = Arc::new(RegistroMetricas::default());
+        metricas.salida("final", "WORKER FINAL", "liquidada");
+        let log = TaggedLogger::new("METRICAS", Arc::new(Logger::new_to_stdout()));
+        let direccion = iniciar_servidor_metricas(log, metricas, 0).unwrap();
+
+        let mut stream = TcpStream::connect(direccion).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut respuesta = String::new();
+        stream.read_to_string(&mut respuesta).unwrap();
+
+        assert!(respuesta.starts_with("HTTP/1.1 200 OK"));
+        assert!(respuesta.contains("resultado=\"liquidada\"} 1"));
+    }
+}
@@ -0,0 +1,300 @@
+use std::{collections::BTreeMap, fs::{self, File}, sync::Mutex};
+use csv::Writer;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cliente::Saldo,
+    perfiles::HistorialCliente,
+    transaccion::{TipoTransaccion, Transaccion},
+};
+
+const UMBRAL_MODELO_DEFAULT: f64 = 0.5;
+const MS_POR_HORA: u128 = 3_600_000;
+
+/// Modelo entrenado afuera que reemplaza al detector al azar
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfiguracionModelo {
+    /// Archivo JSON del modelo; sin él se usa el detector al azar
+    pub archivo: Option<String>,
+    /// Desde esta probabilidad de fraude la transacción se rechaza
+    pub umbral: f64,
+}
+
+impl Default for ConfiguracionModelo {
+    fn default() -> Self {
+        Self { archivo: None, umbral: UMBRAL_MODELO_DEFAULT }
+    }
+}
+
+/// Lo que el modelo sabe de una transacción. Las tres últimas salen del
+/// perfil del cliente y valen 0 si no tiene.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Caracteristica {
+    Monto,
+    /// ln(1 + monto)
+    LogMonto,
+    /// 1 si es un cash out, 0 si es un cash in
+    CashOut,
+    /// Hora del día (UTC) del timestamp, de 0 a 23
+    Hora,
+    SaldoDisponible,
+    /// Monto sobre el saldo contable del cliente
+    MontoSobreSaldo,
+    /// Operaciones en el perfil del cliente
+    OperacionesCliente,
+    /// Desvíos estándar entre el monto y la media del cliente
+    DesvioMonto,
+    /// Operaciones del cliente en el día de la transacción
+    OperacionesHoy,
+}
+
+impl Caracteristica {
+    pub fn usa_perfil(&self) -> bool {
+        matches!(self, Caracteristica::OperacionesCliente | Caracteristica::DesvioMonto | Caracteristica::OperacionesHoy)
+    }
+}
+
+/// Valores de las características de una transacción
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct VectorCaracteristicas {
+    monto: f64,
+    cash_out: bool,
+    hora: u32,
+    saldo: Saldo,
+    historial: HistorialCliente,
+}
+
+impl VectorCaracteristicas {
+    pub fn calcular(transaccion: &Transaccion, saldo: Option<Saldo>, historial: HistorialCliente) -> Self {
+        Self {
+            monto: transaccion.monto as f64,
+            cash_out: transaccion.tipo == TipoTransaccion::CashOut,
+            hora: (transaccion.timestamp / MS_POR_HORA % 24) as u32,
+            saldo: saldo.unwrap_or_default(),
+            historial,
+        }
+    }
+
+    pub fn valor(&self, caracteristica: Caracteristica) -> f64 {
+        match caracteristica {
+            Caracteristica::Monto => self.monto,
+            Caracteristica::LogMonto => self.monto.ln_1p(),
+            Caracteristica::CashOut => if self.cash_out { 1.0 } else { 0.0 },
+            Caracteristica::Hora => self.hora.into(),
+            Caracteristica::SaldoDisponible => self.saldo.disponible().into(),
+            Caracteristica::MontoSobreSaldo => self.monto / f64::from(self.saldo.contable).max(1.0),
+            Caracteristica::OperacionesCliente => self.historial.operaciones as f64,
+            Caracteristica::DesvioMonto => self.historial.desvio_monto,
+            Caracteristica::OperacionesHoy => self.historial.operaciones_hoy as f64,
+        }
+    }
+}
+
+/// Nodo de un árbol: se baja por `menor` si la característica está por
+/// debajo del umbral y por `mayor` si no
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Nodo {
+    Division {
+        caracteristica: Caracteristica,
+        umbral: f64,
+        menor: Box<Nodo>,
+        mayor: Box<Nodo>,
+    },
+    Hoja { valor: f64 },
+}
+
+impl Nodo {
+    fn evaluar(&self, vector: &VectorCaracteristicas) -> f64 {
+        match self {
+            Nodo::Division { caracteristica, umbral, menor, mayor } => {
+                if vector.valor(*caracteristica) < *umbral { menor.evaluar(vector) } else { mayor.evaluar(vector) }
+            }
+            Nodo::Hoja { valor } => *valor,
+        }
+    }
+
+    fn caracteristicas(&self, usadas: &mut Vec<Caracteristica>) {
+        if let Nodo::Division { caracteristica, menor, mayor, .. } = self {
+            usadas.push(*caracteristica);
+            menor.caracteristicas(usadas);
+            mayor.caracteristicas(usadas);
+        }
+    }
+}
+
+/// En los dos casos la probabilidad es la logística de `sesgo` más la
+/// suma de los términos: los pesos por sus características o las hojas a
+/// las que llega la transacción en cada árbol.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "tipo", rename_all = "snake_case")]
+pub enum FormaModelo {
+    RegresionLogistica { sesgo: f64, pesos: BTreeMap<Caracteristica, f64> },
+    Arboles { sesgo: f64, arboles: Vec<Nodo> },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModeloFraude {
+    /// Queda registrada con cada decisión
+    pub version: String,
+    #[serde(flatten)]
+    pub forma: FormaModelo,
+}
+
+impl ModeloFraude {
+    pub fn leer(ruta: &str) -> Result<Self, String> {
+        let texto = fs::read_to_string(ruta).map_err(|e| format!("No se pudo leer {}: {}", ruta, e))?;
+        serde_json::from_str(&texto).map_err(|e| format!("Modelo inválido en {}: {}", ruta, e))
+    }
+
+    /// Probabilidad de que la transacción sea fraude
+    pub fn probabilidad(&self, vector: &VectorCaracteristicas) -> f64 {
+        let logit = match &self.forma {
+            FormaModelo::RegresionLogistica { sesgo, pesos } => {
+                sesgo + pesos.iter().map(|(caracteristica, peso)| peso * vector.valor(*caracteristica)).sum::<f64>()
+            }
+            FormaModelo::Arboles { sesgo, arboles } => sesgo + arboles.iter().map(|arbol| arbol.evaluar(vector)).sum::<f64>(),
+        };
+        1.0 / (1.0 + (-logit).exp())
+    }
+
+    pub fn caracteristicas(&self) -> Vec<Caracteristica> {
+        let mut usadas = match &self.forma {
+            FormaModelo::RegresionLogistica { pesos, .. } => pesos.keys().copied().collect(),
+            FormaModelo::Arboles { arboles, .. } => {
+                let mut usadas = vec![];
+                arboles.iter().for_each(|arbol| arbol.caracteristicas(&mut usadas));
+                usadas
+            }
+        };
+        usadas.sort();
+        usadas.dedup();
+        usadas
+    }
+}
+
+/// Una fila de `decisiones.csv`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Decision {
+    #[serde(rename = "Transaction")]
+    pub id_transaccion: u32,
+    #[serde(rename = "Model_version")]
+    pub version: String,
+    #[serde(rename = "Probability")]
+    pub probabilidad: f64,
+    #[serde(rename = "Rejected")]
+    pub rechazada: bool,
+}
+
+/// El modelo con su umbral, compartido por los procesadores ia, que deja
+/// cada decisión en el archivo de decisiones. El de la sombra no tiene
+/// archivo: sus decisiones solo van a la comparación.
+pub struct ClasificadorFraude {
+    modelo: ModeloFraude,
+    umbral: f64,
+    decisiones: Option<Mutex<Writer<File>>>,
+}
+
+impl ClasificadorFraude {
+    /// None si la configuración no tiene modelo
+    pub fn cargar(configuracion: &ConfiguracionModelo, archivo_decisiones: Option<&str>) -> Result<Option<Self>, String> {
+        let ruta = match &configuracion.archivo {
+            Some(ruta) => ruta,
+            None => return Ok(None),
+        };
+        let modelo = ModeloFraude::leer(ruta)?;
+        let decisiones = archivo_decisiones
+            .map(|archivo| Writer::from_path(archivo).map_err(|e| format!("No se pudo crear {}: {}", archivo, e)))
+            .transpose()?;
+
+        Ok(Some(Self { modelo, umbral: configuracion.umbral, decisiones: decisiones.map(Mutex::new) }))
+    }
+
+    pub fn modelo(&self) -> &ModeloFraude {
+        &self.modelo
+    }
+
+    pub fn umbral(&self) -> f64 {
+        self.umbral
+    }
+
+    pub fn decidir(&self, id_transaccion: u32, vector: &VectorCaracteristicas) -> Decision {
+        let probabilidad = self.modelo.probabilidad(vector);
+        let decision = Decision {
+            id_transaccion,
+            version: self.modelo.version.clone(),
+            probabilidad,
+            rechazada: probabilidad >= self.umbral,
+        };
+        if let Some(decisiones) = &self.decisiones {
+            let mut decisiones = decisiones.lock().expect("decisiones poisoned");
+            decisiones.serialize(&decision).expect("No se pudo escribir la decisión");
+        }
+
+        decision
+    }
+
+    pub fn flush(&self) -> std::io::Result<()> {
+        match &self.decisiones {
+            Some(decisiones) => decisiones.lock().expect("decisiones poisoned").flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn crear_vector(monto: f32, tipo: TipoTransaccion) -> VectorCaracteristicas {
+        let transaccion = Transaccion { timestamp: 3 * MS_POR_HORA, ..Transaccion::de_prueba(1, Uuid::new_v4(), tipo, monto) };
+        VectorCaracteristicas::calcular(&transaccion, Some(Saldo { contable: 1000.0, retenido: 0.0 }), HistorialCliente::default())
+    }
+
+    #[test]
+    fn regresion_logistica_del_archivo() {
+        let modelo: ModeloFraude = serde_json::from_str(r#"{
+            "version": "rl-1",
+            "tipo": "regresion_logistica",
+            "sesgo": -4,
+            "pesos": { "monto_sobre_saldo": 6.0, "cash_out": 1.0 }
+        }"#).unwrap();
+
+        assert_eq!(modelo.caracteristicas(), vec![Caracteristica::CashOut, Caracteristica::MontoSobreSaldo]);
+        let baja = modelo.probabilidad(&crear_vector(100.0, TipoTransaccion::CashIn));
+        assert!((baja - 1.0 / (1.0 + 3.4f64.exp())).abs() < 1e-9, "{}", baja);
+        assert!(modelo.probabilidad(&crear_vector(900.0, TipoTransaccion::CashOut)) > 0.9);
+    }
+
+    #[test]
+    fn arboles_suman_las_hojas_de_cada_arbol() {
+        let modelo: ModeloFraude = serde_json::from_str(r#"{
+            "version": "arboles-1",
+            "tipo": "arboles",
+            "sesgo": 0,
+            "arboles": [
+                { "caracteristica": "monto", "umbral": 500, "menor": { "valor": -1 }, "mayor": { "valor": 1 } },
+                {
+                    "caracteristica": "hora", "umbral": 6,
+                    "menor": { "caracteristica": "cash_out", "umbral": 0.5, "menor": { "valor": 0 }, "mayor": { "valor": 2 } },
+                    "mayor": { "valor": -1 }
+                }
+            ]
+        }"#).unwrap();
+
+        assert_eq!(modelo.probabilidad(&crear_vector(100.0, TipoTransaccion::CashIn)), 1.0 / (1.0 + 1f64.exp()));
+        assert_eq!(modelo.probabilidad(&crear_vector(800.0, TipoTransaccion::CashOut)), 1.0 / (1.0 + (-3f64).exp()));
+        assert_eq!(modelo.caracteristicas(), vec![Caracteristica::Monto, Caracteristica::CashOut, Caracteristica::Hora]);
+    }
+
+    #[test]
+    fn un_modelo_con_caracteristicas_desconocidas_no_se_carga() {
+        let error = serde_json::from_str::<ModeloFraude>(r#"{
+            "version": "rl-2", "tipo": "regresion_logistica", "sesgo": 0, "pesos": { "edad": 1.0 }
+        }"#).unwrap_err();
+        assert!(error.to_string().contains("edad"), "{}", error);
+    }
+}
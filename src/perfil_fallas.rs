@@ -0,0 +1,171 @@
+use std::time::Duration;
+use rand::{Rng, prelude::StdRng};
+
+/// Distribución de la demora con la que contesta el proveedor
+#[derive(Debug, Clone, PartialEq)]
+pub enum Latencia {
+    Nula,
+    Fija(Duration),
+    Uniforme(Duration, Duration),
+    /// Exponencial con la media indicada
+    Exponencial(Duration),
+}
+
+/// Intervalo, contado desde que arrancó el proveedor, en el que no
+/// atiende ninguna solicitud
+#[derive(Debug, Clone, PartialEq)]
+pub struct Caida {
+    pub desde: Duration,
+    pub hasta: Duration,
+}
+
+/// Fallas que inyecta el proveedor externo simulado. Las tasas son
+/// probabilidades por solicitud.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PerfilFallas {
+    pub latencia: Latencia,
+    pub tasa_error: f64,
+    pub tasa_timeout: f64,
+    pub duracion_timeout: Duration,
+    pub tasa_limitacion: f64,
+    pub caidas: Vec<Caida>,
+}
+
+impl Default for PerfilFallas {
+    fn default() -> Self {
+        Self {
+            latencia: Latencia::Nula,
+            tasa_error: 0.0,
+            tasa_timeout: 0.0,
+            duracion_timeout: Duration::from_secs(1),
+            tasa_limitacion: 0.0,
+            caidas: vec![],
+        }
+    }
+}
+
+impl PerfilFallas {
+    /// Perfiles predefinidos: normal, lento, inestable, limitado y caido
+    pub fn desde_nombre(nombre: &str) -> Result<Self, String> {
+        let normal = Self::default();
+        match nombre {
+            "normal" => Ok(normal),
+            "lento" => Ok(Self {
+                latencia: Latencia::Exponencial(Duration::from_millis(40)),
+                ..normal
+            }),
+            "inestable" => Ok(Self {
+                latencia: Latencia::Uniforme(Duration::from_millis(1), Duration::from_millis(20)),
+                tasa_error: 0.2,
+                tasa_timeout: 0.05,
+                duracion_timeout: Duration::from_millis(500),
+                ..normal
+            }),
+            "limitado" => Ok(Self {
+                latencia: Latencia::Fija(Duration::from_millis(5)),
+                tasa_limitacion: 0.3,
+                ..normal
+            }),
+            "caido" => Ok(Self {
+                caidas: vec![Caida { desde: Duration::from_secs(0), hasta: Duration::MAX }],
+                ..normal
+            }),
+            _ => Err(format!("Perfil de fallas desconocido: {}", nombre)),
+        }
+    }
+
+    /// Agrega las caídas a las que ya tiene el perfil
+    pub fn con_caidas(mut self, caidas: Vec<Caida>) -> Self {
+        self.caidas.extend(caidas);
+        self
+    }
+
+    /// Indica si el proveedor está caído a `transcurrido` de su inicio
+    pub fn en_caida(&self, transcurrido: Duration) -> bool {
+        self.caidas.iter().any(|caida| caida.desde <= transcurrido && transcurrido < caida.hasta)
+    }
+
+    pub fn muestrear_latencia(&self, rng: &mut StdRng) -> Duration {
+        match self.latencia {
+            Latencia::Nula => Duration::from_secs(0),
+            Latencia::Fija(demora) => demora,
+            Latencia::Uniforme(minima, maxima) if minima < maxima => rng.gen_range(minima..maxima),
+            Latencia::Uniforme(minima, _) => minima,
+            Latencia::Exponencial(media) => {
+                let u: f64 = rng.gen();
+                media.mul_f64(-(1.0 - u).ln())
+            }
+        }
+    }
+}
+
+/// Interpreta una lista de caídas en segundos, por ejemplo "5-8,20-22.5"
+pub fn parsear_caidas(texto: &str) -> Result<Vec<Caida>, String> {
+    texto
+        .split(',')
+        .filter(|intervalo| !intervalo.trim().is_empty())
+        .map(|intervalo| {
+            let (desde, hasta) = intervalo
+                .split_once('-')
+                .ok_or_else(|| format!("Caída inválida '{}': se espera <desde>-<hasta>", intervalo))?;
+            let desde = parsear_segundos(desde)?;
+            let hasta = parsear_segundos(hasta)?;
+            if desde >= hasta {
+                return Err(format!("Caída inválida '{}': el inicio debe ser anterior al fin", intervalo));
+            }
+            Ok(Caida { desde, hasta })
+        })
+        .collect()
+}
+
+/// Interpreta un cambio de perfil de la forma <segundos>:<perfil>
+pub fn parsear_cambio_perfil(cambio: &str) -> Result<(Duration, PerfilFallas), String> {
+    let (segundos, nombre) = cambio
+        .split_once(':')
+        .ok_or_else(|| format!("Cambio de perfil inválido '{}': se espera <segundos>:<perfil>", cambio))?;
+    let espera = segundos
+        .parse::<f64>()
+        .ok()
+        .and_then(|segundos| Duration::try_from_secs_f64(segundos).ok())
+        .ok_or_else(|| format!("Cambio de perfil inválido '{}': segundos incorrectos", cambio))?;
+
+    Ok((espera, PerfilFallas::desde_nombre(nombre)?))
+}
+
+fn parsear_segundos(texto: &str) -> Result<Duration, String> {
+    texto
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .and_then(|segundos| Duration::try_from_secs_f64(segundos).ok())
+        .ok_or_else(|| format!("Cantidad de segundos inválida: '{}'", texto))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsear_caidas_interpreta_intervalos_en_segundos() {
+        let caidas = parsear_caidas("5-8,20-22.5").unwrap();
+        assert_eq!(caidas, vec![
+            Caida { desde: Duration::from_secs(5), hasta: Duration::from_secs(8) },
+            Caida { desde: Duration::from_secs(20), hasta: Duration::from_millis(22_500) },
+        ]);
+        assert!(parsear_caidas("8-5").is_err());
+        assert!(parsear_caidas("cinco-ocho").is_err());
+        // Fuera del rango de Duration es un error, no un panic
+        assert!(parsear_caidas("0-1e20").is_err());
+        assert!(parsear_cambio_perfil("1e20:caido").is_err());
+        assert!(parsear_cambio_perfil("-1:caido").is_err());
+    }
+
+    #[test]
+    fn proveedor_esta_caido_solo_dentro_del_intervalo() {
+        let perfil = PerfilFallas::default().con_caidas(parsear_caidas("5-8").unwrap());
+        assert!(!perfil.en_caida(Duration::from_secs(4)));
+        assert!(perfil.en_caida(Duration::from_secs(5)));
+        assert!(perfil.en_caida(Duration::from_millis(7_999)));
+        assert!(!perfil.en_caida(Duration::from_secs(8)));
+    }
+}
@@ -0,0 +1,398 @@
+use std::{collections::HashMap, fmt, fs, path::Path, sync::Mutex};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::transaccion::{TipoTransaccion, Transaccion};
+
+const UMBRAL_DEFAULT: f64 = 4.0;
+const MINIMO_OPERACIONES_DEFAULT: u64 = 20;
+const VENTANA_DEFAULT: u64 = 200;
+const CALENTAMIENTO_DEFAULT: &str = "global";
+// Días con actividad que pesan en la frecuencia diaria
+const VENTANA_DIAS: u64 = 7;
+const MS_POR_HORA: u128 = 3_600_000;
+const HORAS: usize = 24;
+// Para que un cliente que siempre mueve el mismo monto no tenga desvío 0
+const DESVIO_MINIMO: f64 = 1.0;
+
+/// Qué se hace con un cliente que todavía no tiene operaciones suficientes
+/// para que su perfil sea confiable
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Calentamiento {
+    /// Se aprueban sus transacciones mientras se arma el perfil
+    Aprobar,
+    /// Se lo compara con el perfil de todos los clientes juntos
+    Global,
+}
+
+impl Calentamiento {
+    pub fn desde_nombre(nombre: &str) -> Result<Self, String> {
+        match nombre {
+            "aprobar" => Ok(Calentamiento::Aprobar),
+            "global" => Ok(Calentamiento::Global),
+            _ => Err(format!("Calentamiento inválido '{}': se espera aprobar o global", nombre)),
+        }
+    }
+}
+
+/// Detector de comportamiento anómalo por cliente. Hay que activarlo:
+/// rechaza transacciones que sin él se liquidarían.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfiguracionPerfiles {
+    pub activo: bool,
+    /// Desde este puntaje la transacción se rechaza
+    pub umbral: f64,
+    /// Operaciones que necesita un perfil para usarse
+    pub minimo_operaciones: u64,
+    /// aprobar o global, para los clientes con menos operaciones
+    pub calentamiento: String,
+    /// Cantidad aproximada de operaciones recientes que pesan en el perfil
+    pub ventana: u64,
+    /// Si está, los perfiles se leen al empezar y se guardan al terminar
+    pub archivo: Option<String>,
+}
+
+impl Default for ConfiguracionPerfiles {
+    fn default() -> Self {
+        Self {
+            activo: false,
+            umbral: UMBRAL_DEFAULT,
+            minimo_operaciones: MINIMO_OPERACIONES_DEFAULT,
+            calentamiento: CALENTAMIENTO_DEFAULT.into(),
+            ventana: VENTANA_DEFAULT,
+            archivo: None,
+        }
+    }
+}
+
+impl ConfiguracionPerfiles {
+    pub fn calentamiento(&self) -> Result<Calentamiento, String> {
+        Calentamiento::desde_nombre(&self.calentamiento)
+    }
+}
+
+/// Cuánto se aparta una transacción de cada aspecto del perfil. El monto
+/// y la frecuencia van en desvíos estándar; la hora y el tipo, en bits
+/// de sorpresa frente a una distribución pareja.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Desvios {
+    pub monto: f64,
+    pub hora: f64,
+    pub tipo: f64,
+    pub frecuencia: f64,
+}
+
+impl Desvios {
+    /// Los aspectos se suman como distancias independientes
+    pub fn puntaje(&self) -> f64 {
+        (self.monto.powi(2) + self.hora.powi(2) + self.tipo.powi(2) + self.frecuencia.powi(2)).sqrt()
+    }
+}
+
+/// Lo que dice el perfil de un cliente sobre una transacción, para los
+/// modelos entrenados afuera
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct HistorialCliente {
+    pub operaciones: u64,
+    /// Desvíos estándar entre el monto y la media del cliente
+    pub desvio_monto: f64,
+    /// Operaciones del cliente en el día de la transacción
+    pub operaciones_hoy: u64,
+}
+
+/// Comportamiento reciente de un cliente, o de todos juntos. Las medias
+/// son móviles: cada operación pesa 1 / min(operaciones, ventana).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PerfilCliente {
+    pub operaciones: u64,
+    media_monto: f64,
+    varianza_monto: f64,
+    /// Proporción de operaciones en cada hora del día (UTC)
+    horas: [f64; HORAS],
+    /// Proporción de cash in y de cash out
+    tipos: [f64; 2],
+    /// Día en curso, contado desde el epoch, y sus operaciones
+    dia: u64,
+    operaciones_dia: u64,
+    /// Operaciones por día con actividad, sin contar el día en curso
+    media_diaria: Option<f64>,
+    dias: u64,
+}
+
+impl PerfilCliente {
+    fn ventana_efectiva(&self, ventana: u64) -> f64 {
+        self.operaciones.min(ventana).max(1) as f64
+    }
+
+    fn desvios(&self, transaccion: &Transaccion, ventana: u64, con_frecuencia: bool) -> Desvios {
+        let n = self.ventana_efectiva(ventana);
+        // Cada hora y cada tipo arrancan con una operación, para no dividir por 0
+        let sorpresa = |proporcion: f64, casos: usize| {
+            let probabilidad = (proporcion * n + 1.0) / (n + casos as f64);
+            (1.0 / (probabilidad * casos as f64)).log2().max(0.0)
+        };
+        let (dia, hora) = dia_y_hora(transaccion.timestamp);
+        let frecuencia = match self.media_diaria {
+            Some(media) if con_frecuencia && dia == self.dia => {
+                ((self.operaciones_dia + 1) as f64 - media).max(0.0) / media.max(1.0).sqrt()
+            }
+            _ => 0.0,
+        };
+
+        Desvios {
+            monto: (transaccion.monto as f64 - self.media_monto).abs() / self.varianza_monto.sqrt().max(DESVIO_MINIMO),
+            hora: sorpresa(self.horas[hora], HORAS),
+            tipo: sorpresa(self.tipos[indice_tipo(transaccion.tipo)], 2),
+            frecuencia,
+        }
+    }
+
+    fn historial(&self, transaccion: &Transaccion, ventana: u64) -> HistorialCliente {
+        let (dia, _) = dia_y_hora(transaccion.timestamp);
+        HistorialCliente {
+            operaciones: self.operaciones,
+            desvio_monto: self.desvios(transaccion, ventana, false).monto,
+            operaciones_hoy: if dia == self.dia { self.operaciones_dia } else { 0 },
+        }
+    }
+
+    fn aprender(&mut self, transaccion: &Transaccion, ventana: u64) {
+        let (dia, hora) = dia_y_hora(transaccion.timestamp);
+        if self.operaciones == 0 {
+            self.dia = dia;
+        } else if dia > self.dia {
+            self.dias += 1;
+            let peso = 1.0 / self.dias.min(VENTANA_DIAS) as f64;
+            let anterior = self.media_diaria.unwrap_or(0.0);
+            self.media_diaria = Some(anterior + peso * (self.operaciones_dia as f64 - anterior));
+            self.dia = dia;
+            self.operaciones_dia = 0;
+        }
+        self.operaciones_dia += 1;
+
+        self.operaciones += 1;
+        let peso = 1.0 / self.ventana_efectiva(ventana);
+        let delta = transaccion.monto as f64 - self.media_monto;
+        self.media_monto += peso * delta;
+        self.varianza_monto = (1.0 - peso) * (self.varianza_monto + peso * delta * delta);
+        for (indice, proporcion) in self.horas.iter_mut().enumerate() {
+            *proporcion += peso * (if indice == hora { 1.0 } else { 0.0 } - *proporcion);
+        }
+        for (indice, proporcion) in self.tipos.iter_mut().enumerate() {
+            *proporcion += peso * (if indice == indice_tipo(transaccion.tipo) { 1.0 } else { 0.0 } - *proporcion);
+        }
+    }
+}
+
+fn dia_y_hora(timestamp: u128) -> (u64, usize) {
+    let horas = timestamp / MS_POR_HORA;
+    ((horas / HORAS as u128) as u64, (horas % HORAS as u128) as usize)
+}
+
+fn indice_tipo(tipo: TipoTransaccion) -> usize {
+    match tipo {
+        TipoTransaccion::CashIn => 0,
+        TipoTransaccion::CashOut => 1,
+    }
+}
+
+/// Una transacción que se aparta demasiado del perfil contra el que se
+/// la comparó
+#[derive(Debug, Clone, PartialEq)]
+pub struct Anomalia {
+    pub desvios: Desvios,
+    /// "cliente" o "global"
+    pub perfil: &'static str,
+}
+
+impl fmt::Display for Anomalia {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let d = &self.desvios;
+        write!(
+            f,
+            "puntaje {:.1} contra el perfil {} (monto {:.1}, hora {:.1}, tipo {:.1}, frecuencia {:.1})",
+            d.puntaje(), self.perfil, d.monto, d.hora, d.tipo, d.frecuencia
+        )
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Perfiles {
+    global: PerfilCliente,
+    clientes: HashMap<Uuid, PerfilCliente>,
+}
+
+/// Perfiles de todos los clientes, compartidos por los procesadores ia
+pub struct PerfilesClientes {
+    configuracion: ConfiguracionPerfiles,
+    calentamiento: Calentamiento,
+    perfiles: Mutex<Perfiles>,
+}
+
+impl PerfilesClientes {
+    /// Parte de los perfiles guardados en el archivo de la configuración,
+    /// si existe
+    pub fn cargar(configuracion: ConfiguracionPerfiles) -> Result<Self, String> {
+        let perfiles = match configuracion.archivo.as_deref().filter(|ruta| Path::new(ruta).exists()) {
+            Some(ruta) => {
+                let texto = fs::read_to_string(ruta).map_err(|e| format!("No se pudo leer {}: {}", ruta, e))?;
+                serde_json::from_str(&texto).map_err(|e| format!("Perfiles inválidos en {}: {}", ruta, e))?
+            }
+            None => Perfiles::default(),
+        };
+
+        Ok(Self {
+            calentamiento: configuracion.calentamiento()?,
+            configuracion,
+            perfiles: Mutex::new(perfiles),
+        })
+    }
+
+    /// Escribe los perfiles en el archivo de la configuración, si hay
+    /// uno. Devuelve la cantidad de clientes guardados.
+    pub fn guardar(&self) -> Result<Option<usize>, String> {
+        let ruta = match &self.configuracion.archivo {
+            Some(ruta) => ruta,
+            None => return Ok(None),
+        };
+        let perfiles = self.perfiles();
+        let texto = serde_json::to_string(&*perfiles).map_err(|e| e.to_string())?;
+        // Se reemplaza de una vez para no dejar un archivo a medias
+        let temporal = format!("{}.tmp", ruta);
+        fs::write(&temporal, texto)
+            .and_then(|_| fs::rename(&temporal, ruta))
+            .map_err(|e| format!("No se pudo escribir {}: {}", ruta, e))?;
+
+        Ok(Some(perfiles.clientes.len()))
+    }
+
+    /// Compara la transacción con el perfil de su cliente, o con el global
+    /// si el del cliente todavía está en calentamiento. Si no es anómala
+    /// pasa a formar parte de los dos perfiles. Lo anómalo no se aprende:
+    /// si un cliente cambia de comportamiento de verdad, se lo sigue
+    /// rechazando hasta que se borre su perfil del archivo.
+    pub fn evaluar(&self, transaccion: &Transaccion) -> Option<Anomalia> {
+        let configuracion = &self.configuracion;
+        let mut perfiles = self.perfiles();
+        let Perfiles { global, clientes } = &mut *perfiles;
+        let cliente = clientes.entry(transaccion.id_cliente).or_default();
+
+        let comparacion = if cliente.operaciones >= configuracion.minimo_operaciones {
+            Some((cliente.desvios(transaccion, configuracion.ventana, true), "cliente"))
+        } else if self.calentamiento == Calentamiento::Global && global.operaciones >= configuracion.minimo_operaciones {
+            // La frecuencia diaria del global es la de todos los clientes juntos
+            Some((global.desvios(transaccion, configuracion.ventana, false), "global"))
+        } else {
+            None
+        };
+        if let Some((desvios, perfil)) = comparacion {
+            if desvios.puntaje() >= configuracion.umbral {
+                return Some(Anomalia { desvios, perfil });
+            }
+        }
+
+        cliente.aprender(transaccion, configuracion.ventana);
+        global.aprender(transaccion, configuracion.ventana);
+        None
+    }
+
+    /// Sin perfil, el historial es todo 0
+    pub fn historial(&self, transaccion: &Transaccion) -> HistorialCliente {
+        self.perfiles()
+            .clientes
+            .get(&transaccion.id_cliente)
+            .map(|perfil| perfil.historial(transaccion, self.configuracion.ventana))
+            .unwrap_or_default()
+    }
+
+    pub fn perfil(&self, id_cliente: Uuid) -> Option<PerfilCliente> {
+        self.perfiles().clientes.get(&id_cliente).cloned()
+    }
+
+    fn perfiles(&self) -> std::sync::MutexGuard<'_, Perfiles> {
+        self.perfiles.lock().expect("perfiles poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 10:00 UTC
+    const TIMESTAMP: u128 = 1_700_000_000_000 / (24 * MS_POR_HORA) * (24 * MS_POR_HORA) + 10 * MS_POR_HORA;
+
+    fn crear_transaccion(id_cliente: Uuid, monto: f32, timestamp: u128) -> Transaccion {
+        Transaccion { timestamp, ..Transaccion::de_prueba(1, id_cliente, TipoTransaccion::CashIn, monto) }
+    }
+
+    fn crear_perfiles(calentamiento: &str, archivo: Option<String>) -> PerfilesClientes {
+        PerfilesClientes::cargar(ConfiguracionPerfiles {
+            calentamiento: calentamiento.into(),
+            archivo,
+            minimo_operaciones: 10,
+            ..Default::default()
+        }).unwrap()
+    }
+
+    /// Montos entre 90 y 110 a la misma hora
+    fn entrenar(perfiles: &PerfilesClientes, id_cliente: Uuid, operaciones: u32) {
+        for i in 0..operaciones {
+            let monto = 90.0 + (i % 5) as f32 * 5.0;
+            assert_eq!(perfiles.evaluar(&crear_transaccion(id_cliente, monto, TIMESTAMP)), None);
+        }
+    }
+
+    #[test]
+    fn se_rechaza_lo_que_se_aparta_del_perfil_del_cliente_y_no_se_aprende() {
+        let perfiles = crear_perfiles("aprobar", None);
+        let cliente = Uuid::new_v4();
+        entrenar(&perfiles, cliente, 200);
+
+        let anomalia = perfiles.evaluar(&crear_transaccion(cliente, 1000.0, TIMESTAMP)).unwrap();
+        assert_eq!(anomalia.perfil, "cliente");
+        assert!(anomalia.desvios.monto > 50.0, "{}", anomalia);
+        assert_eq!(perfiles.perfil(cliente).unwrap().operaciones, 200);
+
+        // Un monto poco habitual a la hora de siempre pasa, y a una hora en
+        // la que nunca operó ya no
+        let otra_hora = TIMESTAMP + 5 * MS_POR_HORA;
+        assert_eq!(perfiles.evaluar(&crear_transaccion(cliente, 120.0, TIMESTAMP)), None);
+        let anomalia = perfiles.evaluar(&crear_transaccion(cliente, 120.0, otra_hora)).unwrap();
+        assert!(anomalia.desvios.hora > 3.0 && anomalia.desvios.monto < 4.0, "{}", anomalia);
+    }
+
+    #[test]
+    fn los_clientes_nuevos_siguen_la_politica_de_calentamiento() {
+        let nuevo = Uuid::new_v4();
+        let aprobar = crear_perfiles("aprobar", None);
+        entrenar(&aprobar, Uuid::new_v4(), 30);
+        assert_eq!(aprobar.evaluar(&crear_transaccion(nuevo, 5000.0, TIMESTAMP)), None);
+
+        let global = crear_perfiles("global", None);
+        entrenar(&global, Uuid::new_v4(), 30);
+        let anomalia = global.evaluar(&crear_transaccion(nuevo, 5000.0, TIMESTAMP)).unwrap();
+        assert_eq!(anomalia.perfil, "global");
+        assert_eq!(global.evaluar(&crear_transaccion(nuevo, 95.0, TIMESTAMP)), None);
+    }
+
+    #[test]
+    fn los_perfiles_guardados_siguen_al_otro_dia() {
+        let ruta = std::env::temp_dir().join(format!("perfiles_tests_{}.json", std::process::id()));
+        let archivo = Some(ruta.to_string_lossy().into_owned());
+        let cliente = Uuid::new_v4();
+        let primer_dia = crear_perfiles("aprobar", archivo.clone());
+        entrenar(&primer_dia, cliente, 20);
+        assert_eq!(primer_dia.guardar().unwrap(), Some(1));
+
+        let segundo_dia = crear_perfiles("aprobar", archivo);
+        let manana = TIMESTAMP + 24 * MS_POR_HORA;
+        assert_eq!(segundo_dia.evaluar(&crear_transaccion(cliente, 100.0, manana)), None);
+        assert!(segundo_dia.evaluar(&crear_transaccion(cliente, 1000.0, manana)).is_some());
+        // El día anterior queda como referencia de la frecuencia diaria
+        let perfil = segundo_dia.perfil(cliente).unwrap();
+        assert_eq!((perfil.operaciones, perfil.media_diaria), (21, Some(20.0)));
+
+        let _ = fs::remove_file(&ruta);
+    }
+}
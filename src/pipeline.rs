@@ -0,0 +1,417 @@
+use std::{
+    sync::{Arc, Mutex, mpsc::{channel, Receiver}},
+    thread, thread::JoinHandle,
+};
+use rand::{Rng, prelude::StdRng};
+use uuid::Uuid;
+
+use crate::{
+    apagado::Apagado,
+    cliente::{Cliente, Saldo, buscar_cliente},
+    configuracion::{Configuracion, CuotasProveedor, PoliticasSupervisor},
+    contexto::ContextoPipeline,
+    estados::{AlmacenEstados, RegistroEstado},
+    etapa::{Canal, ConstructorPipeline, Flujo, OpcionesEtapa},
+    ia::{ConfiguracionIA, ProcesadorIA},
+    limitador::ProveedorLimitado,
+    logger::{Logger, TaggedLogger},
+    metricas::{RegistroMetricas, cola_de_tipo, iniciar_servidor_metricas},
+    modelo::ClasificadorFraude,
+    perfiles::PerfilesClientes,
+    procesador::{Enrutador, Procesador},
+    proveedor_autorizacion::{ConexionProveedor, ProveedorAutorizacion},
+    proveedor_externo::{ProveedorEnProceso, ProveedorExterno},
+    proveedor_http::ProveedorHttp,
+    rechazos::WorkerRechazos,
+    redaccion::Redactor,
+    reintentos::Disyuntor,
+    sombra::RegistroSombra,
+    supervisor::{Latido, RegistroFuentes, Supervisor},
+    transaccion::{TipoTransaccion, Transaccion},
+    traza::RegistroTrazas,
+    worker::{TipoWorker, Worker},
+    worker_final::WorkerFinal,
+};
+
+/// Un pipeline corriendo: se le envían transacciones, se consultan los
+/// saldos y los estados mientras corre y al final se lo termina. Las
+/// liquidadas, rechazadas y fallidas se escriben en los archivos de la
+/// configuración como en una corrida desde la línea de comandos.
+pub struct Pipeline {
+    log: TaggedLogger,
+    contexto: ContextoPipeline,
+    enrutador: Enrutador,
+    fuentes: RegistroFuentes,
+    politicas: PoliticasSupervisor,
+    supervisor: JoinHandle<Result<Vec<String>, String>>,
+    handle_proveedor: Option<JoinHandle<()>>,
+    limitador: Option<Arc<ProveedorLimitado>>,
+    perfiles: Option<Arc<PerfilesClientes>>,
+    clasificador: Option<Arc<ClasificadorFraude>>,
+    /// Solo con un detector en modo sombra, con el archivo de comparación
+    comparacion: Option<(Arc<RegistroSombra>, String)>,
+}
+
+/// Lo que comparten los procesadores ia de un detector
+#[derive(Clone)]
+struct DetectorIA {
+    configuracion: ConfiguracionIA,
+    generador: Arc<Mutex<StdRng>>,
+    perfiles: Option<Arc<PerfilesClientes>>,
+    clasificador: Option<Arc<ClasificadorFraude>>,
+}
+
+impl DetectorIA {
+    /// Falla si el modelo necesita los perfiles de clientes y están apagados
+    fn new(configuracion: ConfiguracionIA, semilla: u64, perfiles: Option<Arc<PerfilesClientes>>, clasificador: Option<Arc<ClasificadorFraude>>, seccion: &str) -> Result<Self, String> {
+        if let Some(clasificador) = &clasificador {
+            let modelo = clasificador.modelo();
+            let sin_perfil: Vec<_> = modelo.caracteristicas().into_iter().filter(|c| c.usa_perfil()).collect();
+            if perfiles.is_none() && !sin_perfil.is_empty() {
+                return Err(format!("El modelo {} usa {:?}, que salen de los perfiles de clientes, y {}.perfiles.activo es false", modelo.version, sin_perfil, seccion));
+            }
+        }
+
+        Ok(Self { configuracion, generador: ProcesadorIA::generador(semilla), perfiles, clasificador })
+    }
+
+    fn procesador(&self) -> ProcesadorIA {
+        let mut procesador = ProcesadorIA::new(self.configuracion.clone(), self.generador.clone());
+        if let Some(perfiles) = &self.perfiles {
+            procesador = procesador.con_perfiles(perfiles.clone());
+        }
+        if let Some(clasificador) = &self.clasificador {
+            procesador = procesador.con_clasificador(clasificador.clone());
+        }
+        procesador
+    }
+}
+
+impl Pipeline {
+    /// Arranca todas las etapas con la configuración dada sobre estos
+    /// clientes. Las semillas que falten se eligen al azar.
+    pub fn iniciar(configuracion: &Configuracion, clientes: Arc<Vec<Arc<Cliente>>>, logger: Arc<Logger>, redactor: Arc<Redactor>) -> Result<Self, String> {
+        configuracion.validar()?;
+        let archivos = &configuracion.archivos;
+        let mut rng = rand::thread_rng();
+        let semilla_ia = configuracion.semillas.ia.unwrap_or_else(|| rng.gen());
+        let semilla_proveedor = configuracion.semillas.proveedor.unwrap_or_else(|| rng.gen());
+
+        let perfil_proveedor = configuracion.proveedor.perfil_fallas()?;
+        let politica_reintentos = configuracion.proveedor.politica_reintentos();
+        let url_proveedor = configuracion.proveedor.url.as_deref();
+        let CuotasProveedor { global: cuota_proveedor, cash_in: cuota_cashin, cash_out: cuota_cashout } = configuracion.proveedor.cuotas()?;
+        let lote = configuracion.proveedor.lote()?;
+        let cambio_perfil = configuracion.proveedor.cambio_perfil()?;
+        let politicas = configuracion.supervisor.politicas()?;
+
+        let log = TaggedLogger::new("PIPELINE", logger.clone());
+        let configuracion_perfiles = &configuracion.ia.perfiles;
+        let perfiles = match configuracion_perfiles.activo {
+            true => Some(Arc::new(PerfilesClientes::cargar(configuracion_perfiles.clone())?)),
+            false => None,
+        };
+        if let (Some(_), Some(archivo)) = (&perfiles, &configuracion_perfiles.archivo) {
+            log.write(&format!("Perfiles de clientes en {}", archivo));
+        }
+        let clasificador = ClasificadorFraude::cargar(&configuracion.ia.modelo, Some(&archivos.decisiones))?.map(Arc::new);
+        let detector = DetectorIA::new(configuracion.ia.clone(), semilla_ia, perfiles.clone(), clasificador.clone(), "ia")?;
+        if let Some(clasificador) = &clasificador {
+            log.write(&format!("Usando el modelo {} con umbral {}, decisiones en {}", clasificador.modelo().version, clasificador.umbral(), archivos.decisiones));
+        }
+        // La sombra tiene su propio generador, modelo y perfiles: nada de lo
+        // que decide llega al detector activo. Sus perfiles no se guardan.
+        let sombra = match &configuracion.ia.sombra {
+            Some(sombra) => {
+                let perfiles = match sombra.perfiles.activo {
+                    true => Some(Arc::new(PerfilesClientes::cargar(sombra.perfiles.clone())?)),
+                    false => None,
+                };
+                let clasificador = ClasificadorFraude::cargar(&sombra.modelo, None)?.map(Arc::new);
+                match &clasificador {
+                    Some(clasificador) => log.write(&format!(
+                        "Modelo {} con umbral {} en modo sombra, comparación en {}",
+                        clasificador.modelo().version, clasificador.umbral(), archivos.comparacion
+                    )),
+                    None => log.write(&format!(
+                        "Detector al azar con probabilidad {} en modo sombra, comparación en {}",
+                        sombra.probabilidad_lavado, archivos.comparacion
+                    )),
+                }
+                let detector = DetectorIA::new(sombra.as_ref().clone(), semilla_ia.wrapping_add(1), perfiles, clasificador, "ia.sombra")?;
+                Some((detector, Arc::new(RegistroSombra::crear(&archivos.comparacion)?)))
+            }
+            None => None,
+        };
+        let estados = Arc::new(AlmacenEstados::new(TaggedLogger::new("ESTADOS", logger.clone())));
+        let metricas = Arc::new(RegistroMetricas::default());
+        let contexto = ContextoPipeline {
+            clientes,
+            estados: estados.clone(),
+            metricas: metricas.clone(),
+            trazas: configuracion.observabilidad.traza.as_ref().map(|_| Arc::new(RegistroTrazas::default())),
+            redactor,
+            apagado: Arc::new(Apagado::default()),
+            latido: Arc::new(Latido::default()),
+        };
+        let mut supervisor = Supervisor::new(
+            TaggedLogger::new("SUPERVISOR", logger.clone()),
+            configuracion.supervisor.sin_progreso(),
+            configuracion.supervisor.reinicios
+        ).con_en_curso(Box::new(move || estados.en_curso()));
+        if configuracion.autoescalado.activo {
+            log.write(&format!("Autoescalado activo, revisando cada {:?}", configuracion.autoescalado.intervalo()));
+            supervisor = supervisor.con_autoescalado(configuracion.autoescalado.clone(), metricas.clone());
+        }
+        if let Some(puerto) = configuracion.observabilidad.puerto_metricas {
+            let direccion = iniciar_servidor_metricas(TaggedLogger::new("METRICAS", logger.clone()), metricas, puerto)?;
+            log.write(&format!("Métricas disponibles en http://{}/metrics", direccion));
+        }
+
+        let (proveedor, handle_proveedor): (Arc<dyn ProveedorAutorizacion>, _) = match url_proveedor {
+            Some(url) => {
+                log.write(&format!("Usando el proveedor de autorizaciones en {}", url));
+                (Arc::new(ProveedorHttp::new(url)?), None)
+            },
+            None => {
+                log.write("Iniciando proveedor externo de autorizaciones");
+                let (tx_solicitudes, control_proveedor, handle_proveedor) = ProveedorExterno::iniciar(
+                    TaggedLogger::new("PROVEEDOR", logger.clone()),
+                    semilla_proveedor,
+                    perfil_proveedor.clone()
+                );
+                if let Some((demora, nuevo_perfil)) = cambio_perfil {
+                    // Degradar (o recuperar) al proveedor en medio de la corrida
+                    let log_cambio = TaggedLogger::new("PROVEEDOR", logger.clone());
+                    thread::spawn(move || {
+                        thread::sleep(demora);
+                        log_cambio.write(&format!("Cambiando perfil de fallas a {:?}", nuevo_perfil));
+                        control_proveedor.cambiar_perfil(nuevo_perfil);
+                    });
+                }
+                (Arc::new(ProveedorEnProceso::new(tx_solicitudes)), Some(handle_proveedor))
+            }
+        };
+
+        // Las cuotas se comparten entre todos los workers que llaman al proveedor
+        let mut limitador = None;
+        let mut proveedor_autorizacion = ConexionProveedor::new(
+            proveedor.clone(),
+            politica_reintentos,
+            Arc::new(Disyuntor::default())
+        );
+        if cuota_proveedor.is_some() || cuota_cashin.is_some() || cuota_cashout.is_some() {
+            log.write(&format!(
+                "Limitando llamadas al proveedor: global {:?}, cash in {:?}, cash out {:?}",
+                cuota_proveedor, cuota_cashin, cuota_cashout
+            ));
+            let proveedor_limitado = Arc::new(ProveedorLimitado::new(proveedor, cuota_proveedor, cuota_cashin, cuota_cashout));
+            limitador = Some(proveedor_limitado.clone());
+            proveedor_autorizacion = proveedor_autorizacion.con_limitador(proveedor_limitado);
+        }
+        if let Some(lote) = lote {
+            log.write(&format!("Autorizando de a lotes de hasta {} transacciones (espera máxima {:?})", lote.tamano, lote.espera));
+            proveedor_autorizacion = proveedor_autorizacion.con_lotes(lote);
+        }
+
+        let (tx_transacciones_rechazadas, rx_transacciones_rechazadas) = channel();
+        let latido_rechazos = Arc::new(Latido::default());
+        let handle_worker_rechazos = WorkerRechazos::iniciar(
+            TaggedLogger::new("RECHAZOS", logger.clone()),
+            rx_transacciones_rechazadas,
+            &archivos.rechazadas,
+            contexto.con_latido(latido_rechazos.clone())
+        );
+        let (tx_transacciones_fallidas, rx_transacciones_fallidas) = channel();
+        let latido_fallidas = Arc::new(Latido::default());
+        let handle_worker_fallidas = WorkerRechazos::iniciar(
+            TaggedLogger::new("FALLIDAS", logger.clone()),
+            rx_transacciones_fallidas,
+            &archivos.fallidas,
+            contexto.con_latido(latido_fallidas.clone())
+        );
+
+        // Cada etapa arranca cuando se conecta la siguiente: los workers de
+        // cada tipo, la IA y al final el worker final
+        log.write("Iniciando workers, procesadores ia y worker final");
+        // Todas las colas entre etapas tienen un carril por prioridad
+        let envejecimiento = configuracion.prioridad.envejecimiento();
+        let (tx_cashin, rx_cashin) = Canal::Acotado(configuracion.workers.cola).crear(envejecimiento);
+        let (tx_cashout, rx_cashout) = Canal::Acotado(configuracion.workers.cola).crear(envejecimiento);
+        // El enrutador manda a rechazadas los ids repetidos
+        let tx_repetidas = tx_transacciones_rechazadas.clone();
+        let mut pipeline = ConstructorPipeline::new(
+            &mut supervisor,
+            contexto.clone(),
+            logger,
+            tx_transacciones_rechazadas,
+            tx_transacciones_fallidas
+        ).con_envejecimiento(envejecimiento);
+        let mut autorizadas = vec![];
+        for (tipo_worker, tipo, rx, hilos, limites) in [
+            (TipoWorker::CashIn, TipoTransaccion::CashIn, rx_cashin, configuracion.workers.cash_in, configuracion.autoescalado.cash_in),
+            (TipoWorker::CashOut, TipoTransaccion::CashOut, rx_cashout, configuracion.workers.cash_out, configuracion.autoescalado.cash_out),
+        ] {
+            let proveedor = proveedor_autorizacion.clone();
+            autorizadas.push(pipeline.etapa(
+                Flujo::desde(rx),
+                OpcionesEtapa::new(&format!("WORKER {}", tipo_worker), cola_de_tipo(tipo))
+                    .con_politica(politicas.workers)
+                    .con_hilos(hilos, limites),
+                move || Worker::new(proveedor.clone())
+            ));
+        }
+        // Solo los workers tienen que quedar conectados al proveedor, para
+        // que termine cuando terminan ellos
+        drop(proveedor_autorizacion);
+        let autorizadas = autorizadas.into_iter().reduce(Flujo::unir).expect("Hay workers de los dos tipos");
+
+        let comparacion = sombra.as_ref().map(|(_, registro)| (registro.clone(), archivos.comparacion.clone()));
+        let validadas = pipeline.etapa(
+            autorizadas,
+            OpcionesEtapa::new("PROCESADOR IA", "ia")
+                .con_canal(Canal::Acotado(configuracion.workers.cola))
+                .con_politica(politicas.ia)
+                .con_hilos(configuracion.workers.ia, configuracion.autoescalado.ia),
+            move || {
+                let procesador = detector.procesador();
+                match &sombra {
+                    Some((sombra, registro)) => procesador.con_sombra(sombra.procesador(), registro.clone()),
+                    None => procesador,
+                }
+            }
+        );
+
+        let ruta_saldos = archivos.saldos.clone();
+        pipeline.etapa_final(
+            validadas,
+            OpcionesEtapa::new("WORKER FINAL", "final").con_politica(politicas.worker_final),
+            move || WorkerFinal::new(&ruta_saldos)
+        );
+        supervisor.vigilar("RECHAZOS", politicas.rechazos, latido_rechazos, handle_worker_rechazos);
+        supervisor.vigilar("FALLIDAS", politicas.rechazos, latido_fallidas, handle_worker_fallidas);
+
+        // Cada etapa termina cuando se cierra su canal de entrada. Si alguna
+        // entra en pánico o se traba, el supervisor lo dice y aplica su política.
+        let fuentes = supervisor.registro_fuentes();
+        let supervisor = thread::spawn(move || supervisor.esperar());
+
+        Ok(Self {
+            enrutador: Enrutador::new(tx_cashin, tx_cashout, contexto.clone())
+                .con_prioridades(configuracion.prioridad.clone())
+                .con_rechazadas(tx_repetidas),
+            log,
+            contexto,
+            fuentes,
+            politicas,
+            supervisor,
+            handle_proveedor,
+            limitador,
+            perfiles,
+            clasificador,
+            comparacion,
+        })
+    }
+
+    /// Mete una transacción en el pipeline. Devuelve false si ya se había
+    /// enviado una con el mismo id: no se vuelve a procesar y queda en
+    /// rechazadas. Espera mientras las colas de los workers estén llenas.
+    pub fn enviar(&self, transaccion: Transaccion) -> Result<bool, String> {
+        if self.contexto.apagado.drenando() {
+            return Err(format!("Se pidió el apagado: la transacción {} no entra al pipeline", transaccion.id));
+        }
+        self.enrutador.enrutar(transaccion)
+    }
+
+    /// Para enviar transacciones desde otros hilos. `terminar` espera a
+    /// que se suelten todas las copias.
+    pub fn entrada(&self) -> Enrutador {
+        self.enrutador.clone()
+    }
+
+    /// Lee las transacciones de un archivo en un hilo propio que vigila el
+    /// supervisor como una etapa más
+    pub fn leer_archivo(&self, ruta_archivo: &str) -> Result<(), String> {
+        let latido = Arc::new(Latido::default());
+        let handle = Procesador::iniciar(ruta_archivo, self.entrada(), self.contexto.con_latido(latido.clone()))
+            .map_err(|e| format!("{}", e))?;
+        if !self.fuentes.vigilar("PROCESADOR", self.politicas.procesador, latido, handle) {
+            return Err("El pipeline ya terminó".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Recibe cada transacción que llega a un estado final desde ahora:
+    /// liquidada, rechazada (con el motivo) o fallida
+    pub fn suscribir(&self) -> Receiver<RegistroEstado> {
+        self.contexto.estados.suscribir()
+    }
+
+    /// El saldo actual del cliente, si es uno de los de la corrida
+    pub fn saldo(&self, id_cliente: Uuid) -> Option<Saldo> {
+        buscar_cliente(&self.contexto.clientes, id_cliente).map(|cliente| cliente.get_saldos())
+    }
+
+    pub fn estado(&self, id_transaccion: u32) -> Option<RegistroEstado> {
+        self.contexto.estados.consultar(id_transaccion)
+    }
+
+    /// Lo que comparten las etapas: los estados, las métricas, las trazas
+    /// y el pedido de apagado de la corrida
+    pub fn contexto(&self) -> &ContextoPipeline {
+        &self.contexto
+    }
+
+    /// Cierra la entrada y espera a que las etapas terminen lo que tienen
+    /// en curso. Devuelve los problemas de las etapas que se reiniciaron o
+    /// se dejaron de vigilar, o el diagnóstico si la corrida falló. Aun
+    /// si falló se escriben las decisiones y se guardan los perfiles.
+    pub fn terminar(self) -> Result<Vec<String>, String> {
+        let Self { log, contexto, enrutador, fuentes, supervisor, handle_proveedor, limitador, perfiles, clasificador, comparacion, .. } = self;
+        drop(enrutador);
+        drop(fuentes);
+        let resultado = supervisor.join().map_err(|_| "El supervisor entró en pánico".to_string()).and_then(|resultado| resultado);
+        contexto.apagado.terminar();
+        if resultado.is_ok() {
+            log.write("Todas las etapas terminaron");
+        }
+
+        if let Some(clasificador) = clasificador {
+            if let Err(e) = clasificador.flush() {
+                log.warn(&format!("No se pudieron escribir las decisiones del modelo: {}", e));
+            }
+        }
+
+        if let Some((registro, archivo)) = comparacion {
+            log.write(&format!("Modo sombra: {}, comparación en {}", registro.resumen(), archivo));
+        }
+
+        // Lo aprendido en esta corrida queda para la siguiente
+        match perfiles.map(|perfiles| perfiles.guardar()).transpose() {
+            Ok(Some(Some(cantidad))) => log.write(&format!("Perfiles de {} clientes guardados", cantidad)),
+            Ok(_) => (),
+            Err(e) => log.warn(&e),
+        }
+
+        // Soltar el limitador también suelta su referencia al proveedor
+        if let Some(limitador) = limitador {
+            if let Some(uso) = limitador.uso_global() {
+                log.write(&format!("Uso de la cuota global del proveedor: {}", uso));
+            }
+            log.write(&format!("Uso del proveedor cash in: {}", limitador.uso(TipoTransaccion::CashIn)));
+            log.write(&format!("Uso del proveedor cash out: {}", limitador.uso(TipoTransaccion::CashOut)));
+        }
+
+        // Detener el proveedor. Si un worker trabado quedó suelto sigue
+        // teniendo su canal de solicitudes y no se lo puede esperar.
+        if let (Some(handle_proveedor), Ok(incidentes)) = (handle_proveedor, &resultado) {
+            if incidentes.is_empty() {
+                handle_proveedor.join().expect("Cannot join provider thread");
+                log.write("El proveedor externo finalizó");
+            }
+        }
+
+        resultado
+    }
+}
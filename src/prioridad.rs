@@ -0,0 +1,299 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Arc, Condvar, Mutex, MutexGuard, mpsc::{RecvTimeoutError, SendError, TryRecvError}},
+    time::{Duration, Instant},
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{etapa::EnPipeline, transaccion::Transaccion};
+
+const ENVEJECIMIENTO_DEFAULT: u64 = 200; // 200 millis
+
+/// Carril por el que pasa una transacción en las colas del pipeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Prioridad {
+    Alta,
+    #[default]
+    Normal,
+}
+
+impl Prioridad {
+    pub const TODAS: [Prioridad; 2] = [Prioridad::Alta, Prioridad::Normal];
+
+    /// Como se la etiqueta en las métricas
+    pub fn nombre(&self) -> &'static str {
+        match self {
+            Prioridad::Alta => "alta",
+            Prioridad::Normal => "normal",
+        }
+    }
+}
+
+impl fmt::Display for Prioridad {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.nombre())
+    }
+}
+
+/// Qué transacciones van por el carril de alta prioridad y cuánto puede
+/// esperar una normal antes de pasar delante
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfiguracionPrioridad {
+    /// Desde este monto la transacción tiene prioridad alta
+    pub monto_alta: Option<f32>,
+    /// Segmento de clientes cuyas transacciones tienen prioridad alta
+    pub clientes: Vec<Uuid>,
+    /// Ventaja máxima de una transacción de prioridad alta: una normal que
+    /// llegó a la cola esto antes que la alta se atiende primero
+    pub envejecimiento_ms: u64,
+}
+
+impl Default for ConfiguracionPrioridad {
+    fn default() -> Self {
+        Self { monto_alta: None, clientes: vec![], envejecimiento_ms: ENVEJECIMIENTO_DEFAULT }
+    }
+}
+
+impl ConfiguracionPrioridad {
+    /// La columna Priority del archivo manda; si no viene, decide el monto
+    /// o el segmento del cliente
+    pub fn clasificar(&self, transaccion: &Transaccion) -> Prioridad {
+        if let Some(prioridad) = transaccion.prioridad {
+            return prioridad;
+        }
+        let por_monto = self.monto_alta.is_some_and(|monto| transaccion.monto >= monto);
+        if por_monto || self.clientes.contains(&transaccion.id_cliente) {
+            Prioridad::Alta
+        } else {
+            Prioridad::Normal
+        }
+    }
+
+    pub fn envejecimiento(&self) -> Duration {
+        Duration::from_millis(self.envejecimiento_ms)
+    }
+}
+
+/// Cola de una etapa con un carril por prioridad. Se atiende primero el
+/// de prioridad alta, salvo que la normal más vieja haya llegado más del
+/// envejecimiento antes que la alta más vieja: así una normal espera a lo
+/// sumo eso de más y no se queda esperando para siempre.
+pub fn canal_prioridad<T>(capacidad: Option<usize>, envejecimiento: Duration) -> (EmisorPrioridad<T>, ReceptorPrioridad<T>) {
+    let compartida = Arc::new(Compartida {
+        carriles: Mutex::new(Carriles { alta: VecDeque::new(), normal: VecDeque::new(), emisores: 1, receptores: 1 }),
+        con_trabajo: Condvar::new(),
+        con_lugar: Condvar::new(),
+        capacidad,
+        envejecimiento,
+    });
+
+    (EmisorPrioridad(compartida.clone()), ReceptorPrioridad(compartida))
+}
+
+struct Carriles<T> {
+    alta: VecDeque<(Instant, T)>,
+    normal: VecDeque<(Instant, T)>,
+    emisores: usize,
+    receptores: usize,
+}
+
+impl<T> Carriles<T> {
+    fn len(&self) -> usize {
+        self.alta.len() + self.normal.len()
+    }
+
+    fn tomar(&mut self, envejecimiento: Duration) -> Option<T> {
+        let normal_primero = match (self.alta.front(), self.normal.front()) {
+            (Some((alta, _)), Some((normal, _))) => *normal + envejecimiento <= *alta,
+            (None, _) => true,
+            (Some(_), None) => false,
+        };
+        let carril = if normal_primero { &mut self.normal } else { &mut self.alta };
+        carril.pop_front().map(|(_, valor)| valor)
+    }
+}
+
+struct Compartida<T> {
+    carriles: Mutex<Carriles<T>>,
+    con_trabajo: Condvar,
+    con_lugar: Condvar,
+    /// Sin capacidad no se espera para encolar
+    capacidad: Option<usize>,
+    envejecimiento: Duration,
+}
+
+impl<T> Compartida<T> {
+    fn carriles(&self) -> MutexGuard<'_, Carriles<T>> {
+        self.carriles.lock().expect("cola de prioridad poisoned")
+    }
+}
+
+pub struct EmisorPrioridad<T>(Arc<Compartida<T>>);
+
+impl<T: EnPipeline> EmisorPrioridad<T> {
+    /// Encola por el carril de la transacción; espera si la cola está
+    /// llena. Falla si ya no hay quien reciba.
+    pub fn send(&self, valor: T) -> Result<(), SendError<T>> {
+        let compartida = &self.0;
+        let mut carriles = compartida.carriles();
+        while carriles.receptores > 0 && compartida.capacidad.is_some_and(|capacidad| carriles.len() >= capacidad) {
+            carriles = compartida.con_lugar.wait(carriles).expect("cola de prioridad poisoned");
+        }
+        if carriles.receptores == 0 {
+            return Err(SendError(valor));
+        }
+        let carril = match valor.transaccion().prioridad.unwrap_or_default() {
+            Prioridad::Alta => &mut carriles.alta,
+            Prioridad::Normal => &mut carriles.normal,
+        };
+        carril.push_back((Instant::now(), valor));
+        compartida.con_trabajo.notify_one();
+
+        Ok(())
+    }
+}
+
+impl<T> Clone for EmisorPrioridad<T> {
+    fn clone(&self) -> Self {
+        self.0.carriles().emisores += 1;
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Drop for EmisorPrioridad<T> {
+    fn drop(&mut self) {
+        self.0.carriles().emisores -= 1;
+        self.0.con_trabajo.notify_all();
+    }
+}
+
+/// Lo comparten los hilos de la etapa
+pub struct ReceptorPrioridad<T>(Arc<Compartida<T>>);
+
+impl<T> ReceptorPrioridad<T> {
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut carriles = self.0.carriles();
+        match carriles.tomar(self.0.envejecimiento) {
+            Some(valor) => {
+                self.0.con_lugar.notify_one();
+                Ok(valor)
+            }
+            None if carriles.emisores == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Espera hasta que llegue algo o se suelten todos los emisores
+    #[cfg(test)]
+    pub fn recv(&self) -> Result<T, std::sync::mpsc::RecvError> {
+        let mut carriles = self.0.carriles();
+        loop {
+            if let Some(valor) = carriles.tomar(self.0.envejecimiento) {
+                self.0.con_lugar.notify_one();
+                return Ok(valor);
+            }
+            if carriles.emisores == 0 {
+                return Err(std::sync::mpsc::RecvError);
+            }
+            carriles = self.0.con_trabajo.wait(carriles).expect("cola de prioridad poisoned");
+        }
+    }
+
+    /// Recibe hasta que se suelten todos los emisores
+    #[cfg(test)]
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        std::iter::from_fn(move || self.recv().ok())
+    }
+
+    pub fn recv_timeout(&self, espera: Duration) -> Result<T, RecvTimeoutError> {
+        let limite = Instant::now() + espera;
+        let mut carriles = self.0.carriles();
+        loop {
+            if let Some(valor) = carriles.tomar(self.0.envejecimiento) {
+                self.0.con_lugar.notify_one();
+                return Ok(valor);
+            }
+            if carriles.emisores == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let restante = limite.saturating_duration_since(Instant::now());
+            if restante == Duration::from_secs(0) {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            carriles = self.0.con_trabajo.wait_timeout(carriles, restante).expect("cola de prioridad poisoned").0;
+        }
+    }
+}
+
+impl<T> Clone for ReceptorPrioridad<T> {
+    fn clone(&self) -> Self {
+        self.0.carriles().receptores += 1;
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Drop for ReceptorPrioridad<T> {
+    fn drop(&mut self) {
+        self.0.carriles().receptores -= 1;
+        self.0.con_lugar.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::transaccion::TipoTransaccion;
+
+    fn crear_transaccion(id: u32, monto: f32, prioridad: Option<Prioridad>) -> Transaccion {
+        Transaccion { prioridad, ..Transaccion::de_prueba(id, Uuid::new_v4(), TipoTransaccion::CashIn, monto) }
+    }
+
+    #[test]
+    fn clasifica_por_columna_monto_y_segmento() {
+        let mut configuracion = ConfiguracionPrioridad { monto_alta: Some(1000.0), ..Default::default() };
+        assert_eq!(configuracion.clasificar(&crear_transaccion(1, 1000.0, None)), Prioridad::Alta);
+        assert_eq!(configuracion.clasificar(&crear_transaccion(2, 999.0, None)), Prioridad::Normal);
+        assert_eq!(configuracion.clasificar(&crear_transaccion(3, 5000.0, Some(Prioridad::Normal))), Prioridad::Normal);
+
+        let transaccion = crear_transaccion(4, 10.0, None);
+        configuracion.clientes.push(transaccion.id_cliente);
+        assert_eq!(configuracion.clasificar(&transaccion), Prioridad::Alta);
+    }
+
+    #[test]
+    fn atiende_primero_la_alta_salvo_que_la_normal_haya_envejecido() {
+        let (emisor, receptor) = canal_prioridad(None, Duration::from_millis(50));
+        for id in 1..=3 {
+            emisor.send(crear_transaccion(id, 10.0, Some(Prioridad::Normal))).unwrap();
+        }
+        emisor.send(crear_transaccion(4, 10.0, Some(Prioridad::Alta))).unwrap();
+        let ids = |cantidad| (0..cantidad).map(|_| receptor.try_recv().unwrap().id).collect::<Vec<_>>();
+        assert_eq!(ids(2), vec![4, 1]);
+
+        thread::sleep(Duration::from_millis(60));
+        emisor.send(crear_transaccion(5, 10.0, Some(Prioridad::Alta))).unwrap();
+        assert_eq!(ids(3), vec![2, 3, 5]);
+
+        drop(emisor);
+        assert_eq!(receptor.recv_timeout(Duration::from_millis(10)).unwrap_err(), RecvTimeoutError::Disconnected);
+    }
+
+    #[test]
+    fn el_emisor_espera_lugar_en_una_cola_acotada() {
+        let (emisor, receptor) = canal_prioridad(Some(1), Duration::from_secs(1));
+        emisor.send(crear_transaccion(1, 10.0, None)).unwrap();
+        let handle = thread::spawn(move || emisor.send(crear_transaccion(2, 10.0, None)).is_ok());
+        thread::sleep(Duration::from_millis(50));
+        assert!(!handle.is_finished());
+
+        assert_eq!(receptor.recv_timeout(Duration::from_secs(1)).unwrap().id, 1);
+        assert!(handle.join().unwrap());
+        assert_eq!(receptor.try_recv().unwrap().id, 2);
+    }
+}
@@ -1,26 +1,107 @@
-
 use std::{
-    sync::mpsc::Sender,
+    sync::{Arc, mpsc::Sender},
     fs::File,
     thread, thread::JoinHandle,
 };
 use csv::Reader;
-use crate::transaccion::{Transaccion, TipoTransaccion};
+use crate::{
+    contexto::ContextoPipeline,
+    estados::EstadoTransaccion,
+    metricas::cola_de_tipo,
+    prioridad::{ConfiguracionPrioridad, EmisorPrioridad},
+    transaccion::{Transaccion, TipoTransaccion, TransaccionRechazada},
+    traza::{EtapaTraza, Traza},
+};
+
+const ETAPA: &str = "procesador";
+/// Motivo con el que queda en rechazadas una transacción cuyo id ya entró
+pub const MOTIVO_ID_REPETIDO: &str = "Id de transacción repetido";
+
+/// Por donde entran las transacciones al pipeline: registra cada una,
+/// le asigna la prioridad y la manda a los workers de su tipo. Se clona
+/// para enviar desde varios hilos; el pipeline termina cuando se sueltan
+/// todas las copias.
+#[derive(Clone)]
+pub struct Enrutador {
+    cashin: EmisorPrioridad<Transaccion>,
+    cashout: EmisorPrioridad<Transaccion>,
+    contexto: ContextoPipeline,
+    prioridades: Arc<ConfiguracionPrioridad>,
+    rechazadas: Option<Sender<TransaccionRechazada>>,
+}
+
+impl Enrutador {
+    pub(crate) fn new(cashin: EmisorPrioridad<Transaccion>, cashout: EmisorPrioridad<Transaccion>, contexto: ContextoPipeline) -> Self {
+        Self { cashin, cashout, contexto, prioridades: Arc::new(ConfiguracionPrioridad::default()), rechazadas: None }
+    }
+
+    pub(crate) fn con_prioridades(self, prioridades: ConfiguracionPrioridad) -> Self {
+        Self { prioridades: Arc::new(prioridades), ..self }
+    }
+
+    /// Las transacciones con un id que ya entró van a rechazadas
+    pub(crate) fn con_rechazadas(self, rechazadas: Sender<TransaccionRechazada>) -> Self {
+        Self { rechazadas: Some(rechazadas), ..self }
+    }
+
+    /// Devuelve false si ya entró una transacción con ese id: no se
+    /// vuelve a procesar y queda en rechazadas. Las colas de los workers
+    /// son acotadas, así que espera mientras estén llenas.
+    pub fn enrutar(&self, mut transaccion: Transaccion) -> Result<bool, String> {
+        transaccion.traza.iniciar();
+        let trazar = self.contexto.trazas.is_some();
+        if trazar {
+            transaccion.traza.entrar(EtapaTraza::Procesador);
+        }
+        transaccion.prioridad = Some(self.prioridades.clasificar(&transaccion));
+        self.contexto.metricas.entrada(ETAPA, ETAPA);
+        if self.contexto.estados.consultar(transaccion.id).is_some()
+            || !self.contexto.estados.registrar(transaccion.id, EstadoTransaccion::Pendiente, None) {
+            self.contexto.metricas.salida(ETAPA, ETAPA, "repetida");
+            if let Some(rechazadas) = &self.rechazadas {
+                // La traza y el estado son los de la que entró primero
+                transaccion.traza = Traza::default();
+                let id_transaccion = transaccion.id;
+                rechazadas
+                    .send(TransaccionRechazada::new(transaccion, MOTIVO_ID_REPETIDO))
+                    .map_err(|_| format!("El pipeline ya terminó: la transacción repetida {} no quedó registrada", id_transaccion))?;
+            }
+            return Ok(false);
+        }
+        let channel = match transaccion.tipo {
+            TipoTransaccion::CashIn => &self.cashin,
+            TipoTransaccion::CashOut => &self.cashout
+        };
+
+        self.contexto.estados.registrar(transaccion.id, EstadoTransaccion::Enrutada, None);
+        if trazar {
+            transaccion.traza.salir(EtapaTraza::Procesador);
+        }
+        self.contexto.metricas.salida(ETAPA, ETAPA, "enrutada");
+        self.contexto.metricas.encolar(cola_de_tipo(transaccion.tipo));
+        let id_transaccion = transaccion.id;
+        channel.send(transaccion).map_err(|_| format!("El pipeline ya terminó: la transacción {} quedó sin procesar", id_transaccion))?;
+
+        Ok(true)
+    }
+}
 
 pub struct Procesador {
     file: Reader<File>,
-    cashin: Sender<Transaccion>,
-    cashout: Sender<Transaccion>,
+    enrutador: Enrutador,
+    contexto: ContextoPipeline,
 }
 
 impl Procesador {
-   pub fn iniciar(file: &str, tx_cashin: Sender<Transaccion>, tx_cashout: Sender<Transaccion>) -> Result<JoinHandle<()>, csv::Error> {
+   pub fn iniciar(file: &str,
+                  enrutador: Enrutador,
+                  contexto: ContextoPipeline) -> Result<JoinHandle<()>, csv::Error> {
         let reader = csv::Reader::from_path(file)?;
         let handle = thread::spawn(move || {
             let mut procesador = Self {
                 file: reader,
-                cashin: tx_cashin,
-                cashout: tx_cashout
+                enrutador,
+                contexto
             };
 
             procesador.procesar();
@@ -29,26 +110,44 @@ impl Procesador {
         Ok(handle)
    }
 
+    /// Lee y enruta las transacciones del archivo. Las colas de los
+    /// workers son acotadas: si se pide el apagado, lo que queda sin leer
+    /// no llega a entrar al pipeline.
     pub fn procesar(&mut self) {
-        for registro in self.file.deserialize() {
-            let transaccion: Transaccion = registro.unwrap();
-            let channel = match transaccion.tipo {
-                TipoTransaccion::CashIn => &self.cashin,
-                TipoTransaccion::CashOut => &self.cashout
-            };
-
-            channel.send(transaccion).expect("channel cerrado");
+        let mut registros = self.file.deserialize::<Transaccion>();
+        while let Some(registro) = registros.next() {
+            if self.contexto.apagado.drenando() {
+                self.contexto.apagado.dejar_sin_leer(1 + registros.count());
+                break;
+            }
+            let transaccion = registro.unwrap();
+            self.contexto.latido.empezar(transaccion.id);
+            // Un id repetido no se vuelve a procesar
+            self.enrutador.enrutar(transaccion).expect("channel cerrado");
+            self.contexto.latido.terminar();
         }
     }
 }
 
 #[cfg(test)]
+// Los tests originales escriben los literales a su manera
+#[allow(clippy::inconsistent_digit_grouping)]
 mod tests {
-    use std::sync::mpsc::channel;
+    use std::{sync::{Arc, mpsc::channel}, time::Duration};
 
     use super::*;
     use csv::Writer;
     use uuid::Uuid;
+    use crate::{
+        apagado::Apagado,
+        estados::AlmacenEstados,
+        logger::{Logger, TaggedLogger},
+        metricas::RegistroMetricas,
+        prioridad::canal_prioridad,
+        redaccion::Redactor,
+        traza::Traza,
+        supervisor::Latido,
+    };
 
     #[test]
     fn procesador_envia_por_canal_cashin_cuando_lee_un_cashin() {
@@ -60,15 +159,17 @@ mod tests {
             id_cliente: Uuid::new_v4(),
             timestamp: 112315846_128,
             tipo: TipoTransaccion::CashIn,
-            monto: 123.33
+            monto: 123.33,
+            traza: Traza::default(),
+            prioridad: None
         };
         archivo.serialize(transaccion).unwrap();
         archivo.flush().unwrap();
 
-        let (tx_cashin, rx_cashin) = channel();
-        let (tx_cashout, _rx_cashout) = channel();
+        let (tx_cashin, rx_cashin) = canal_prioridad(Some(10), Duration::ZERO);
+        let (tx_cashout, _rx_cashout) = canal_prioridad(Some(10), Duration::ZERO);
 
-        let handle = Procesador::iniciar(ruta_archivo_tests, tx_cashin, tx_cashout).unwrap();
+        let handle = Procesador::iniciar(ruta_archivo_tests, Enrutador::new(tx_cashin, tx_cashout, crear_contexto()), crear_contexto()).unwrap();
         handle.join().unwrap();
         assert_eq!(rx_cashin.recv().unwrap().id, id_transaccion);
     }
@@ -83,15 +184,78 @@ mod tests {
             id_cliente: Uuid::new_v4(),
             timestamp: 112315846_128,
             tipo: TipoTransaccion::CashOut,
-            monto: 123.33
+            monto: 123.33,
+            traza: Traza::default(),
+            prioridad: None
         }).unwrap();
         archivo.flush().unwrap();
 
-        let (tx_cashin, _rx_cashin) = channel();
-        let (tx_cashout, rx_cashout) = channel();
+        let (tx_cashin, _rx_cashin) = canal_prioridad(Some(10), Duration::ZERO);
+        let (tx_cashout, rx_cashout) = canal_prioridad(Some(10), Duration::ZERO);
 
-        let handle = Procesador::iniciar(ruta_archivo_tests, tx_cashin, tx_cashout).unwrap();
+        let handle = Procesador::iniciar(ruta_archivo_tests, Enrutador::new(tx_cashin, tx_cashout, crear_contexto()), crear_contexto()).unwrap();
         handle.join().unwrap();
         assert_eq!(rx_cashout.recv().unwrap().id, id_transaccion);
     }
+
+    #[test]
+    fn procesador_no_reenvia_un_id_repetido() {
+        let ruta_archivo_tests = "archivo_tests_7.csv";
+        let mut archivo = Writer::from_path(ruta_archivo_tests).unwrap();
+        for monto in [10.0, 20.0] {
+            archivo.serialize(Transaccion::de_prueba(4, Uuid::new_v4(), TipoTransaccion::CashIn, monto)).unwrap();
+        }
+        archivo.flush().unwrap();
+
+        let (tx_cashin, rx_cashin) = canal_prioridad(Some(10), Duration::ZERO);
+        let (tx_cashout, _rx_cashout) = canal_prioridad(Some(10), Duration::ZERO);
+        let contexto = crear_contexto();
+
+        let (tx_rechazadas, rx_rechazadas) = channel();
+        let enrutador = Enrutador::new(tx_cashin, tx_cashout, contexto.clone()).con_rechazadas(tx_rechazadas);
+
+        let handle = Procesador::iniciar(ruta_archivo_tests, enrutador, contexto.clone()).unwrap();
+        handle.join().unwrap();
+        assert_eq!(rx_cashin.iter().map(|t| t.monto).collect::<Vec<_>>(), vec![10.0]);
+        assert_eq!(contexto.estados.consultar(4).unwrap().estado, EstadoTransaccion::Enrutada);
+        // La repetida queda en rechazadas para poder conciliarla
+        let repetida = rx_rechazadas.recv().unwrap();
+        assert_eq!((repetida.transaccion.monto, repetida.motivo.as_str()), (20.0, MOTIVO_ID_REPETIDO));
+        assert!(contexto.metricas.exportar_prometheus().contains("resultado=\"repetida\"} 1"));
+    }
+
+    #[test]
+    fn procesador_deja_de_leer_cuando_se_pide_el_apagado() {
+        let ruta_archivo_tests = "archivo_tests_19.csv";
+        let mut archivo = Writer::from_path(ruta_archivo_tests).unwrap();
+        for id in 1..=5 {
+            archivo.serialize(Transaccion::de_prueba(id, Uuid::new_v4(), TipoTransaccion::CashIn, 10.0)).unwrap();
+        }
+        archivo.flush().unwrap();
+
+        let (tx_cashin, rx_cashin) = canal_prioridad(Some(1), Duration::ZERO);
+        let (tx_cashout, _rx_cashout) = canal_prioridad(Some(1), Duration::ZERO);
+        let contexto = crear_contexto();
+
+        let handle = Procesador::iniciar(ruta_archivo_tests, Enrutador::new(tx_cashin, tx_cashout, contexto.clone()), contexto.clone()).unwrap();
+        assert_eq!(rx_cashin.recv().unwrap().id, 1);
+        contexto.apagado.pedir();
+        // Lo que ya estaba leído se entrega; el resto queda en el archivo
+        let enrutadas = 1 + rx_cashin.iter().count();
+        handle.join().unwrap();
+        assert!(enrutadas < 5);
+        assert_eq!(enrutadas + contexto.apagado.sin_leer(), 5);
+    }
+
+    fn crear_contexto() -> ContextoPipeline {
+        ContextoPipeline {
+            clientes: Arc::new(vec![]),
+            estados: Arc::new(AlmacenEstados::new(TaggedLogger::new("ESTADOS", Arc::new(Logger::new_to_stdout())))),
+            metricas: Arc::new(RegistroMetricas::default()),
+            trazas: None,
+            redactor: Arc::new(Redactor::default()),
+            apagado: Arc::new(Apagado::default()),
+            latido: Arc::new(Latido::default()),
+        }
+    }
 }
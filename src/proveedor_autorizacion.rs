@@ -0,0 +1,220 @@
+use std::{sync::{Arc, Mutex}, time::Duration};
+use uuid::Uuid;
+
+use crate::{
+    limitador::ProveedorLimitado,
+    proveedor_externo::RespuestaAutorizacion,
+    reintentos::{Disyuntor, PoliticaReintentos},
+    transaccion::{TipoTransaccion, Transaccion},
+};
+
+const ESPERA_LOTE_DEFAULT: u64 = 20; // 20 millis
+
+/// El proveedor ya no atiende solicitudes y no lo va a volver a hacer
+#[derive(Debug, PartialEq)]
+pub struct ProveedorCerrado;
+
+/// Servicio que autoriza transacciones
+pub trait ProveedorAutorizacion: Send + Sync {
+    /// Pide autorización para la transacción esperando la respuesta a
+    /// lo sumo `timeout`.
+    fn solicitar(&self, transaccion: &Transaccion, timeout: Duration) -> Result<RespuestaAutorizacion, ProveedorCerrado>;
+
+    /// Pide autorización para varias transacciones en una sola llamada.
+    /// Tiene que devolver exactamente una respuesta por transacción, en el
+    /// mismo orden: la falla de una no afecta a las demás. Si faltan
+    /// respuestas, el worker manda las transacciones sin respuesta a
+    /// fallidas.
+    fn solicitar_lote(&self, transacciones: &[&Transaccion], timeout: Duration) -> Result<Vec<RespuestaAutorizacion>, ProveedorCerrado> {
+        transacciones
+            .iter()
+            .map(|transaccion| self.solicitar(transaccion, timeout))
+            .collect()
+    }
+}
+
+/// Cuántas transacciones junta un worker antes de pedir la
+/// autorización del lote, y cuánto espera como máximo para juntarlas
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConfiguracionLote {
+    pub tamano: usize,
+    pub espera: Duration,
+}
+
+impl ConfiguracionLote {
+    /// Interpreta un lote de la forma <tamaño>[:<espera en milisegundos>]
+    pub fn parsear(texto: &str) -> Result<Self, String> {
+        let error = || format!("Lote inválido '{}': se espera <tamaño>[:<espera en milisegundos>]", texto);
+        let (tamano, espera) = match texto.split_once(':') {
+            Some((tamano, espera)) => (tamano, espera.trim().parse::<u64>().map_err(|_| error())?),
+            None => (texto, ESPERA_LOTE_DEFAULT),
+        };
+        let tamano = tamano.trim().parse::<usize>().ok().filter(|t| *t > 0).ok_or_else(error)?;
+
+        Ok(Self {
+            tamano,
+            espera: Duration::from_millis(espera),
+        })
+    }
+}
+
+/// Extremo que usan los workers para pedir autorizaciones, junto con
+/// la política de reintentos y el disyuntor que comparten. Con `lote`
+/// los workers piden las autorizaciones de a lotes y con `limitador`
+/// esperan su turno en las cuotas antes de cada llamada.
+#[derive(Clone)]
+pub struct ConexionProveedor {
+    proveedor: Arc<dyn ProveedorAutorizacion>,
+    pub politica: PoliticaReintentos,
+    pub disyuntor: Arc<Disyuntor>,
+    pub lote: Option<ConfiguracionLote>,
+    limitador: Option<Arc<ProveedorLimitado>>,
+}
+
+impl ConexionProveedor {
+    pub fn new(proveedor: Arc<dyn ProveedorAutorizacion>,
+               politica: PoliticaReintentos,
+               disyuntor: Arc<Disyuntor>) -> Self {
+        Self {
+            proveedor,
+            politica,
+            disyuntor,
+            lote: None,
+            limitador: None,
+        }
+    }
+
+    pub fn con_lotes(self, lote: ConfiguracionLote) -> Self {
+        Self {
+            lote: Some(lote),
+            ..self
+        }
+    }
+
+    /// El proveedor se sigue llamando directamente: la espera por la
+    /// cuota queda afuera de la duración de la llamada
+    pub fn con_limitador(self, limitador: Arc<ProveedorLimitado>) -> Self {
+        Self {
+            limitador: Some(limitador),
+            ..self
+        }
+    }
+
+    /// Espera el turno de una llamada con transacciones de esos tipos y
+    /// devuelve cuánto esperó
+    pub fn esperar_turno(&self, tipos: &[TipoTransaccion]) -> Duration {
+        self.limitador.as_ref().map(|limitador| limitador.esperar_turno(tipos)).unwrap_or_default()
+    }
+
+    /// Pide autorización esperando a lo sumo el timeout de la política
+    pub fn solicitar(&self, transaccion: &Transaccion) -> Result<RespuestaAutorizacion, ProveedorCerrado> {
+        self.proveedor.solicitar(transaccion, self.politica.timeout)
+    }
+
+    pub fn solicitar_lote(&self, transacciones: &[&Transaccion]) -> Result<Vec<RespuestaAutorizacion>, ProveedorCerrado> {
+        self.proveedor.solicitar_lote(transacciones, self.politica.timeout)
+    }
+}
+
+/// Proveedor determinístico para tests, también los de quien usa la
+/// biblioteca. Contesta en orden las respuestas configuradas, volviendo
+/// a empezar cuando se terminan. Sin respuestas configuradas autoriza
+/// todo con un hash derivado del id de la transacción.
+#[derive(Default)]
+pub struct ProveedorMock {
+    respuestas: Vec<RespuestaAutorizacion>,
+    solicitudes: Mutex<Vec<u32>>,
+    lotes: Mutex<Vec<usize>>,
+}
+
+impl ProveedorMock {
+    pub fn con_respuestas(respuestas: Vec<RespuestaAutorizacion>) -> Self {
+        Self {
+            respuestas,
+            ..Self::default()
+        }
+    }
+
+    pub fn hash_para(id_transaccion: u32) -> Uuid {
+        Uuid::from_u128(id_transaccion as u128)
+    }
+
+    /// Ids de las transacciones que se pidieron, en orden
+    pub fn solicitudes(&self) -> Vec<u32> {
+        self.solicitudes.lock().expect("mock poisoned").clone()
+    }
+
+    /// Tamaño de cada lote que se pidió, en orden
+    pub fn lotes(&self) -> Vec<usize> {
+        self.lotes.lock().expect("mock poisoned").clone()
+    }
+}
+
+impl ProveedorAutorizacion for ProveedorMock {
+    fn solicitar(&self, transaccion: &Transaccion, _timeout: Duration) -> Result<RespuestaAutorizacion, ProveedorCerrado> {
+        let mut solicitudes = self.solicitudes.lock().expect("mock poisoned");
+        let respuesta = if self.respuestas.is_empty() {
+            RespuestaAutorizacion::Autorizada(Self::hash_para(transaccion.id))
+        } else {
+            self.respuestas[solicitudes.len() % self.respuestas.len()].clone()
+        };
+        solicitudes.push(transaccion.id);
+
+        Ok(respuesta)
+    }
+
+    fn solicitar_lote(&self, transacciones: &[&Transaccion], timeout: Duration) -> Result<Vec<RespuestaAutorizacion>, ProveedorCerrado> {
+        self.lotes.lock().expect("mock poisoned").push(transacciones.len());
+        transacciones
+            .iter()
+            .map(|transaccion| self.solicitar(transaccion, timeout))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{proveedor_externo::ErrorProveedor, transaccion::TipoTransaccion};
+
+    #[test]
+    fn conexion_reintenta_sobre_el_mismo_proveedor() {
+        let mock = Arc::new(ProveedorMock::con_respuestas(vec![
+            RespuestaAutorizacion::Error(ErrorProveedor::Limitado),
+            RespuestaAutorizacion::Denegada("Sin fondos".into()),
+        ]));
+        let conexion = ConexionProveedor::new(mock.clone(), PoliticaReintentos::default(), Arc::new(Disyuntor::default()));
+        let transaccion = crear_transaccion(5);
+
+        assert_eq!(conexion.solicitar(&transaccion), Ok(RespuestaAutorizacion::Error(ErrorProveedor::Limitado)));
+        assert_eq!(conexion.solicitar(&transaccion), Ok(RespuestaAutorizacion::Denegada("Sin fondos".into())));
+        assert_eq!(conexion.solicitar(&transaccion), Ok(RespuestaAutorizacion::Error(ErrorProveedor::Limitado)));
+        assert_eq!(mock.solicitudes(), vec![5, 5, 5]);
+    }
+
+    #[test]
+    fn mock_sin_respuestas_autoriza_con_hash_derivado_del_id() {
+        let mock = ProveedorMock::default();
+        let respuesta = mock.solicitar(&crear_transaccion(8), Duration::from_secs(1));
+
+        assert_eq!(respuesta, Ok(RespuestaAutorizacion::Autorizada(ProveedorMock::hash_para(8))));
+    }
+
+    #[test]
+    fn parsear_lote_con_y_sin_espera() {
+        assert_eq!(
+            ConfiguracionLote::parsear("50:5"),
+            Ok(ConfiguracionLote { tamano: 50, espera: Duration::from_millis(5) })
+        );
+        assert_eq!(
+            ConfiguracionLote::parsear("10"),
+            Ok(ConfiguracionLote { tamano: 10, espera: Duration::from_millis(ESPERA_LOTE_DEFAULT) })
+        );
+        assert!(ConfiguracionLote::parsear("0").is_err());
+        assert!(ConfiguracionLote::parsear("10:x").is_err());
+    }
+
+    fn crear_transaccion(id: u32) -> Transaccion {
+        Transaccion::de_prueba(id, Uuid::new_v4(), TipoTransaccion::CashOut, 10.0)
+    }
+}
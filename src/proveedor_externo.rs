@@ -1,32 +1,364 @@
-use std::{sync::mpsc, thread};
-use uuid::Uuid;
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    fmt,
+    sync::{
+        mpsc::{self, Sender, Receiver, RecvTimeoutError},
+        Arc, RwLock,
+    },
+    thread,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+use rand::{Rng, SeedableRng, prelude::StdRng};
+use uuid::{Builder, Uuid, Variant, Version};
 
-use crate::transaccion::HashAutorizacion;
+use crate::{
+    logger::{NivelLog, TaggedLogger},
+    perfil_fallas::PerfilFallas,
+    proveedor_autorizacion::{ProveedorAutorizacion, ProveedorCerrado},
+    transaccion::{HashAutorizacion, TipoTransaccion, Transaccion},
+};
+
+const PROBABILIDAD_DE_DENEGADA: f64 = 0.05; // 5%
+
+/// Fallas con las que puede contestar el proveedor
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorProveedor {
+    Interno,
+    NoDisponible,
+    Limitado,
+    SinRespuesta,
+}
+
+impl fmt::Display for ErrorProveedor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorProveedor::Interno => write!(f, "error interno del proveedor"),
+            ErrorProveedor::NoDisponible => write!(f, "proveedor no disponible"),
+            ErrorProveedor::Limitado => write!(f, "demasiadas solicitudes"),
+            ErrorProveedor::SinRespuesta => write!(f, "el proveedor no respondió"),
+        }
+    }
+}
+
+/// Respuesta del proveedor externo a una solicitud de autorización
+#[derive(Debug, Clone, PartialEq)]
+pub enum RespuestaAutorizacion {
+    Autorizada(HashAutorizacion),
+    Denegada(String),
+    Error(ErrorProveedor),
+}
+
+/// Pedido de autorización de una transacción. El proveedor contesta
+/// por el canal `respuesta`.
+pub struct SolicitudAutorizacion {
+    pub id_transaccion: u32,
+    pub id_cliente: Uuid,
+    pub tipo: TipoTransaccion,
+    pub monto: f32,
+    pub respuesta: Sender<RespuestaAutorizacion>,
+}
+
+/// Cada llamada al proveedor lleva una o más solicitudes (un lote).
+/// Las solicitudes de un lote comparten la demora de la llamada pero
+/// se deciden y contestan por separado.
+pub type LlamadaProveedor = Vec<SolicitudAutorizacion>;
+
+impl SolicitudAutorizacion {
+    pub fn new(transaccion: &Transaccion, respuesta: Sender<RespuestaAutorizacion>) -> Self {
+        Self {
+            id_transaccion: transaccion.id,
+            id_cliente: transaccion.id_cliente,
+            tipo: transaccion.tipo,
+            monto: transaccion.monto,
+            respuesta,
+        }
+    }
+}
+
+/// Proveedor externo simulado que corre en un hilo de este proceso
+pub struct ProveedorEnProceso {
+    llamadas: Sender<LlamadaProveedor>,
+}
+
+impl ProveedorEnProceso {
+    pub fn new(llamadas: Sender<LlamadaProveedor>) -> Self {
+        Self { llamadas }
+    }
+}
+
+impl ProveedorAutorizacion for ProveedorEnProceso {
+    fn solicitar(&self, transaccion: &Transaccion, timeout: Duration) -> Result<RespuestaAutorizacion, ProveedorCerrado> {
+        let mut respuestas = self.solicitar_lote(&[transaccion], timeout)?;
+        Ok(respuestas.remove(0))
+    }
+
+    fn solicitar_lote(&self, transacciones: &[&Transaccion], timeout: Duration) -> Result<Vec<RespuestaAutorizacion>, ProveedorCerrado> {
+        let (solicitudes, receptores): (Vec<_>, Vec<_>) = transacciones
+            .iter()
+            .map(|transaccion| {
+                let (tx_respuesta, rx_respuesta) = mpsc::channel();
+                (SolicitudAutorizacion::new(transaccion, tx_respuesta), rx_respuesta)
+            })
+            .unzip();
+        self.llamadas.send(solicitudes).map_err(|_| ProveedorCerrado)?;
+
+        // El proveedor puede tardar demasiado o descartar alguna solicitud
+        let limite = Instant::now() + timeout;
+        Ok(receptores
+            .into_iter()
+            .map(|rx_respuesta| rx_respuesta
+                .recv_timeout(limite.saturating_duration_since(Instant::now()))
+                .unwrap_or(RespuestaAutorizacion::Error(ErrorProveedor::SinRespuesta)))
+            .collect())
+    }
+}
+
+/// Permite cambiar el perfil de fallas mientras el proveedor corre
+#[derive(Clone)]
+pub struct ControlProveedor {
+    perfil: Arc<RwLock<PerfilFallas>>,
+}
+
+impl ControlProveedor {
+    pub fn cambiar_perfil(&self, perfil: PerfilFallas) {
+        *self.perfil.write().expect("perfil poisoned") = perfil;
+    }
+}
+
+/// Respuesta que el proveedor todavía no entregó. Si `respuesta` es
+/// None la solicitud se descarta sin contestar (timeout).
+struct RespuestaDiferida {
+    instante: Instant,
+    secuencia: u64,
+    respuesta: Option<RespuestaAutorizacion>,
+    canal: Sender<RespuestaAutorizacion>,
+}
+
+impl PartialEq for RespuestaDiferida {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for RespuestaDiferida {}
+
+impl PartialOrd for RespuestaDiferida {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for RespuestaDiferida {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.instante, self.secuencia).cmp(&(other.instante, other.secuencia))
+    }
+}
 
 pub struct ProveedorExterno {
-    clientes: mpsc::Sender<HashAutorizacion>,
+    log: TaggedLogger,
+    llamadas: Receiver<LlamadaProveedor>,
+    rng: StdRng,
+    perfil: Arc<RwLock<PerfilFallas>>,
+    pendientes: BinaryHeap<Reverse<RespuestaDiferida>>,
+    secuencia: u64,
+    inicio: Instant,
 }
 
 impl ProveedorExterno {
-    pub fn iniciar() -> (mpsc::Receiver<HashAutorizacion>, thread::JoinHandle<()>) {
+    pub fn iniciar(log: TaggedLogger, semilla: u64, perfil: PerfilFallas)
+        -> (Sender<LlamadaProveedor>, ControlProveedor, JoinHandle<()>)
+    {
         let (tx, rx) = mpsc::channel();
+        let perfil = Arc::new(RwLock::new(perfil));
+        let control = ControlProveedor { perfil: perfil.clone() };
         let handle = thread::spawn(move || {
-            let proveedor = Self { clientes: tx };
+            let mut proveedor = Self {
+                log,
+                llamadas: rx,
+                rng: StdRng::seed_from_u64(semilla),
+                perfil,
+                pendientes: BinaryHeap::new(),
+                secuencia: 0,
+                inicio: Instant::now(),
+            };
 
-            proveedor.crear_hashes();
+            proveedor.atender_solicitudes();
         });
 
-        (rx, handle)
+        (tx, control, handle)
     }
 
-    pub fn crear_hashes(&self) {
+    pub fn atender_solicitudes(&mut self) {
+        self.log.write("Proveedor iniciado");
         loop {
-            let hash: HashAutorizacion = Uuid::new_v4();
+            self.entregar_vencidas();
+
+            // Esperar una nueva solicitud sin demorar las respuestas pendientes
+            let recibida = match self.pendientes.peek() {
+                Some(Reverse(proxima)) => {
+                    let espera = proxima.instante.saturating_duration_since(Instant::now());
+                    match self.llamadas.recv_timeout(espera) {
+                        Ok(solicitud) => Some(solicitud),
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => None,
+                    }
+                },
+                None => self.llamadas.recv().ok(),
+            };
+
+            match recibida {
+                Some(llamada) => self.atender(llamada),
+                // Nadie más pide autorizaciones
+                None => break,
+            }
+        }
+
+        while let Some(Reverse(proxima)) = self.pendientes.peek() {
+            thread::sleep(proxima.instante.saturating_duration_since(Instant::now()));
+            self.entregar_vencidas();
+        }
+        self.log.write("Proveedor terminado");
+    }
+
+    fn atender(&mut self, llamada: LlamadaProveedor) {
+        let perfil = self.perfil.read().expect("perfil poisoned").clone();
+        // Todo el lote viaja en una única llamada, con una única demora
+        let latencia = perfil.muestrear_latencia(&mut self.rng);
+        for solicitud in llamada {
+            let respuesta = self.evaluar(&solicitud, &perfil);
+            let demora = match respuesta {
+                Some(_) => latencia,
+                None => perfil.duracion_timeout,
+            };
+            self.log.evento(
+                NivelLog::Debug,
+                &format!(
+                    "Solicitud de {} (id = {}, monto = {}): {:?} en {:?}",
+                    solicitud.tipo, solicitud.id_transaccion, solicitud.monto, respuesta, demora
+                ),
+                &[("transaction_id", &solicitud.id_transaccion), ("client_id", &solicitud.id_cliente)]
+            );
+
+            self.secuencia += 1;
+            self.pendientes.push(Reverse(RespuestaDiferida {
+                instante: Instant::now() + demora,
+                secuencia: self.secuencia,
+                respuesta,
+                canal: solicitud.respuesta,
+            }));
+        }
+    }
 
-            if self.clientes.send(hash).is_err() {
-                // Nadie más quiere hashes
-                break;
+    fn entregar_vencidas(&mut self) {
+        let ahora = Instant::now();
+        while self.pendientes.peek().is_some_and(|Reverse(proxima)| proxima.instante <= ahora) {
+            let Reverse(vencida) = self.pendientes.pop().expect("heap vacío");
+            if let Some(respuesta) = vencida.respuesta {
+                // Si el worker ya no espera la respuesta no hay nada que hacer
+                let _ = vencida.canal.send(respuesta);
             }
         }
     }
+
+    /// Decide la respuesta a la solicitud según el perfil de fallas.
+    /// None significa que la solicitud no se va a contestar.
+    fn evaluar(&mut self, solicitud: &SolicitudAutorizacion, perfil: &PerfilFallas) -> Option<RespuestaAutorizacion> {
+        if perfil.en_caida(self.inicio.elapsed()) {
+            return Some(RespuestaAutorizacion::Error(ErrorProveedor::NoDisponible));
+        }
+
+        let falla: f64 = self.rng.gen();
+        if falla < perfil.tasa_limitacion {
+            return Some(RespuestaAutorizacion::Error(ErrorProveedor::Limitado));
+        }
+        if falla < perfil.tasa_limitacion + perfil.tasa_timeout {
+            return None;
+        }
+        if falla < perfil.tasa_limitacion + perfil.tasa_timeout + perfil.tasa_error {
+            return Some(RespuestaAutorizacion::Error(ErrorProveedor::Interno));
+        }
+
+        if solicitud.monto <= 0.0 {
+            return Some(RespuestaAutorizacion::Denegada(format!("Monto inválido: {}", solicitud.monto)));
+        }
+
+        let denegar: f64 = self.rng.gen();
+        if denegar < PROBABILIDAD_DE_DENEGADA {
+            return Some(RespuestaAutorizacion::Denegada("Operación no permitida por el proveedor".into()));
+        }
+
+        Some(RespuestaAutorizacion::Autorizada(self.generar_hash()))
+    }
+
+    /// Genera un hash v4 a partir del rng para que la corrida sea reproducible
+    fn generar_hash(&mut self) -> HashAutorizacion {
+        Builder::from_bytes(self.rng.gen())
+            .set_variant(Variant::RFC4122)
+            .set_version(Version::Random)
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{logger::Logger, perfil_fallas::parsear_caidas};
+
+    #[test]
+    fn proveedor_deniega_transacciones_con_monto_invalido() {
+        let respuestas = solicitar(PerfilFallas::default(), 264, -10.0, 1);
+
+        match &respuestas[0] {
+            Some(RespuestaAutorizacion::Denegada(_)) => {},
+            otra => panic!("Se esperaba una denegación, se obtuvo {:?}", otra),
+        }
+    }
+
+    #[test]
+    fn proveedor_caido_no_autoriza() {
+        let perfil = PerfilFallas::default().con_caidas(parsear_caidas("0-60").unwrap());
+        let respuestas = solicitar(perfil, 264, 10.0, 3);
+
+        assert!(respuestas.iter().all(|r| *r == Some(RespuestaAutorizacion::Error(ErrorProveedor::NoDisponible))));
+    }
+
+    #[test]
+    fn proveedor_con_misma_semilla_responde_lo_mismo() {
+        let perfil = PerfilFallas {
+            tasa_error: 0.3,
+            tasa_timeout: 0.2,
+            duracion_timeout: Duration::from_millis(1),
+            ..PerfilFallas::default()
+        };
+        let respuestas = solicitar(perfil.clone(), 4_242, 10.0, 20);
+
+        assert_eq!(respuestas, solicitar(perfil, 4_242, 10.0, 20));
+        assert!(respuestas.contains(&None));
+        assert!(respuestas.contains(&Some(RespuestaAutorizacion::Error(ErrorProveedor::Interno))));
+    }
+
+    /// Envía n solicitudes en orden y devuelve lo que contestó el proveedor
+    /// (None si no contestó)
+    fn solicitar(perfil: PerfilFallas, semilla: u64, monto: f32, n: u32) -> Vec<Option<RespuestaAutorizacion>> {
+        let (tx_solicitudes, _control, handle) = ProveedorExterno::iniciar(
+            TaggedLogger::new("PROVEEDOR", Arc::new(Logger::new_to_stdout())),
+            semilla,
+            perfil
+        );
+        let mut respuestas = vec![];
+        for id in 0..n {
+            let (tx_respuesta, rx_respuesta) = mpsc::channel();
+            let transaccion = Transaccion::de_prueba(id, Uuid::nil(), TipoTransaccion::CashOut, monto);
+            tx_solicitudes.send(vec![SolicitudAutorizacion::new(&transaccion, tx_respuesta)]).unwrap();
+            respuestas.push(rx_respuesta.recv().ok());
+        }
+        drop(tx_solicitudes);
+        handle.join().unwrap();
+
+        respuestas
+    }
 }
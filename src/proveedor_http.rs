@@ -0,0 +1,183 @@
+use std::{
+    io::{self, prelude::*},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use crate::{
+    api_proveedor_http::{PedidoAutorizacion, ResultadoAutorizacion, RUTA_AUTORIZACIONES, SUFIJO_LOTE},
+    proveedor_autorizacion::{ProveedorAutorizacion, ProveedorCerrado},
+    proveedor_externo::{ErrorProveedor, RespuestaAutorizacion},
+    transaccion::{TipoTransaccion, Transaccion},
+};
+
+/// Proveedor fuera de proceso al que se le piden las autorizaciones
+/// por HTTP (ver `api_proveedor_http`)
+pub struct ProveedorHttp {
+    host: String,
+    ruta: String,
+}
+
+impl ProveedorHttp {
+    /// Recibe una url de la forma http://host:puerto/ruta. Sin ruta se
+    /// usa la de la API.
+    pub fn new(url: &str) -> Result<Self, String> {
+        let sin_esquema = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("Url de proveedor inválida '{}': solo se soporta http://", url))?;
+        let (host, ruta) = match sin_esquema.find('/') {
+            Some(i) => (&sin_esquema[..i], &sin_esquema[i..]),
+            None => (sin_esquema, RUTA_AUTORIZACIONES),
+        };
+        if host.to_socket_addrs().is_err() {
+            return Err(format!("Url de proveedor inválida '{}': host o puerto incorrecto", url));
+        }
+
+        Ok(Self {
+            host: host.into(),
+            ruta: ruta.into(),
+        })
+    }
+
+    /// Hace el POST y devuelve el código de estado y el cuerpo de la respuesta
+    fn enviar(&self, ruta: &str, cuerpo: &str, timeout: Duration) -> io::Result<(u16, String)> {
+        let direccion = self.host
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host sin direcciones"))?;
+        let mut stream = TcpStream::connect_timeout(&direccion, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        let pedido = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            ruta, self.host, cuerpo.len(), cuerpo
+        );
+        stream.write_all(pedido.as_bytes())?;
+
+        let mut respuesta = String::new();
+        stream.read_to_string(&mut respuesta)?;
+        parsear_respuesta(&respuesta)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "respuesta HTTP mal formada"))
+    }
+}
+
+fn parsear_respuesta(respuesta: &str) -> Option<(u16, String)> {
+    let (encabezado, cuerpo) = respuesta.split_once("\r\n\r\n")?;
+    let estado = encabezado.split_whitespace().nth(1)?.parse().ok()?;
+
+    Some((estado, cuerpo.into()))
+}
+
+fn pedido_para(transaccion: &Transaccion) -> PedidoAutorizacion {
+    PedidoAutorizacion {
+        id_transaccion: transaccion.id,
+        id_cliente: transaccion.id_cliente,
+        tipo: match transaccion.tipo {
+            TipoTransaccion::CashIn => "cash_in".into(),
+            TipoTransaccion::CashOut => "cash_out".into(),
+        },
+        monto: transaccion.monto,
+    }
+}
+
+fn interpretar(resultado: ResultadoAutorizacion) -> RespuestaAutorizacion {
+    match resultado {
+        ResultadoAutorizacion::Autorizada { hash } => RespuestaAutorizacion::Autorizada(hash),
+        ResultadoAutorizacion::Denegada { motivo } => RespuestaAutorizacion::Denegada(motivo),
+    }
+}
+
+/// Error del proveedor para una llamada que no terminó en 200
+fn error_de_llamada(resultado: io::Result<(u16, String)>) -> ErrorProveedor {
+    match resultado {
+        Ok((429, _)) => ErrorProveedor::Limitado,
+        Ok((503, _)) => ErrorProveedor::NoDisponible,
+        Ok(_) => ErrorProveedor::Interno,
+        Err(e) if e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock =>
+            ErrorProveedor::SinRespuesta,
+        // No se pudo conectar
+        Err(_) => ErrorProveedor::NoDisponible,
+    }
+}
+
+impl ProveedorAutorizacion for ProveedorHttp {
+    fn solicitar(&self, transaccion: &Transaccion, timeout: Duration) -> Result<RespuestaAutorizacion, ProveedorCerrado> {
+        let cuerpo = serde_json::to_string(&pedido_para(transaccion)).expect("pedido no serializable");
+
+        let respuesta = match self.enviar(&self.ruta, &cuerpo, timeout) {
+            Ok((200, cuerpo)) => match serde_json::from_str(&cuerpo) {
+                Ok(resultado) => interpretar(resultado),
+                Err(_) => RespuestaAutorizacion::Error(ErrorProveedor::Interno),
+            },
+            otro => RespuestaAutorizacion::Error(error_de_llamada(otro)),
+        };
+
+        Ok(respuesta)
+    }
+
+    fn solicitar_lote(&self, transacciones: &[&Transaccion], timeout: Duration) -> Result<Vec<RespuestaAutorizacion>, ProveedorCerrado> {
+        let pedidos: Vec<_> = transacciones.iter().map(|transaccion| pedido_para(transaccion)).collect();
+        let cuerpo = serde_json::to_string(&pedidos).expect("pedidos no serializables");
+
+        let error = match self.enviar(&format!("{}{}", self.ruta, SUFIJO_LOTE), &cuerpo, timeout) {
+            Ok((200, cuerpo)) => match serde_json::from_str::<Vec<ResultadoAutorizacion>>(&cuerpo) {
+                Ok(resultados) if resultados.len() == transacciones.len() =>
+                    return Ok(resultados.into_iter().map(interpretar).collect()),
+                _ => ErrorProveedor::Interno,
+            },
+            otro => error_de_llamada(otro),
+        };
+
+        // Sin resultados individuales la falla alcanza a todo el lote
+        Ok(vec![RespuestaAutorizacion::Error(error); transacciones.len()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn proveedor_http_interpreta_la_respuesta_del_servidor() {
+        let hash = Uuid::new_v4();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/autorizaciones", listener.local_addr().unwrap());
+        let servidor = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut pedido = vec![];
+            let mut buffer = [0; 1024];
+            while !pedido.ends_with(b"}") {
+                let leido = stream.read(&mut buffer).unwrap();
+                pedido.extend_from_slice(&buffer[..leido]);
+            }
+            let cuerpo = serde_json::to_string(&ResultadoAutorizacion::Autorizada { hash }).unwrap();
+            write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", cuerpo.len(), cuerpo).unwrap();
+
+            String::from_utf8(pedido).unwrap()
+        });
+
+        let transaccion = Transaccion::de_prueba(9, Uuid::new_v4(), TipoTransaccion::CashIn, 10.0);
+        let respuesta = ProveedorHttp::new(&url).unwrap().solicitar(&transaccion, Duration::from_secs(5));
+
+        assert_eq!(respuesta, Ok(RespuestaAutorizacion::Autorizada(hash)));
+        let pedido = servidor.join().unwrap();
+        assert!(pedido.starts_with("POST /autorizaciones HTTP/1.1"));
+        assert!(pedido.contains("\"id_transaccion\":9"));
+    }
+
+    #[test]
+    fn proveedor_http_sin_servidor_no_esta_disponible() {
+        // Reservar un puerto y liberarlo para que nadie escuche en él
+        let direccion = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let transaccion = Transaccion::de_prueba(9, Uuid::new_v4(), TipoTransaccion::CashOut, 10.0);
+        let respuesta = ProveedorHttp::new(&format!("http://{}", direccion))
+            .unwrap()
+            .solicitar(&transaccion, Duration::from_secs(1));
+
+        assert_eq!(respuesta, Ok(RespuestaAutorizacion::Error(ErrorProveedor::NoDisponible)));
+    }
+}
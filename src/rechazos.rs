@@ -0,0 +1,166 @@
+use std::{sync::mpsc::Receiver, thread, thread::JoinHandle};
+use csv::Writer;
+
+use crate::{
+    archivos::recibir_vaciando,
+    contexto::ContextoPipeline,
+    logger::{NivelLog, TaggedLogger},
+    redaccion::Redactado,
+    transaccion::TransaccionRechazada,
+};
+
+/// Recibe las transacciones que no deben liquidarse y las deja
+/// registradas, con su motivo, en un archivo.
+pub struct WorkerRechazos {
+    log: TaggedLogger,
+    rx_transacciones_rechazadas: Receiver<TransaccionRechazada>,
+    ruta_archivo: String,
+    contexto: ContextoPipeline,
+}
+
+impl WorkerRechazos {
+    pub fn iniciar(log: TaggedLogger,
+                   rx_transacciones_rechazadas: Receiver<TransaccionRechazada>,
+                   ruta_archivo: &str,
+                   contexto: ContextoPipeline)
+        -> JoinHandle<()>
+    {
+        let ruta_archivo = ruta_archivo.to_string();
+        thread::spawn(move || {
+            let worker = Self {
+                log,
+                rx_transacciones_rechazadas,
+                ruta_archivo,
+                contexto,
+            };
+
+            worker.procesar_rechazos();
+        })
+    }
+
+    fn procesar_rechazos(&self) {
+        self.log.write(&format!("Worker de rechazos iniciado, escribiendo en {}", self.ruta_archivo));
+        let mut writer = Writer::from_path(&self.ruta_archivo).expect("El archivo de rechazos no pudo ser abierto");
+
+        while let Some(mut transaccion_rechazada) = recibir_vaciando(&self.rx_transacciones_rechazadas, &mut writer) {
+            self.contexto.latido.empezar(transaccion_rechazada.transaccion.id);
+            self.log.evento(
+                NivelLog::Info,
+                &format!("Transacción registrada: {}", transaccion_rechazada),
+                &[("transaction_id", &transaccion_rechazada.transaccion.id), ("client_id", &transaccion_rechazada.transaccion.id_cliente)]
+            );
+            writer.serialize(Redactado(&transaccion_rechazada, &self.contexto.redactor)).unwrap();
+
+            let transaccion = &mut transaccion_rechazada.transaccion;
+            self.contexto.completar_traza(transaccion.id, std::mem::take(&mut transaccion.traza));
+            self.contexto.latido.terminar();
+        }
+        writer.flush().expect("No se pudo escribir el archivo de rechazos");
+        self.log.write("Worker de rechazos terminado");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, mpsc::channel};
+
+    use super::*;
+    use csv::StringRecord;
+    use uuid::Uuid;
+    use crate::{
+        apagado::Apagado,
+        estados::AlmacenEstados,
+        metricas::RegistroMetricas,
+        logger::{FormatoLog, Logger},
+        redaccion::Redactor,
+        transaccion::{Transaccion, TipoTransaccion},
+        traza::{EtapaTraza, RegistroTrazas, Traza, Tramo},
+        supervisor::Latido,
+    };
+
+    #[test]
+    fn worker_rechazos_registra_el_motivo_del_rechazo() {
+        let ruta_archivo_tests = "archivo_tests_5.csv";
+        let (tx_rechazadas, rx_rechazadas) = channel();
+        let transaccion = Transaccion {
+            traza: Traza { inicio: Some(0), tramos: vec![Tramo { etapa: EtapaTraza::Worker, entrada: 0, salida: Some(5) }] },
+            ..Transaccion::de_prueba(7, Uuid::new_v4(), TipoTransaccion::CashOut, 50.0)
+        };
+
+        tx_rechazadas.send(TransaccionRechazada::new(transaccion, "Denegada")).unwrap();
+        drop(tx_rechazadas);
+        let logger = Arc::new(Logger::new_to_stdout());
+        let trazas = Arc::new(RegistroTrazas::default());
+        let handle = WorkerRechazos::iniciar(
+            TaggedLogger::new("RECHAZOS", logger.clone()),
+            rx_rechazadas,
+            ruta_archivo_tests,
+            ContextoPipeline {
+                clientes: Arc::new(vec![]),
+                estados: Arc::new(AlmacenEstados::new(TaggedLogger::new("ESTADOS", logger))),
+                metricas: Arc::new(RegistroMetricas::default()),
+                trazas: Some(trazas.clone()),
+                redactor: Arc::new(Redactor::default()),
+                apagado: Arc::new(Apagado::default()),
+                latido: Arc::new(Latido::default()),
+            }
+        );
+        handle.join().unwrap();
+
+        let mut reader = csv::Reader::from_path(ruta_archivo_tests).unwrap();
+        let mut record = StringRecord::new();
+        reader.read_record(&mut record).unwrap();
+        assert_eq!(record[0], *"7");
+        assert_eq!(record[3], *"cash_out");
+        assert_eq!(record[5], *"Denegada");
+        // La traza de la transacción rechazada queda guardada
+        assert_eq!(trazas.latencias()[0].etapa, EtapaTraza::Worker);
+    }
+
+    #[test]
+    fn worker_rechazos_usa_el_mismo_seudonimo_en_el_log_y_en_el_archivo() {
+        let ruta_archivo_tests = "archivo_tests_14.csv";
+        let ruta_log_tests = "archivo_tests_14.log";
+        let redactor = Arc::new(Redactor::desde_nombre("seudonimo", Some("clave")).unwrap());
+        let (tx_rechazadas, rx_rechazadas) = channel();
+        let id_cliente = Uuid::new_v4();
+        let transaccion = Transaccion::de_prueba(8, id_cliente, TipoTransaccion::CashIn, 50.0);
+
+        tx_rechazadas.send(TransaccionRechazada::new(transaccion, "Denegada")).unwrap();
+        drop(tx_rechazadas);
+        let logger = Arc::new(
+            Logger::new_to_file(ruta_log_tests)
+                .unwrap()
+                .con_formato(FormatoLog::Json)
+                .con_redactor(redactor.clone())
+        );
+        let handle = WorkerRechazos::iniciar(
+            TaggedLogger::new("RECHAZOS", logger.clone()),
+            rx_rechazadas,
+            ruta_archivo_tests,
+            ContextoPipeline {
+                clientes: Arc::new(vec![]),
+                estados: Arc::new(AlmacenEstados::new(TaggedLogger::new("ESTADOS", logger))),
+                metricas: Arc::new(RegistroMetricas::default()),
+                trazas: None,
+                redactor: redactor.clone(),
+                apagado: Arc::new(Apagado::default()),
+                latido: Arc::new(Latido::default()),
+            }
+        );
+        handle.join().unwrap();
+
+        let log = std::fs::read_to_string(ruta_log_tests).unwrap();
+        let registrada: serde_json::Value = log
+            .lines()
+            .map(|linea| serde_json::from_str::<serde_json::Value>(linea).unwrap())
+            .find(|linea| linea["transaction_id"] == "8")
+            .unwrap();
+        let mut reader = csv::Reader::from_path(ruta_archivo_tests).unwrap();
+        let mut record = StringRecord::new();
+        reader.read_record(&mut record).unwrap();
+        assert_eq!(record[1], redactor.cliente(&id_cliente));
+        assert_eq!(registrada["client_id"], redactor.cliente(&id_cliente));
+        assert!(!log.contains(&id_cliente.to_string()));
+    }
+}
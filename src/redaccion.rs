@@ -0,0 +1,122 @@
+use std::fmt::Write as _;
+use hmac::{Hmac, Mac};
+use serde::{Serialize, Serializer};
+use sha2::Sha256;
+use uuid::Uuid;
+
+/// Variable de entorno con la clave para seudonimizar. No se toma por
+/// CLI para que no quede en el encabezado del log.
+pub const VARIABLE_CLAVE_REDACCION: &str = "DINERO_CLAVE_REDACCION";
+
+const CARACTERES_VISIBLES: usize = 4;
+const BYTES_SEUDONIMO: usize = 8;
+const PREFIJO_SEUDONIMO: &str = "cli-";
+
+/// Cómo se muestran los identificadores de clientes en los artefactos
+/// de la corrida
+#[derive(Debug, Clone, PartialEq)]
+pub enum ModoRedaccion {
+    /// Tal cual
+    Ninguno,
+    /// Solo los últimos caracteres: `****1a2b`
+    Enmascarar,
+    /// Un token derivado con HMAC-SHA256 de la clave: el mismo id da
+    /// siempre el mismo token, así que se pueden cruzar archivos
+    Seudonimizar(Vec<u8>),
+}
+
+/// Oculta los identificadores de clientes en el log y en los archivos
+/// que escribe la corrida
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redactor {
+    modo: ModoRedaccion,
+}
+
+impl Default for Redactor {
+    fn default() -> Self {
+        Self::new(ModoRedaccion::Ninguno)
+    }
+}
+
+impl Redactor {
+    pub fn new(modo: ModoRedaccion) -> Self {
+        Self { modo }
+    }
+
+    /// Interpreta el modo por nombre: ninguno, mascara o seudonimo. El
+    /// seudónimo necesita una clave.
+    pub fn desde_nombre(nombre: &str, clave: Option<&str>) -> Result<Self, String> {
+        let modo = match nombre {
+            "ninguno" => ModoRedaccion::Ninguno,
+            "mascara" => ModoRedaccion::Enmascarar,
+            "seudonimo" => match clave.filter(|c| !c.is_empty()) {
+                Some(clave) => ModoRedaccion::Seudonimizar(clave.as_bytes().to_vec()),
+                None => return Err(format!("La redacción con seudónimos necesita una clave en {}", VARIABLE_CLAVE_REDACCION)),
+            },
+            _ => return Err(format!("Redacción inválida '{}': se espera ninguno, mascara o seudonimo", nombre)),
+        };
+
+        Ok(Self::new(modo))
+    }
+
+    pub fn cliente(&self, id_cliente: &Uuid) -> String {
+        self.redactar(&id_cliente.to_hyphenated().to_string())
+    }
+
+    /// Aplica la redacción a un identificador ya escrito como texto
+    pub fn redactar(&self, valor: &str) -> String {
+        match &self.modo {
+            ModoRedaccion::Ninguno => valor.to_string(),
+            ModoRedaccion::Enmascarar => {
+                let visibles: String = valor.chars().rev().take(CARACTERES_VISIBLES).collect::<Vec<_>>().into_iter().rev().collect();
+                format!("****{}", visibles)
+            }
+            ModoRedaccion::Seudonimizar(clave) => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(clave).expect("HMAC acepta claves de cualquier largo");
+                mac.update(valor.as_bytes());
+                let mut token = PREFIJO_SEUDONIMO.to_string();
+                for byte in mac.finalize().into_bytes().iter().take(BYTES_SEUDONIMO) {
+                    let _ = write!(token, "{:02x}", byte);
+                }
+                token
+            }
+        }
+    }
+}
+
+/// Registros que se exportan con los identificadores de clientes
+/// redactados
+pub trait SerializarRedactado {
+    fn serializar_redactado<S: Serializer>(&self, serializer: S, redactor: &Redactor) -> Result<S::Ok, S::Error>;
+}
+
+/// Envuelve un registro para serializarlo con el redactor dado
+pub struct Redactado<'a, T>(pub &'a T, pub &'a Redactor);
+
+impl<T: SerializarRedactado> Serialize for Redactado<'_, T> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.0.serializar_redactado(serializer, self.1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cada_modo_oculta_el_id_a_su_manera() {
+        let id = Uuid::parse_str("54198cf2-1c8a-49e0-8e5b-3be063a8c9b8").unwrap();
+        let seudonimo = Redactor::desde_nombre("seudonimo", Some("clave")).unwrap();
+        let otra_clave = Redactor::desde_nombre("seudonimo", Some("otra")).unwrap();
+
+        assert_eq!(Redactor::default().cliente(&id), "54198cf2-1c8a-49e0-8e5b-3be063a8c9b8");
+        assert_eq!(Redactor::desde_nombre("mascara", None).unwrap().cliente(&id), "****c9b8");
+        assert_eq!(seudonimo.cliente(&id), seudonimo.cliente(&id));
+        assert_eq!(seudonimo.cliente(&id).len(), PREFIJO_SEUDONIMO.len() + 2 * BYTES_SEUDONIMO);
+        assert_ne!(seudonimo.cliente(&id), otra_clave.cliente(&id));
+        assert!(Redactor::desde_nombre("seudonimo", None).is_err());
+    }
+}
@@ -0,0 +1,226 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use rand::Rng;
+
+use crate::transaccion::Transaccion;
+
+const TIMEOUT_DEFAULT: u64 = 200; // 200 millis
+const MAXIMO_INTENTOS_DEFAULT: u32 = 5;
+const ESPERA_BASE: Duration = Duration::from_millis(10);
+const ESPERA_MAXIMA: Duration = Duration::from_secs(2);
+const FALLAS_PARA_ABRIR: u32 = 5;
+const TIEMPO_ABIERTO: Duration = Duration::from_millis(500);
+
+/// Cómo se reintentan las solicitudes al proveedor
+#[derive(Debug, Clone)]
+pub struct PoliticaReintentos {
+    pub timeout: Duration,
+    pub maximo_intentos: u32,
+    pub espera_base: Duration,
+    pub espera_maxima: Duration,
+}
+
+impl Default for PoliticaReintentos {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_millis(TIMEOUT_DEFAULT),
+            maximo_intentos: MAXIMO_INTENTOS_DEFAULT,
+            espera_base: ESPERA_BASE,
+            espera_maxima: ESPERA_MAXIMA,
+        }
+    }
+}
+
+impl PoliticaReintentos {
+    /// Espera antes de volver a intentar luego de `intentos` fallidos:
+    /// backoff exponencial con jitter completo.
+    pub fn espera(&self, intentos: u32, rng: &mut impl Rng) -> Duration {
+        let exponente = intentos.saturating_sub(1).min(16);
+        let tope = self.espera_base
+            .saturating_mul(1 << exponente)
+            .min(self.espera_maxima);
+
+        tope.mul_f64(rng.gen())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EstadoDisyuntor {
+    Cerrado,
+    Abierto(Instant),
+    SemiAbierto,
+}
+
+struct EstadoInterno {
+    estado: EstadoDisyuntor,
+    fallas_consecutivas: u32,
+}
+
+/// Circuit breaker compartido por todos los workers que hablan con el
+/// proveedor. Se abre luego de varias fallas seguidas y, pasado un
+/// tiempo, deja pasar una única solicitud de prueba.
+pub struct Disyuntor {
+    interno: Mutex<EstadoInterno>,
+    fallas_para_abrir: u32,
+    tiempo_abierto: Duration,
+}
+
+impl Default for Disyuntor {
+    fn default() -> Self {
+        Self::new(FALLAS_PARA_ABRIR, TIEMPO_ABIERTO)
+    }
+}
+
+impl Disyuntor {
+    pub fn new(fallas_para_abrir: u32, tiempo_abierto: Duration) -> Self {
+        Self {
+            interno: Mutex::new(EstadoInterno {
+                estado: EstadoDisyuntor::Cerrado,
+                fallas_consecutivas: 0,
+            }),
+            fallas_para_abrir,
+            tiempo_abierto,
+        }
+    }
+
+    /// Indica si se puede llamar al proveedor ahora
+    pub fn permitir(&self) -> bool {
+        let mut interno = self.interno.lock().expect("disyuntor poisoned");
+        match interno.estado {
+            EstadoDisyuntor::Cerrado => true,
+            EstadoDisyuntor::Abierto(hasta) if Instant::now() >= hasta => {
+                interno.estado = EstadoDisyuntor::SemiAbierto;
+                true
+            },
+            // Abierto, o semiabierto con la prueba en curso
+            _ => false,
+        }
+    }
+
+    /// Registra una respuesta del proveedor. Devuelve true si el
+    /// disyuntor se cerró a causa de ella.
+    pub fn registrar_exito(&self) -> bool {
+        let mut interno = self.interno.lock().expect("disyuntor poisoned");
+        let cerrar = interno.estado != EstadoDisyuntor::Cerrado;
+        interno.estado = EstadoDisyuntor::Cerrado;
+        interno.fallas_consecutivas = 0;
+
+        cerrar
+    }
+
+    /// Registra una falla del proveedor. Devuelve true si el disyuntor
+    /// se abrió a causa de ella.
+    pub fn registrar_falla(&self) -> bool {
+        let mut interno = self.interno.lock().expect("disyuntor poisoned");
+        interno.fallas_consecutivas += 1;
+        let abrir = match interno.estado {
+            EstadoDisyuntor::SemiAbierto => true,
+            EstadoDisyuntor::Cerrado => interno.fallas_consecutivas >= self.fallas_para_abrir,
+            EstadoDisyuntor::Abierto(_) => false,
+        };
+        if abrir {
+            interno.estado = EstadoDisyuntor::Abierto(Instant::now() + self.tiempo_abierto);
+        }
+
+        abrir
+    }
+
+    /// Cuánto falta para que el disyuntor abierto deje pasar la
+    /// solicitud de prueba
+    pub fn espera_hasta_la_prueba(&self) -> Duration {
+        match self.estado() {
+            EstadoDisyuntor::Abierto(hasta) => hasta.saturating_duration_since(Instant::now()),
+            _ => Duration::from_secs(0),
+        }
+    }
+
+    pub fn estado(&self) -> EstadoDisyuntor {
+        self.interno.lock().expect("disyuntor poisoned").estado
+    }
+}
+
+/// Transacción que espera para volver a procesarse
+pub struct TransaccionEstacionada<T = Transaccion> {
+    pub transaccion: T,
+    pub intentos: u32,
+    pub proximo_intento: Instant,
+}
+
+impl<T> TransaccionEstacionada<T> {
+    pub fn new(transaccion: T) -> Self {
+        Self {
+            transaccion,
+            intentos: 0,
+            proximo_intento: Instant::now(),
+        }
+    }
+}
+
+/// Cola de transacciones que esperan para volver a procesarse, por
+/// ejemplo a que el proveedor se recupere
+pub struct ColaReintentos<T = Transaccion> {
+    estacionadas: Mutex<VecDeque<TransaccionEstacionada<T>>>,
+}
+
+impl<T> Default for ColaReintentos<T> {
+    fn default() -> Self {
+        Self { estacionadas: Mutex::new(VecDeque::new()) }
+    }
+}
+
+impl<T> ColaReintentos<T> {
+    pub fn estacionar(&self, transaccion: TransaccionEstacionada<T>) {
+        self.estacionadas.lock().expect("cola de reintentos poisoned").push_back(transaccion);
+    }
+
+    /// Saca la primera transacción cuya espera ya venció
+    pub fn tomar_vencida(&self) -> Option<TransaccionEstacionada<T>> {
+        let mut estacionadas = self.estacionadas.lock().expect("cola de reintentos poisoned");
+        let ahora = Instant::now();
+        let posicion = estacionadas.iter().position(|t| t.proximo_intento <= ahora)?;
+
+        estacionadas.remove(posicion)
+    }
+
+    pub fn len(&self) -> usize {
+        self.estacionadas.lock().expect("cola de reintentos poisoned").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{SeedableRng, prelude::StdRng};
+
+    #[test]
+    fn espera_crece_exponencialmente_hasta_el_maximo() {
+        let politica = PoliticaReintentos::default();
+        let mut rng = StdRng::seed_from_u64(264);
+        for intentos in 1..30 {
+            let tope = (ESPERA_BASE * 2u32.pow(intentos.min(16) - 1)).min(ESPERA_MAXIMA);
+            assert!(politica.espera(intentos, &mut rng) <= tope);
+        }
+    }
+
+    #[test]
+    fn disyuntor_se_abre_luego_de_fallas_consecutivas_y_prueba_al_vencer() {
+        let disyuntor = Disyuntor::new(2, Duration::from_millis(20));
+        assert!(!disyuntor.registrar_falla());
+        assert!(disyuntor.registrar_falla());
+        assert!(!disyuntor.permitir());
+
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(disyuntor.permitir());
+        // Solo se deja pasar una solicitud de prueba
+        assert!(!disyuntor.permitir());
+        assert!(disyuntor.registrar_exito());
+        assert_eq!(disyuntor.estado(), EstadoDisyuntor::Cerrado);
+    }
+}
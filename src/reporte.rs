@@ -0,0 +1,234 @@
+use std::{collections::HashMap, fmt};
+use csv::Writer;
+use serde::{Serializer, ser::SerializeStruct};
+use uuid::Uuid;
+
+use crate::{
+    archivos::{self, ArchivosCorrida, RegistroRechazo, RegistroSaldo},
+    procesador::MOTIVO_ID_REPETIDO,
+    redaccion::{Redactado, Redactor, SerializarRedactado},
+    transaccion::TipoTransaccion,
+};
+
+/// Lo que pasó con las transacciones de un cliente
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResumenCliente {
+    pub id_cliente: Uuid,
+    pub liquidadas: u32,
+    /// Suma de los cash in liquidados
+    pub ingresado: f32,
+    /// Suma de los cash out liquidados
+    pub retirado: f32,
+    pub rechazadas: u32,
+    pub fallidas: u32,
+    /// Saldo contable después de su última liquidación
+    pub saldo_final: Option<f32>,
+}
+
+impl SerializarRedactado for ResumenCliente {
+    fn serializar_redactado<S>(&self, serializer: S, redactor: &Redactor) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("ResumenCliente", 7)?;
+        state.serialize_field("User_id", &redactor.cliente(&self.id_cliente))?;
+        state.serialize_field("Settled", &self.liquidadas)?;
+        state.serialize_field("Cash_in", &self.ingresado)?;
+        state.serialize_field("Cash_out", &self.retirado)?;
+        state.serialize_field("Rejected", &self.rechazadas)?;
+        state.serialize_field("Failed", &self.fallidas)?;
+        state.serialize_field("Final_balance", &self.saldo_final)?;
+        state.end()
+    }
+}
+
+/// Totales de un tipo de transacción
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResumenTipo {
+    pub tipo: TipoTransaccion,
+    pub liquidadas: u32,
+    pub monto_liquidado: f32,
+    pub rechazadas: u32,
+    pub fallidas: u32,
+}
+
+impl fmt::Display for ResumenTipo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} liquidadas por {:.2}, {} rechazadas, {} fallidas",
+            self.tipo, self.liquidadas, self.monto_liquidado, self.rechazadas, self.fallidas
+        )
+    }
+}
+
+/// Resumen de una corrida armado a partir de sus archivos
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reporte {
+    /// En el orden del archivo de clientes, con los que no tienen
+    /// transacciones en cero, y después los que solo aparecen en el
+    /// archivo de transacciones
+    pub clientes: Vec<ResumenCliente>,
+    pub por_tipo: Vec<ResumenTipo>,
+    /// Cantidad de rechazos y fallas por motivo, de más a menos
+    pub motivos: Vec<(String, u32)>,
+}
+
+impl Reporte {
+    /// Escribe el resumen por cliente, con los ids redactados
+    pub fn escribir(&self, ruta_archivo: &str, redactor: &Redactor) -> Result<(), csv::Error> {
+        let mut writer = Writer::from_path(ruta_archivo)?;
+        for cliente in &self.clientes {
+            writer.serialize(Redactado(cliente, redactor))?;
+        }
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Resume las salidas de una corrida. Los clientes se toman de los
+/// archivos de entrada, así el reporte no depende de cómo se redactaron
+/// los ids en los archivos de salida.
+pub fn armar_reporte(archivos: &ArchivosCorrida) -> Result<Reporte, csv::Error> {
+    let (entrada, repetidas) = archivos::leer_entrada(&archivos.transacciones)?;
+    let clientes_simulados = archivos::leer_clientes(&archivos.clientes)?;
+    let saldos: Vec<RegistroSaldo> = archivos::leer(&archivos.saldos)?;
+    let rechazadas: Vec<RegistroRechazo> = archivos::leer(&archivos.rechazadas)?;
+    let fallidas: Vec<RegistroRechazo> = archivos::leer(&archivos.fallidas)?;
+
+    let cliente_de: HashMap<_, _> = entrada.iter().map(|transaccion| (transaccion.id, transaccion.id_cliente)).collect();
+    let mut clientes: Vec<ResumenCliente> = vec![];
+    let mut indices = HashMap::new();
+    let ids_clientes = clientes_simulados
+        .iter()
+        .map(|cliente| cliente.id)
+        .chain(entrada.iter().chain(&repetidas).map(|transaccion| transaccion.id_cliente));
+    for id_cliente in ids_clientes {
+        indices.entry(id_cliente).or_insert_with(|| {
+            clientes.push(ResumenCliente {
+                id_cliente,
+                liquidadas: 0,
+                ingresado: 0.0,
+                retirado: 0.0,
+                rechazadas: 0,
+                fallidas: 0,
+                saldo_final: None,
+            });
+            clientes.len() - 1
+        });
+    }
+    let mut por_tipo: Vec<ResumenTipo> = [TipoTransaccion::CashIn, TipoTransaccion::CashOut]
+        .iter()
+        .map(|tipo| ResumenTipo { tipo: *tipo, liquidadas: 0, monto_liquidado: 0.0, rechazadas: 0, fallidas: 0 })
+        .collect();
+    let indice_tipo = |tipo: TipoTransaccion| match tipo {
+        TipoTransaccion::CashIn => 0,
+        TipoTransaccion::CashOut => 1,
+    };
+    let resumen_cliente = |id_transaccion: u32| {
+        cliente_de
+            .get(&id_transaccion)
+            .and_then(|id_cliente| indices.get(id_cliente))
+            .copied()
+    };
+
+    for saldo in &saldos {
+        let tipo = &mut por_tipo[indice_tipo(saldo.tipo)];
+        tipo.liquidadas += 1;
+        tipo.monto_liquidado += saldo.monto;
+        if let Some(indice) = resumen_cliente(saldo.id_transaccion) {
+            let cliente = &mut clientes[indice];
+            cliente.liquidadas += 1;
+            match saldo.tipo {
+                TipoTransaccion::CashIn => cliente.ingresado += saldo.monto,
+                TipoTransaccion::CashOut => cliente.retirado += saldo.monto,
+            }
+            cliente.saldo_final = Some(saldo.saldo_final);
+        }
+    }
+
+    let mut motivos: HashMap<String, u32> = HashMap::new();
+    for (rechazo, fallida) in rechazadas.iter().map(|r| (r, false)).chain(fallidas.iter().map(|r| (r, true))) {
+        let tipo = &mut por_tipo[indice_tipo(rechazo.tipo)];
+        // Una fila con id repetido es del cliente de esa fila, no del de
+        // la transacción que entró primero
+        let indice = match rechazo.motivo == MOTIVO_ID_REPETIDO {
+            true => repetidas
+                .iter()
+                .find(|t| t.id == rechazo.id_transaccion && t.tipo == rechazo.tipo && t.monto == rechazo.monto)
+                .and_then(|transaccion| indices.get(&transaccion.id_cliente).copied()),
+            false => resumen_cliente(rechazo.id_transaccion),
+        };
+        let cliente = indice.map(|indice| &mut clientes[indice]);
+        if fallida {
+            tipo.fallidas += 1;
+            if let Some(cliente) = cliente {
+                cliente.fallidas += 1;
+            }
+        } else {
+            tipo.rechazadas += 1;
+            if let Some(cliente) = cliente {
+                cliente.rechazadas += 1;
+            }
+        }
+        *motivos.entry(rechazo.motivo.clone()).or_insert(0) += 1;
+    }
+    let mut motivos: Vec<_> = motivos.into_iter().collect();
+    motivos.sort_by(|(motivo_a, cantidad_a), (motivo_b, cantidad_b)| cantidad_b.cmp(cantidad_a).then(motivo_a.cmp(motivo_b)));
+
+    Ok(Reporte { clientes, por_tipo, motivos })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn reporte_resume_por_cliente_tipo_y_motivo_con_ids_redactados() {
+        let c1 = Uuid::parse_str("54198cf2-1c8a-49e0-8e5b-3be063a8c9b8").unwrap();
+        let c2 = Uuid::new_v4();
+        let sin_transacciones = Uuid::new_v4();
+        let archivos = ArchivosCorrida {
+            transacciones: "archivo_tests_18_transacciones.csv".into(),
+            clientes: "archivo_tests_18_clientes.csv".into(),
+            saldos: "archivo_tests_18_saldos.csv".into(),
+            rechazadas: "archivo_tests_18_rechazadas.csv".into(),
+            fallidas: "archivo_tests_18_fallidas.csv".into(),
+            ..ArchivosCorrida::default()
+        };
+        let ruta_reporte = "archivo_tests_18_reporte.csv";
+        fs::write(&archivos.transacciones, format!(
+            "Transaction,User_id,Timestamp,Type,Amount\n1,{0},1,cash_out,30\n2,{1},2,cash_in,30\n3,{0},3,cash_out,500\n4,{0},4,cash_in,5\n",
+            c1, c2
+        )).unwrap();
+        fs::write(&archivos.clientes, format!("User_id,Balance\n{},100\n{},50\n{},10\n", c1, c2, sin_transacciones)).unwrap();
+        fs::write(&archivos.saldos, "Transaction,Type,Amount,Final_balance\n1,cash_out,30,70\n2,cash_in,30,80\n").unwrap();
+        fs::write(&archivos.rechazadas, "Transaction,Type,Amount,Reason\n3,cash_out,500,Saldo insuficiente\n").unwrap();
+        fs::write(&archivos.fallidas, "Transaction,Type,Amount,Reason\n4,cash_in,5,Proveedor no disponible\n").unwrap();
+
+        let reporte = armar_reporte(&archivos).unwrap();
+        reporte.escribir(ruta_reporte, &Redactor::desde_nombre("mascara", None).unwrap()).unwrap();
+
+        assert_eq!(reporte.clientes[0], ResumenCliente {
+            id_cliente: c1,
+            liquidadas: 1,
+            ingresado: 0.0,
+            retirado: 30.0,
+            rechazadas: 1,
+            fallidas: 1,
+            saldo_final: Some(70.0),
+        });
+        assert_eq!(reporte.clientes[1].saldo_final, Some(80.0));
+        // Sin transacciones también tiene su fila
+        assert_eq!((reporte.clientes[2].id_cliente, reporte.clientes[2].liquidadas, reporte.clientes[2].saldo_final), (sin_transacciones, 0, None));
+        assert_eq!(reporte.por_tipo[1].to_string(), "CashOut: 1 liquidadas por 30.00, 1 rechazadas, 0 fallidas");
+        assert_eq!(reporte.motivos.len(), 2);
+        let mut reader = csv::Reader::from_path(ruta_reporte).unwrap();
+        let registro = reader.records().next().unwrap().unwrap();
+        assert_eq!(&registro[0], "****c9b8");
+        assert_eq!(&registro[1], "1");
+    }
+}
@@ -0,0 +1,253 @@
+use std::{
+    fs::{self, File},
+    io::{self, prelude::*, BufWriter},
+    time::{Duration, Instant},
+};
+use flate2::{write::GzEncoder, Compression};
+
+const ARCHIVOS_RETENIDOS_DEFAULT: usize = 5;
+const EXTENSION_GZIP: &str = "gz";
+
+/// Cuándo se rota el archivo de log y qué se hace con los rotados
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfiguracionRotacion {
+    /// Se rota antes de que el archivo supere este tamaño en bytes
+    pub tamano_maximo: Option<u64>,
+    /// Se rota cuando el archivo lleva abierto este tiempo
+    pub intervalo: Option<Duration>,
+    /// Los rotados se comprimen con gzip
+    pub comprimir: bool,
+    /// Cantidad de archivos rotados que se conservan, sin contar el actual
+    pub maximo_archivos: usize,
+}
+
+impl Default for ConfiguracionRotacion {
+    fn default() -> Self {
+        Self {
+            tamano_maximo: None,
+            intervalo: None,
+            comprimir: false,
+            maximo_archivos: ARCHIVOS_RETENIDOS_DEFAULT,
+        }
+    }
+}
+
+impl ConfiguracionRotacion {
+    /// Interpreta un tamaño en bytes con sufijo opcional K, M o G
+    pub fn parsear_tamano(texto: &str) -> Result<u64, String> {
+        let texto = texto.trim();
+        let (numero, multiplicador) = match texto.chars().last().map(|c| c.to_ascii_uppercase()) {
+            Some('K') => (&texto[..texto.len() - 1], 1 << 10),
+            Some('M') => (&texto[..texto.len() - 1], 1 << 20),
+            Some('G') => (&texto[..texto.len() - 1], 1 << 30),
+            _ => (texto, 1),
+        };
+        numero
+            .parse::<u64>()
+            .ok()
+            .filter(|n| *n > 0)
+            .map(|n| n * multiplicador)
+            .ok_or_else(|| format!("Tamaño inválido '{}': se espera un número de bytes con sufijo opcional K, M o G", texto))
+    }
+}
+
+/// Archivo de log que se rota por tamaño o por tiempo. El actual se
+/// llama siempre igual y los rotados llevan un número, del más nuevo
+/// (`.1`) al más viejo. Solo se rota entre mensajes, así que ninguno
+/// queda partido entre dos archivos; cada archivo nuevo empieza con el
+/// encabezado.
+pub struct ArchivoRotativo {
+    ruta: String,
+    configuracion: ConfiguracionRotacion,
+    encabezado: String,
+    archivo: BufWriter<File>,
+    escritos: u64,
+    abierto: Instant,
+}
+
+impl ArchivoRotativo {
+    pub fn new(ruta: &str, configuracion: ConfiguracionRotacion, encabezado: &str) -> io::Result<Self> {
+        let mut archivo = Self {
+            ruta: ruta.into(),
+            configuracion,
+            encabezado: encabezado.into(),
+            archivo: BufWriter::new(File::create(ruta)?),
+            escritos: 0,
+            abierto: Instant::now(),
+        };
+        archivo.escribir_encabezado()?;
+
+        Ok(archivo)
+    }
+
+    /// Escribe un mensaje completo, rotando antes si corresponde
+    pub fn escribir(&mut self, mensaje: &str) -> io::Result<()> {
+        if self.debe_rotar(mensaje.len() as u64) {
+            self.rotar()?;
+        }
+        self.archivo.write_all(mensaje.as_bytes())?;
+        self.escritos += mensaje.len() as u64;
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.archivo.flush()
+    }
+
+    fn debe_rotar(&self, proximo: u64) -> bool {
+        // Un archivo con solo el encabezado no se rota: el mensaje no
+        // entraría en ninguno
+        let con_mensajes = self.escritos > self.encabezado.len() as u64;
+        let por_tamano = self.configuracion.tamano_maximo.is_some_and(|maximo| self.escritos + proximo > maximo);
+        let por_tiempo = self.configuracion.intervalo.is_some_and(|intervalo| self.abierto.elapsed() >= intervalo);
+
+        con_mensajes && (por_tamano || por_tiempo)
+    }
+
+    fn rotar(&mut self) -> io::Result<()> {
+        self.archivo.flush()?;
+        let maximo = self.configuracion.maximo_archivos;
+        for extension in ["", EXTENSION_GZIP].iter() {
+            let _ = fs::remove_file(self.ruta_rotado(maximo, extension));
+        }
+        for numero in (1..maximo).rev() {
+            for extension in ["", EXTENSION_GZIP].iter() {
+                let origen = self.ruta_rotado(numero, extension);
+                if fs::metadata(&origen).is_ok() {
+                    fs::rename(&origen, self.ruta_rotado(numero + 1, extension))?;
+                }
+            }
+        }
+        if maximo > 0 {
+            fs::rename(&self.ruta, self.ruta_rotado(1, ""))?;
+            if self.configuracion.comprimir {
+                comprimir(&self.ruta_rotado(1, ""), &self.ruta_rotado(1, EXTENSION_GZIP))?;
+            }
+        }
+
+        self.archivo = BufWriter::new(File::create(&self.ruta)?);
+        self.escritos = 0;
+        self.abierto = Instant::now();
+        self.escribir_encabezado()
+    }
+
+    fn ruta_rotado(&self, numero: usize, extension: &str) -> String {
+        if extension.is_empty() {
+            format!("{}.{}", self.ruta, numero)
+        } else {
+            format!("{}.{}.{}", self.ruta, numero, extension)
+        }
+    }
+
+    fn escribir_encabezado(&mut self) -> io::Result<()> {
+        self.archivo.write_all(self.encabezado.as_bytes())?;
+        self.escritos = self.encabezado.len() as u64;
+
+        Ok(())
+    }
+}
+
+/// Comprime `origen` en `destino` y borra el original
+fn comprimir(origen: &str, destino: &str) -> io::Result<()> {
+    let mut encoder = GzEncoder::new(File::create(destino)?, Compression::default());
+    io::copy(&mut File::open(origen)?, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(origen)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+    use flate2::read::GzDecoder;
+
+    use super::*;
+    use crate::logger::{Logger, TaggedLogger};
+
+    #[test]
+    fn rota_por_tamano_conservando_solo_los_ultimos_archivos() {
+        let ruta_archivo_tests = "archivo_tests_11.log";
+        let configuracion = ConfiguracionRotacion {
+            tamano_maximo: Some(30),
+            maximo_archivos: 2,
+            ..ConfiguracionRotacion::default()
+        };
+        let mut archivo = ArchivoRotativo::new(ruta_archivo_tests, configuracion, "# corrida\n").unwrap();
+        for i in 0..5 {
+            archivo.escribir(&format!("mensaje {}\n", i)).unwrap();
+        }
+        archivo.flush().unwrap();
+
+        // 10 bytes de encabezado y 10 por mensaje: dos mensajes por archivo
+        let leer = |sufijo: &str| fs::read_to_string(format!("{}{}", ruta_archivo_tests, sufijo)).unwrap();
+        assert_eq!(leer(""), "# corrida\nmensaje 4\n");
+        assert_eq!(leer(".1"), "# corrida\nmensaje 2\nmensaje 3\n");
+        assert_eq!(leer(".2"), "# corrida\nmensaje 0\nmensaje 1\n");
+        assert!(fs::metadata(format!("{}.3", ruta_archivo_tests)).is_err());
+    }
+
+    #[test]
+    fn comprime_los_archivos_rotados() {
+        let ruta_archivo_tests = "archivo_tests_12.log";
+        let configuracion = ConfiguracionRotacion {
+            intervalo: Some(Duration::from_secs(0)),
+            comprimir: true,
+            maximo_archivos: 1,
+            ..ConfiguracionRotacion::default()
+        };
+        let mut archivo = ArchivoRotativo::new(ruta_archivo_tests, configuracion, "").unwrap();
+        archivo.escribir("primero\n").unwrap();
+        archivo.escribir("segundo\n").unwrap();
+        archivo.flush().unwrap();
+
+        let mut rotado = String::new();
+        GzDecoder::new(File::open(format!("{}.1.gz", ruta_archivo_tests)).unwrap())
+            .read_to_string(&mut rotado)
+            .unwrap();
+        assert_eq!(rotado, "primero\n");
+        assert!(fs::metadata(format!("{}.1", ruta_archivo_tests)).is_err());
+        assert_eq!(fs::read_to_string(ruta_archivo_tests).unwrap(), "segundo\n");
+    }
+
+    #[test]
+    fn rotar_con_varios_hilos_no_pierde_ni_mezcla_mensajes() {
+        let ruta_archivo_tests = "archivo_tests_13.log";
+        // Los rotados de corridas anteriores se sumarían a los de esta
+        for numero in 1..100 {
+            let _ = fs::remove_file(format!("{}.{}", ruta_archivo_tests, numero));
+        }
+        let configuracion = ConfiguracionRotacion {
+            tamano_maximo: Some(2_000),
+            maximo_archivos: 100,
+            ..ConfiguracionRotacion::default()
+        };
+        let logger = Arc::new(Logger::new_to_file_con_rotacion(ruta_archivo_tests, configuracion, "# corrida\n").unwrap());
+        let handles: Vec<_> = (0..4)
+            .map(|hilo| {
+                let log = TaggedLogger::new(&format!("HILO {}", hilo), logger.clone());
+                thread::spawn(move || (0..100).for_each(|i| log.write(&format!("mensaje {}", i))))
+            })
+            .collect();
+        handles.into_iter().for_each(|handle| handle.join().unwrap());
+
+        let mut lineas = vec![];
+        for numero in 0..100 {
+            let ruta = if numero == 0 { ruta_archivo_tests.to_string() } else { format!("{}.{}", ruta_archivo_tests, numero) };
+            match fs::read_to_string(ruta) {
+                Ok(contenido) => lineas.extend(contenido.lines().skip(1).map(String::from)),
+                Err(_) => break,
+            }
+        }
+        let mut mensajes: Vec<(String, String)> = lineas
+            .iter()
+            .map(|linea| {
+                let partes: Vec<_> = linea.split('|').map(str::trim).collect();
+                assert_eq!(partes.len(), 4, "línea mezclada: {}", linea);
+                (partes[2].to_string(), partes[3].to_string())
+            })
+            .collect();
+        mensajes.sort();
+        mensajes.dedup();
+        assert_eq!(mensajes.len(), 400);
+    }
+}
@@ -5,26 +5,28 @@ use csv::Writer;
 use rand::{SeedableRng, prelude::StdRng};
 use uuid::Uuid;
 use crate::{
-    cliente::Cliente,
+    cliente::{Cliente, ConfiguracionSimulacion},
     logger::TaggedLogger
 };
 
-// Simular transacciones de un dia entre los n_clientes clientes y guardar las transacción pendientes en el archivo
+// Simular transacciones de un dia entre los clientes de la simulación y guardar las transacción pendientes en el archivo
 pub fn simular_transacciones(log: TaggedLogger,
                              ruta_archivo: &str,
-                             n_clientes: u32,
+                             simulacion: ConfiguracionSimulacion,
                              semilla: u64) -> Result<Arc<Vec<Arc<Cliente>>>, csv::Error> {
 
-    log.write(&format!("Generando {} con {} clientes", ruta_archivo, n_clientes));
+    log.write(&format!("Generando {} con {} clientes", ruta_archivo, simulacion.clientes));
+    let simulacion = Arc::new(simulacion);
     let n_transaccion = Arc::new(AtomicU32::new(1));
 
     // Crear los clientes
     let mut clientes = vec![];
     let rng = Arc::new(Mutex::new(StdRng::seed_from_u64(semilla)));
-    for _ in 0..n_clientes {
+    for _ in 0..simulacion.clientes {
         let cliente = Arc::new(
-            Cliente::new(
+            Cliente::simulado(
                 Uuid::new_v4(),
+                simulacion.clone(),
                 n_transaccion.clone(),
                 rng.clone()
             )
@@ -0,0 +1,120 @@
+use std::{fmt, fs::File, sync::Mutex};
+use csv::Writer;
+use serde::{Deserialize, Serialize};
+
+use crate::ia::Veredicto;
+
+/// Una fila del archivo de comparación: lo que decidieron el detector
+/// activo y el de la sombra sobre la misma transacción
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Comparacion {
+    #[serde(rename = "Transaction")]
+    pub id_transaccion: u32,
+    #[serde(rename = "Agree")]
+    pub coinciden: bool,
+    #[serde(rename = "Active_rejected")]
+    pub activo_rechaza: bool,
+    #[serde(rename = "Active_score")]
+    pub puntaje_activo: f64,
+    #[serde(rename = "Active_reason")]
+    pub razon_activo: String,
+    #[serde(rename = "Shadow_rejected")]
+    pub sombra_rechaza: bool,
+    #[serde(rename = "Shadow_score")]
+    pub puntaje_sombra: f64,
+    #[serde(rename = "Shadow_reason")]
+    pub razon_sombra: String,
+}
+
+/// Cuántas veces coincidieron los dos detectores y, si no, cuál rechazó
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResumenSombra {
+    pub total: usize,
+    pub coincidencias: usize,
+    /// Rechazadas por el activo y aprobadas por la sombra
+    pub solo_activo: usize,
+    /// Aprobadas por el activo y rechazadas por la sombra
+    pub solo_sombra: usize,
+}
+
+impl fmt::Display for ResumenSombra {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let porcentaje = if self.total == 0 { 100.0 } else { 100.0 * self.coincidencias as f64 / self.total as f64 };
+        write!(
+            f,
+            "{} de {} coinciden ({:.1}%), {} rechazadas solo por el activo, {} solo por la sombra",
+            self.coincidencias, self.total, porcentaje, self.solo_activo, self.solo_sombra
+        )
+    }
+}
+
+/// El archivo de comparación de toda la corrida, que comparten los
+/// procesadores ia, con la cuenta de coincidencias
+pub struct RegistroSombra {
+    comparaciones: Mutex<(Writer<File>, ResumenSombra)>,
+}
+
+impl RegistroSombra {
+    pub fn crear(ruta_archivo: &str) -> Result<Self, String> {
+        let archivo = Writer::from_path(ruta_archivo).map_err(|e| format!("No se pudo crear {}: {}", ruta_archivo, e))?;
+        Ok(Self { comparaciones: Mutex::new((archivo, ResumenSombra::default())) })
+    }
+
+    /// Escribe la fila en el momento, así una corrida abortada conserva
+    /// lo que se llegó a comparar
+    pub fn registrar(&self, id_transaccion: u32, activo: &Veredicto, sombra: &Veredicto) {
+        let comparacion = Comparacion {
+            id_transaccion,
+            coinciden: activo.rechazada() == sombra.rechazada(),
+            activo_rechaza: activo.rechazada(),
+            puntaje_activo: activo.puntaje,
+            razon_activo: activo.razon(),
+            sombra_rechaza: sombra.rechazada(),
+            puntaje_sombra: sombra.puntaje,
+            razon_sombra: sombra.razon(),
+        };
+        let mut comparaciones = self.comparaciones.lock().expect("comparaciones poisoned");
+        let (archivo, resumen) = &mut *comparaciones;
+        archivo.serialize(&comparacion).expect("No se pudo escribir la comparación");
+        archivo.flush().expect("No se pudo escribir la comparación");
+        resumen.total += 1;
+        match (comparacion.activo_rechaza, comparacion.sombra_rechaza) {
+            (true, false) => resumen.solo_activo += 1,
+            (false, true) => resumen.solo_sombra += 1,
+            _ => resumen.coincidencias += 1,
+        }
+    }
+
+    pub fn resumen(&self) -> ResumenSombra {
+        self.comparaciones.lock().expect("comparaciones poisoned").1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn veredicto(motivo: Option<&'static str>, puntaje: f64) -> Veredicto {
+        Veredicto { motivo, puntaje, detector: "el modelo de prueba".into(), anomalia: None }
+    }
+
+    #[test]
+    fn cada_comparacion_queda_escrita_con_los_dos_puntajes_al_registrarla() {
+        let archivo = std::env::temp_dir().join(format!("comparacion_{}.csv", uuid::Uuid::new_v4()));
+        let ruta = archivo.to_str().unwrap();
+        let registro = RegistroSombra::crear(ruta).unwrap();
+        registro.registrar(3, &veredicto(None, 0.1), &veredicto(None, 0.2));
+        registro.registrar(2, &veredicto(None, 0.3), &veredicto(Some("Fraude según el modelo"), 0.8));
+        registro.registrar(1, &veredicto(Some("Lavado de dinero detectado"), 0.95), &veredicto(None, 0.4));
+
+        // Sin terminar la corrida: las filas ya están en el archivo
+        let filas: Vec<Comparacion> = csv::Reader::from_path(ruta).unwrap().deserialize().map(Result::unwrap).collect();
+        std::fs::remove_file(ruta).unwrap();
+        assert_eq!(registro.resumen(), ResumenSombra { total: 3, coincidencias: 1, solo_activo: 1, solo_sombra: 1 });
+        assert_eq!(filas.iter().map(|fila| fila.id_transaccion).collect::<Vec<_>>(), vec![3, 2, 1]);
+        assert_eq!((filas[1].puntaje_activo, filas[1].puntaje_sombra), (0.3, 0.8));
+        assert_eq!(filas[1].razon_sombra, "Fraude según el modelo: el modelo de prueba dio 0.800");
+        assert_eq!(filas[2].razon_sombra, "Aprobada: el modelo de prueba dio 0.400");
+        assert!(filas[0].coinciden && !filas[1].coinciden);
+    }
+}
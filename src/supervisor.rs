@@ -0,0 +1,504 @@
+use std::{
+    any::Any,
+    sync::{Arc, atomic::{AtomicBool, AtomicU64, Ordering}, mpsc::{channel, Receiver, Sender}},
+    thread, thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    autoescalado::{Autoescalador, ConfiguracionAutoescalado, Escalado, LimitesPool, MuestraPool},
+    logger::{NivelLog, TaggedLogger},
+    metricas::{ESCALADOS, HILOS_POOL, PROFUNDIDAD_COLA, RegistroMetricas},
+};
+
+const INTERVALO_SUPERVISION: Duration = Duration::from_millis(100);
+
+/// Qué hace el supervisor con una etapa que entra en pánico o deja de
+/// avanzar
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PoliticaEtapa {
+    /// Arranca otro hilo en su lugar. Solo se pueden reiniciar las etapas
+    /// que comparten su canal de entrada.
+    Reiniciar,
+    /// Termina la corrida registrando las transacciones en curso
+    Fallar,
+    /// Deja de vigilarla y la corrida sigue sin ella
+    Continuar,
+}
+
+impl PoliticaEtapa {
+    pub fn desde_nombre(nombre: &str) -> Result<Self, String> {
+        match nombre {
+            "reiniciar" => Ok(PoliticaEtapa::Reiniciar),
+            "fallar" => Ok(PoliticaEtapa::Fallar),
+            "continuar" => Ok(PoliticaEtapa::Continuar),
+            _ => Err(format!("Política inválida '{}': se espera reiniciar, fallar o continuar", nombre)),
+        }
+    }
+}
+
+/// Lo que comparten el hilo de una etapa y el supervisor: el hilo marca
+/// cada vez que toma y termina una transacción, para que el supervisor
+/// sepa si avanza, y el supervisor le puede pedir que se retire
+#[derive(Debug)]
+pub struct Latido {
+    origen: Instant,
+    /// Milisegundos desde `origen` hasta el último avance
+    ultimo_avance: AtomicU64,
+    /// Id de la transacción en curso más uno; 0 si la etapa espera trabajo
+    en_curso: AtomicU64,
+    terminadas: AtomicU64,
+    retirar: AtomicBool,
+}
+
+impl Default for Latido {
+    fn default() -> Self {
+        Self {
+            origen: Instant::now(),
+            ultimo_avance: AtomicU64::new(0),
+            en_curso: AtomicU64::new(0),
+            terminadas: AtomicU64::new(0),
+            retirar: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Latido {
+    pub fn empezar(&self, id_transaccion: u32) {
+        self.en_curso.store(u64::from(id_transaccion) + 1, Ordering::SeqCst);
+        self.avanzar();
+    }
+
+    pub fn terminar(&self) {
+        self.en_curso.store(0, Ordering::SeqCst);
+        self.terminadas.fetch_add(1, Ordering::SeqCst);
+        self.avanzar();
+    }
+
+    pub fn en_curso(&self) -> Option<u32> {
+        match self.en_curso.load(Ordering::SeqCst) {
+            0 => None,
+            id => Some((id - 1) as u32),
+        }
+    }
+
+    pub fn terminadas(&self) -> u64 {
+        self.terminadas.load(Ordering::SeqCst)
+    }
+
+    pub fn desde_ultimo_avance(&self) -> Duration {
+        self.origen.elapsed().saturating_sub(Duration::from_millis(self.ultimo_avance.load(Ordering::SeqCst)))
+    }
+
+    /// Le pide al hilo que termine antes de tomar otra transacción
+    pub fn pedir_retiro(&self) {
+        self.retirar.store(true, Ordering::SeqCst);
+    }
+
+    pub fn retiro_pedido(&self) -> bool {
+        self.retirar.load(Ordering::SeqCst)
+    }
+
+    fn avanzar(&self) {
+        self.ultimo_avance.store(self.origen.elapsed().as_millis() as u64, Ordering::SeqCst);
+    }
+}
+
+/// Arranca el hilo número `id` de un pool con el latido que tiene que ir
+/// marcando. Se vuelve a llamar para reiniciarlo o para agregar hilos.
+pub type IniciarHilo = Box<dyn FnMut(u32, Arc<Latido>) -> JoinHandle<()> + Send>;
+
+/// Cuántas transacciones siguen sin llegar a un estado final
+pub type EnCurso = Box<dyn Fn() -> usize + Send>;
+
+/// Hilos intercambiables que comparten su canal de entrada: se pueden
+/// reiniciar y, con el autoescalado, agregar o retirar
+pub struct Pool {
+    /// Cada hilo se llama como el pool seguido de su número
+    pub nombre: String,
+    pub politica: PoliticaEtapa,
+    /// Con cuántos hilos arranca
+    pub hilos: u32,
+    /// Cola de las métricas en la que espera el trabajo del pool
+    pub cola: &'static str,
+    pub limites: LimitesPool,
+    pub iniciar: IniciarHilo,
+}
+
+struct PoolVigilado {
+    nombre: String,
+    politica: PoliticaEtapa,
+    cola: &'static str,
+    /// Se suelta cuando terminan todos los hilos, para cerrar los canales
+    /// que tiene tomados
+    iniciar: Option<IniciarHilo>,
+    proximo_id: u32,
+    /// Transacciones que terminaron los hilos que ya no están
+    terminadas: u64,
+    autoescalador: Option<Autoescalador>,
+}
+
+struct EtapaVigilada {
+    nombre: String,
+    politica: PoliticaEtapa,
+    latido: Arc<Latido>,
+    /// None una vez que se juntó el hilo
+    handle: Option<JoinHandle<()>>,
+    /// El pool y el número de hilo, si es parte de uno
+    hilo: Option<(usize, u32)>,
+    reinicios: u32,
+}
+
+/// Es dueño de los hilos de las etapas y espera a que terminen. Si una
+/// entra en pánico o se queda con una transacción más de `sin_progreso`
+/// aplica la política de la etapa; si ninguna avanza en ese tiempo da la
+/// corrida por trabada. Con el autoescalado también ajusta la cantidad
+/// de hilos de cada pool.
+pub struct Supervisor {
+    log: TaggedLogger,
+    sin_progreso: Duration,
+    reinicios: u32,
+    etapas: Vec<EtapaVigilada>,
+    pools: Vec<PoolVigilado>,
+    /// Las que se dejaron de vigilar con la política continuar
+    abandonadas: Vec<String>,
+    autoescalado: Option<(ConfiguracionAutoescalado, Arc<RegistroMetricas>)>,
+    /// Sin transacciones en curso las etapas quietas solo esperan trabajo
+    en_curso: Option<EnCurso>,
+    /// Las fuentes que se registran mientras el supervisor ya espera
+    fuentes: Option<Receiver<EtapaVigilada>>,
+}
+
+/// Registra etapas que arrancan cuando el supervisor ya está esperando en
+/// otro hilo. Van delante de las demás: son las que alimentan al pipeline.
+#[derive(Clone)]
+pub struct RegistroFuentes(Sender<EtapaVigilada>);
+
+impl RegistroFuentes {
+    /// Como `Supervisor::vigilar`. Devuelve false si el supervisor ya
+    /// terminó de esperar.
+    pub fn vigilar(&self, nombre: &str, politica: PoliticaEtapa, latido: Arc<Latido>, handle: JoinHandle<()>) -> bool {
+        self.0.send(EtapaVigilada { nombre: nombre.to_string(), politica, latido, handle: Some(handle), hilo: None, reinicios: 0 }).is_ok()
+    }
+}
+
+impl Supervisor {
+    pub fn new(log: TaggedLogger, sin_progreso: Duration, reinicios: u32) -> Self {
+        Self { log, sin_progreso, reinicios, etapas: vec![], pools: vec![], abandonadas: vec![], autoescalado: None, en_curso: None, fuentes: None }
+    }
+
+    /// La corrida no se da por trabada si no hay transacciones en curso:
+    /// la entrada puede quedar abierta sin que llegue nada
+    pub fn con_en_curso(mut self, en_curso: EnCurso) -> Self {
+        self.en_curso = Some(en_curso);
+        self
+    }
+
+    /// Para vigilar fuentes que arrancan después. El supervisor sigue
+    /// esperando mientras quede alguna etapa viva.
+    pub fn registro_fuentes(&mut self) -> RegistroFuentes {
+        let (tx, rx) = channel();
+        self.fuentes = Some(rx);
+        RegistroFuentes(tx)
+    }
+
+    /// Ajusta los pools que se vigilen después según la profundidad de su
+    /// cola en `metricas`, y deja ahí la cantidad de hilos y los cambios
+    pub fn con_autoescalado(mut self, configuracion: ConfiguracionAutoescalado, metricas: Arc<RegistroMetricas>) -> Self {
+        self.autoescalado = Some((configuracion, metricas));
+        self
+    }
+
+    /// Vigila una etapa que ya arrancó y no se puede reiniciar. Las etapas
+    /// se registran en el orden del pipeline.
+    pub fn vigilar(&mut self, nombre: &str, politica: PoliticaEtapa, latido: Arc<Latido>, handle: JoinHandle<()>) {
+        self.etapas.push(EtapaVigilada { nombre: nombre.to_string(), politica, latido, handle: Some(handle), hilo: None, reinicios: 0 });
+    }
+
+    /// Arranca los hilos de un pool y los vigila
+    pub fn vigilar_pool(&mut self, pool: Pool) {
+        let indice = self.pools.len();
+        let Pool { nombre, politica, hilos, cola, limites, iniciar } = pool;
+        let autoescalador = self.autoescalado.as_ref().map(|(configuracion, _)| Autoescalador::new(configuracion, limites));
+        self.pools.push(PoolVigilado { nombre, politica, cola, iniciar: Some(iniciar), proximo_id: 0, terminadas: 0, autoescalador });
+        for _ in 0..hilos {
+            self.agregar_hilo(indice);
+        }
+        self.informar_hilos(indice, hilos);
+    }
+
+    fn agregar_hilo(&mut self, indice: usize) -> String {
+        let vigilado = &mut self.pools[indice];
+        let id = vigilado.proximo_id;
+        vigilado.proximo_id += 1;
+        let latido = Arc::new(Latido::default());
+        let handle = iniciar_hilo(vigilado, id, latido.clone());
+        let nombre = format!("{} {}", vigilado.nombre, id);
+        self.etapas.push(EtapaVigilada {
+            nombre: nombre.clone(),
+            politica: vigilado.politica,
+            latido,
+            handle: Some(handle),
+            hilo: Some((indice, id)),
+            reinicios: 0,
+        });
+
+        nombre
+    }
+
+    /// Espera a que terminen todas las etapas. Devuelve los problemas de
+    /// las que se reiniciaron o se dejaron de vigilar, o el diagnóstico
+    /// de la que hizo fallar la corrida.
+    pub fn esperar(mut self) -> Result<Vec<String>, String> {
+        let mut incidentes = vec![];
+        let mut ultima_terminada = Instant::now();
+        let mut ultimo_autoescalado = Instant::now();
+        while !self.etapas.is_empty() {
+            thread::sleep(INTERVALO_SUPERVISION);
+            if let Some(fuentes) = &self.fuentes {
+                for (posicion, fuente) in fuentes.try_iter().enumerate() {
+                    self.etapas.insert(posicion, fuente);
+                }
+            }
+            // De atrás para adelante: si falla una etapa, las anteriores
+            // entran en pánico al escribirle y eso no es lo que hay que
+            // diagnosticar primero
+            let etapas = std::mem::take(&mut self.etapas);
+            for mut etapa in etapas.into_iter().rev() {
+                let diagnostico = if etapa.handle.as_ref().is_none_or(JoinHandle::is_finished) {
+                    if let Some((indice, _)) = etapa.hilo {
+                        self.pools[indice].terminadas += etapa.latido.terminadas();
+                    }
+                    match etapa.handle.take().map_or(Ok(()), JoinHandle::join) {
+                        Ok(()) => {
+                            self.log.debug(&format!("{} terminó", etapa.nombre));
+                            ultima_terminada = Instant::now();
+                            continue;
+                        }
+                        Err(panico) => format!(
+                            "{} entró en pánico{}: {}",
+                            etapa.nombre, describir_en_curso(&etapa.latido), mensaje_de_panico(&*panico)
+                        ),
+                    }
+                } else if etapa.latido.en_curso().is_some() && etapa.latido.desde_ultimo_avance() > self.sin_progreso {
+                    // El hilo trabado no se puede detener: si se reinicia
+                    // o se sigue sin él, queda suelto
+                    format!(
+                        "{} no avanza hace {:.0?}{}",
+                        etapa.nombre, etapa.latido.desde_ultimo_avance(), describir_en_curso(&etapa.latido)
+                    )
+                } else {
+                    self.etapas.push(etapa);
+                    continue;
+                };
+
+                self.log.evento(NivelLog::Error, &diagnostico, &[]);
+                match (etapa.politica, etapa.hilo) {
+                    (PoliticaEtapa::Reiniciar, Some((indice, id))) if etapa.reinicios < self.reinicios => {
+                        let latido = Arc::new(Latido::default());
+                        let handle = iniciar_hilo(&mut self.pools[indice], id, latido.clone());
+                        self.log.warn(&format!("Reiniciando {} ({} de {} reinicios)", etapa.nombre, etapa.reinicios + 1, self.reinicios));
+                        self.etapas.push(EtapaVigilada { latido, handle: Some(handle), reinicios: etapa.reinicios + 1, ..etapa });
+                    }
+                    (PoliticaEtapa::Reiniciar, _) => {
+                        return Err(format!("{} (ya se reinició {} veces)", diagnostico, etapa.reinicios));
+                    }
+                    (PoliticaEtapa::Fallar, _) => return Err(diagnostico),
+                    (PoliticaEtapa::Continuar, _) => {
+                        self.log.warn(&format!("{} se deja de vigilar: la corrida sigue sin ella", etapa.nombre));
+                        self.abandonadas.push(etapa.nombre);
+                    }
+                }
+                incidentes.push(diagnostico);
+            }
+            self.etapas.reverse();
+            let etapas = &self.etapas;
+            for (indice, vigilado) in self.pools.iter_mut().enumerate() {
+                if !etapas.iter().any(|etapa| matches!(etapa.hilo, Some((pool, _)) if pool == indice)) {
+                    vigilado.iniciar = None;
+                }
+            }
+
+            if let Some(diagnostico) = self.diagnosticar_corrida_trabada(ultima_terminada) {
+                self.log.evento(NivelLog::Error, &diagnostico, &[]);
+                return Err(diagnostico);
+            }
+
+            let intervalo = self.autoescalado.as_ref().map(|(configuracion, _)| configuracion.intervalo());
+            if intervalo.is_some_and(|intervalo| ultimo_autoescalado.elapsed() >= intervalo) {
+                self.autoescalar(ultimo_autoescalado.elapsed());
+                ultimo_autoescalado = Instant::now();
+            }
+        }
+
+        Ok(incidentes)
+    }
+
+    /// Revisa cada pool y le agrega o retira un hilo si hace falta
+    fn autoescalar(&mut self, transcurrido: Duration) {
+        let metricas = match &self.autoescalado {
+            Some((_, metricas)) => metricas.clone(),
+            None => return,
+        };
+        for indice in 0..self.pools.len() {
+            let del_pool: Vec<&EtapaVigilada> = self.etapas
+                .iter()
+                .filter(|etapa| matches!(etapa.hilo, Some((pool, _)) if pool == indice))
+                .collect();
+            let activos: Vec<&EtapaVigilada> = del_pool.iter().copied().filter(|etapa| !etapa.latido.retiro_pedido()).collect();
+            // Un pool sin hilos ya terminó: su canal de entrada se cerró
+            if activos.is_empty() {
+                continue;
+            }
+            let vigilado = &self.pools[indice];
+            let muestra = MuestraPool {
+                profundidad: metricas.valor(&PROFUNDIDAD_COLA, &[("cola", vigilado.cola)]),
+                terminadas: vigilado.terminadas + del_pool.iter().map(|etapa| etapa.latido.terminadas()).sum::<u64>(),
+                hilos: activos.len() as u32,
+                ocupados: activos.iter().filter(|etapa| etapa.latido.en_curso().is_some()).count() as u32,
+            };
+            // Se retira el último de los que están libres, si hay alguno
+            let a_retirar = activos
+                .iter()
+                .rev()
+                .find(|etapa| etapa.latido.en_curso().is_none())
+                .or_else(|| activos.last())
+                .map(|etapa| (etapa.nombre.clone(), etapa.latido.clone()));
+
+            let vigilado = &mut self.pools[indice];
+            let escalado = match vigilado.autoescalador.as_mut().and_then(|autoescalador| autoescalador.revisar(muestra, transcurrido)) {
+                Some(escalado) => escalado,
+                None => continue,
+            };
+            let rendimiento = vigilado.autoescalador.as_ref().map_or(0.0, Autoescalador::rendimiento);
+            let nombre_pool = vigilado.nombre.clone();
+            let (hilos, cambio) = match escalado {
+                Escalado::Subir => (muestra.hilos + 1, format!("se agrega {}", self.agregar_hilo(indice))),
+                Escalado::Bajar => {
+                    let (nombre, latido) = a_retirar.expect("un pool con hilos activos");
+                    latido.pedir_retiro();
+                    (muestra.hilos - 1, format!("se retira {}", nombre))
+                }
+            };
+            self.log.write(&format!(
+                "Autoescalado de {}: {} -> {} hilos ({}; cola {}, {:.1} por segundo)",
+                nombre_pool, muestra.hilos, hilos, cambio, muestra.profundidad, rendimiento
+            ));
+            metricas.incrementar(&ESCALADOS, &[("pool", &nombre_pool), ("direccion", escalado.nombre())]);
+            self.informar_hilos(indice, hilos);
+        }
+    }
+
+    fn informar_hilos(&self, indice: usize, hilos: u32) {
+        if let Some((_, metricas)) = &self.autoescalado {
+            metricas.fijar(&HILOS_POOL, &[("pool", &self.pools[indice].nombre)], hilos.into());
+        }
+    }
+
+    /// Si ninguna etapa avanzó ni terminó en `sin_progreso`, todas están
+    /// esperando algo que no va a llegar
+    fn diagnosticar_corrida_trabada(&self, ultima_terminada: Instant) -> Option<String> {
+        if self.en_curso.as_ref().is_some_and(|en_curso| en_curso() == 0) {
+            return None;
+        }
+        let quieta = self.etapas.iter().map(|etapa| etapa.latido.desde_ultimo_avance()).min()?;
+        if quieta <= self.sin_progreso || ultima_terminada.elapsed() <= self.sin_progreso {
+            return None;
+        }
+
+        let esperando: Vec<_> = self.etapas.iter().map(|etapa| etapa.nombre.as_str()).collect();
+        let mut diagnostico = format!("Ninguna etapa avanzó en {:.0?}; siguen esperando: {}", quieta, esperando.join(", "));
+        if !self.abandonadas.is_empty() {
+            diagnostico.push_str(&format!("; sin vigilar: {}", self.abandonadas.join(", ")));
+        }
+        Some(diagnostico)
+    }
+}
+
+fn iniciar_hilo(vigilado: &mut PoolVigilado, id: u32, latido: Arc<Latido>) -> JoinHandle<()> {
+    let iniciar = vigilado.iniciar.as_mut().expect("un pool con hilos vivos");
+    iniciar(id, latido)
+}
+
+fn describir_en_curso(latido: &Latido) -> String {
+    latido.en_curso().map(|id| format!(" con la transacción {}", id)).unwrap_or_default()
+}
+
+fn mensaje_de_panico(panico: &(dyn Any + Send)) -> &str {
+    panico
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panico.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("sin mensaje")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicU32;
+
+    use super::*;
+    use crate::logger::Logger;
+
+    fn crear_supervisor(sin_progreso: Duration) -> Supervisor {
+        Supervisor::new(TaggedLogger::new("SUPERVISOR", Arc::new(Logger::new_to_stdout())), sin_progreso, 2)
+    }
+
+    fn crear_pool(nombre: &str, iniciar: IniciarHilo) -> Pool {
+        Pool {
+            nombre: nombre.to_string(),
+            politica: PoliticaEtapa::Reiniciar,
+            hilos: 1,
+            cola: "ia",
+            limites: LimitesPool::default(),
+            iniciar,
+        }
+    }
+
+    #[test]
+    fn reinicia_el_hilo_que_entra_en_panico_hasta_agotar_los_reinicios() {
+        let mut supervisor = crear_supervisor(Duration::from_secs(60));
+        let arranques = Arc::new(AtomicU32::new(0));
+        let arranques_hilo = arranques.clone();
+        supervisor.vigilar_pool(crear_pool("WORKER", Box::new(move |_, latido: Arc<Latido>| {
+            let arranque = arranques_hilo.fetch_add(1, Ordering::SeqCst);
+            thread::spawn(move || {
+                latido.empezar(7);
+                if arranque == 0 {
+                    panic!("Channel cerrado");
+                }
+                latido.terminar();
+            })
+        })));
+        let incidentes = supervisor.esperar().unwrap();
+        assert_eq!(arranques.load(Ordering::SeqCst), 2);
+        assert_eq!(incidentes, vec!["WORKER 0 entró en pánico con la transacción 7: Channel cerrado".to_string()]);
+
+        let mut supervisor = crear_supervisor(Duration::from_secs(60));
+        supervisor.vigilar_pool(crear_pool("PROCESADOR IA", Box::new(|_, _| thread::spawn(|| panic!("siempre")))));
+        let diagnostico = supervisor.esperar().unwrap_err();
+        assert_eq!(diagnostico, "PROCESADOR IA 0 entró en pánico: siempre (ya se reinició 2 veces)");
+    }
+
+    #[test]
+    fn falla_la_corrida_si_una_etapa_no_avanza_y_nombra_la_trabada() {
+        let mut supervisor = crear_supervisor(Duration::from_millis(200));
+        let latido = Arc::new(Latido::default());
+        let latido_etapa = latido.clone();
+        let handle = thread::spawn(move || {
+            latido_etapa.empezar(3);
+            thread::sleep(Duration::from_secs(2));
+        });
+        supervisor.vigilar("WORKER FINAL", PoliticaEtapa::Fallar, latido, handle);
+        let diagnostico = supervisor.esperar().unwrap_err();
+        assert!(diagnostico.starts_with("WORKER FINAL no avanza hace"), "{}", diagnostico);
+        assert!(diagnostico.ends_with("con la transacción 3"), "{}", diagnostico);
+
+        // Una etapa que solo espera trabajo no está trabada, pero si
+        // ninguna avanza la corrida lo está
+        let mut supervisor = crear_supervisor(Duration::from_millis(200));
+        supervisor.vigilar("RECHAZOS", PoliticaEtapa::Fallar, Arc::new(Latido::default()), thread::spawn(|| thread::sleep(Duration::from_secs(2))));
+        let diagnostico = supervisor.esperar().unwrap_err();
+        assert!(diagnostico.starts_with("Ninguna etapa avanzó en"), "{}", diagnostico);
+        assert!(diagnostico.ends_with("siguen esperando: RECHAZOS"), "{}", diagnostico);
+    }
+}
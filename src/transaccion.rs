@@ -1,7 +1,14 @@
 use serde::{Deserialize, Serialize, Serializer, ser::SerializeStruct};
-use std::fmt;
+use std::{fmt, time::SystemTime};
 
-#[derive(Debug, Serialize, Deserialize)]
+use crate::{
+    cliente::Saldo,
+    prioridad::Prioridad,
+    redaccion::{Redactor, SerializarRedactado},
+    traza::Traza,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum TipoTransaccion {
     #[serde(rename = "cash_in")]
     CashIn,
@@ -9,6 +16,12 @@ pub enum TipoTransaccion {
     CashOut
 }
 
+impl fmt::Display for TipoTransaccion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Transaccion {
     #[serde(rename = "Transaction")]
@@ -20,7 +33,22 @@ pub struct Transaccion {
     #[serde(rename = "Type")]
     pub tipo: TipoTransaccion,
     #[serde(rename = "Amount")]
-    pub monto: f32
+    pub monto: f32,
+    /// Paso por las etapas del pipeline; no forma parte del archivo
+    #[serde(skip)]
+    pub traza: Traza,
+    /// Columna opcional del archivo. Al entrar al pipeline queda la que
+    /// corresponde según la configuración de prioridades.
+    #[serde(rename = "Priority", default, skip_serializing_if = "Option::is_none")]
+    pub prioridad: Option<Prioridad>,
+}
+
+#[cfg(test)]
+impl Transaccion {
+    /// Una transacción de los tests, sin traza ni prioridad
+    pub fn de_prueba(id: u32, id_cliente: uuid::Uuid, tipo: TipoTransaccion, monto: f32) -> Self {
+        Self { id, id_cliente, timestamp: 112_315_846_128, tipo, monto, traza: Traza::default(), prioridad: None }
+    }
 }
 
 pub type HashAutorizacion = uuid::Uuid;
@@ -40,29 +68,73 @@ impl fmt::Display for TransaccionAutorizada {
 #[derive(Debug)]
 pub struct TransaccionExitosa {
     pub transaccion: TransaccionAutorizada,
-    pub saldo_final: f32,
+    pub saldo_final: Saldo,
     pub timestamp: u128
 }
 
-impl Serialize for TransaccionExitosa {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+/// Se exporta con el id del cliente redactado
+impl SerializarRedactado for TransaccionExitosa {
+    fn serializar_redactado<S>(&self, serializer: S, redactor: &Redactor) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        // 7 is the number of fields in the struct.
-        let mut state = serializer.serialize_struct("TransaccionExitosa", 7)?;
+        let mut state = serializer.serialize_struct("TransaccionExitosa", 10)?;
         state.serialize_field("Transaction", &self.transaccion.transaccion.id)?;
-        state.serialize_field("User_id", &self.transaccion.transaccion.id_cliente)?;
+        state.serialize_field("User_id", &redactor.cliente(&self.transaccion.transaccion.id_cliente))?;
         state.serialize_field("Transaction_Timestamp", &self.transaccion.transaccion.timestamp)?;
         state.serialize_field("Type", &self.transaccion.transaccion.tipo)?;
         state.serialize_field("Amount", &self.transaccion.transaccion.monto)?;
         state.serialize_field("Authorization_hash", &self.transaccion.autorizacion)?;
         state.serialize_field("Timestamp", &self.timestamp)?;
-        state.serialize_field("Final_balance", &self.saldo_final)?;
+        state.serialize_field("Final_balance", &self.saldo_final.contable)?;
+        state.serialize_field("Held_balance", &self.saldo_final.retenido)?;
+        state.serialize_field("Available_balance", &self.saldo_final.disponible())?;
+        state.end()
+    }
+}
+
+#[derive(Debug)]
+pub struct TransaccionRechazada {
+    pub transaccion: Transaccion,
+    pub motivo: String,
+    pub timestamp: u128
+}
+
+/// Se exporta con el id del cliente redactado
+impl SerializarRedactado for TransaccionRechazada {
+    fn serializar_redactado<S>(&self, serializer: S, redactor: &Redactor) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("TransaccionRechazada", 7)?;
+        state.serialize_field("Transaction", &self.transaccion.id)?;
+        state.serialize_field("User_id", &redactor.cliente(&self.transaccion.id_cliente))?;
+        state.serialize_field("Transaction_Timestamp", &self.transaccion.timestamp)?;
+        state.serialize_field("Type", &self.transaccion.tipo)?;
+        state.serialize_field("Amount", &self.transaccion.monto)?;
+        state.serialize_field("Reason", &self.motivo)?;
+        state.serialize_field("Timestamp", &self.timestamp)?;
         state.end()
     }
 }
 
+impl fmt::Display for TransaccionRechazada {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TransaccionRechazada (id = {}, motivo = {})", self.transaccion.id, self.motivo)
+    }
+}
+
+impl TransaccionRechazada {
+    pub fn new(transaccion: Transaccion, motivo: &str) -> Self {
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).expect("SystemTime before UNIX EPOCH!").as_millis();
+        Self {
+            transaccion,
+            motivo: motivo.into(),
+            timestamp
+        }
+    }
+}
+
 impl TransaccionAutorizada {
     pub fn new(transaccion: Transaccion, autorizacion: HashAutorizacion) -> Self {
         Self {
@@ -0,0 +1,280 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, BufWriter},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+use serde::Serialize;
+use serde_json::json;
+
+/// Etapas del pipeline que registran por dónde pasó una transacción
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum EtapaTraza {
+    Procesador,
+    Worker,
+    ProcesadorIA,
+    WorkerFinal,
+    /// Estacionada en la cola de reintentos, fuera de toda etapa
+    EsperaReintento,
+}
+
+impl EtapaTraza {
+    const TODAS: [EtapaTraza; 5] = [
+        EtapaTraza::Procesador,
+        EtapaTraza::Worker,
+        EtapaTraza::ProcesadorIA,
+        EtapaTraza::WorkerFinal,
+        EtapaTraza::EsperaReintento,
+    ];
+}
+
+impl fmt::Display for EtapaTraza {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Paso de una transacción por una etapa, en microsegundos desde UNIX EPOCH
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tramo {
+    pub etapa: EtapaTraza,
+    pub entrada: u64,
+    pub salida: Option<u64>,
+}
+
+/// Recorrido de una transacción por las etapas del pipeline. Viaja con
+/// la transacción y cada etapa anota cuándo la tomó y cuándo la soltó;
+/// el tiempo entre la salida de una etapa y la entrada a la siguiente es
+/// el que pasó esperando en la cola. Los pasos solo se anotan si la
+/// corrida guarda las trazas; el inicio se anota siempre.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Traza {
+    /// Cuándo entró al pipeline
+    pub inicio: Option<u64>,
+    pub tramos: Vec<Tramo>,
+}
+
+impl Traza {
+    pub fn iniciar(&mut self) {
+        self.inicio = Some(ahora());
+    }
+
+    pub fn entrar(&mut self, etapa: EtapaTraza) {
+        self.tramos.push(Tramo {
+            etapa,
+            entrada: ahora(),
+            salida: None,
+        });
+    }
+
+    /// Cierra el último paso abierto por la etapa
+    pub fn salir(&mut self, etapa: EtapaTraza) {
+        if let Some(tramo) = self.tramos.iter_mut().rev().find(|t| t.etapa == etapa && t.salida.is_none()) {
+            tramo.salida = Some(ahora());
+        }
+    }
+
+    /// Desde que entró al pipeline hasta ahora
+    pub fn duracion(&self) -> Option<Duration> {
+        let inicio = self.inicio?;
+        Some(Duration::from_micros(ahora().saturating_sub(inicio)))
+    }
+
+    /// Tiempo en cola antes de cada etapa, salvo la primera
+    fn esperas(&self) -> impl Iterator<Item = (EtapaTraza, u64)> + '_ {
+        self.tramos.windows(2).filter_map(|par| {
+            par[0].salida.map(|salida| (par[1].etapa, par[1].entrada.saturating_sub(salida)))
+        })
+    }
+}
+
+fn ahora() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).expect("SystemTime before UNIX EPOCH!").as_micros() as u64
+}
+
+/// Percentiles de tiempo en cola y en proceso de una etapa, en microsegundos
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatenciasEtapa {
+    pub etapa: EtapaTraza,
+    pub cantidad: usize,
+    pub cola: [u64; 4],
+    pub proceso: [u64; 4],
+}
+
+impl fmt::Display for LatenciasEtapa {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [c50, c90, c99, cmax] = self.cola;
+        let [p50, p90, p99, pmax] = self.proceso;
+        write!(
+            f,
+            "{}: {} transacciones. Cola p50 {}us p90 {}us p99 {}us máx {}us. Proceso p50 {}us p90 {}us p99 {}us máx {}us",
+            self.etapa, self.cantidad, c50, c90, c99, cmax, p50, p90, p99, pmax
+        )
+    }
+}
+
+/// Percentiles 50, 90, 99 y máximo por rango más cercano
+fn percentiles(mut valores: Vec<u64>) -> [u64; 4] {
+    if valores.is_empty() {
+        return [0; 4];
+    }
+    valores.sort_unstable();
+    let rango = |p: f64| valores[((p * valores.len() as f64).ceil() as usize).clamp(1, valores.len()) - 1];
+
+    [rango(0.5), rango(0.9), rango(0.99), valores[valores.len() - 1]]
+}
+
+#[derive(Serialize)]
+struct EventoTraza {
+    name: String,
+    cat: &'static str,
+    ph: &'static str,
+    ts: u64,
+    dur: u64,
+    pid: u32,
+    tid: u32,
+    args: serde_json::Value,
+}
+
+/// Trazas de las transacciones que ya terminaron su recorrido
+#[derive(Default)]
+pub struct RegistroTrazas {
+    trazas: Mutex<Vec<(u32, String, Traza)>>,
+}
+
+impl RegistroTrazas {
+    /// Guarda la traza de una transacción que terminó con el resultado dado
+    pub fn completar(&self, id_transaccion: u32, resultado: &str, traza: Traza) {
+        self.trazas.lock().expect("trazas poisoned").push((id_transaccion, resultado.into(), traza));
+    }
+
+    pub fn latencias(&self) -> Vec<LatenciasEtapa> {
+        let mut colas: HashMap<EtapaTraza, Vec<u64>> = HashMap::new();
+        let mut procesos: HashMap<EtapaTraza, Vec<u64>> = HashMap::new();
+        for (_, _, traza) in self.trazas.lock().expect("trazas poisoned").iter() {
+            for tramo in &traza.tramos {
+                if let Some(salida) = tramo.salida {
+                    procesos.entry(tramo.etapa).or_default().push(salida.saturating_sub(tramo.entrada));
+                }
+            }
+            for (etapa, espera) in traza.esperas() {
+                colas.entry(etapa).or_default().push(espera);
+            }
+        }
+
+        EtapaTraza::TODAS
+            .iter()
+            .filter_map(|etapa| {
+                let proceso = procesos.remove(etapa)?;
+                Some(LatenciasEtapa {
+                    etapa: *etapa,
+                    cantidad: proceso.len(),
+                    cola: percentiles(colas.remove(etapa).unwrap_or_default()),
+                    proceso: percentiles(proceso),
+                })
+            })
+            .collect()
+    }
+
+    /// Escribe las trazas en formato Chrome trace event, para abrirlas
+    /// con chrome://tracing o Perfetto. Cada transacción es un hilo con
+    /// sus pasos por las etapas y sus esperas en cola.
+    pub fn exportar_chrome(&self, ruta_archivo: &str) -> io::Result<()> {
+        let mut eventos = vec![];
+        for (id, resultado, traza) in self.trazas.lock().expect("trazas poisoned").iter() {
+            eventos.push(json!({
+                "name": "thread_name",
+                "ph": "M",
+                "pid": 1,
+                "tid": id,
+                "args": { "name": format!("Transacción {}", id) },
+            }));
+            let mut salida_anterior = None;
+            for tramo in &traza.tramos {
+                if let Some(salida) = salida_anterior {
+                    eventos.push(serde_json::to_value(EventoTraza {
+                        name: format!("Cola {}", tramo.etapa),
+                        cat: "cola",
+                        ph: "X",
+                        ts: salida,
+                        dur: tramo.entrada.saturating_sub(salida),
+                        pid: 1,
+                        tid: *id,
+                        args: json!({ "transaccion": id }),
+                    })?);
+                }
+                let salida = match tramo.salida {
+                    Some(s) => s,
+                    None => continue
+                };
+                eventos.push(serde_json::to_value(EventoTraza {
+                    name: tramo.etapa.to_string(),
+                    cat: "etapa",
+                    ph: "X",
+                    ts: tramo.entrada,
+                    dur: salida.saturating_sub(tramo.entrada),
+                    pid: 1,
+                    tid: *id,
+                    args: json!({ "transaccion": id, "resultado": resultado }),
+                })?);
+                salida_anterior = Some(salida);
+            }
+        }
+
+        let archivo = BufWriter::new(File::create(ruta_archivo)?);
+        serde_json::to_writer(archivo, &json!({ "traceEvents": eventos, "displayTimeUnit": "ms" }))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latencias_separan_el_tiempo_en_cola_del_tiempo_en_proceso() {
+        let registro = RegistroTrazas::default();
+        for id in 0..10 {
+            registro.completar(id, "liquidada", Traza {
+                inicio: Some(0),
+                tramos: vec![
+                    Tramo { etapa: EtapaTraza::Procesador, entrada: 0, salida: Some(10) },
+                    Tramo { etapa: EtapaTraza::Worker, entrada: 10 + 100 * id as u64, salida: Some(1_000 + 100 * id as u64) },
+                ],
+            });
+        }
+
+        let latencias = registro.latencias();
+        assert_eq!(latencias.len(), 2);
+        assert_eq!(latencias[0].proceso, [10, 10, 10, 10]);
+        assert_eq!(latencias[1].etapa, EtapaTraza::Worker);
+        assert_eq!(latencias[1].cola, [400, 800, 900, 900]);
+        assert_eq!(latencias[1].proceso, [990, 990, 990, 990]);
+    }
+
+    #[test]
+    fn exportar_chrome_escribe_etapas_y_esperas() {
+        let ruta_archivo_tests = "archivo_tests_8.json";
+        let mut traza = Traza::default();
+        traza.entrar(EtapaTraza::Worker);
+        traza.salir(EtapaTraza::Worker);
+        traza.entrar(EtapaTraza::ProcesadorIA);
+        traza.salir(EtapaTraza::ProcesadorIA);
+        let registro = RegistroTrazas::default();
+        registro.completar(3, "rechazada", traza);
+        registro.exportar_chrome(ruta_archivo_tests).unwrap();
+
+        let exportado: serde_json::Value = serde_json::from_reader(File::open(ruta_archivo_tests).unwrap()).unwrap();
+        let nombres: Vec<_> = exportado["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|evento| evento["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(nombres, vec!["thread_name", "Worker", "Cola ProcesadorIA", "ProcesadorIA"]);
+        assert_eq!(exportado["traceEvents"][3]["args"]["resultado"], "rechazada");
+    }
+}
@@ -0,0 +1,237 @@
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    archivos::{self, ArchivosCorrida, RegistroEstadoFinal, RegistroRechazo, RegistroSaldo},
+    estados::EstadoTransaccion,
+    procesador::MOTIVO_ID_REPETIDO,
+    redaccion::Redactor,
+    transaccion::TipoTransaccion,
+};
+
+// Los saldos son f32: se toleran diferencias de redondeo
+const TOLERANCIA_SALDO: f32 = 0.01;
+
+/// Resultado de conciliar los archivos de salida de una corrida con
+/// sus entradas
+#[derive(Debug, Default, PartialEq)]
+pub struct Verificacion {
+    pub transacciones: usize,
+    pub liquidadas: usize,
+    pub rechazadas: usize,
+    pub fallidas: usize,
+    /// Cada diferencia encontrada, descripta para el log
+    pub discrepancias: Vec<String>,
+}
+
+impl fmt::Display for Verificacion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} transacciones: {} liquidadas, {} rechazadas, {} fallidas; {} discrepancias",
+            self.transacciones, self.liquidadas, self.rechazadas, self.fallidas, self.discrepancias.len()
+        )
+    }
+}
+
+/// Concilia las salidas de una corrida:
+/// - cada transacción de la entrada termina en exactamente uno de los
+///   archivos de saldos, rechazadas o fallidas, con su tipo y monto
+/// - `estados.csv` coincide con el archivo en el que terminó
+/// - cada fila con un id repetido está en rechazadas por ese motivo
+/// - el saldo final de cada cliente en `saldos.csv` es su saldo
+///   inicial más lo liquidado, en el orden en que se liquidó
+///
+/// Los ids de clientes de los mensajes pasan por el redactor.
+pub fn verificar(archivos: &ArchivosCorrida, redactor: &Redactor) -> Result<Verificacion, csv::Error> {
+    let (entrada, mut repetidas) = archivos::leer_entrada(&archivos.transacciones)?;
+    let clientes = archivos::leer_clientes(&archivos.clientes)?;
+    let saldos: Vec<RegistroSaldo> = archivos::leer(&archivos.saldos)?;
+    let rechazadas: Vec<RegistroRechazo> = archivos::leer(&archivos.rechazadas)?;
+    let fallidas: Vec<RegistroRechazo> = archivos::leer(&archivos.fallidas)?;
+    let estados: HashMap<u32, EstadoTransaccion> = archivos::leer::<RegistroEstadoFinal>(&archivos.estados)?
+        .into_iter()
+        .map(|registro| (registro.id_transaccion, registro.estado))
+        .collect();
+
+    let mut verificacion = Verificacion {
+        transacciones: entrada.len() + repetidas.len(),
+        liquidadas: saldos.len(),
+        rechazadas: rechazadas.len(),
+        fallidas: fallidas.len(),
+        discrepancias: vec![],
+    };
+    let discrepancias = &mut verificacion.discrepancias;
+    let por_id: HashMap<_, _> = entrada.iter().map(|transaccion| (transaccion.id, transaccion)).collect();
+
+    // Las filas con un id repetido no cuentan como destino de la
+    // transacción que entró primero
+    let (rechazos_repetidos, rechazadas): (Vec<_>, Vec<_>) = rechazadas.iter().partition(|rechazo| rechazo.motivo == MOTIVO_ID_REPETIDO);
+    for rechazo in rechazos_repetidos {
+        let repetida = repetidas.iter().position(|transaccion| {
+            transaccion.id == rechazo.id_transaccion && transaccion.tipo == rechazo.tipo && transaccion.monto == rechazo.monto
+        });
+        match repetida {
+            Some(indice) => drop(repetidas.swap_remove(indice)),
+            None => discrepancias.push(format!(
+                "La transacción {} figura en {} con id repetido pero no está repetida en {}",
+                rechazo.id_transaccion, archivos.rechazadas, archivos.transacciones
+            )),
+        }
+    }
+    for transaccion in &repetidas {
+        discrepancias.push(format!(
+            "La transacción repetida {} ({} por {}) no está en {}",
+            transaccion.id, transaccion.tipo, transaccion.monto, archivos.rechazadas
+        ));
+    }
+
+    // Archivo en el que terminó cada transacción
+    let mut destinos: HashMap<u32, Vec<(&str, EstadoTransaccion)>> = HashMap::new();
+    let salidas = saldos
+        .iter()
+        .map(|saldo| (&archivos.saldos, EstadoTransaccion::Liquidada, saldo.id_transaccion, saldo.tipo, saldo.monto))
+        .chain(rechazadas.into_iter().map(|rechazo| (&archivos.rechazadas, EstadoTransaccion::Rechazada, rechazo.id_transaccion, rechazo.tipo, rechazo.monto)))
+        .chain(fallidas.iter().map(|rechazo| (&archivos.fallidas, EstadoTransaccion::Fallida, rechazo.id_transaccion, rechazo.tipo, rechazo.monto)));
+    for (archivo, estado, id, tipo, monto) in salidas {
+        match por_id.get(&id) {
+            None => discrepancias.push(format!("La transacción {} de {} no está en {}", id, archivo, archivos.transacciones)),
+            Some(transaccion) if transaccion.tipo != tipo || transaccion.monto != monto => discrepancias.push(format!(
+                "La transacción {} figura en {} como {} por {} y en la entrada como {} por {}",
+                id, archivo, tipo, monto, transaccion.tipo, transaccion.monto
+            )),
+            Some(_) => {}
+        }
+        destinos.entry(id).or_default().push((archivo.as_str(), estado));
+    }
+
+    for transaccion in &entrada {
+        let destinos = destinos.get(&transaccion.id).map(Vec::as_slice).unwrap_or_default();
+        let estado = estados.get(&transaccion.id);
+        match destinos {
+            [] => discrepancias.push(format!("La transacción {} no terminó en ningún archivo de salida", transaccion.id)),
+            [(archivo, esperado)] if estado != Some(esperado) => discrepancias.push(format!(
+                "La transacción {} está en {} pero en {} figura como {:?}",
+                transaccion.id, archivo, archivos.estados, estado
+            )),
+            [_] => {}
+            _ => discrepancias.push(format!(
+                "La transacción {} terminó en más de un archivo: {:?}",
+                transaccion.id, destinos.iter().map(|(archivo, _)| *archivo).collect::<Vec<_>>()
+            )),
+        }
+    }
+
+    // Se repiten las liquidaciones sobre el saldo inicial, como las
+    // aplicó el worker final
+    let mut esperados: HashMap<_, _> = clientes.iter().map(|cliente| (cliente.id, cliente.get_saldos().contable)).collect();
+    let mut informados = HashMap::new();
+    for saldo in &saldos {
+        let id_cliente = match por_id.get(&saldo.id_transaccion) {
+            Some(transaccion) => transaccion.id_cliente,
+            None => continue,
+        };
+        match esperados.get_mut(&id_cliente) {
+            Some(esperado) => match saldo.tipo {
+                TipoTransaccion::CashIn => *esperado += saldo.monto,
+                TipoTransaccion::CashOut => *esperado -= saldo.monto,
+            },
+            None => {
+                discrepancias.push(format!(
+                    "El cliente {} de la transacción {} no está en {}",
+                    redactor.cliente(&id_cliente), saldo.id_transaccion, archivos.clientes
+                ));
+                continue;
+            }
+        }
+        informados.insert(id_cliente, saldo.saldo_final);
+    }
+    for cliente in clientes.iter() {
+        if let Some(informado) = informados.get(&cliente.id) {
+            let esperado = esperados[&cliente.id];
+            if (informado - esperado).abs() > TOLERANCIA_SALDO {
+                discrepancias.push(format!(
+                    "El saldo final del cliente {} es {} y debería ser {}",
+                    redactor.cliente(&cliente.id), informado, esperado
+                ));
+            }
+        }
+    }
+
+    Ok(verificacion)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn verificar_encuentra_transacciones_perdidas_y_saldos_incorrectos() {
+        let c1 = Uuid::new_v4();
+        let c2 = Uuid::new_v4();
+        let archivos = ArchivosCorrida {
+            transacciones: "archivo_tests_17_transacciones.csv".into(),
+            clientes: "archivo_tests_17_clientes.csv".into(),
+            saldos: "archivo_tests_17_saldos.csv".into(),
+            rechazadas: "archivo_tests_17_rechazadas.csv".into(),
+            fallidas: "archivo_tests_17_fallidas.csv".into(),
+            estados: "archivo_tests_17_estados.csv".into(),
+            ..ArchivosCorrida::default()
+        };
+        fs::write(&archivos.clientes, format!("User_id,Balance\n{},100\n{},50\n", c1, c2)).unwrap();
+        fs::write(&archivos.transacciones, format!(
+            "Transaction,User_id,Timestamp,Type,Amount\n1,{0},1,cash_out,30\n2,{1},2,cash_in,30\n3,{0},3,cash_out,500\n",
+            c1, c2
+        )).unwrap();
+        fs::write(&archivos.rechazadas, "Transaction,Type,Amount,Reason\n3,cash_out,500,Saldo insuficiente\n").unwrap();
+        fs::write(&archivos.fallidas, "Transaction,Type,Amount,Reason\n").unwrap();
+        fs::write(&archivos.estados, "Transaction,State,Reason,Timestamp\n1,liquidada,,1\n2,liquidada,,2\n3,rechazada,,3\n").unwrap();
+
+        fs::write(&archivos.saldos, "Transaction,Type,Amount,Final_balance\n1,cash_out,30,70\n2,cash_in,30,80\n").unwrap();
+        let verificacion = verificar(&archivos, &Redactor::default()).unwrap();
+        assert_eq!(verificacion.discrepancias, Vec::<String>::new());
+        assert_eq!((verificacion.transacciones, verificacion.liquidadas, verificacion.rechazadas), (3, 2, 1));
+
+        fs::write(&archivos.saldos, "Transaction,Type,Amount,Final_balance\n1,cash_out,30,71\n").unwrap();
+        let verificacion = verificar(&archivos, &Redactor::default()).unwrap();
+        assert_eq!(verificacion.discrepancias.len(), 2);
+        assert!(verificacion.discrepancias[0].contains("La transacción 2 no terminó"));
+        assert!(verificacion.discrepancias[1].contains(&format!("cliente {} es 71 y debería ser 70", c1)));
+    }
+
+    #[test]
+    fn verificar_concilia_los_ids_repetidos_con_rechazadas() {
+        let c1 = Uuid::new_v4();
+        let archivos = ArchivosCorrida {
+            transacciones: "archivo_tests_22_transacciones.csv".into(),
+            clientes: "archivo_tests_22_clientes.csv".into(),
+            saldos: "archivo_tests_22_saldos.csv".into(),
+            rechazadas: "archivo_tests_22_rechazadas.csv".into(),
+            fallidas: "archivo_tests_22_fallidas.csv".into(),
+            estados: "archivo_tests_22_estados.csv".into(),
+            ..ArchivosCorrida::default()
+        };
+        fs::write(&archivos.clientes, format!("User_id,Balance\n{},100\n", c1)).unwrap();
+        fs::write(&archivos.transacciones, format!(
+            "Transaction,User_id,Timestamp,Type,Amount\n1,{0},1,cash_in,30\n1,{0},2,cash_out,10\n2,{0},3,cash_in,5\n2,{0},4,cash_in,7\n",
+            c1
+        )).unwrap();
+        fs::write(&archivos.saldos, "Transaction,Type,Amount,Final_balance\n1,cash_in,30,130\n2,cash_in,5,135\n").unwrap();
+        fs::write(&archivos.fallidas, "Transaction,Type,Amount,Reason\n").unwrap();
+        fs::write(&archivos.estados, "Transaction,State,Reason,Timestamp\n1,liquidada,,1\n2,liquidada,,2\n").unwrap();
+
+        fs::write(&archivos.rechazadas, format!("Transaction,Type,Amount,Reason\n1,cash_out,10,{0}\n2,cash_in,7,{0}\n", MOTIVO_ID_REPETIDO)).unwrap();
+        let verificacion = verificar(&archivos, &Redactor::default()).unwrap();
+        assert_eq!(verificacion.discrepancias, Vec::<String>::new());
+        assert_eq!((verificacion.transacciones, verificacion.liquidadas, verificacion.rechazadas), (4, 2, 2));
+
+        fs::write(&archivos.rechazadas, format!("Transaction,Type,Amount,Reason\n1,cash_out,10,{}\n", MOTIVO_ID_REPETIDO)).unwrap();
+        let verificacion = verificar(&archivos, &Redactor::default()).unwrap();
+        assert_eq!(verificacion.discrepancias, vec!["La transacción repetida 2 (CashIn por 7) no está en archivo_tests_22_rechazadas.csv".to_string()]);
+        for archivo in [&archivos.transacciones, &archivos.clientes, &archivos.saldos, &archivos.rechazadas, &archivos.fallidas, &archivos.estados] {
+            let _ = fs::remove_file(archivo);
+        }
+    }
+}
@@ -1,19 +1,25 @@
 use std::{
-    sync::{
-        mpsc,
-        mpsc::{Sender, Receiver},
-        Arc, Mutex,
-    },
-    thread,
-    thread::JoinHandle,
+    time::{Duration, Instant},
     fmt,
 };
 
 use crate::{
-    logger::{Logger, TaggedLogger},
-    transaccion::{HashAutorizacion, Transaccion, TransaccionAutorizada}
+    cliente::buscar_cliente,
+    estados::EstadoTransaccion,
+    etapa::{ContextoEtapa, Etapa, Rechazo, Resultado},
+    logger::NivelLog,
+    metricas::{ESPERA_CUOTA_PROVEEDOR, LATENCIA_PROVEEDOR},
+    proveedor_autorizacion::{ConexionProveedor, ConfiguracionLote},
+    proveedor_externo::RespuestaAutorizacion,
+    transaccion::{TipoTransaccion, Transaccion, TransaccionAutorizada},
+    traza::EtapaTraza,
 };
 
+// Lo mínimo que espera una transacción que encontró el disyuntor abierto
+const ESPERA_DISYUNTOR_ABIERTO: Duration = Duration::from_millis(50);
+// El motivo del proveedor es texto libre: solo va al detalle
+const DENEGADA_POR_PROVEEDOR: &str = "Denegada por el proveedor";
+
 #[derive(Debug)]
 pub enum TipoWorker {
     CashIn,
@@ -26,143 +32,428 @@ impl fmt::Display for TipoWorker {
     }
 }
 
-/// Inicia n_workers del tipo tipo_worker
-pub fn iniciar_workers_de_tipo(n_workers: u32,
-                       tipo_worker: TipoWorker,
-                       rx_transacciones: Arc<Mutex<Receiver<Transaccion>>>,
-                       proveedor_autorizacion: Arc<Mutex<Receiver<HashAutorizacion>>>,
-                       tx_transacciones_autorizadas: Sender<TransaccionAutorizada>,
-                       logger: Arc<Logger>)
-    -> Vec<JoinHandle<()>>
-{
-    let mut handles_worker = vec![];
-    for worker_id in 0..n_workers {
-        handles_worker.push(
-            Worker::iniciar(
-                TaggedLogger::new(&format!("WORKER {} {}", tipo_worker, worker_id), logger.clone()),
-                rx_transacciones.clone(),
-                proveedor_autorizacion.clone(),
-                tx_transacciones_autorizadas.clone()
-            )
-        );
-    }
-
-    handles_worker
-}
+type ResultadoWorker = Resultado<Transaccion, TransaccionAutorizada>;
 
+/// Pide al proveedor la autorización de cada transacción. Los workers de
+/// un mismo tipo comparten el canal de entrada y la cola de reintentos.
 pub struct Worker {
-    log: TaggedLogger,
-    rx_transacciones: Arc<Mutex<Receiver<Transaccion>>>,
-    proveedor_autorizacion: Arc<Mutex<Receiver<HashAutorizacion>>>,
-    tx_transacciones_autorizadas: Sender<TransaccionAutorizada>,
+    proveedor: ConexionProveedor,
 }
 
 impl Worker {
-    pub fn iniciar(log: TaggedLogger,
-                   rx_transacciones: Arc<Mutex<Receiver<Transaccion>>>,
-                   proveedor_autorizacion: Arc<Mutex<Receiver<HashAutorizacion>>>,
-                   tx_transacciones_autorizadas: Sender<TransaccionAutorizada>)
-        -> JoinHandle<()>
-    {
-        thread::spawn(move || {
-            let worker = Self {
-                log,
-                rx_transacciones,
-                proveedor_autorizacion,
-                tx_transacciones_autorizadas
-            };
-
-            worker.procesar();
-        })
-    }
-
-    fn procesar(&self) {
-        self.log.write("Worker iniciado");
-        while let Some(transaccion) = self.obtener_transaccion() {
-            let hash = match self.obtener_hash() {
-                Ok(h) => h,
-                Err(_) => {
-                    println!("Se cerró el hasheador");
-                    break;
+    pub fn new(proveedor: ConexionProveedor) -> Self {
+        Self { proveedor }
+    }
+
+    /// Devuelve al cliente el monto retenido de un cash out que no se va
+    /// a liquidar
+    fn liberar_saldo(&self, transaccion: &Transaccion, contexto: &ContextoEtapa) {
+        if transaccion.tipo != TipoTransaccion::CashOut {
+            return;
+        }
+        if let Some(cliente) = buscar_cliente(&contexto.pipeline.clientes, transaccion.id_cliente) {
+            cliente.liberar(transaccion.monto);
+        }
+    }
+
+    fn procesar_respuesta(&self, transaccion: Transaccion, intentos: u32, respuesta: RespuestaAutorizacion, contexto: &ContextoEtapa) -> ResultadoWorker {
+        match respuesta {
+            RespuestaAutorizacion::Autorizada(hash) => {
+                self.registrar_respuesta_del_proveedor(contexto);
+                let transaccion_autorizada = TransaccionAutorizada::new(
+                    transaccion,
+                    hash
+                );
+                contexto.log.evento(
+                    NivelLog::Info,
+                    &format!("{}", transaccion_autorizada),
+                    &[("transaction_id", &transaccion_autorizada.transaccion.id), ("client_id", &transaccion_autorizada.transaccion.id_cliente)]
+                );
+
+                Resultado::Seguir(transaccion_autorizada)
+            },
+            RespuestaAutorizacion::Denegada(motivo) => {
+                self.registrar_respuesta_del_proveedor(contexto);
+                self.liberar_saldo(&transaccion, contexto);
+                Resultado::Rechazar(transaccion, Rechazo::new(DENEGADA_POR_PROVEEDOR, motivo))
+            },
+            RespuestaAutorizacion::Error(error) => {
+                if self.proveedor.disyuntor.registrar_falla() {
+                    contexto.log.warn("Disyuntor abierto: se dejan de enviar solicitudes al proveedor");
+                }
+                let intentos = intentos + 1;
+
+                if intentos >= self.proveedor.politica.maximo_intentos {
+                    self.liberar_saldo(&transaccion, contexto);
+                    Resultado::Fallar(transaccion, format!("Sin autorización luego de {} intentos: {}", intentos, error))
+                } else {
+                    let espera = self.proveedor.politica.espera(intentos, &mut rand::thread_rng());
+                    contexto.log.evento(
+                        NivelLog::Warn,
+                        &format!("Error del proveedor (intento {}): {}. Reintentando en {:?}", intentos, error, espera),
+                        &[("transaction_id", &transaccion.id)]
+                    );
+                    Resultado::Reintentar(transaccion, espera)
                 }
-            };
+            }
+        }
+    }
+
+    fn registrar_respuesta_del_proveedor(&self, contexto: &ContextoEtapa) {
+        if self.proveedor.disyuntor.registrar_exito() {
+            contexto.log.write("Disyuntor cerrado: el proveedor volvió a responder");
+        }
+    }
+}
 
-            let transaccion_autorizada = TransaccionAutorizada::new(
-                transaccion, 
-                hash
-            );
-            self.log.write(&*format!("{}", transaccion_autorizada));
+impl Etapa for Worker {
+    type Entrada = Transaccion;
+    type Salida = TransaccionAutorizada;
+    const METRICAS: &'static str = "worker";
+    const TRAZA: Option<EtapaTraza> = Some(EtapaTraza::Worker);
+    const ESTADO: Option<EstadoTransaccion> = Some(EstadoTransaccion::Autorizada);
 
-            self.enviar_transaccion_autorizada(transaccion_autorizada);
+    /// Retiene el monto de un cash out nuevo antes de pedir su
+    /// autorización. Si el saldo disponible no alcanza la transacción se
+    /// rechaza sin llamar al proveedor.
+    fn admitir(&mut self, transaccion: &Transaccion, contexto: &ContextoEtapa) -> Result<(), Rechazo> {
+        if transaccion.tipo != TipoTransaccion::CashOut {
+            return Ok(());
         }
-        self.log.write("Worker terminado");
-    }
-
-    fn obtener_transaccion(&self) -> Option<Transaccion> {
-        let proveedor = self
-            .rx_transacciones
-            .lock()
-            .expect("Mutex de transacciones poisoned");
-        
-        match proveedor.recv() {
-            Ok(t) => Some(t),
-            Err(_) => None
+
+        match buscar_cliente(&contexto.pipeline.clientes, transaccion.id_cliente) {
+            Some(cliente) if cliente.retener(transaccion.monto) => {
+                contexto.log.evento(
+                    NivelLog::Debug,
+                    &format!("Retenidos {} (disponible {})", transaccion.monto, cliente.get_saldos().disponible()),
+                    &[("transaction_id", &transaccion.id), ("client_id", &cliente.id)]
+                );
+                Ok(())
+            }
+            Some(_) => Err("Saldo disponible insuficiente".into()),
+            None => Err("Cliente desconocido".into()),
         }
     }
 
-    fn obtener_hash(&self) -> Result<HashAutorizacion, mpsc::RecvError> {
-        let proveedor = self
-            .proveedor_autorizacion
-            .lock()
-            .expect("Mutex de hashes poisoned");
-        
-        proveedor.recv()
+    fn procesar(&mut self, transaccion: Transaccion, intentos: u32, contexto: &ContextoEtapa) -> ResultadoWorker {
+        self.procesar_lote(vec![(transaccion, intentos)], contexto).remove(0)
     }
 
-    fn enviar_transaccion_autorizada(&self, transaccion_autorizada: TransaccionAutorizada) {
-        self.tx_transacciones_autorizadas.send(transaccion_autorizada).expect("Channel cerrado");
+    fn lote(&self) -> Option<ConfiguracionLote> {
+        self.proveedor.lote
+    }
+
+    /// Pide la autorización de las transacciones en una sola llamada al
+    /// proveedor y resuelve cada una según su propia respuesta
+    fn procesar_lote(&mut self, lote: Vec<(Transaccion, u32)>, contexto: &ContextoEtapa) -> Vec<ResultadoWorker> {
+        if !self.proveedor.disyuntor.permitir() {
+            // No llamar a un proveedor que está fallando. Encontrarlo así
+            // cuenta como un intento: si la caída sigue, la transacción
+            // termina en fallidas en vez de esperar para siempre.
+            let politica = &self.proveedor.politica;
+            let espera = self.proveedor.disyuntor.espera_hasta_la_prueba().min(politica.espera_maxima).max(ESPERA_DISYUNTOR_ABIERTO);
+            return lote
+                .into_iter()
+                .map(|(transaccion, intentos)| {
+                    let intentos = intentos + 1;
+                    if intentos >= politica.maximo_intentos {
+                        self.liberar_saldo(&transaccion, contexto);
+                        return Resultado::Fallar(transaccion, format!("Sin autorización luego de {} intentos: el disyuntor sigue abierto", intentos));
+                    }
+                    contexto.log.evento(
+                        NivelLog::Debug,
+                        &format!("Disyuntor {:?}: transacción estacionada (intento {})", self.proveedor.disyuntor.estado(), intentos),
+                        &[("transaction_id", &transaccion.id)]
+                    );
+                    Resultado::Reintentar(transaccion, espera)
+                })
+                .collect();
+        }
+
+        let tipos: Vec<_> = lote.iter().map(|(transaccion, _)| transaccion.tipo).collect();
+        let espera = self.proveedor.esperar_turno(&tipos);
+        contexto.pipeline.metricas.observar(&ESPERA_CUOTA_PROVEEDOR, &[("worker", contexto.log.tag())], espera.as_secs_f64());
+        let inicio = Instant::now();
+        let respuestas = match self.proveedor.lote {
+            Some(_) => {
+                let transacciones: Vec<_> = lote.iter().map(|(transaccion, _)| transaccion).collect();
+                contexto.log.write(&format!("Solicitando autorización de un lote de {} transacciones", transacciones.len()));
+                self.proveedor.solicitar_lote(&transacciones)
+            }
+            None => self.proveedor.solicitar(&lote[0].0).map(|respuesta| vec![respuesta]),
+        };
+        contexto.pipeline.metricas.observar(&LATENCIA_PROVEEDOR, &[("worker", contexto.log.tag())], inicio.elapsed().as_secs_f64());
+
+        match respuestas {
+            Ok(respuestas) => {
+                // Lo que quedó sin respuesta no puede quedar en el aire
+                let (pedidas, contestadas) = (lote.len(), respuestas.len());
+                if contestadas != pedidas {
+                    contexto.log.warn(&format!("El proveedor contestó {} respuestas para un lote de {}", contestadas, pedidas));
+                }
+                let mut respuestas = respuestas.into_iter();
+                lote
+                    .into_iter()
+                    .map(|(transaccion, intentos)| match respuestas.next() {
+                        Some(respuesta) => self.procesar_respuesta(transaccion, intentos, respuesta, contexto),
+                        None => {
+                            self.liberar_saldo(&transaccion, contexto);
+                            Resultado::Fallar(transaccion, format!("Sin respuesta del proveedor en un lote de {} ({} respuestas)", pedidas, contestadas))
+                        }
+                    })
+                    .collect()
+            }
+            Err(_) => {
+                self.proveedor.disyuntor.registrar_falla();
+                lote
+                    .into_iter()
+                    .map(|(transaccion, _)| {
+                        self.liberar_saldo(&transaccion, contexto);
+                        Resultado::Fallar(transaccion, "El proveedor dejó de atender solicitudes".to_string())
+                    })
+                    .collect()
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::sync::mpsc::channel;
+    use std::sync::{Arc, Mutex, atomic::AtomicU32, mpsc::channel, mpsc::Receiver};
 
     use super::*;
+    use rand::{SeedableRng, prelude::StdRng};
     use uuid::Uuid;
-    use crate::transaccion::TipoTransaccion;
+    use crate::{
+        apagado::Apagado,
+        cliente::Cliente,
+        estados::AlmacenEstados,
+        etapa::{Canal, EntradaEtapa, Receptor, SalidasEtapa, Siguiente, iniciar_etapa},
+        logger::{Logger, TaggedLogger},
+        metricas::RegistroMetricas,
+        redaccion::Redactor,
+        contexto::ContextoPipeline,
+        proveedor_autorizacion::{ProveedorAutorizacion, ProveedorCerrado, ProveedorMock},
+        proveedor_externo::ErrorProveedor,
+        reintentos::{Disyuntor, PoliticaReintentos},
+        transaccion::TransaccionRechazada,
+        supervisor::Latido,
+    };
 
     #[test]
     fn worker_solicitia_hash_y_envia_transaccion_autorizada() {
         let id_transaccion = 2;
-        let transaccion = Transaccion {
-            id: id_transaccion,
-            id_cliente: Uuid::new_v4(),
-            timestamp: 112315846_128,
-            tipo: TipoTransaccion::CashIn,
-            monto: 123.33
-        };
-
-        let (tx_transacciones, rx_transacciones_) = channel();
-        let rx_transacciones = Arc::new(Mutex::new(rx_transacciones_));
-        let (tx_autorizacion, rx_autorizacion_) = channel();
-        let rx_autorizacion = Arc::new(Mutex::new(rx_autorizacion_));
-        let (tx_transacciones_autorizadas, rx_transacciones_autorizadas) = channel();
-
-        tx_transacciones.send(transaccion).unwrap();
         let hash = Uuid::new_v4();
-        tx_autorizacion.send(hash).unwrap();
+        let salidas = iniciar_worker_con_respuesta(id_transaccion, RespuestaAutorizacion::Autorizada(hash));
 
-        Worker::iniciar(crear_logger(),
-                   rx_transacciones,
-                   rx_autorizacion,
-                   tx_transacciones_autorizadas);
-        let recibida = rx_transacciones_autorizadas.recv().unwrap();
+        let recibida = salidas.autorizadas.recv().unwrap();
         assert_eq!(recibida.transaccion.id, id_transaccion);
         assert_eq!(recibida.autorizacion, hash);
     }
 
+    #[test]
+    fn worker_envia_transaccion_denegada_a_rechazos() {
+        let id_transaccion = 3;
+        let salidas = iniciar_worker_con_respuesta(id_transaccion, RespuestaAutorizacion::Denegada("Sin fondos".into()));
+
+        let rechazada = salidas.rechazadas.recv().unwrap();
+        assert_eq!(rechazada.transaccion.id, id_transaccion);
+        assert_eq!(rechazada.motivo, "Sin fondos");
+        assert!(salidas.autorizadas.recv().is_err());
+        // El texto del proveedor no llega a la etiqueta de la métrica
+        let exportado = salidas.metricas.exportar_prometheus();
+        assert!(exportado.contains("motivo=\"Denegada por el proveedor\""));
+        assert!(!exportado.contains("Sin fondos"));
+    }
+
+    #[test]
+    fn worker_envia_a_fallidas_cuando_se_agotan_los_reintentos() {
+        let id_transaccion = 4;
+        let salidas = iniciar_worker_con_respuesta(id_transaccion, RespuestaAutorizacion::Error(ErrorProveedor::Interno));
+
+        let fallida = salidas.fallidas.recv().unwrap();
+        assert_eq!(fallida.transaccion.id, id_transaccion);
+        assert!(fallida.motivo.starts_with("Sin autorización luego de 3 intentos"));
+        assert_eq!(salidas.estados.consultar(id_transaccion).unwrap().estado, EstadoTransaccion::Fallida);
+        assert!(salidas.autorizadas.recv().is_err());
+        assert!(salidas.rechazadas.recv().is_err());
+    }
+
+    #[test]
+    fn worker_con_lotes_resuelve_cada_transaccion_del_lote_por_separado() {
+        let hash = Uuid::new_v4();
+        let mock = Arc::new(ProveedorMock::con_respuestas(vec![
+            RespuestaAutorizacion::Autorizada(hash),
+            RespuestaAutorizacion::Error(ErrorProveedor::Interno),
+            RespuestaAutorizacion::Denegada("Sin fondos".into()),
+        ]));
+        let politica = PoliticaReintentos {
+            maximo_intentos: 1,
+            ..PoliticaReintentos::default()
+        };
+        let conexion = ConexionProveedor::new(mock.clone(), politica, Arc::new(Disyuntor::default()))
+            .con_lotes(ConfiguracionLote { tamano: 3, espera: Duration::from_secs(1) });
+        let salidas = iniciar_worker(vec![crear_transaccion(1), crear_transaccion(2), crear_transaccion(3)], conexion, vec![]);
+
+        assert_eq!(salidas.autorizadas.recv().unwrap().transaccion.id, 1);
+        assert_eq!(salidas.fallidas.recv().unwrap().transaccion.id, 2);
+        assert_eq!(salidas.rechazadas.recv().unwrap().transaccion.id, 3);
+        assert!(salidas.autorizadas.recv().is_err());
+        assert_eq!(mock.lotes(), vec![3]);
+    }
+
+    #[test]
+    fn worker_cash_out_retiene_el_saldo_y_lo_libera_si_se_deniega() {
+        let cliente = Arc::new(Cliente::new(
+            Uuid::new_v4(),
+            Arc::new(AtomicU32::new(1)),
+            Arc::new(Mutex::new(StdRng::seed_from_u64(264)))
+        ));
+        let saldo = cliente.get_saldos().contable;
+        let mock = Arc::new(ProveedorMock::con_respuestas(vec![RespuestaAutorizacion::Denegada("Sin fondos".into())]));
+        let conexion = ConexionProveedor::new(mock.clone(), PoliticaReintentos::default(), Arc::new(Disyuntor::default()));
+        let transacciones = vec![
+            Transaccion { id: 5, id_cliente: cliente.id, tipo: TipoTransaccion::CashOut, monto: saldo * 2.0, ..crear_transaccion(5) },
+            Transaccion { id: 6, id_cliente: cliente.id, tipo: TipoTransaccion::CashOut, monto: 10.0, ..crear_transaccion(6) },
+        ];
+        let salidas = iniciar_worker(transacciones, conexion, vec![cliente.clone()]);
+
+        let sin_saldo = salidas.rechazadas.recv().unwrap();
+        assert_eq!(sin_saldo.transaccion.id, 5);
+        assert_eq!(sin_saldo.motivo, "Saldo disponible insuficiente");
+        let denegada = salidas.rechazadas.recv().unwrap();
+        assert_eq!(denegada.transaccion.id, 6);
+        assert_eq!(mock.solicitudes(), vec![6]);
+        assert_eq!(cliente.get_saldos().retenido, 0.0);
+        assert_eq!(cliente.get_saldos().contable, saldo);
+    }
+
+    #[test]
+    fn worker_con_lotes_envia_a_fallidas_lo_que_quedo_sin_respuesta() {
+        let cliente = Arc::new(Cliente::con_saldo(Uuid::new_v4(), 100.0));
+        let conexion = ConexionProveedor::new(Arc::new(ProveedorIncompleto), PoliticaReintentos::default(), Arc::new(Disyuntor::default()))
+            .con_lotes(ConfiguracionLote { tamano: 2, espera: Duration::from_secs(1) });
+        let transacciones = vec![
+            Transaccion { id_cliente: cliente.id, tipo: TipoTransaccion::CashOut, monto: 10.0, ..crear_transaccion(1) },
+            Transaccion { id_cliente: cliente.id, tipo: TipoTransaccion::CashOut, monto: 20.0, ..crear_transaccion(2) },
+        ];
+        let salidas = iniciar_worker(transacciones, conexion, vec![cliente.clone()]);
+
+        assert_eq!(salidas.autorizadas.recv().unwrap().transaccion.id, 1);
+        let fallida = salidas.fallidas.recv().unwrap();
+        assert_eq!((fallida.transaccion.id, fallida.motivo.as_str()), (2, "Sin respuesta del proveedor en un lote de 2 (1 respuestas)"));
+        assert_eq!(salidas.estados.consultar(2).unwrap().estado, EstadoTransaccion::Fallida);
+        // Solo queda retenido el cash out autorizado
+        assert_eq!(cliente.get_saldos().retenido, 10.0);
+    }
+
+    #[test]
+    fn worker_envia_a_fallidas_si_el_disyuntor_sigue_abierto() {
+        let cliente = Arc::new(Cliente::con_saldo(Uuid::new_v4(), 100.0));
+        let disyuntor = Arc::new(Disyuntor::new(1, Duration::from_secs(60)));
+        disyuntor.registrar_falla();
+        let politica = PoliticaReintentos {
+            maximo_intentos: 3,
+            espera_maxima: Duration::from_millis(1),
+            ..PoliticaReintentos::default()
+        };
+        let mock = Arc::new(ProveedorMock::default());
+        let transacciones = vec![Transaccion { id_cliente: cliente.id, tipo: TipoTransaccion::CashOut, monto: 10.0, ..crear_transaccion(7) }];
+        let salidas = iniciar_worker(transacciones, ConexionProveedor::new(mock.clone(), politica, disyuntor), vec![cliente.clone()]);
+
+        let fallida = salidas.fallidas.recv().unwrap();
+        assert_eq!((fallida.transaccion.id, fallida.motivo.as_str()), (7, "Sin autorización luego de 3 intentos: el disyuntor sigue abierto"));
+        assert!(mock.solicitudes().is_empty());
+        assert_eq!(cliente.get_saldos().retenido, 0.0);
+    }
+
+    /// Contesta solo la primera transacción de cada lote
+    struct ProveedorIncompleto;
+
+    impl ProveedorAutorizacion for ProveedorIncompleto {
+        fn solicitar(&self, transaccion: &Transaccion, _timeout: Duration) -> Result<RespuestaAutorizacion, ProveedorCerrado> {
+            Ok(RespuestaAutorizacion::Autorizada(ProveedorMock::hash_para(transaccion.id)))
+        }
+
+        fn solicitar_lote(&self, transacciones: &[&Transaccion], timeout: Duration) -> Result<Vec<RespuestaAutorizacion>, ProveedorCerrado> {
+            transacciones.iter().take(1).map(|transaccion| self.solicitar(transaccion, timeout)).collect()
+        }
+    }
+
+    struct ReceptoresSalidas {
+        autorizadas: Receptor<TransaccionAutorizada>,
+        rechazadas: Receiver<TransaccionRechazada>,
+        fallidas: Receiver<TransaccionRechazada>,
+        estados: Arc<AlmacenEstados>,
+        metricas: Arc<RegistroMetricas>,
+    }
+
+    /// Inicia un worker con una única transacción y un proveedor que
+    /// contesta siempre `respuesta`.
+    fn iniciar_worker_con_respuesta(id_transaccion: u32, respuesta: RespuestaAutorizacion) -> ReceptoresSalidas {
+        let politica = PoliticaReintentos {
+            maximo_intentos: 3,
+            espera_base: Duration::from_millis(1),
+            ..PoliticaReintentos::default()
+        };
+        let proveedor = Arc::new(ProveedorMock::con_respuestas(vec![respuesta]));
+
+        iniciar_worker(
+            vec![crear_transaccion(id_transaccion)],
+            ConexionProveedor::new(proveedor, politica, Arc::new(Disyuntor::default())),
+            vec![]
+        )
+    }
+
+    /// Inicia un worker que autoriza las transacciones dadas y termina
+    fn iniciar_worker(transacciones: Vec<Transaccion>,
+                      proveedor: ConexionProveedor,
+                      clientes: Vec<Arc<Cliente>>) -> ReceptoresSalidas {
+        let (tx_transacciones, rx_transacciones) = channel();
+        let estados = Arc::new(AlmacenEstados::new(crear_logger()));
+        let metricas = Arc::new(RegistroMetricas::default());
+        let (tx_transacciones_autorizadas, rx_transacciones_autorizadas) = Canal::Acotado(16).crear(Duration::from_millis(200));
+        let (tx_transacciones_rechazadas, rx_transacciones_rechazadas) = channel();
+        let (tx_transacciones_fallidas, rx_transacciones_fallidas) = channel();
+
+        for transaccion in transacciones {
+            estados.forzar(transaccion.id, EstadoTransaccion::Enrutada);
+            tx_transacciones.send(transaccion).unwrap();
+        }
+        drop(tx_transacciones);
+
+        iniciar_etapa(move || Worker::new(proveedor),
+                   crear_logger(),
+                   EntradaEtapa::new(rx_transacciones, "cash_in"),
+                   SalidasEtapa {
+                       siguiente: Some(Siguiente { emisor: tx_transacciones_autorizadas, cola: "ia" }),
+                       rechazadas: tx_transacciones_rechazadas,
+                       fallidas: tx_transacciones_fallidas,
+                   },
+                   ContextoPipeline {
+                       clientes: Arc::new(clientes),
+                       estados: estados.clone(),
+                       metricas: metricas.clone(),
+                       trazas: None,
+                       redactor: Arc::new(Redactor::default()),
+                       apagado: Arc::new(Apagado::default()),
+                       latido: Arc::new(Latido::default()),
+                   });
+
+        ReceptoresSalidas {
+            autorizadas: rx_transacciones_autorizadas,
+            rechazadas: rx_transacciones_rechazadas,
+            fallidas: rx_transacciones_fallidas,
+            estados,
+            metricas,
+        }
+    }
+
+    fn crear_transaccion(id: u32) -> Transaccion {
+        Transaccion::de_prueba(id, Uuid::new_v4(), TipoTransaccion::CashIn, 123.33)
+    }
+
     fn crear_logger() -> TaggedLogger {
         TaggedLogger::new("WORKER", Arc::new(Logger::new_to_stdout()))
     }
-}
\ No newline at end of file
+}
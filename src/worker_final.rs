@@ -1,78 +1,126 @@
-use std::{sync::{Arc, mpsc::Receiver}, thread, thread::JoinHandle, time::SystemTime};
+use std::{fs::File, time::{Instant, SystemTime}};
 use csv::Writer;
 
 use crate::{
-    logger::TaggedLogger,
+    logger::NivelLog,
     transaccion::{TipoTransaccion, TransaccionAutorizada, TransaccionExitosa},
-    cliente::Cliente,
+    cliente::buscar_cliente,
+    estados::EstadoTransaccion,
+    etapa::{ContextoEtapa, Etapa, Resultado},
+    metricas::LIQUIDACIONES_POR_SEGUNDO,
+    redaccion::Redactado,
+    traza::EtapaTraza,
 };
 
-const ARCHIVO_SALDOS: &str = "saldos.csv";
-
+/// Liquida cada transacción en el saldo del cliente y la escribe en el
+/// archivo de saldos finales
 pub struct WorkerFinal {
-    log: TaggedLogger,
-    rx_transacciones_validadas: Receiver<TransaccionAutorizada>,
-    clientes: Arc<Vec<Arc<Cliente>>>
+    ruta_archivo: String,
+    /// Se abre al iniciar el hilo de la etapa
+    writer: Option<Writer<File>>,
+    inicio: Instant,
+    liquidadas: u32,
 }
 
 impl WorkerFinal {
-    pub fn iniciar(log: TaggedLogger,
-                   rx_transacciones_validadas: Receiver<TransaccionAutorizada>,
-                   clientes: Arc<Vec<Arc<Cliente>>>)
-        -> JoinHandle<()>
-    {
-        thread::spawn(move || {
-            let worker = Self {
-                log,
-                rx_transacciones_validadas,
-                clientes
-            };
-
-            worker.procesar_transacciones();
-        })
+    pub fn new(ruta_archivo: &str) -> Self {
+        Self {
+            ruta_archivo: ruta_archivo.to_string(),
+            writer: None,
+            inicio: Instant::now(),
+            liquidadas: 0,
+        }
     }
 
-    fn procesar_transacciones(&self) {
-        self.log.write("Worker final iniciado");
-        let mut writer = Writer::from_path(ARCHIVO_SALDOS).expect("El archivo de saldos finales no pudo ser abierto");
-
-        while let Some(transaccion_autorizada) = self.obtener_transaccion() {
-            self.log.write(&*format!("Transacción recibida: {}", transaccion_autorizada));
-            let cliente_id = transaccion_autorizada.transaccion.id_cliente;
-            let cliente_objetivo = self.clientes.iter().find( |&cliente| cliente.id == cliente_id).expect(&*format!("No se encuentra cliente con id {}", cliente_id));
-            let monto = transaccion_autorizada.transaccion.monto;
-            match transaccion_autorizada.transaccion.tipo {
-                TipoTransaccion::CashIn => cliente_objetivo.cash_in(monto),
-                TipoTransaccion::CashOut => cliente_objetivo.cash_out(monto),
-            }
-            self.log.write(&*format!("Transacción procesada: {}", transaccion_autorizada));
-
-            let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).expect("SystemTime before UNIX EPOCH!").as_millis();
-            writer.serialize(TransaccionExitosa {
-                transaccion: transaccion_autorizada,
-                saldo_final: cliente_objetivo.get_saldo(),
-                timestamp
-            }).unwrap();
-        }
-        self.log.write("Worker final terminado");
+    fn writer(&mut self) -> &mut Writer<File> {
+        self.writer.as_mut().expect("El worker final no se inició")
+    }
+}
+
+impl Etapa for WorkerFinal {
+    type Entrada = TransaccionAutorizada;
+    type Salida = TransaccionExitosa;
+    const METRICAS: &'static str = "final";
+    const TRAZA: Option<EtapaTraza> = Some(EtapaTraza::WorkerFinal);
+    const ESTADO: Option<EstadoTransaccion> = Some(EstadoTransaccion::Liquidada);
+
+    fn iniciar(&mut self, contexto: &ContextoEtapa) {
+        contexto.log.write(&format!("Worker final iniciado, escribiendo en {}", self.ruta_archivo));
+        self.writer = Some(Writer::from_path(&self.ruta_archivo).expect("El archivo de saldos finales no pudo ser abierto"));
+        self.inicio = Instant::now();
     }
 
-    fn obtener_transaccion(&self) -> Option<TransaccionAutorizada> {
-        match self.rx_transacciones_validadas.recv() {
-            Ok(t) => Some(t),
-            Err(_) => None
+    fn procesar(&mut self, transaccion_autorizada: TransaccionAutorizada, _intentos: u32, contexto: &ContextoEtapa) -> Resultado<TransaccionAutorizada, TransaccionExitosa> {
+        contexto.log.debug(&format!("Transacción recibida: {}", transaccion_autorizada));
+        let cliente_id = transaccion_autorizada.transaccion.id_cliente;
+        let cliente_objetivo = buscar_cliente(&contexto.pipeline.clientes, cliente_id).unwrap_or_else(|| panic!("No se encuentra cliente con id {}", contexto.pipeline.redactor.cliente(&cliente_id)));
+        let monto = transaccion_autorizada.transaccion.monto;
+        match transaccion_autorizada.transaccion.tipo {
+            TipoTransaccion::CashIn => cliente_objetivo.cash_in(monto),
+            // El worker cash out retuvo el monto antes de pedir la autorización
+            TipoTransaccion::CashOut => cliente_objetivo.capturar(monto),
         }
+        contexto.log.evento(
+            NivelLog::Info,
+            &format!("Transacción procesada: {}", transaccion_autorizada),
+            &[("transaction_id", &transaccion_autorizada.transaccion.id), ("client_id", &cliente_id)]
+        );
+
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).expect("SystemTime before UNIX EPOCH!").as_millis();
+        let exitosa = TransaccionExitosa {
+            transaccion: transaccion_autorizada,
+            saldo_final: cliente_objetivo.get_saldos(),
+            timestamp
+        };
+        self.writer().serialize(Redactado(&exitosa, &contexto.pipeline.redactor)).unwrap();
+        self.liquidadas += 1;
+        contexto.pipeline.metricas.fijar(
+            &LIQUIDACIONES_POR_SEGUNDO,
+            &[("worker", contexto.log.tag())],
+            self.liquidadas as f64 / self.inicio.elapsed().as_secs_f64()
+        );
+
+        Resultado::Seguir(exitosa)
+    }
+
+    /// Si no hay nada pendiente vacía el buffer del archivo, así una
+    /// corrida abortada no deja filas a medio escribir
+    fn en_espera(&mut self, _contexto: &ContextoEtapa) {
+        self.writer().flush().expect("No se pudo escribir el archivo de saldos finales");
+    }
+
+    fn terminar(&mut self, contexto: &ContextoEtapa) {
+        self.writer().flush().expect("No se pudo escribir el archivo de saldos finales");
+        let duracion = self.inicio.elapsed();
+        contexto.log.write(&format!(
+            "{} transacciones liquidadas en {:.2?} ({:.1} por segundo)",
+            self.liquidadas, duracion, self.liquidadas as f64 / duracion.as_secs_f64()
+        ));
     }
 }
 
 #[cfg(test)]
+// Los tests originales escriben los literales a su manera
+#[allow(clippy::mistyped_literal_suffixes, clippy::inconsistent_digit_grouping, clippy::redundant_field_names)]
 mod tests {
-    use std::sync::{Mutex, atomic::AtomicU32, mpsc::channel};
+    use std::sync::{Arc, Mutex, atomic::AtomicU32, mpsc::channel};
 
     use super::*;
     use csv::StringRecord;
     use rand::{SeedableRng, prelude::StdRng};
-    use crate::{logger::Logger, transaccion::{Transaccion, TransaccionAutorizada, TipoTransaccion}};
+    use crate::{
+        apagado::Apagado,
+        cliente::Cliente,
+        contexto::ContextoPipeline,
+        estados::AlmacenEstados,
+        etapa::{EntradaEtapa, SalidasEtapa, iniciar_etapa},
+        metricas::RegistroMetricas,
+        redaccion::Redactor,
+        logger::{Logger, TaggedLogger},
+        transaccion::{Transaccion, TransaccionAutorizada, TipoTransaccion},
+        traza::Traza,
+        supervisor::Latido,
+    };
     use uuid::Uuid;
 
     #[test]
@@ -81,7 +129,7 @@ mod tests {
 
         let (tx_transacciones_validadas, rx_transacciones_validadas) = channel();
 
-        let saldo_anterior = cliente.get_saldo();
+        let saldo_anterior = cliente.get_saldos().contable;
         let transaccion_id = 2;
         let monto = 123.33;
         let transaccion = Transaccion {
@@ -89,7 +137,9 @@ mod tests {
             id_cliente: cliente.id,
             timestamp: 112315846_128,
             tipo: TipoTransaccion::CashIn,
-            monto
+            monto,
+            traza: Traza::default(),
+            prioridad: None
         };
         let hash = Uuid::new_v4();
         let transaccion_autorizada = TransaccionAutorizada {
@@ -97,14 +147,30 @@ mod tests {
             autorizacion: hash
         };
 
+        let estados = Arc::new(AlmacenEstados::new(crear_logger()));
+        estados.forzar(transaccion_id, EstadoTransaccion::Validada);
+
         tx_transacciones_validadas.send(transaccion_autorizada).unwrap();
-        let handle = WorkerFinal::iniciar(crear_logger(),
-                   rx_transacciones_validadas,
-                   Arc::new(vec![cliente.clone()]));
+        let ruta_archivo_tests = "archivo_tests_16.csv";
+        let (tx_transacciones_rechazadas, _) = channel();
+        let (tx_transacciones_fallidas, _) = channel();
+        let handle = iniciar_etapa(move || WorkerFinal::new(ruta_archivo_tests),
+                   crear_logger(),
+                   EntradaEtapa::new(rx_transacciones_validadas, "final"),
+                   SalidasEtapa { siguiente: None, rechazadas: tx_transacciones_rechazadas, fallidas: tx_transacciones_fallidas },
+                   ContextoPipeline {
+                       clientes: Arc::new(vec![cliente.clone()]),
+                       estados: estados.clone(),
+                       metricas: Arc::new(RegistroMetricas::default()),
+                       trazas: None,
+                       redactor: Arc::new(Redactor::default()),
+                       apagado: Arc::new(Apagado::default()),
+                       latido: Arc::new(Latido::default()),
+                   });
         drop(tx_transacciones_validadas);
         handle.join().unwrap();
 
-        let mut reader = csv::Reader::from_path(ARCHIVO_SALDOS).unwrap();
+        let mut reader = csv::Reader::from_path(ruta_archivo_tests).unwrap();
         let mut record = StringRecord::new();
         reader.read_record(&mut record).unwrap();
         assert_eq!(record[0], transaccion_id.to_string());
@@ -113,6 +179,9 @@ mod tests {
         assert_eq!(record[4], monto.to_string());
         assert_eq!(record[5], hash.to_hyphenated().to_string());
         assert_eq!(record[7], (saldo_anterior + monto).to_string());
+        assert_eq!(record[8].parse::<f32>().unwrap(), 0.0);
+        assert_eq!(record[9], (saldo_anterior + monto).to_string());
+        assert_eq!(estados.consultar(transaccion_id).unwrap().estado, EstadoTransaccion::Liquidada);
     }
 
     fn crear_cliente() -> Cliente {
@@ -0,0 +1,103 @@
+use std::{
+    fs,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    thread,
+    time::Duration,
+};
+
+const ESTADOS_FINALES: [&str; 3] = ["liquidada", "rechazada", "fallida"];
+
+fn crear_directorio_de_trabajo(nombre: &str) -> PathBuf {
+    let directorio = std::env::temp_dir().join(format!("dinero_oxidado_{}_{}", nombre, std::process::id()));
+    let _ = fs::remove_dir_all(&directorio);
+    fs::create_dir_all(&directorio).unwrap();
+
+    directorio
+}
+
+/// Simula una corrida larga y arranca a procesarla, esperando a que el
+/// procesador empiece a leer el archivo
+fn iniciar_procesamiento(directorio: &Path) -> (Child, BufReader<std::process::ChildStdout>) {
+    let simulacion = Command::new(env!("CARGO_BIN_EXE_dinero-oxidado"))
+        .current_dir(directorio)
+        .args(["simular", "-c", "100", "-s", "3"])
+        .output()
+        .unwrap();
+    assert!(simulacion.status.success());
+
+    let mut proceso = Command::new(env!("CARGO_BIN_EXE_dinero-oxidado"))
+        .current_dir(directorio)
+        .args(["procesar"])
+        .env("DINERO_WORKERS_COLA", "10")
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("No se pudo correr el pipeline");
+    let mut salida = BufReader::new(proceso.stdout.take().unwrap());
+    let mut linea = String::new();
+    while !linea.contains("Iniciando procesador del archivo") {
+        linea.clear();
+        assert!(salida.read_line(&mut linea).unwrap() > 0, "El pipeline terminó antes de procesar");
+    }
+    thread::sleep(Duration::from_millis(300));
+
+    (proceso, salida)
+}
+
+fn enviar_sigint(proceso: &Child) {
+    let estado = Command::new("kill").args(["-INT", &proceso.id().to_string()]).status().unwrap();
+    assert!(estado.success());
+}
+
+fn leer_resto(salida: BufReader<std::process::ChildStdout>) -> String {
+    salida.lines().map(Result::unwrap).collect::<Vec<_>>().join("\n")
+}
+
+#[test]
+fn la_primera_senal_termina_lo_leido_y_deja_el_resto_sin_leer() {
+    let directorio = crear_directorio_de_trabajo("apagado_drenaje");
+    let (mut proceso, salida) = iniciar_procesamiento(&directorio);
+
+    enviar_sigint(&proceso);
+    let log = leer_resto(salida);
+    assert_eq!(proceso.wait().unwrap().code(), Some(0), "{}", log);
+    assert!(log.contains("SIGINT recibida"), "{}", log);
+    assert!(log.contains("Corrida interrumpida: se terminaron las"), "{}", log);
+    assert!(log.contains("Terminado"), "{}", log);
+    assert!(!directorio.join("en_curso.csv").exists());
+
+    // Todo lo leído llegó a un estado final y los saldos quedaron completos
+    let estados = fs::read_to_string(directorio.join("estados.csv")).unwrap();
+    assert!(estados.lines().count() > 1);
+    for linea in estados.lines().skip(1) {
+        assert!(ESTADOS_FINALES.iter().any(|estado| linea.contains(estado)), "{}", linea);
+    }
+    let saldos = fs::read_to_string(directorio.join("saldos.csv")).unwrap();
+    let columnas = saldos.lines().next().unwrap().split(',').count();
+    assert!(saldos.lines().all(|linea| linea.split(',').count() == columnas), "{}", saldos);
+
+    let _ = fs::remove_dir_all(&directorio);
+}
+
+#[test]
+fn la_segunda_senal_aborta_y_registra_las_transacciones_en_curso() {
+    let directorio = crear_directorio_de_trabajo("apagado_aborto");
+    let (mut proceso, salida) = iniciar_procesamiento(&directorio);
+
+    enviar_sigint(&proceso);
+    thread::sleep(Duration::from_millis(20));
+    enviar_sigint(&proceso);
+    let log = leer_resto(salida);
+    assert_eq!(proceso.wait().unwrap().code(), Some(130), "{}", log);
+    assert!(log.contains("SIGINT recibida otra vez: abortando"), "{}", log);
+    assert!(log.contains("Corrida abortada con"), "{}", log);
+    assert!(directorio.join("estados.csv").exists());
+
+    let en_curso = fs::read_to_string(directorio.join("en_curso.csv")).unwrap();
+    for linea in en_curso.lines().skip(1) {
+        assert!(!ESTADOS_FINALES.iter().any(|estado| linea.contains(estado)), "{}", linea);
+    }
+
+    let _ = fs::remove_dir_all(&directorio);
+}
@@ -0,0 +1,49 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+fn crear_directorio_de_trabajo(nombre: &str) -> PathBuf {
+    let directorio = std::env::temp_dir().join(format!("dinero_oxidado_{}_{}", nombre, std::process::id()));
+    let _ = fs::remove_dir_all(&directorio);
+    fs::create_dir_all(&directorio).unwrap();
+
+    directorio
+}
+
+fn correr(directorio: &Path, argumentos: &[&str], entorno: &[(&str, &str)]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_dinero-oxidado"))
+        .current_dir(directorio)
+        .args(argumentos)
+        .envs(entorno.iter().copied())
+        .output()
+        .expect("No se pudo correr el pipeline")
+}
+
+#[test]
+fn agrega_hilos_a_los_pools_atrasados_y_lo_registra_en_log_y_metricas() {
+    let directorio = crear_directorio_de_trabajo("autoescalado");
+    correr(&directorio, &["simular", "-c", "50", "-s", "2"], &[]);
+
+    let proceso = correr(
+        &directorio,
+        &["procesar", "-i", "1", "-o", "1", "-p", "1", "--autoescalar", "--metricas", "metricas.txt"],
+        &[("DINERO_AUTOESCALADO_CICLOS", "2"), ("DINERO_AUTOESCALADO_IA_MAXIMO", "3")],
+    );
+    let log = String::from_utf8_lossy(&proceso.stdout);
+    assert!(log.contains("Terminado"), "{}", log);
+    assert!(log.contains("Autoescalado de PROCESADOR IA: 1 -> 2 hilos (se agrega PROCESADOR IA 1"), "{}", log);
+    assert!(!log.contains("Autoescalado de PROCESADOR IA: 3 -> 4"), "{}", log);
+
+    let metricas = fs::read_to_string(directorio.join("metricas.txt")).unwrap();
+    assert!(metricas.contains("dinero_escalados_total{pool=\"PROCESADOR IA\",direccion=\"subir\"}"), "{}", metricas);
+    assert!(metricas.contains("dinero_hilos_pool{pool=\"WORKER CashOut\"}"), "{}", metricas);
+
+    // El mínimo no puede ser mayor que los workers con los que se arranca
+    let proceso = correr(&directorio, &["procesar", "-p", "1", "--autoescalar"], &[("DINERO_AUTOESCALADO_IA_MINIMO", "2")]);
+    let log = String::from_utf8_lossy(&proceso.stdout);
+    assert!(log.contains("autoescalado.ia"), "{}", log);
+
+    let _ = fs::remove_dir_all(&directorio);
+}
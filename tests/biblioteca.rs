@@ -0,0 +1,165 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+use uuid::Uuid;
+
+use dinero_oxidado::{
+    Cliente, Configuracion, EstadoTransaccion, LATENCIA_PRIORIDAD, Logger, Pipeline, Prioridad,
+    ProveedorAutorizacion, ProveedorMock, Redactor, RespuestaAutorizacion, TipoTransaccion,
+    Transaccion, Traza,
+};
+
+fn crear_directorio_de_trabajo(nombre: &str) -> PathBuf {
+    let directorio = std::env::temp_dir().join(format!("dinero_oxidado_{}_{}", nombre, std::process::id()));
+    let _ = fs::remove_dir_all(&directorio);
+    fs::create_dir_all(&directorio).unwrap();
+
+    directorio
+}
+
+/// Sin lavado de dinero y con el proveedor sin fallas, así todo lo que
+/// tiene saldo se liquida
+fn crear_configuracion(directorio: &Path) -> Configuracion {
+    let mut configuracion = Configuracion::default();
+    let en_directorio = |archivo: &str| directorio.join(archivo).to_string_lossy().into_owned();
+    let archivos = &mut configuracion.archivos;
+    archivos.saldos = en_directorio("saldos.csv");
+    archivos.rechazadas = en_directorio("rechazadas.csv");
+    archivos.fallidas = en_directorio("fallidas.csv");
+    configuracion.ia.probabilidad_lavado = 0.0;
+    configuracion.semillas.ia = Some(1);
+    configuracion.semillas.proveedor = Some(1);
+
+    configuracion
+}
+
+fn iniciar(configuracion: &Configuracion, clientes: Vec<Arc<Cliente>>) -> Pipeline {
+    Pipeline::iniciar(
+        configuracion,
+        Arc::new(clientes),
+        Arc::new(Logger::new_to_stdout()),
+        Arc::new(Redactor::default())
+    ).unwrap()
+}
+
+fn transaccion(id: u32, cliente: &Cliente, tipo: TipoTransaccion, monto: f32) -> Transaccion {
+    Transaccion { id, id_cliente: cliente.id, timestamp: 112_315_846_128, tipo, monto, traza: Traza::default(), prioridad: None }
+}
+
+#[test]
+fn el_pipeline_embebido_avisa_las_liquidadas_y_las_rechazadas() {
+    let directorio = crear_directorio_de_trabajo("biblioteca");
+    let ana = Arc::new(Cliente::con_saldo(Uuid::new_v4(), 100.0));
+    let beto = Arc::new(Cliente::con_saldo(Uuid::new_v4(), 100.0));
+    let pipeline = iniciar(&crear_configuracion(&directorio), vec![ana.clone(), beto.clone()]);
+    let resultados = pipeline.suscribir();
+
+    assert!(pipeline.enviar(transaccion(1, &ana, TipoTransaccion::CashIn, 50.0)).unwrap());
+    assert!(pipeline.enviar(transaccion(2, &beto, TipoTransaccion::CashOut, 500.0)).unwrap());
+    // Un id repetido no se vuelve a procesar
+    assert!(!pipeline.enviar(transaccion(1, &beto, TipoTransaccion::CashIn, 10.0)).unwrap());
+
+    let mut recibidos: Vec<_> = (0..2).map(|_| resultados.recv_timeout(Duration::from_secs(10)).unwrap()).collect();
+    recibidos.sort_by_key(|registro| registro.id_transaccion);
+    assert_eq!(recibidos[0].estado, EstadoTransaccion::Liquidada);
+    assert_eq!(recibidos[1].estado, EstadoTransaccion::Rechazada);
+    assert_eq!(recibidos[1].motivo.as_deref(), Some("Saldo disponible insuficiente"));
+    assert_eq!(pipeline.saldo(ana.id).unwrap().contable, 150.0);
+    assert_eq!(pipeline.saldo(beto.id).unwrap().disponible(), 100.0);
+    assert_eq!(pipeline.saldo(Uuid::new_v4()), None);
+    assert_eq!(pipeline.estado(1).unwrap().estado, EstadoTransaccion::Liquidada);
+
+    assert_eq!(pipeline.terminar().unwrap(), Vec::<String>::new());
+    let saldos = fs::read_to_string(directorio.join("saldos.csv")).unwrap();
+    assert_eq!(saldos.lines().count(), 2, "{}", saldos);
+    // El id repetido queda en rechazadas para poder conciliarlo
+    let rechazadas = fs::read_to_string(directorio.join("rechazadas.csv")).unwrap();
+    assert!(rechazadas.lines().any(|linea| linea.starts_with("1,") && linea.contains("Id de transacción repetido")), "{}", rechazadas);
+
+    let _ = fs::remove_dir_all(&directorio);
+}
+
+#[test]
+fn las_latencias_quedan_separadas_por_prioridad() {
+    let directorio = crear_directorio_de_trabajo("biblioteca_prioridad");
+    let cliente = Arc::new(Cliente::con_saldo(Uuid::new_v4(), 100.0));
+    let mut configuracion = crear_configuracion(&directorio);
+    configuracion.prioridad.monto_alta = Some(50.0);
+    let pipeline = iniciar(&configuracion, vec![cliente.clone()]);
+    let resultados = pipeline.suscribir();
+
+    pipeline.enviar(transaccion(1, &cliente, TipoTransaccion::CashIn, 80.0)).unwrap();
+    pipeline.enviar(transaccion(2, &cliente, TipoTransaccion::CashIn, 5.0)).unwrap();
+    // La columna Priority pesa más que el monto
+    let mut explicita = transaccion(3, &cliente, TipoTransaccion::CashIn, 1.0);
+    explicita.prioridad = Some(Prioridad::Alta);
+    pipeline.enviar(explicita).unwrap();
+    let liquidadas: Vec<_> = (0..3)
+        .map(|_| resultados.recv_timeout(Duration::from_secs(10)).unwrap())
+        .filter(|registro| registro.estado == EstadoTransaccion::Liquidada)
+        .map(|registro| registro.id_transaccion)
+        .collect();
+
+    let contexto = pipeline.contexto().clone();
+    assert!(pipeline.terminar().unwrap().is_empty());
+    let cantidad = |prioridad: Prioridad| {
+        contexto.metricas.resumen(&LATENCIA_PRIORIDAD, &[("prioridad", prioridad.nombre())]).map_or(0, |resumen| resumen.cantidad)
+    };
+    let altas = liquidadas.iter().filter(|id| **id != 2).count() as u64;
+    assert_eq!((cantidad(Prioridad::Alta), cantidad(Prioridad::Normal)), (altas, liquidadas.len() as u64 - altas), "{:?}", liquidadas);
+
+    let _ = fs::remove_dir_all(&directorio);
+}
+
+#[test]
+fn terminar_espera_lo_enviado_desde_otros_hilos() {
+    let directorio = crear_directorio_de_trabajo("biblioteca_hilos");
+    let cliente = Arc::new(Cliente::con_saldo(Uuid::new_v4(), 100.0));
+    let pipeline = iniciar(&crear_configuracion(&directorio), vec![cliente.clone()]);
+
+    let hilos: Vec<_> = (0..2).map(|hilo| {
+        let entrada = pipeline.entrada();
+        let cliente = cliente.clone();
+        thread::spawn(move || {
+            for id in 0..10 {
+                entrada.enrutar(transaccion(hilo * 10 + id, &cliente, TipoTransaccion::CashIn, 1.0)).unwrap();
+            }
+        })
+    }).collect();
+    for hilo in hilos {
+        hilo.join().unwrap();
+    }
+
+    let contexto = pipeline.contexto().clone();
+    assert!(pipeline.terminar().unwrap().is_empty());
+    // El proveedor no autoriza algunas operaciones aunque no falle
+    let resumen = contexto.estados.resumen();
+    let liquidadas = resumen[&EstadoTransaccion::Liquidada];
+    assert_eq!(liquidadas + resumen.get(&EstadoTransaccion::Rechazada).unwrap_or(&0), 20, "{:?}", resumen);
+    assert_eq!(cliente.get_saldos().contable, 100.0 + liquidadas as f32);
+    assert_eq!(contexto.estados.en_curso(), 0);
+
+    let _ = fs::remove_dir_all(&directorio);
+}
+
+#[test]
+fn el_proveedor_mock_contesta_en_orden_lo_configurado() {
+    let cliente = Cliente::con_saldo(Uuid::new_v4(), 100.0);
+    let proveedor = ProveedorMock::con_respuestas(vec![
+        RespuestaAutorizacion::Denegada("Sin fondos".into()),
+        RespuestaAutorizacion::Autorizada(ProveedorMock::hash_para(7)),
+    ]);
+    let lote = [transaccion(1, &cliente, TipoTransaccion::CashOut, 5.0), transaccion(2, &cliente, TipoTransaccion::CashIn, 5.0)];
+
+    let respuestas = proveedor.solicitar_lote(&lote.iter().collect::<Vec<_>>(), Duration::from_secs(1)).unwrap();
+
+    assert_eq!(respuestas, vec![
+        RespuestaAutorizacion::Denegada("Sin fondos".into()),
+        RespuestaAutorizacion::Autorizada(ProveedorMock::hash_para(7)),
+    ]);
+    assert_eq!((proveedor.solicitudes(), proveedor.lotes()), (vec![1, 2], vec![2]));
+}
@@ -0,0 +1,94 @@
+use std::{
+    fs,
+    io::{BufRead, BufReader},
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+};
+
+/// Mata al proveedor local aunque el test falle
+struct ProcesoProveedor(Child);
+
+impl Drop for ProcesoProveedor {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn iniciar_proveedor_local() -> (ProcesoProveedor, String) {
+    let mut proveedor = Command::new(env!("CARGO_BIN_EXE_proveedor_local"))
+        .args(["--semilla", "264"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("No se pudo iniciar el proveedor local");
+
+    let mut linea = String::new();
+    BufReader::new(proveedor.stdout.take().unwrap()).read_line(&mut linea).unwrap();
+    let direccion = linea
+        .trim()
+        .strip_prefix("Escuchando en ")
+        .expect("El proveedor local no informó su dirección")
+        .to_string();
+
+    (ProcesoProveedor(proveedor), format!("http://{}/autorizaciones", direccion))
+}
+
+fn crear_directorio_de_trabajo(nombre: &str) -> PathBuf {
+    let directorio = std::env::temp_dir().join(format!("dinero_oxidado_{}_{}", nombre, std::process::id()));
+    let _ = fs::remove_dir_all(&directorio);
+    fs::create_dir_all(&directorio).unwrap();
+
+    directorio
+}
+
+fn contar_registros(ruta: PathBuf) -> usize {
+    csv::Reader::from_path(ruta).unwrap().records().count()
+}
+
+#[test]
+fn pipeline_autoriza_contra_el_proveedor_local_por_http() {
+    let (_proveedor, url) = iniciar_proveedor_local();
+    let directorio = crear_directorio_de_trabajo("http");
+
+    let salida = Command::new(env!("CARGO_BIN_EXE_dinero-oxidado"))
+        .current_dir(&directorio)
+        .args(["todo", "-c", "5", "-s", "1", "-a", "2", "--proveedor_url", &url])
+        .output()
+        .expect("No se pudo correr el pipeline");
+    let log = String::from_utf8_lossy(&salida.stdout);
+
+    assert!(!log.contains("ERROR"), "{}", log);
+    assert!(log.contains(&format!("Usando el proveedor de autorizaciones en {}", url)));
+    assert!(log.contains("Terminado"));
+    assert!(contar_registros(directorio.join("saldos.csv")) > 0);
+    // El proveedor local nunca falla, así que nada agota los reintentos
+    assert_eq!(contar_registros(directorio.join("fallidas.csv")), 0);
+    // Toda transacción termina liquidada o rechazada, sin saltos de estado
+    assert!(!log.contains("Transición inválida"), "{}", log);
+    assert_eq!(
+        contar_registros(directorio.join("estados.csv")),
+        contar_registros(directorio.join("saldos.csv")) + contar_registros(directorio.join("rechazadas.csv"))
+    );
+
+    let _ = fs::remove_dir_all(&directorio);
+}
+
+#[test]
+fn pipeline_autoriza_de_a_lotes_contra_el_proveedor_local() {
+    let (_proveedor, url) = iniciar_proveedor_local();
+    let directorio = crear_directorio_de_trabajo("http_lotes");
+
+    let salida = Command::new(env!("CARGO_BIN_EXE_dinero-oxidado"))
+        .current_dir(&directorio)
+        .args(["todo", "-c", "5", "-s", "1", "-a", "2", "--proveedor_url", &url, "--lote", "8:20"])
+        .output()
+        .expect("No se pudo correr el pipeline");
+    let log = String::from_utf8_lossy(&salida.stdout);
+
+    assert!(!log.contains("ERROR"), "{}", log);
+    assert!(log.contains("Solicitando autorización de un lote de"));
+    assert!(contar_registros(directorio.join("saldos.csv")) > 0);
+    assert_eq!(contar_registros(directorio.join("fallidas.csv")), 0);
+
+    let _ = fs::remove_dir_all(&directorio);
+}
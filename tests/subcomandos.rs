@@ -0,0 +1,174 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+fn crear_directorio_de_trabajo(nombre: &str) -> PathBuf {
+    let directorio = std::env::temp_dir().join(format!("dinero_oxidado_{}_{}", nombre, std::process::id()));
+    let _ = fs::remove_dir_all(&directorio);
+    fs::create_dir_all(&directorio).unwrap();
+
+    directorio
+}
+
+fn correr(directorio: &Path, argumentos: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_dinero-oxidado"))
+        .current_dir(directorio)
+        .args(argumentos)
+        .output()
+        .expect("No se pudo correr el pipeline")
+}
+
+#[test]
+fn simular_procesar_verificar_y_reportar_por_separado_con_rutas_propias() {
+    let directorio = crear_directorio_de_trabajo("subcomandos");
+    let entradas = ["--transacciones", "entrada.csv", "--archivo_clientes", "cuentas.csv"];
+    let salidas = ["--saldos", "s.csv", "--rechazadas", "r.csv", "--fallidas", "f.csv", "--estados", "e.csv"];
+
+    let simulacion = correr(&directorio, &[&["simular", "-c", "5", "-s", "1"][..], &entradas].concat());
+    assert!(directorio.join("entrada.csv").exists());
+    assert!(directorio.join("cuentas.csv").exists());
+    assert!(!directorio.join("s.csv").exists(), "{}", String::from_utf8_lossy(&simulacion.stdout));
+
+    let proceso = correr(&directorio, &[&["procesar", "-a", "2", "-e", "3"][..], &entradas, &salidas].concat());
+    let log = String::from_utf8_lossy(&proceso.stdout);
+    assert!(log.contains("5 clientes leídos de cuentas.csv"), "{}", log);
+    assert!(log.contains("Terminado"), "{}", log);
+    assert!(!directorio.join("saldos.csv").exists());
+
+    let verificacion = correr(&directorio, &[&["verificar"][..], &entradas, &salidas].concat());
+    let log = String::from_utf8_lossy(&verificacion.stdout);
+    assert!(log.contains("; 0 discrepancias"), "{}", log);
+
+    let reporte = correr(&directorio, &["reportar", "--transacciones", "entrada.csv", "--archivo_clientes", "cuentas.csv", "--saldos", "s.csv", "--rechazadas", "r.csv", "--fallidas", "f.csv", "--reporte", "resumen.csv", "--redaccion", "mascara"]);
+    let log = String::from_utf8_lossy(&reporte.stdout);
+    assert!(log.contains("Resumen de 5 clientes escrito en resumen.csv"), "{}", log);
+    let resumen = fs::read_to_string(directorio.join("resumen.csv")).unwrap();
+    assert!(resumen.lines().skip(1).all(|linea| linea.starts_with("****")), "{}", resumen);
+
+    // Una salida alterada no pasa la verificación
+    let saldos = fs::read_to_string(directorio.join("s.csv")).unwrap();
+    fs::write(directorio.join("s.csv"), saldos.lines().take(saldos.lines().count() - 1).collect::<Vec<_>>().join("\n")).unwrap();
+    let verificacion = correr(&directorio, &[&["verificar"][..], &entradas, &salidas].concat());
+    let log = String::from_utf8_lossy(&verificacion.stdout);
+    assert!(log.contains("ERROR: La verificación encontró"), "{}", log);
+
+    let _ = fs::remove_dir_all(&directorio);
+}
+
+#[test]
+fn config_mostrar_combina_archivo_argumentos_y_entorno() {
+    let directorio = crear_directorio_de_trabajo("config");
+    fs::write(directorio.join("dinero.toml"), "[workers]\nia = 2\ncash_in = 3\ncash_out = 4\n\n[proveedor]\nperfil = \"lento\"\n").unwrap();
+
+    let salida = Command::new(env!("CARGO_BIN_EXE_dinero-oxidado"))
+        .current_dir(&directorio)
+        .args(["config", "mostrar", "-i", "6", "-o", "7"])
+        .env("DINERO_WORKERS_CASH_OUT", "8")
+        .output()
+        .unwrap();
+    let configuracion = String::from_utf8_lossy(&salida.stdout);
+    assert!(configuracion.contains("[workers]\nia = 2\ncash_in = 6\ncash_out = 8\n"), "{}", configuracion);
+    assert!(configuracion.contains("perfil = 'lento'"), "{}", configuracion);
+
+    let salida = correr(&directorio, &["procesar", "-p", "muchos"]);
+    let log = String::from_utf8_lossy(&salida.stdout);
+    assert!(log.contains("ERROR: workers.ia inválido en los argumentos: 'muchos'"), "{}", log);
+
+    let _ = fs::remove_dir_all(&directorio);
+}
+
+#[test]
+fn procesar_con_un_modelo_registra_su_version_en_cada_decision() {
+    let directorio = crear_directorio_de_trabajo("subcomandos_modelo");
+    // Fraude desde los 500 de monto
+    fs::write(directorio.join("modelo.json"), r#"{
+        "version": "prueba-1",
+        "tipo": "regresion_logistica",
+        "sesgo": -10.0,
+        "pesos": { "monto": 0.02 }
+    }"#).unwrap();
+    correr(&directorio, &["simular", "-c", "5", "-s", "2"]);
+
+    let proceso = correr(&directorio, &["procesar", "-a", "2", "-e", "3", "--modelo", "modelo.json"]);
+    let log = String::from_utf8_lossy(&proceso.stdout);
+    assert!(log.contains("Usando el modelo prueba-1 con umbral 0.5"), "{}", log);
+
+    let decisiones = fs::read_to_string(directorio.join("decisiones.csv")).unwrap();
+    let rechazadas = fs::read_to_string(directorio.join("rechazadas.csv")).unwrap();
+    let transacciones = fs::read_to_string(directorio.join("transacciones.csv")).unwrap();
+    let monto = |id: &str| -> f64 {
+        transacciones.lines().find(|linea| linea.starts_with(&format!("{},", id))).unwrap().split(',').nth(4).unwrap().parse().unwrap()
+    };
+    assert_eq!(decisiones.lines().next(), Some("Transaction,Model_version,Probability,Rejected"));
+    assert!(decisiones.lines().count() > 1, "{}", decisiones);
+    for decision in decisiones.lines().skip(1) {
+        let campos: Vec<_> = decision.split(',').collect();
+        assert_eq!(campos[1], "prueba-1");
+        assert_eq!(campos[3] == "true", monto(campos[0]) >= 500.0, "{}", decision);
+        let rechazo_del_modelo = format!("{},", campos[0]);
+        let rechazada = rechazadas.lines().any(|linea| linea.starts_with(&rechazo_del_modelo) && linea.contains("Fraude según el modelo"));
+        assert_eq!(rechazada, campos[3] == "true", "{}", decision);
+    }
+
+    let verificacion = correr(&directorio, &["verificar"]);
+    assert!(String::from_utf8_lossy(&verificacion.stdout).contains("; 0 discrepancias"));
+
+    let _ = fs::remove_dir_all(&directorio);
+}
+
+#[test]
+fn un_modelo_en_modo_sombra_se_compara_sin_cambiar_lo_que_se_liquida() {
+    let directorio = crear_directorio_de_trabajo("subcomandos_sombra");
+    // Sin perfiles los dos detectores dependen solo del monto
+    fs::write(directorio.join("dinero.toml"), "[ia.perfiles]\nactivo = false\n\n[ia.sombra.perfiles]\nactivo = false\n").unwrap();
+    for (archivo, version, sesgo) in [("activo.json", "activo-1", -10.0), ("candidato.json", "candidato-2", -6.0)] {
+        // Fraude desde los 500 de monto para el activo y desde los 300 para el candidato
+        fs::write(directorio.join(archivo), format!(
+            r#"{{ "version": "{}", "tipo": "regresion_logistica", "sesgo": {}, "pesos": {{ "monto": 0.02 }} }}"#, version, sesgo
+        )).unwrap();
+    }
+    correr(&directorio, &["simular", "-c", "5", "-s", "2"]);
+
+    let proceso = correr(&directorio, &["procesar", "-a", "2", "-e", "3", "--modelo", "activo.json", "--modelo_sombra", "candidato.json"]);
+    let log = String::from_utf8_lossy(&proceso.stdout);
+    assert!(log.contains("Modelo candidato-2 con umbral 0.5 en modo sombra, comparación en comparacion_sombra.csv"), "{}", log);
+    assert!(log.contains("Modo sombra: "), "{}", log);
+
+    let transacciones = fs::read_to_string(directorio.join("transacciones.csv")).unwrap();
+    let monto = |id: &str| -> f64 {
+        transacciones.lines().find(|linea| linea.starts_with(&format!("{},", id))).unwrap().split(',').nth(4).unwrap().parse().unwrap()
+    };
+    let comparacion = fs::read_to_string(directorio.join("comparacion_sombra.csv")).unwrap();
+    let decisiones = fs::read_to_string(directorio.join("decisiones.csv")).unwrap();
+    let rechazadas = fs::read_to_string(directorio.join("rechazadas.csv")).unwrap();
+    assert_eq!(
+        comparacion.lines().next(),
+        Some("Transaction,Agree,Active_rejected,Active_score,Active_reason,Shadow_rejected,Shadow_score,Shadow_reason")
+    );
+    // Cada transacción que decidió el activo tiene su comparación
+    assert_eq!(comparacion.lines().count(), decisiones.lines().count());
+    assert!(!decisiones.contains("candidato-2"), "{}", decisiones);
+    let mut desacuerdos = 0;
+    for fila in comparacion.lines().skip(1) {
+        let campos: Vec<_> = fila.split(',').collect();
+        let monto = monto(campos[0]);
+        assert_eq!(campos[2] == "true", monto >= 500.0, "{}", fila);
+        assert_eq!(campos[5] == "true", monto >= 300.0, "{}", fila);
+        assert_eq!(campos[1] == "true", campos[2] == campos[5], "{}", fila);
+        assert!(campos[7].contains("el modelo candidato-2 con umbral 0.5 dio"), "{}", fila);
+        // Lo que rechaza solo la sombra se liquida igual
+        let rechazada = rechazadas.lines().any(|linea| linea.starts_with(&format!("{},", campos[0])));
+        assert_eq!(rechazada, campos[2] == "true", "{}", fila);
+        if campos[1] == "false" {
+            desacuerdos += 1;
+        }
+    }
+    assert!(log.contains(&format!("0 rechazadas solo por el activo, {} solo por la sombra", desacuerdos)), "{}", log);
+
+    let verificacion = correr(&directorio, &["verificar"]);
+    assert!(String::from_utf8_lossy(&verificacion.stdout).contains("; 0 discrepancias"));
+
+    let _ = fs::remove_dir_all(&directorio);
+}
@@ -0,0 +1,50 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+fn crear_directorio_de_trabajo(nombre: &str) -> PathBuf {
+    let directorio = std::env::temp_dir().join(format!("dinero_oxidado_{}_{}", nombre, std::process::id()));
+    let _ = fs::remove_dir_all(&directorio);
+    fs::create_dir_all(&directorio).unwrap();
+
+    directorio
+}
+
+fn correr(directorio: &Path, argumentos: &[&str], entorno: &[(&str, &str)]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_dinero-oxidado"))
+        .current_dir(directorio)
+        .args(argumentos)
+        .envs(entorno.iter().copied())
+        .output()
+        .expect("No se pudo correr el pipeline")
+}
+
+#[test]
+fn una_etapa_en_panico_hace_fallar_la_corrida_con_su_diagnostico() {
+    let directorio = crear_directorio_de_trabajo("supervisor");
+    correr(&directorio, &["simular", "-c", "5", "-s", "1"], &[]);
+    // Sin la mayoría de los clientes el worker final entra en pánico al
+    // liquidar sus transacciones
+    let clientes = fs::read_to_string(directorio.join("clientes.csv")).unwrap();
+    fs::write(directorio.join("clientes.csv"), clientes.lines().take(2).collect::<Vec<_>>().join("\n")).unwrap();
+
+    let proceso = correr(&directorio, &["procesar", "--anomalias"], &[("DINERO_IA_PERFILES_ARCHIVO", "perfiles.json")]);
+    let log = String::from_utf8_lossy(&proceso.stdout);
+    assert!(log.contains("ERROR: Corrida fallida: WORKER FINAL entró en pánico con la transacción"), "{}", log);
+    // Aunque la corrida falló, lo aprendido de los clientes no se pierde
+    assert!(log.contains("clientes guardados"), "{}", log);
+    assert!(directorio.join("perfiles.json").exists());
+    assert!(log.contains("No se encuentra cliente con id"), "{}", log);
+    assert!(!log.contains("Reiniciando"), "{}", log);
+    let en_curso = fs::read_to_string(directorio.join("en_curso.csv")).unwrap();
+    assert!(en_curso.lines().count() > 1, "{}", en_curso);
+
+    // El worker final es dueño de su canal de entrada: no se puede reiniciar
+    let proceso = correr(&directorio, &["procesar"], &[("DINERO_SUPERVISOR_WORKER_FINAL", "reiniciar")]);
+    let log = String::from_utf8_lossy(&proceso.stdout);
+    assert!(log.contains("supervisor: worker_final: no se puede reiniciar"), "{}", log);
+
+    let _ = fs::remove_dir_all(&directorio);
+}